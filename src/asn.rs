@@ -0,0 +1,83 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use arc_swap::ArcSwapOption;
+use log::{debug, info};
+use maxminddb::{geoip2, Reader};
+
+/// ASN database of the `IP-ASN` rules, shared by every rule layer.
+static ASN_DATABASE: ArcSwapOption<Reader<Vec<u8>>> = ArcSwapOption::const_empty();
+
+/// Load the MMDB file (e.g. GeoLite2-ASN.mmdb) the `IP-ASN` rules look IPs
+/// up in, replacing the one loaded before. Without one, `IP-ASN` rules never
+/// match.
+pub fn load_asn_database(path: impl AsRef<Path>) -> Result<()> {
+    let reader = Reader::open_readfile(path.as_ref())?;
+    info!(
+        "Loaded ASN database {:?} ({})",
+        path.as_ref(),
+        reader.metadata.database_type
+    );
+    ASN_DATABASE.store(Some(Arc::new(reader)));
+    Ok(())
+}
+
+/// Autonomous system announcing `ip`, `None` without a database or entry.
+pub(crate) fn lookup_asn(ip: IpAddr) -> Option<u32> {
+    let database = ASN_DATABASE.load();
+    let database = database.as_ref()?;
+    match database.lookup::<geoip2::Asn>(ip) {
+        Ok(asn) => asn.autonomous_system_number,
+        Err(e) => {
+            debug!("No ASN for {}: {}", ip, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Smallest IPv4 MMDB: `0.0.0.0/1` announced by `asn`, nothing else.
+    pub(crate) fn test_database(asn: u16) -> Vec<u8> {
+        let string = |s: &str| [&[0x40 | s.len() as u8][..], s.as_bytes()].concat();
+        // One node of 24 bit records: left is the data, right not found
+        let mut mmdb = vec![0, 0, 17, 0, 0, 1];
+        mmdb.extend_from_slice(&[0; 16]);
+        mmdb.push(0xE1);
+        mmdb.extend(string("autonomous_system_number"));
+        mmdb.extend_from_slice(&[0xC2, (asn >> 8) as u8, asn as u8]);
+        mmdb.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        mmdb.push(0xE9);
+        for (key, value) in [
+            ("binary_format_major_version", &[0xA1, 2][..]),
+            ("binary_format_minor_version", &[0xA0]),
+            ("build_epoch", &[0x00, 0x02]),
+            ("database_type", &string("Test-ASN")),
+            ("description", &[0xE0]),
+            ("ip_version", &[0xA1, 4]),
+            ("languages", &[0x00, 0x04]),
+            ("node_count", &[0xC1, 1]),
+            ("record_size", &[0xA1, 24]),
+        ] {
+            mmdb.extend(string(key));
+            mmdb.extend_from_slice(value);
+        }
+        mmdb
+    }
+
+    #[test]
+    fn looks_up_the_announcing_asn() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kitty_asn_{}.mmdb", std::process::id()));
+        std::fs::write(&path, test_database(13335))?;
+        let loaded = load_asn_database(&path);
+        std::fs::remove_file(&path)?;
+        loaded?;
+        assert_eq!(lookup_asn("1.1.1.1".parse()?), Some(13335));
+        assert_eq!(lookup_asn("192.168.1.1".parse()?), None);
+        Ok(())
+    }
+}
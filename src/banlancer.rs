@@ -1,58 +1,803 @@
+use addr::parse_domain_name;
+use arc_swap::{ArcSwap, Guard};
+use log::warn;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::config::NoNodePolicy;
+use crate::groups::{GroupChoice, ProxyGroups};
+use crate::learned::LearnedRoutes;
+use crate::outbound::{OutboundOptions, UpstreamHop};
+use crate::snapshot::NodeSnapshot;
 use crate::traits::BanlancerTrait;
-use crate::types::Address;
+use crate::types::{Address, ResponseCode};
 use crate::NodeInfo;
 
+/// How often `select_node` looks for a recovered node under `NoNodePolicy::Wait`.
+const NODE_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+/// Destinations with a latency history, the least recently used is forgotten first.
+const MAX_LATENCY_DESTINATIONS: usize = 1024;
+/// Weight of a new sample in the moving average of a node's latency.
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+/// Time for the failure penalty of a node to halve.
+const FAILURE_HALF_LIFE: Duration = Duration::from_secs(60);
+/// Time for a node back up to get its full share of new connections.
+const SLOW_START: Duration = Duration::from_secs(30);
+/// Share of its weight a node gets right after coming back up.
+const SLOW_START_MIN_RAMP: f32 = 0.05;
+
+/// Network a destination belongs to, standing in for its ASN: the /24 (IPv4)
+/// or /48 (IPv6) of an address, the registrable domain of a host name.
+pub fn destination_key(destination: &Address) -> String {
+    fn ip_key(ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                format!("{}.{}.{}.0/24", a, b, c)
+            }
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+            }
+        }
+    }
+    match destination {
+        Address::SocketAddress(addr) => ip_key(addr.ip()),
+        Address::DomainNameAddress(domain, _) => match domain.parse() {
+            Ok(ip) => ip_key(ip),
+            Err(_) => match parse_domain_name(domain) {
+                Ok(name) => name.root().unwrap_or(domain).to_string(),
+                Err(_) => domain.to_string(),
+            },
+        },
+    }
+}
+
+/// Connect latencies of the nodes used for one destination network.
+#[derive(Clone)]
+struct LatencyHistory {
+    nodes: HashMap<SocketAddr, Duration>,
+    last_used: Instant,
+}
+
+/// Recent connect failures of a node, each counting 1 and halving every
+/// `FAILURE_HALF_LIFE`, a flaky node gets picked less instead of going down.
+#[derive(Default)]
+struct FailureMemory(Mutex<Option<(f64, Instant)>>);
+
+impl FailureMemory {
+    fn penalty(&self) -> f64 {
+        let last = *self.0.lock().unwrap();
+        last.map_or(0.0, |(penalty, at)| decay(penalty, at.elapsed()))
+    }
+
+    fn record_failure(&self) {
+        let mut last = self.0.lock().unwrap();
+        let now = Instant::now();
+        let penalty = last.map_or(0.0, |(penalty, at)| decay(penalty, now - at));
+        *last = Some((penalty + 1.0, now));
+    }
+}
+
+fn decay(penalty: f64, elapsed: Duration) -> f64 {
+    penalty * 0.5f64.powf(elapsed.as_secs_f64() / FAILURE_HALF_LIFE.as_secs_f64())
+}
+
+/// A node with its counters, shared with the connections using it and
+/// carried over by `with_nodes` while the node keeps its address.
+struct NodeState {
+    info: NodeInfo,
+    connections: Arc<AtomicUsize>,
+    /// Marked down, skipped by `pick_node`
+    down: Arc<AtomicBool>,
+    /// Drained for maintenance, skipped by `pick_node` whatever its health
+    draining: Arc<AtomicBool>,
+    failures: Arc<FailureMemory>,
+    /// Last time the node was marked up again after being down
+    recovered_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl NodeState {
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Up and not draining, new connections may use it.
+    fn is_available(&self) -> bool {
+        !self.is_down() && !self.is_draining()
+    }
+
+    /// Share of its weight the node gets, growing from `SLOW_START_MIN_RAMP`
+    /// to 1 over `SLOW_START` after it came back up.
+    fn ramp(&self) -> f32 {
+        let recovered_at = *self.recovered_at.lock().unwrap();
+        let Some(recovered_at) = recovered_at else {
+            return 1.0;
+        };
+        let ramp = recovered_at.elapsed().as_secs_f32() / SLOW_START.as_secs_f32();
+        ramp.clamp(SLOW_START_MIN_RAMP, 1.0)
+    }
+
+    /// Connection count relative to the ramped up weight. A node in slow
+    /// start counts as busy even while idle, otherwise least connected
+    /// picking would send it every new connection.
+    fn load(&self) -> f32 {
+        let ramp = self.ramp();
+        let connections = self.connections() as f32 + 1.0 - ramp;
+        connections / (self.info.node_number as f32 * ramp)
+    }
+}
+
+/// Nodes of a listener with their connection counts. Counts and health are
+/// atomics, picking a node and counting connections don't lock, except for
+/// the latency histories of latency routing and the short lock reading the
+/// failure penalty of a node.
 #[derive(Default)]
 pub struct ConnectionStatsBanlancer {
-    statistics_map: HashMap<NodeInfo, usize>,
+    nodes: Vec<NodeState>,
+    /// Keyed by `destination_key`
+    latencies: Mutex<HashMap<String, LatencyHistory>>,
 }
 
 impl ConnectionStatsBanlancer {
-    pub fn from_vec(node_infos: &Vec<NodeInfo>) -> Self {
-        let mut statistics_map: HashMap<NodeInfo, usize> = HashMap::with_capacity(node_infos.len());
-        for node_info in node_infos.iter() {
-            statistics_map.insert(*node_info, 0);
+    fn node(&self, socket_addr: &SocketAddr) -> Option<&NodeState> {
+        self.nodes
+            .iter()
+            .find(|node| node.info.socket_addr == *socket_addr)
+    }
+
+    /// Healthy nodes not draining and below their `max_connections`, with
+    /// their connection count relative to their weight, raised by their
+    /// recent failures and while they slow start.
+    fn healthy_loads(&self) -> impl Iterator<Item = (NodeInfo, f32)> + '_ {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_available())
+            .filter(|node| node.info.max_connections.is_none_or(|max| node.connections() < max))
+            .map(|node| {
+                let load = node.load();
+                // A fresh failure weighs like twice the load of an idle node
+                let penalty = node.failures.penalty() as f32;
+                (node.info, (1.0 + load) * (1.0 + penalty) - 1.0)
+            })
+    }
+
+    /// Least connected node among those not marked down or at capacity.
+    /// Picking involves no randomness: ties go to the node listed first, so
+    /// balancing replays the same way in tests.
+    pub fn pick_node(&self) -> Option<NodeInfo> {
+        self.pick(None, |_| true)
+    }
+
+    /// Node connecting fastest to the `destination_key` network, weighted by
+    /// its load. Nodes never used for the destination are tried first, least
+    /// connected one first.
+    pub fn pick_node_for(&self, destination: &str) -> Option<NodeInfo> {
+        self.pick(Some(destination), |_| true)
+    }
+
+    /// Best node other than `first`, raced against it by hedged connects.
+    pub fn pick_runner_up(&self, first: SocketAddr, destination: Option<&str>) -> Option<NodeInfo> {
+        self.pick(destination, |node| *node != first)
+    }
+
+    /// Best node among `nodes`, as `pick_node` or `pick_node_for`.
+    pub fn pick_among(&self, nodes: &[SocketAddr], destination: Option<&str>) -> Option<NodeInfo> {
+        self.pick(destination, |node| nodes.contains(node))
+    }
+
+    /// First node of `preferred` not marked down nor at capacity.
+    pub fn pick_first(&self, preferred: &[SocketAddr]) -> Option<NodeInfo> {
+        preferred.iter().find_map(|node| {
+            self.healthy_loads()
+                .map(|(node_info, _)| node_info)
+                .find(|node_info| node_info.socket_addr == *node)
+        })
+    }
+
+    fn pick<F>(&self, destination: Option<&str>, candidate: F) -> Option<NodeInfo>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let candidates = || {
+            self.healthy_loads()
+                .filter(|(node_info, _)| candidate(&node_info.socket_addr))
+        };
+        // Only latency routing waits on the histories
+        let latencies = destination.map(|_| self.latencies.lock().unwrap());
+        let history = latencies
+            .as_ref()
+            .zip(destination)
+            .and_then(|(latencies, destination)| latencies.get(destination));
+        let Some(history) = history else {
+            return candidates()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(node_info, _)| node_info);
+        };
+        let untried = candidates()
+            .filter(|(node_info, _)| !history.nodes.contains_key(&node_info.socket_addr))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((node_info, _)) = untried {
+            return Some(node_info);
         }
-        Self { statistics_map }
+        candidates()
+            .filter_map(|(node_info, load)| {
+                let latency = history.nodes.get(&node_info.socket_addr)?;
+                Some((node_info, latency.as_secs_f32() * (1.0 + load)))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(node_info, _)| node_info)
     }
 
-    pub async fn get_least_connected_node(&self) -> NodeInfo {
-        let new_map: HashMap<NodeInfo, f32> = self
-            .statistics_map
+    /// Add a connect latency of `node` to the `destination_key` network.
+    pub fn record_latency(&self, node: SocketAddr, destination: &str, latency: Duration) {
+        let now = Instant::now();
+        let mut latencies = self.latencies.lock().unwrap();
+        if !latencies.contains_key(destination) && latencies.len() >= MAX_LATENCY_DESTINATIONS {
+            let stalest = latencies
+                .iter()
+                .min_by_key(|(_, history)| history.last_used)
+                .map(|(destination, _)| destination.clone());
+            if let Some(stalest) = stalest {
+                latencies.remove(&stalest);
+            }
+        }
+        let history = latencies
+            .entry(destination.to_string())
+            .or_insert_with(|| LatencyHistory {
+                nodes: HashMap::new(),
+                last_used: now,
+            });
+        history.last_used = now;
+        history
+            .nodes
+            .entry(node)
+            .and_modify(|average| {
+                *average = average.mul_f64(1.0 - LATENCY_EWMA_WEIGHT)
+                    + latency.mul_f64(LATENCY_EWMA_WEIGHT)
+            })
+            .or_insert(latency);
+    }
+
+    /// Count a failed connect through `socket_addr`, lowering its chances
+    /// to be picked until the failure decays.
+    pub fn record_failure(&self, socket_addr: SocketAddr) {
+        if let Some(node) = self.node(&socket_addr) {
+            node.failures.record_failure();
+        }
+    }
+
+    /// Mark a node down or up, a node coming back up slow starts.
+    pub fn set_node_healthy(&self, socket_addr: SocketAddr, healthy: bool) {
+        if let Some(node) = self.node(&socket_addr) {
+            let was_down = node.down.swap(!healthy, Ordering::Relaxed);
+            if was_down && healthy {
+                *node.recovered_at.lock().unwrap() = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Stop (or resume) picking `socket_addr` for new connections, its open
+    /// connections are left alone. `false` for unknown nodes.
+    pub fn set_node_draining(&self, socket_addr: SocketAddr, draining: bool) -> bool {
+        let Some(node) = self.node(&socket_addr) else {
+            return false;
+        };
+        node.draining.store(draining, Ordering::Relaxed);
+        true
+    }
+
+    pub fn node_snapshots(&self) -> Vec<NodeSnapshot> {
+        let mut nodes: Vec<NodeSnapshot> = self
+            .nodes
             .iter()
-            .map(|(&key, value)| (key, *value as f32 / key.node_number as f32))
+            .map(|node| NodeSnapshot {
+                addr: node.info.socket_addr,
+                weight: node.info.node_number,
+                connections: node.connections(),
+                max_connections: node.info.max_connections,
+                healthy: !node.is_down(),
+                draining: node.is_draining(),
+                failure_penalty: node.failures.penalty(),
+            })
             .collect();
-        let target = new_map
+        nodes.sort_by_key(|node| node.addr);
+        nodes
+    }
+
+    /// Whether `socket_addr` is a node neither marked down nor draining.
+    pub fn is_healthy(&self, socket_addr: &SocketAddr) -> bool {
+        self.node(socket_addr).is_some_and(NodeState::is_available)
+    }
+
+    pub fn healthy_count(&self) -> usize {
+        self.nodes.iter().filter(|node| node.is_available()).count()
+    }
+
+    /// Whether `socket_addr` is a node not marked down, though maybe
+    /// draining: it will take connections again, unlike a dead one.
+    pub(crate) fn is_up(&self, socket_addr: &SocketAddr) -> bool {
+        self.node(socket_addr).is_some_and(|node| !node.is_down())
+    }
+
+    /// Balancer for `node_infos`, connection counts and health are carried
+    /// over for nodes keeping their socket address. Connections still open on
+    /// removed nodes are not counted anywhere anymore. Weights below 1 count
+    /// as 1, a node can't be weighted out.
+    pub fn with_nodes(&self, node_infos: &[NodeInfo]) -> Self {
+        let nodes = node_infos
             .iter()
-            .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-            .map(|(key, _)| key)
-            .unwrap()
-            .to_owned();
-        target
+            .map(|node_info| NodeInfo {
+                node_number: node_info.node_number.max(1),
+                ..*node_info
+            })
+            .map(|info| match self.node(&info.socket_addr) {
+                Some(kept) => NodeState {
+                    info,
+                    connections: Arc::clone(&kept.connections),
+                    down: Arc::clone(&kept.down),
+                    draining: Arc::clone(&kept.draining),
+                    failures: Arc::clone(&kept.failures),
+                    recovered_at: Arc::clone(&kept.recovered_at),
+                },
+                None => NodeState {
+                    info,
+                    connections: Arc::default(),
+                    down: Arc::default(),
+                    draining: Arc::default(),
+                    failures: Arc::default(),
+                    recovered_at: Arc::default(),
+                },
+            })
+            .collect();
+        let is_kept = |addr: &SocketAddr| {
+            node_infos
+                .iter()
+                .any(|node_info| node_info.socket_addr == *addr)
+        };
+        let mut latencies = self.latencies.lock().unwrap().clone();
+        latencies.retain(|_, history| {
+            history.nodes.retain(|addr, _| is_kept(addr));
+            !history.nodes.is_empty()
+        });
+        Self {
+            nodes,
+            latencies: Mutex::new(latencies),
+        }
     }
 
-    pub fn incre_count_by_node_info(&mut self, node_info: &NodeInfo) {
-        self.statistics_map
-            .entry(node_info.to_owned())
-            .and_modify(|v| *v += 1);
+    /// Open connections to the node at `socket_addr`, `None` for unknown nodes.
+    #[cfg(test)]
+    pub fn count_by_addr(&self, socket_addr: &SocketAddr) -> Option<usize> {
+        self.node(socket_addr).map(NodeState::connections)
     }
 
-    pub fn decre_count_by_node_info(&mut self, node_info: &NodeInfo) {
-        self.statistics_map
-            .entry(node_info.to_owned())
-            .and_modify(|v| *v -= 1);
+    /// Count a connection to `node_info` until the returned guard is dropped,
+    /// `None` for unknown nodes. Looked up by address, the node weight may
+    /// have changed since it was picked.
+    #[cfg(test)]
+    pub fn count_connection(&self, node_info: &NodeInfo) -> Option<CountedConnection> {
+        let node = self.node(&node_info.socket_addr)?;
+        node.connections.fetch_add(1, Ordering::Relaxed);
+        Some(CountedConnection(Arc::clone(&node.connections)))
+    }
+
+    /// Count a connection to `node_info` until the returned guard is dropped,
+    /// unless the node reached its `max_connections` since it was picked.
+    fn reserve(&self, node_info: &NodeInfo) -> Option<CountedConnection> {
+        let node = self.node(&node_info.socket_addr)?;
+        let max = node.info.max_connections.unwrap_or(usize::MAX);
+        node.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(CountedConnection(Arc::clone(&node.connections)))
+    }
+
+    /// Node of `pick` with a connection counted on it. Connections racing
+    /// for the last slot of a node make `pick` run again, skipping it.
+    fn pick_reserved(
+        &self,
+        mut pick: impl FnMut(&Self) -> Option<NodeInfo>,
+    ) -> Option<(NodeInfo, CountedConnection)> {
+        loop {
+            let node_info = pick(self)?;
+            if let Some(counted) = self.reserve(&node_info) {
+                return Some((node_info, counted));
+            }
+        }
+    }
+}
+
+/// A connection counted on its node, until dropped.
+#[derive(Debug)]
+pub struct CountedConnection(Arc<AtomicUsize>);
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_sub(1));
     }
 }
 
 impl BanlancerTrait for ConnectionStatsBanlancer {
     async fn get_best_node(&self) -> Address {
-        let node = self.get_least_connected_node().await;
+        let node = self.pick_node().expect("no healthy VPN node");
         Address::from(node)
     }
 }
 
-pub type ArcConnectionStatsBanlancer = Arc<Mutex<Option<ConnectionStatsBanlancer>>>;
+/// VPN nodes of a listener with its proxy groups and learned routes, created
+/// empty along with it and filled by `replace_nodes`. Connections load the
+/// current balancer without locking.
+#[derive(Default)]
+pub struct NodeRegistry {
+    banlancer: ArcSwap<ConnectionStatsBanlancer>,
+    groups: ProxyGroups,
+    learned: LearnedRoutes,
+}
+
+impl NodeRegistry {
+    /// Balancer as of now, later `replace_nodes` calls don't affect it.
+    pub fn load(&self) -> Guard<Arc<ConnectionStatsBanlancer>> {
+        self.banlancer.load()
+    }
+
+    /// Install `node_infos`, migrating the counts of the current nodes.
+    pub fn replace_nodes(&self, node_infos: &[NodeInfo]) {
+        self.banlancer.rcu(|current| current.with_nodes(node_infos));
+    }
+
+    pub fn groups(&self) -> &ProxyGroups {
+        &self.groups
+    }
+
+    pub fn learned(&self) -> &LearnedRoutes {
+        &self.learned
+    }
+
+    /// Node for a proxied connection, counted on it until the returned guard
+    /// is dropped, which a failed connect does right away. `Ok(None)` when
+    /// `policy` falls back to a direct connection because no node is
+    /// healthy. Healthy nodes all at
+    /// capacity fail with `NodesSaturated` unless `policy` waits. With a
+    /// `destination_key` the node is picked by its latency history. With a
+    /// proxy `group` only its nodes are used, all of them for unknown groups.
+    /// A node address as `group` only uses that node.
+    pub async fn select_node(
+        &self,
+        policy: NoNodePolicy,
+        destination: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Option<(NodeInfo, CountedConnection)>, ResponseCode> {
+        let choice = group.and_then(|group| {
+            let choice = self.groups.choice(group).or_else(|| {
+                let node = group.parse::<SocketAddr>().ok()?;
+                Some(GroupChoice::First(vec![node]))
+            });
+            if choice.is_none() {
+                warn!("Unknown proxy group {}, using all nodes", group);
+            }
+            choice
+        });
+        let started = Instant::now();
+        loop {
+            let banlancer = self.load();
+            let picked = banlancer.pick_reserved(|banlancer| match (&choice, destination) {
+                (Some(choice), _) => choice.pick(banlancer, destination),
+                (None, Some(destination)) => banlancer.pick_node_for(destination),
+                (None, None) => banlancer.pick_node(),
+            });
+            if picked.is_some() {
+                return Ok(picked);
+            }
+            // Draining nodes count as saturated, not as down
+            let saturated = match &choice {
+                Some(choice) => choice.has_up(&banlancer),
+                None => banlancer.nodes.iter().any(|node| !node.is_down()),
+            };
+            drop(banlancer);
+            match policy {
+                NoNodePolicy::Wait(timeout) if started.elapsed() < timeout => {
+                    tokio::time::sleep(NODE_WAIT_INTERVAL).await;
+                }
+                // Healthy nodes at capacity, neither fall back to direct nor report them as down
+                _ if saturated => return Err(ResponseCode::NodesSaturated),
+                NoNodePolicy::Direct => return Ok(None),
+                NoNodePolicy::Fail | NoNodePolicy::Wait(_) => {
+                    return Err(ResponseCode::NetworkUnreachable);
+                }
+            }
+        }
+    }
+
+    /// `outbound` reaching `node`, picked by `select_node` for `group`,
+    /// through the other nodes when `group` is a relay. The nodes tunnel to
+    /// the next one with `hop`.
+    pub(crate) fn relay_outbound<'a>(
+        &self,
+        outbound: Cow<'a, OutboundOptions>,
+        group: Option<&str>,
+        node: Option<NodeInfo>,
+        hop: fn(Address) -> UpstreamHop,
+    ) -> Cow<'a, OutboundOptions> {
+        let choice = group.and_then(|group| self.groups.choice(group));
+        match (node, choice) {
+            (Some(node), Some(choice)) if !choice.relay_hops().is_empty() => {
+                Cow::Owned(outbound.relayed(node.socket_addr, choice.relay_hops(), hop))
+            }
+            _ => outbound,
+        }
+    }
+
+    /// Second node of a hedged connect to the `destination_key` network,
+    /// counted as by `select_node`. `None` when `first` is the only one
+    /// available.
+    pub fn select_runner_up(
+        &self,
+        first: SocketAddr,
+        destination: Option<&str>,
+    ) -> Option<(NodeInfo, CountedConnection)> {
+        let banlancer = self.load();
+        banlancer.pick_reserved(|banlancer| banlancer.pick_runner_up(first, destination))
+    }
+
+    /// Record how long `node` took to reach the `destination_key` network.
+    pub fn record_latency(&self, node: SocketAddr, destination: &str, latency: Duration) {
+        self.load().record_latency(node, destination, latency);
+    }
+
+    /// See `ConnectionStatsBanlancer::record_failure`.
+    pub fn record_failure(&self, node: SocketAddr) {
+        self.load().record_failure(node);
+    }
+
+    /// Stop picking `socket_addr` for new connections and wait for its open
+    /// connections to end, at most until `deadline`. Returns the connections
+    /// still open then, `None` for unknown nodes. The node stays drained
+    /// until `resume_node`, or until `replace_nodes` drops it.
+    pub async fn drain_node(&self, socket_addr: SocketAddr, deadline: Instant) -> Option<usize> {
+        if !self.load().set_node_draining(socket_addr, true) {
+            return None;
+        }
+        loop {
+            // Looked up again each time, the nodes may have been replaced
+            let connections = self.load().node(&socket_addr).map_or(0, NodeState::connections);
+            let now = Instant::now();
+            if connections == 0 || now >= deadline {
+                return Some(connections);
+            }
+            tokio::time::sleep(NODE_WAIT_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// Pick a node drained by `drain_node` again, `false` for unknown nodes.
+    pub fn resume_node(&self, socket_addr: SocketAddr) -> bool {
+        self.load().set_node_draining(socket_addr, false)
+    }
+
+    /// See `ConnectionStatsBanlancer::count_connection`.
+    #[cfg(test)]
+    pub fn count_connection(&self, node_info: &NodeInfo) -> Option<CountedConnection> {
+        self.load().count_connection(node_info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn replace_nodes_keeps_counts() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let kept = NodeInfo::new(ip, 1080, 1);
+        let removed = NodeInfo::new(ip, 1081, 1);
+        let banlancer = ConnectionStatsBanlancer::default().with_nodes(&[kept, removed]);
+        let kept_connection = banlancer.count_connection(&kept);
+        let removed_connection = banlancer.count_connection(&removed);
+        banlancer.set_node_healthy(kept.socket_addr, false);
+
+        let reweighted = NodeInfo::new(ip, 1080, 2);
+        let banlancer = banlancer.with_nodes(&[reweighted, NodeInfo::new(ip, 1082, 1)]);
+        assert_eq!(banlancer.count_by_addr(&kept.socket_addr), Some(1));
+        assert_eq!(banlancer.count_by_addr(&removed.socket_addr), None);
+        assert_eq!(banlancer.healthy_count(), 1);
+
+        // in-flight connections of a removed node must not underflow anything
+        drop(removed_connection);
+        drop(kept_connection);
+        assert_eq!(banlancer.count_by_addr(&kept.socket_addr), Some(0));
+    }
+
+    #[test]
+    fn non_positive_weights_count_as_one() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (zero, negative) = (NodeInfo::new(ip, 1080, 0), NodeInfo::new(ip, 1081, -1));
+        let banlancer = ConnectionStatsBanlancer::default().with_nodes(&[zero, negative]);
+        let weights: Vec<_> = banlancer.node_snapshots().iter().map(|node| node.weight).collect();
+        assert_eq!(weights, [1, 1]);
+        let picked = banlancer.pick_node().unwrap();
+        let _connection = banlancer.count_connection(&picked);
+        let other = banlancer.pick_node().unwrap();
+        assert_ne!(other.socket_addr, picked.socket_addr);
+    }
+
+    #[test]
+    fn pick_node_by_destination_latency() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let slow = NodeInfo::new(ip, 1080, 1);
+        let fast = NodeInfo::new(ip, 1081, 1);
+        let banlancer = ConnectionStatsBanlancer::default().with_nodes(&[slow, fast]);
+        let destination =
+            destination_key(&Address::DomainNameAddress("www.google.com".into(), 443));
+        assert_eq!(destination, "google.com");
+
+        banlancer.record_latency(slow.socket_addr, &destination, Duration::from_millis(300));
+        // the other node has not been tried for this destination yet
+        assert_eq!(banlancer.pick_node_for(&destination), Some(fast));
+        banlancer.record_latency(fast.socket_addr, &destination, Duration::from_millis(50));
+        let _connection = banlancer.count_connection(&fast);
+        assert_eq!(banlancer.pick_node_for(&destination), Some(fast));
+        assert_eq!(banlancer.pick_node(), Some(slow));
+    }
+
+    #[test]
+    fn failures_decay() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let flaky = NodeInfo::new(ip, 1080, 1);
+        let steady = NodeInfo::new(ip, 1081, 1);
+        let banlancer = ConnectionStatsBanlancer::default().with_nodes(&[flaky, steady]);
+        let _connection = banlancer.count_connection(&steady);
+        assert_eq!(banlancer.pick_node(), Some(flaky));
+        banlancer.record_failure(flaky.socket_addr);
+        banlancer.record_failure(flaky.socket_addr);
+        assert_eq!(banlancer.pick_node(), Some(steady));
+        // still picked once the other node is busier, no longer down or up
+        let _more = [banlancer.count_connection(&steady), banlancer.count_connection(&steady)];
+        assert_eq!(banlancer.pick_node(), Some(flaky));
+
+        assert!((decay(2.0, FAILURE_HALF_LIFE) - 1.0).abs() < 1e-9);
+        assert!(decay(1.0, FAILURE_HALF_LIFE * 20) < 1e-5);
+    }
+
+    #[test]
+    fn recovered_nodes_slow_start() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let recovered = NodeInfo::new(ip, 1080, 1);
+        let steady = NodeInfo::new(ip, 1081, 1);
+        let banlancer = ConnectionStatsBanlancer::default().with_nodes(&[recovered, steady]);
+        banlancer.set_node_healthy(recovered.socket_addr, false);
+        banlancer.set_node_healthy(recovered.socket_addr, true);
+        let _connections: Vec<_> = (0..5)
+            .map(|_| banlancer.count_connection(&steady))
+            .collect();
+        // idle, but only just back up
+        assert_eq!(banlancer.pick_node(), Some(steady));
+        let node = banlancer.node(&recovered.socket_addr).unwrap();
+        *node.recovered_at.lock().unwrap() = Some(Instant::now() - SLOW_START);
+        assert_eq!(banlancer.pick_node(), Some(recovered));
+    }
+
+    #[tokio::test]
+    async fn select_node_skips_saturated_nodes() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let small = NodeInfo::new(ip, 1080, 1).with_max_connections(1);
+        let large = NodeInfo::new(ip, 1081, 1).with_max_connections(2);
+        let banlancer = NodeRegistry::default();
+        banlancer.replace_nodes(&[small, large]);
+        let mut picked = Vec::new();
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let node = banlancer.select_node(NoNodePolicy::Direct, None, None).await;
+            let (node, counted) = node.unwrap().unwrap();
+            connections.push(counted);
+            picked.push(node.socket_addr.port());
+        }
+        // equally loaded nodes, the first listed wins
+        assert_eq!(picked, [1080, 1081, 1081]);
+        // saturated nodes are not down, the direct fallback does not apply
+        let res = banlancer.select_node(NoNodePolicy::Direct, None, None).await;
+        assert!(matches!(res, Err(ResponseCode::NodesSaturated)));
+        connections.pop();
+        assert!(banlancer.select_node(NoNodePolicy::Direct, None, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn no_node_policies() {
+        let node = NodeInfo::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1080, 1);
+        let banlancer = Arc::new(NodeRegistry::default());
+        banlancer.replace_nodes(&[node]);
+        banlancer.load().set_node_healthy(node.socket_addr, false);
+        let res = banlancer.select_node(NoNodePolicy::Fail, None, None).await;
+        assert!(matches!(res, Err(ResponseCode::NetworkUnreachable)));
+        let res = banlancer.select_node(NoNodePolicy::Direct, None, None).await;
+        assert!(matches!(res, Ok(None)));
+        let wait = NoNodePolicy::Wait(Duration::from_millis(50));
+        let res = banlancer.select_node(wait, None, None).await;
+        assert!(matches!(res, Err(ResponseCode::NetworkUnreachable)));
+
+        // A node coming back within the wait is picked
+        let recovering = Arc::clone(&banlancer);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            recovering.load().set_node_healthy(node.socket_addr, true);
+        });
+        let wait = NoNodePolicy::Wait(Duration::from_secs(5));
+        let (picked, _) = banlancer.select_node(wait, None, None).await.unwrap().unwrap();
+        assert_eq!(picked, node);
+    }
+
+    // Selects racing on worker threads, the runtime needs `rt-multi-thread`
+    #[cfg(feature = "rt-multi-thread")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_selects_respect_max_connections() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = NodeInfo::new(ip, 1080, 1).with_max_connections(4);
+        let banlancer = Arc::new(NodeRegistry::default());
+        banlancer.replace_nodes(&[node]);
+        let selects: Vec<_> = (0..16)
+            .map(|_| {
+                let banlancer = Arc::clone(&banlancer);
+                tokio::spawn(async move {
+                    banlancer.select_node(NoNodePolicy::Fail, None, None).await
+                })
+            })
+            .collect();
+        let mut counted = Vec::new();
+        for select in selects {
+            if let Ok(Some((_, connection))) = select.await.unwrap() {
+                counted.push(connection);
+            }
+        }
+        assert_eq!(counted.len(), 4);
+        assert_eq!(banlancer.load().count_by_addr(&node.socket_addr), Some(4));
+        // a failed connect gives its slot back
+        counted.pop();
+        assert!(banlancer.select_node(NoNodePolicy::Fail, None, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn drained_nodes_are_not_picked() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (old, new) = (NodeInfo::new(ip, 1080, 1), NodeInfo::new(ip, 1081, 1));
+        let banlancer = NodeRegistry::default();
+        banlancer.replace_nodes(&[old, new]);
+        let connection = banlancer.count_connection(&old);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(banlancer.drain_node(old.socket_addr, deadline).await, Some(1));
+        let node = banlancer.select_node(NoNodePolicy::Direct, None, None).await;
+        assert_eq!(node.unwrap().unwrap().0.socket_addr, new.socket_addr);
+        drop(connection);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(banlancer.drain_node(old.socket_addr, deadline).await, Some(0));
+        let unknown = "127.0.0.1:1082".parse().unwrap();
+        assert_eq!(banlancer.drain_node(unknown, deadline).await, None);
+        assert!(banlancer.resume_node(old.socket_addr));
+        assert!(banlancer.load().is_healthy(&old.socket_addr));
+
+        // Draining the only node doesn't send its traffic direct
+        banlancer.replace_nodes(&[old]);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(banlancer.drain_node(old.socket_addr, deadline).await, Some(0));
+        let res = banlancer.select_node(NoNodePolicy::Direct, None, None).await;
+        assert!(matches!(res, Err(ResponseCode::NodesSaturated)));
+        let group = Some(old.socket_addr.to_string());
+        let res = banlancer.select_node(NoNodePolicy::Direct, None, group.as_deref()).await;
+        assert!(matches!(res, Err(ResponseCode::NodesSaturated)));
+    }
+}
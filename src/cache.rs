@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Bytes};
+use hyper::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, PRAGMA, RANGE, SET_COOKIE, VARY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+
+/// Settings of the in-memory cache of plain HTTP GET responses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Responses kept, the least recently used one is evicted first
+    pub max_entries: usize,
+    /// Responses with a larger `Content-Length` (or none) are not cached
+    pub max_body_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 256,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    etag: Option<HeaderValue>,
+    fresh_until: Instant,
+    last_used: Instant,
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut response = Response::new(full_body(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// Result of looking a request up in the cache.
+pub enum Lookup {
+    /// Fresh response, served without asking the origin
+    Hit(Response<BoxBody<Bytes, hyper::Error>>),
+    /// Stale response, to be revalidated with this ETag
+    Revalidate(HeaderValue),
+    Miss,
+}
+
+/// Cache of plain HTTP GET responses honoring `Cache-Control` and `ETag`,
+/// shared by the connections of a listener.
+#[derive(Clone, Default)]
+pub struct ResponseCache(Arc<Mutex<HashMap<String, CachedResponse>>>);
+
+impl ResponseCache {
+    /// Cache key of `req`, `None` for requests the cache stays out of:
+    /// anything but GET, authenticated, ranged or conditional requests and
+    /// clients asking for a fresh copy.
+    pub fn key<B>(req: &Request<B>) -> Option<String> {
+        let headers = req.headers();
+        let bypass = [AUTHORIZATION, RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE]
+            .iter()
+            .any(|name| headers.contains_key(name))
+            || directives(headers, &CACHE_CONTROL).any(|d| d == "no-cache" || d == "no-store")
+            || directives(headers, &PRAGMA).any(|d| d == "no-cache");
+        if req.method() != Method::GET || bypass {
+            return None;
+        }
+        Some(req.uri().to_string())
+    }
+
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let mut entries = self.0.lock().unwrap();
+        let Some(entry) = entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        let now = Instant::now();
+        entry.last_used = now;
+        if now < entry.fresh_until {
+            return Lookup::Hit(entry.to_response());
+        }
+        match &entry.etag {
+            Some(etag) => Lookup::Revalidate(etag.clone()),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Cached response of `key` after the origin answered `304 Not Modified`
+    /// with `headers`, its freshness is renewed from them.
+    pub fn revalidated(
+        &self,
+        key: &str,
+        headers: &HeaderMap,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let mut entries = self.0.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if let Some(freshness) = freshness(headers) {
+            entry.fresh_until = Instant::now() + freshness;
+        }
+        Some(entry.to_response())
+    }
+
+    /// Keep `resp` as the response of `key` when it is cacheable, the
+    /// response is returned with its body buffered in that case.
+    pub async fn store<B>(
+        &self,
+        key: String,
+        resp: Response<B>,
+        config: &CacheConfig,
+    ) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static,
+    {
+        let headers = resp.headers();
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let freshness = freshness(headers);
+        let etag = headers.get(ETAG).cloned();
+        let cacheable = resp.status() == StatusCode::OK
+            && !headers.contains_key(SET_COOKIE)
+            && !headers.contains_key(VARY)
+            && content_length.is_some_and(|len| len <= config.max_body_bytes)
+            && freshness.is_some_and(|freshness| !freshness.is_zero() || etag.is_some());
+        let (Some(freshness), true) = (freshness, cacheable) else {
+            return Ok(resp.map(|b| b.boxed()));
+        };
+
+        let (parts, body) = resp.into_parts();
+        let body = body.collect().await?.to_bytes();
+        let now = Instant::now();
+        let entry = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            etag,
+            fresh_until: now + freshness,
+            last_used: now,
+        };
+        let mut entries = self.0.lock().unwrap();
+        entries.insert(key, entry);
+        while entries.len() > config.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+        Ok(Response::from_parts(parts, full_body(body)))
+    }
+}
+
+fn full_body(body: Bytes) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+/// Comma separated directives of every `name` header, lowercased.
+fn directives<'a>(
+    headers: &'a HeaderMap,
+    name: &'a hyper::header::HeaderName,
+) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+}
+
+/// How long a response stays fresh, `None` when it must not be stored.
+fn freshness(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut no_cache = false;
+    for directive in directives(headers, &CACHE_CONTROL) {
+        match directive.split_once('=') {
+            Some(("max-age", v)) => max_age = v.trim_matches('"').parse().ok(),
+            Some(("s-maxage", v)) => s_maxage = v.trim_matches('"').parse().ok(),
+            None if directive == "no-store" || directive == "private" => return None,
+            None if directive == "no-cache" => no_cache = true,
+            _ => {}
+        }
+    }
+    if no_cache {
+        // Stored, but revalidated every time
+        return Some(Duration::ZERO);
+    }
+    Some(Duration::from_secs(s_maxage.or(max_age).unwrap_or(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(cache_control: &str, etag: Option<&str>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut builder = Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .header(CONTENT_LENGTH, "5");
+        if let Some(etag) = etag {
+            builder = builder.header(ETAG, etag);
+        }
+        builder.body(full_body(Bytes::from("hello"))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn honors_cache_control_and_etag() -> hyper::Result<()> {
+        let cache = ResponseCache::default();
+        let config = CacheConfig::default();
+        let req = Request::get("http://example.com/a.js").body(()).unwrap();
+        let key = ResponseCache::key(&req).unwrap();
+
+        cache
+            .store(key.clone(), response("private, max-age=60", None), &config)
+            .await?;
+        assert!(matches!(cache.lookup(&key), Lookup::Miss));
+
+        cache
+            .store(key.clone(), response("no-cache", Some("\"v1\"")), &config)
+            .await?;
+        assert!(matches!(cache.lookup(&key), Lookup::Revalidate(etag) if etag == "\"v1\""));
+        let headers = response("max-age=60", None).headers().clone();
+        assert!(cache.revalidated(&key, &headers).is_some());
+        assert!(matches!(cache.lookup(&key), Lookup::Hit(_)));
+
+        let ranged = Request::get("http://example.com/a.js")
+            .header(RANGE, "bytes=0-1")
+            .body(())
+            .unwrap();
+        assert_eq!(ResponseCache::key(&ranged), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hyper::header::HeaderName;
+use log::LevelFilter;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::cache::CacheConfig;
+use crate::fault::ChaosConfig;
+use crate::learned::LearnedRouteConfig;
+use crate::listener::{AcceptBackoff, ConnectionId};
+use crate::outbound::{AdaptiveTimeout, OutboundOptions};
+use crate::quota::{ConnectionRateConfig, QuotaConfig};
+use crate::relay::{TunnelSnapshot, Tunnels};
+use crate::responder::LocalResponse;
+use crate::sniff::SniffConfig;
+use crate::traits::RouteHook;
+
+/// `log!` honouring the log target and level of a listener's `ProxyConfig`
+/// and the verbosity of the rule of the connection being served, prefixed
+/// with its id.
+macro_rules! listener_log {
+    ($config:expr, $level:expr, $($arg:tt)+) => {{
+        let config: &$crate::config::ProxyConfig = &$config;
+        if let Some(level) = $crate::listener::connection_log_level(config.log_level, $level) {
+            let target = config.log_target.as_deref().unwrap_or(module_path!());
+            match $crate::listener::current_connection_id() {
+                Some(id) => {
+                    log::log!(target: target, level, "[{}] {}", id, format_args!($($arg)+))
+                }
+                None => log::log!(target: target, level, $($arg)+),
+            }
+        }
+    }};
+}
+
+/// Usernames with their password, clients have to present one of the pairs.
+/// The username authenticated is the one `USER` rules match.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    passwords: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Credentials of a single user, add more with `with_user`.
+    pub fn new(username: &str, password: &str) -> Self {
+        Self::default().with_user(username, password)
+    }
+
+    /// Add `username`, replacing its password if it was known.
+    pub fn with_user(mut self, username: &str, password: &str) -> Self {
+        self.passwords.insert(username.to_string(), password.to_string());
+        self
+    }
+
+    pub fn remove_user(&mut self, username: &str) -> bool {
+        self.passwords.remove(username).is_some()
+    }
+
+    pub fn usernames(&self) -> impl Iterator<Item = &str> {
+        self.passwords.keys().map(String::as_str)
+    }
+
+    /// Whether `password` is the one of `username`.
+    pub fn check(&self, username: &str, password: &[u8]) -> bool {
+        self.passwords
+            .get(username)
+            .is_some_and(|expected| expected.as_bytes() == password)
+    }
+}
+
+/// Behavior of proxied connections when every VPN node is down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoNodePolicy {
+    /// Fail the connection
+    #[default]
+    Fail,
+    /// Connect to the target directly
+    Direct,
+    /// Wait up to the given time for a node to come back, then fail
+    Wait(Duration),
+}
+
+/// Listener settings, read once for every accepted connection.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyConfig {
+    /// Timeout used when connecting to the target or the VPN node
+    pub timeout: Option<Duration>,
+    /// Maximum number of connections served at the same time
+    pub max_connections: Option<usize>,
+    /// Require clients to authenticate as one of the users when set
+    pub credentials: Option<Credentials>,
+    /// Answer failed HTTP requests with an HTML page
+    pub error_page: bool,
+    /// Socket options of outbound connections
+    pub outbound: OutboundOptions,
+    /// Per client IP bandwidth quota
+    pub quota: Option<QuotaConfig>,
+    /// Abort tunnels when the upstream stays silent this long after the client sent data
+    pub stall_timeout: Option<Duration>,
+    /// Evaluate and log the rules but connect every request directly
+    pub dry_run: bool,
+    /// Log target of connection events, e.g. `kitty_proxy::http[8080]`
+    pub log_target: Option<String>,
+    /// Most verbose level logged for connection events
+    pub log_level: Option<LevelFilter>,
+    /// What proxied connections do while no VPN node is healthy
+    pub no_node_policy: NoNodePolicy,
+    /// Prefer the nodes that connected fastest to the same destination network
+    /// before, instead of only the least connected one
+    pub latency_routing: bool,
+    /// Rule id prefixes, e.g. the `games/` layer or `domain-suffix:`, whose
+    /// proxied connections dial the two best nodes at once and keep the one
+    /// finishing the upstream handshake first
+    pub hedged_rules: Vec<String>,
+    /// Cache plain HTTP GET responses in memory, HTTP proxy only
+    pub response_cache: Option<CacheConfig>,
+    /// Route CONNECT and SOCKS tunnels by the SNI of their TLS ClientHello
+    pub sniff: Option<SniffConfig>,
+    /// Test connect every VPN node before `serve()` returns, unreachable ones
+    /// are reported through its runtime error channel
+    pub validate_nodes: bool,
+    /// File the nodes chosen in `select` proxy groups are saved to, and
+    /// restored from when the groups are set
+    pub selection_file: Option<PathBuf>,
+    /// Accept the SOCKS5 UDP-over-TCP command (0x05) of clients that can't
+    /// reach a UDP relay port, their datagrams carried on the control
+    /// connection. Standard UDP ASSOCIATE is left alone
+    pub udp_over_tcp: bool,
+    /// Request header, e.g. `X-Kitty-Route`, with which clients on the
+    /// loopback interface or authenticated ones route one HTTP request
+    /// themselves: `direct`, `proxy`, `reject`, a proxy group or a node
+    /// address. Stripped before forwarding the request
+    pub route_override_header: Option<HeaderName>,
+    /// Read the same route from the SOCKS5 username, `route:<route>` or
+    /// `<user>+route:<route>` when the listener has credentials, for clients
+    /// that can only set SOCKS credentials
+    pub socks_route_hints: bool,
+    /// Ports tried in order when the listener's own port is taken, e.g.
+    /// `7890..=7899`, the port used is told by `local_addr()`
+    pub fallback_ports: Option<RangeInclusive<u16>>,
+    /// Give each target and VPN node a connect timeout following its past
+    /// connect times instead of `timeout`
+    pub adaptive_timeout: Option<AdaptiveTimeout>,
+    /// Send a domain whose connects keep failing through the way its rule
+    /// picked, direct or proxied, the other way for a while
+    pub learned_routes: Option<LearnedRouteConfig>,
+    /// DSCP values of the outbound connections by rule id prefix, e.g.
+    /// `("games/", 46)` or `("domain-suffix:steamcontent.com", 8)`, the first
+    /// matching prefix wins over `outbound.dscp`
+    pub dscp_rules: Vec<(String, u8)>,
+    /// Pause the accept loop between batches of accepts while this many
+    /// connections are open, instead of only yielding to the relays
+    pub accept_backoff: Option<AcceptBackoff>,
+    /// New connections per client IP over a sliding window, beyond which
+    /// they are refused: 429 with `Retry-After` for HTTP clients, X'02' for
+    /// SOCKS clients
+    pub connection_rate: Option<ConnectionRateConfig>,
+    /// Listen on addresses other than loopback ones without `credentials`.
+    /// Off by default, so an open proxy is never exposed to the network by
+    /// mistake
+    pub allow_lan: bool,
+    /// Asked for the route of each TCP connection before connecting, may
+    /// veto or change what the rules decided
+    pub route_hook: Option<SharedRouteHook>,
+    /// Fail connects, slow down and reset tunnels on purpose, for testing
+    /// applications against a degraded proxy
+    pub chaos: Option<ChaosConfig>,
+    /// Answer the connectivity checks of operating systems and browsers
+    /// (`http://connectivitycheck.gstatic.com/generate_204`...) with a 204
+    /// right away instead of forwarding them, HTTP proxy only
+    pub local_connectivity_checks: bool,
+    /// Responses served instead of forwarding plain HTTP requests, keyed by
+    /// lowercase host, see `HttpProxy::set_local_response`
+    pub local_responses: HashMap<String, LocalResponse>,
+}
+
+/// `RouteHook` of a `ProxyConfig`, shared by the connections.
+#[derive(Clone)]
+pub struct SharedRouteHook(pub Arc<dyn RouteHook>);
+
+impl fmt::Debug for SharedRouteHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RouteHook")
+    }
+}
+
+/// Partial update of a `ProxyConfig`, fields left to `None` are kept as is.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyConfigUpdate {
+    pub timeout: Option<Option<Duration>>,
+    pub max_connections: Option<Option<usize>>,
+    pub credentials: Option<Option<Credentials>>,
+    pub error_page: Option<bool>,
+    pub outbound: Option<OutboundOptions>,
+    pub quota: Option<Option<QuotaConfig>>,
+    pub stall_timeout: Option<Option<Duration>>,
+    pub dry_run: Option<bool>,
+    pub log_target: Option<Option<String>>,
+    pub log_level: Option<Option<LevelFilter>>,
+    pub no_node_policy: Option<NoNodePolicy>,
+    pub latency_routing: Option<bool>,
+    pub hedged_rules: Option<Vec<String>>,
+    pub response_cache: Option<Option<CacheConfig>>,
+    pub sniff: Option<Option<SniffConfig>>,
+    pub validate_nodes: Option<bool>,
+    pub selection_file: Option<Option<PathBuf>>,
+    pub udp_over_tcp: Option<bool>,
+    pub route_override_header: Option<Option<HeaderName>>,
+    pub socks_route_hints: Option<bool>,
+    pub fallback_ports: Option<Option<RangeInclusive<u16>>>,
+    pub adaptive_timeout: Option<Option<AdaptiveTimeout>>,
+    pub learned_routes: Option<Option<LearnedRouteConfig>>,
+    pub dscp_rules: Option<Vec<(String, u8)>>,
+    pub accept_backoff: Option<Option<AcceptBackoff>>,
+    pub connection_rate: Option<Option<ConnectionRateConfig>>,
+    pub allow_lan: Option<bool>,
+    pub route_hook: Option<Option<SharedRouteHook>>,
+    pub chaos: Option<Option<ChaosConfig>>,
+    pub local_connectivity_checks: Option<bool>,
+    pub local_responses: Option<HashMap<String, LocalResponse>>,
+}
+
+impl ProxyConfig {
+    /// Whether connections matched by the rule `rule_id` use hedged connects.
+    pub(crate) fn is_hedged(&self, rule_id: &str) -> bool {
+        self.hedged_rules.iter().any(|prefix| rule_id.starts_with(prefix.as_str()))
+    }
+
+    /// Outbound options of the connections matched by the rule `rule_id`.
+    pub(crate) fn outbound_for(&self, rule_id: &str) -> Cow<'_, OutboundOptions> {
+        let dscp = self
+            .dscp_rules
+            .iter()
+            .find(|(prefix, _)| rule_id.starts_with(prefix.as_str()))
+            .map(|(_, dscp)| *dscp);
+        match dscp {
+            Some(dscp) if self.outbound.dscp != Some(dscp) => Cow::Owned(OutboundOptions {
+                dscp: Some(dscp),
+                ..self.outbound.clone()
+            }),
+            _ => Cow::Borrowed(&self.outbound),
+        }
+    }
+
+    pub fn apply(&mut self, update: ProxyConfigUpdate) {
+        if let Some(timeout) = update.timeout {
+            self.timeout = timeout;
+        }
+        if let Some(max_connections) = update.max_connections {
+            self.max_connections = max_connections;
+        }
+        if let Some(credentials) = update.credentials {
+            self.credentials = credentials;
+        }
+        if let Some(error_page) = update.error_page {
+            self.error_page = error_page;
+        }
+        if let Some(outbound) = update.outbound {
+            self.outbound = outbound;
+        }
+        if let Some(quota) = update.quota {
+            self.quota = quota;
+        }
+        if let Some(stall_timeout) = update.stall_timeout {
+            self.stall_timeout = stall_timeout;
+        }
+        if let Some(dry_run) = update.dry_run {
+            self.dry_run = dry_run;
+        }
+        if let Some(log_target) = update.log_target {
+            self.log_target = log_target;
+        }
+        if let Some(log_level) = update.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(no_node_policy) = update.no_node_policy {
+            self.no_node_policy = no_node_policy;
+        }
+        if let Some(latency_routing) = update.latency_routing {
+            self.latency_routing = latency_routing;
+        }
+        if let Some(hedged_rules) = update.hedged_rules {
+            self.hedged_rules = hedged_rules;
+        }
+        if let Some(response_cache) = update.response_cache {
+            self.response_cache = response_cache;
+        }
+        if let Some(sniff) = update.sniff {
+            self.sniff = sniff;
+        }
+        if let Some(validate_nodes) = update.validate_nodes {
+            self.validate_nodes = validate_nodes;
+        }
+        if let Some(selection_file) = update.selection_file {
+            self.selection_file = selection_file;
+        }
+        if let Some(udp_over_tcp) = update.udp_over_tcp {
+            self.udp_over_tcp = udp_over_tcp;
+        }
+        if let Some(route_override_header) = update.route_override_header {
+            self.route_override_header = route_override_header;
+        }
+        if let Some(socks_route_hints) = update.socks_route_hints {
+            self.socks_route_hints = socks_route_hints;
+        }
+        if let Some(fallback_ports) = update.fallback_ports {
+            self.fallback_ports = fallback_ports;
+        }
+        if let Some(adaptive_timeout) = update.adaptive_timeout {
+            self.adaptive_timeout = adaptive_timeout;
+        }
+        if let Some(learned_routes) = update.learned_routes {
+            self.learned_routes = learned_routes;
+        }
+        if let Some(dscp_rules) = update.dscp_rules {
+            self.dscp_rules = dscp_rules;
+        }
+        if let Some(accept_backoff) = update.accept_backoff {
+            self.accept_backoff = accept_backoff;
+        }
+        if let Some(connection_rate) = update.connection_rate {
+            self.connection_rate = connection_rate;
+        }
+        if let Some(allow_lan) = update.allow_lan {
+            self.allow_lan = allow_lan;
+        }
+        if let Some(route_hook) = update.route_hook {
+            self.route_hook = route_hook;
+        }
+        if let Some(chaos) = update.chaos {
+            self.chaos = chaos;
+        }
+        if let Some(local_connectivity_checks) = update.local_connectivity_checks {
+            self.local_connectivity_checks = local_connectivity_checks;
+        }
+        if let Some(local_responses) = update.local_responses {
+            self.local_responses = local_responses;
+        }
+    }
+}
+
+pub type ArcProxyConfig = Arc<RwLock<ProxyConfig>>;
+
+/// Cancellation tokens of the connections served by a listener.
+type ConnectionTokens = Arc<std::sync::Mutex<HashMap<ConnectionId, CancellationToken>>>;
+
+/// Number of connections currently served by a listener and their tunnels.
+#[derive(Clone, Default)]
+pub struct ActiveConnections {
+    count: Arc<AtomicUsize>,
+    tunnels: Tunnels,
+    tokens: ConnectionTokens,
+}
+
+impl ActiveConnections {
+    /// Register a new connection, `None` when `max_connections` is reached.
+    pub fn acquire(&self, max_connections: Option<usize>) -> Option<ConnectionGuard> {
+        let count = self.count.fetch_add(1, Ordering::SeqCst);
+        let guard = ConnectionGuard {
+            count: Arc::clone(&self.count),
+            tunnels: self.tunnels.clone(),
+            tokens: Arc::clone(&self.tokens),
+            id: None,
+        };
+        match max_connections {
+            Some(max) if count >= max => None,
+            _ => Some(guard),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub fn tunnels(&self) -> Vec<TunnelSnapshot> {
+        self.tunnels.snapshots()
+    }
+
+    /// Cancel the tasks of the connection `id`, closing its tunnels. `false`
+    /// when no such connection is open.
+    pub fn kill(&self, id: ConnectionId) -> bool {
+        match self.tokens.lock().unwrap().get(&id) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Releases its slot in `ActiveConnections` when dropped.
+pub struct ConnectionGuard {
+    count: Arc<AtomicUsize>,
+    tunnels: Tunnels,
+    tokens: ConnectionTokens,
+    /// Set once its tasks were spawned, see `register`
+    id: Option<ConnectionId>,
+}
+
+impl ConnectionGuard {
+    /// Make the connection `id` killable through `ActiveConnections::kill`
+    /// until the guard is dropped.
+    pub(crate) fn register(&mut self, id: ConnectionId, cancel: CancellationToken) {
+        self.tokens.lock().unwrap().insert(id, cancel);
+        self.id = Some(id);
+    }
+
+    /// Tunnels of the listener the connection was accepted by.
+    pub(crate) fn tunnels(&self) -> &Tunnels {
+        &self.tunnels
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.tokens.lock().unwrap().remove(&id);
+        }
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether the accept loop of a listener is alive, and since when.
+#[derive(Clone, Default)]
+pub struct ServeState {
+    serving: Arc<AtomicBool>,
+    since: Arc<std::sync::Mutex<Option<SystemTime>>>,
+}
+
+impl ServeState {
+    /// Mark the listener as serving until the returned guard is dropped by
+    /// the accept task, on shutdown or when it dies.
+    pub fn start(&self) -> ServeGuard {
+        *self.since.lock().unwrap() = Some(SystemTime::now());
+        self.serving.store(true, Ordering::SeqCst);
+        ServeGuard(self.clone())
+    }
+
+    pub fn is_serving(&self) -> bool {
+        self.serving.load(Ordering::SeqCst)
+    }
+
+    pub fn serving_since(&self) -> Option<SystemTime> {
+        *self.since.lock().unwrap()
+    }
+}
+
+/// Resets its `ServeState` when dropped.
+pub struct ServeGuard(ServeState);
+
+impl Drop for ServeGuard {
+    fn drop(&mut self) {
+        self.0.serving.store(false, Ordering::SeqCst);
+        *self.0.since.lock().unwrap() = None;
+    }
+}
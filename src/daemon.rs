@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use log::info;
+use tokio::sync::watch::Receiver;
+
+use crate::http_proxy::HttpProxy;
+use crate::listener::{Shutdown, Stopped};
+use crate::rules::SharedRules;
+use crate::socks_proxy::SocksProxy;
+use crate::types::{NodeInfo, ProxyRuntimeError};
+
+/// A listener started by `serve_until_shutdown`.
+pub enum Listener<'a> {
+    Http(&'a mut HttpProxy),
+    Socks(&'a mut SocksProxy),
+}
+
+impl<'a> Listener<'a> {
+    async fn serve(
+        &mut self,
+        match_proxy: Arc<SharedRules>,
+        shutdown: &mut Receiver<bool>,
+        vpn_node_infos: Vec<NodeInfo>,
+    ) -> Result<Stopped, ProxyRuntimeError> {
+        let (mut handle, is_serving) = match self {
+            Listener::Http(proxy) => {
+                let handle = proxy.serve(match_proxy, shutdown, vpn_node_infos).await;
+                (handle, proxy.is_serving())
+            }
+            Listener::Socks(proxy) => {
+                let handle = proxy.serve(match_proxy, shutdown, vpn_node_infos).await;
+                (handle, proxy.is_serving())
+            }
+        };
+        if is_serving {
+            return Ok(handle.stopped);
+        }
+        Err(handle
+            .errors
+            .try_recv()
+            .unwrap_or(ProxyRuntimeError::ListenerClosed))
+    }
+}
+
+/// Send `state` to systemd when started as a `Type=notify` unit.
+#[cfg(target_os = "linux")]
+fn sd_notify(state: &str) {
+    use log::warn;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy().into_owned();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = sent {
+        warn!("sd_notify {} failed: {}", state, e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sd_notify(_state: &str) {}
+
+/// Start every listener, signal readiness once all of them are bound (sd_notify
+/// `READY=1` under systemd, then `on_ready`) and return once `true` is sent on
+/// `shutdown` and the listeners ended their connections.
+pub async fn serve_until_shutdown<F>(
+    mut listeners: Vec<Listener<'_>>,
+    match_proxy: Arc<SharedRules>,
+    vpn_node_infos: Vec<NodeInfo>,
+    mut shutdown: Receiver<bool>,
+    on_ready: F,
+) -> Result<(), ProxyRuntimeError>
+where
+    F: FnOnce(),
+{
+    let mut stopped = Vec::new();
+    for listener in listeners.iter_mut() {
+        let serve = listener.serve(
+            Arc::clone(&match_proxy),
+            &mut shutdown,
+            vpn_node_infos.clone(),
+        );
+        match serve.await {
+            Ok(listener_stopped) => stopped.push(listener_stopped),
+            Err(e) => {
+                // Listeners already started stop once the caller fires shutdown
+                sd_notify("STOPPING=1");
+                return Err(e);
+            }
+        }
+    }
+    sd_notify("READY=1");
+    on_ready();
+    info!("All listeners ready");
+
+    Shutdown::new(&shutdown).requested().await;
+    sd_notify("STOPPING=1");
+    for listener_stopped in stopped {
+        listener_stopped.await;
+    }
+    info!("All listeners stopped");
+    Ok(())
+}
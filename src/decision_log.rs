@@ -0,0 +1,124 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use log::info;
+
+use crate::listener::ConnectionId;
+use crate::traffic_diversion::{RuleDecision, TrafficStreamRule};
+use crate::types::Address;
+
+/// Log target of the decision log, so it can be routed to its own file.
+pub const DECISION_LOG_TARGET: &str = "kitty_proxy::decision";
+
+/// A routing decision in the connection log format of Clash, e.g.
+/// `[TCP] 127.0.0.1:50000 --> www.google.com:443 match DomainSuffix(google.com) using 1.2.3.4:443`
+pub struct DecisionLog<'a> {
+    /// Prefixes the line when set, e.g. `[01J9Z3K8Q2M4X7AB] [TCP] ...`
+    pub connection: Option<ConnectionId>,
+    pub network: &'a str,
+    pub source: Option<SocketAddr>,
+    pub target: String,
+    pub decision: &'a RuleDecision,
+    /// VPN node used, `None` when the connection goes direct or is rejected
+    pub node: Option<SocketAddr>,
+}
+
+impl<'a> DecisionLog<'a> {
+    pub fn log(&self) {
+        info!(target: DECISION_LOG_TARGET, "{}", self);
+    }
+}
+
+impl<'a> fmt::Display for DecisionLog<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self.source {
+            Some(source) => source.to_string(),
+            None => "-".to_string(),
+        };
+        if let Some(connection) = self.connection {
+            write!(f, "[{}] ", connection)?;
+        }
+        write!(
+            f,
+            "[{}] {} --> {} match {}({}) using ",
+            self.network,
+            source,
+            self.target,
+            self.decision.clash_rule_type(),
+            self.decision.clash_payload()
+        )?;
+        match (&self.decision.rule, self.node) {
+            (TrafficStreamRule::Reject, _) => write!(f, "REJECT"),
+            // Clash shows the group then the proxy it picked
+            (_, Some(node)) => match &self.decision.group {
+                Some(group) => write!(f, "{}[{}]", group, node),
+                None => write!(f, "{}", node),
+            },
+            (_, None) => write!(f, "DIRECT"),
+        }
+    }
+}
+
+/// Address a direct connection to a domain was opened to, kept for the
+/// lifetime of the connection whatever later DNS answers say, e.g.
+/// `[TCP] www.google.com:443 pinned to 142.250.185.68:443`. Only logged with
+/// `OutboundOptions::dns_round_robin`, which spreads connections over them.
+pub struct PinnedLog<'a> {
+    pub connection: Option<ConnectionId>,
+    pub target: &'a Address,
+    pub addr: SocketAddr,
+}
+
+impl<'a> PinnedLog<'a> {
+    pub fn log(&self) {
+        info!(target: DECISION_LOG_TARGET, "{}", self);
+    }
+}
+
+impl<'a> fmt::Display for PinnedLog<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(connection) = self.connection {
+            write!(f, "[{}] ", connection)?;
+        }
+        write!(f, "[TCP] {} pinned to {}", self.target, self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clash_format() {
+        let decision = RuleDecision {
+            rule: TrafficStreamRule::Proxy,
+            rule_id: "user/domain-suffix:google.com".to_string(),
+            redirect_port: None,
+            group: None,
+            verbosity: None,
+        };
+        let log = DecisionLog {
+            connection: None,
+            network: "TCP",
+            source: Some("127.0.0.1:50000".parse().unwrap()),
+            target: "www.google.com:443".to_string(),
+            decision: &decision,
+            node: Some("10.0.0.1:8080".parse().unwrap()),
+        };
+        assert_eq!(
+            log.to_string(),
+            "[TCP] 127.0.0.1:50000 --> www.google.com:443 match DomainSuffix(google.com) using 10.0.0.1:8080"
+        );
+    }
+
+    #[test]
+    fn pinned_format() {
+        let target = Address::DomainNameAddress("www.google.com".to_string(), 443);
+        let log = PinnedLog {
+            connection: None,
+            target: &target,
+            addr: "142.250.185.68:443".parse().unwrap(),
+        };
+        assert_eq!(log.to_string(), "[TCP] www.google.com:443 pinned to 142.250.185.68:443");
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+use tokio::sync::OnceCell;
+
+use crate::types::scoped_ipv6;
+
+/// How long resolved addresses are reused, the system resolver doesn't
+/// tell the record TTL.
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Host names cached, expired ones then the oldest are dropped first.
+const MAX_DNS_ENTRIES: usize = 4096;
+
+/// Outcome of one lookup, shared by every caller waiting on it.
+type Lookup = Result<Arc<[IpAddr]>, (io::ErrorKind, String)>;
+
+static RESOLVER: LazyLock<Resolver> = LazyLock::new(Resolver::default);
+
+/// Resolve `host` through the process wide `Resolver`.
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    RESOLVER.resolve(host, port, false).await
+}
+
+/// Resolve `host` like `resolve`, the answers rotated by one more address on
+/// each call so connections to a host with several addresses take turns
+/// starting with each of them.
+pub async fn resolve_rotated(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    RESOLVER.resolve(host, port, true).await
+}
+
+/// Counters of the process wide resolver, since startup.
+pub fn dns_stats() -> DnsStats {
+    RESOLVER.stats()
+}
+
+/// How host name lookups were answered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsStats {
+    /// Answered from the cache
+    pub hits: u64,
+    /// Sent to the system resolver
+    pub misses: u64,
+    /// Waited for a lookup of the same host already in flight
+    pub coalesced: u64,
+}
+
+struct Entry {
+    lookup: Arc<OnceCell<Lookup>>,
+    started: Instant,
+    /// Calls answered by `lookup` so far, the rotation of `resolve_rotated`
+    turns: usize,
+}
+
+/// Cache of host name lookups. Concurrent misses for the same host share a
+/// single lookup, so a burst of connections to a cold destination costs one
+/// query. Failed lookups are not cached.
+#[derive(Default)]
+struct Resolver {
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl Resolver {
+    async fn resolve(&self, host: &str, port: u16, rotate: bool) -> io::Result<Vec<SocketAddr>> {
+        // Cached answers are IPs only, which would lose the zone
+        if let Some((ip, scope_id)) = scoped_ipv6(host) {
+            return Ok(vec![SocketAddrV6::new(ip, port, 0, scope_id).into()]);
+        }
+        let (lookup, turn) = self.entry(host);
+        let res = lookup
+            .get_or_init(|| async {
+                match lookup_host((host, 0)).await {
+                    Ok(addrs) => Ok(addrs.map(|addr| addr.ip()).collect()),
+                    Err(e) => Err((e.kind(), e.to_string())),
+                }
+            })
+            .await;
+        match res {
+            Ok(ips) => {
+                let mut addrs: Vec<_> = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                if rotate && !addrs.is_empty() {
+                    let len = addrs.len();
+                    addrs.rotate_left(turn % len);
+                }
+                Ok(addrs)
+            }
+            Err((kind, message)) => {
+                let mut entries = self.entries.lock().unwrap();
+                if entries.get(host).is_some_and(|entry| Arc::ptr_eq(&entry.lookup, &lookup)) {
+                    entries.remove(host);
+                }
+                Err(io::Error::new(*kind, message.clone()))
+            }
+        }
+    }
+
+    /// Lookup of `host` to wait on, a new one unless cached or in flight,
+    /// with the number of calls it answered before this one.
+    fn entry(&self, host: &str) -> (Arc<OnceCell<Lookup>>, usize) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(host) {
+            let turn = entry.turns;
+            entry.turns += 1;
+            match entry.lookup.get() {
+                None => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    return (Arc::clone(&entry.lookup), turn);
+                }
+                Some(_) if now.duration_since(entry.started) < DNS_CACHE_TTL => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return (Arc::clone(&entry.lookup), turn);
+                }
+                Some(_) => {}
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if !entries.contains_key(host) && entries.len() >= MAX_DNS_ENTRIES {
+            entries.retain(|_, entry| now.duration_since(entry.started) < DNS_CACHE_TTL);
+            if entries.len() >= MAX_DNS_ENTRIES {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.started)
+                    .map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        let lookup = Arc::new(OnceCell::new());
+        let entry = Entry {
+            lookup: Arc::clone(&lookup),
+            started: now,
+            turns: 1,
+        };
+        entries.insert(host.to_string(), entry);
+        (lookup, 0)
+    }
+
+    fn stats(&self) -> DnsStats {
+        DnsStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_misses_share_a_lookup() -> io::Result<()> {
+        let resolver = Arc::new(Resolver::default());
+        let lookups: Vec<_> = (0..8)
+            .map(|_| {
+                let resolver = Arc::clone(&resolver);
+                tokio::spawn(async move { resolver.resolve("localhost", 80, false).await })
+            })
+            .collect();
+        for lookup in lookups {
+            let addrs = lookup.await.unwrap()?;
+            assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+        }
+        let stats = resolver.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits + stats.coalesced, 7);
+
+        resolver.resolve("localhost", 443, false).await?;
+        assert_eq!(resolver.stats().hits, stats.hits + 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotated_answers_take_turns() -> io::Result<()> {
+        let resolver = Resolver::default();
+        let ips: Arc<[IpAddr]> = ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let entry = Entry {
+            lookup: Arc::new(OnceCell::new_with(Some(Ok(ips)))),
+            started: Instant::now(),
+            turns: 0,
+        };
+        resolver.entries.lock().unwrap().insert("example.com".to_string(), entry);
+        let mut firsts = Vec::new();
+        for _ in 0..4 {
+            let addrs = resolver.resolve("example.com", 443, true).await?;
+            assert_eq!(addrs.len(), 3);
+            firsts.push(addrs[0].to_string());
+        }
+        assert_eq!(firsts, ["10.0.0.1:443", "10.0.0.2:443", "10.0.0.3:443", "10.0.0.1:443"]);
+        // Unrotated answers keep the resolver order
+        let addrs = resolver.resolve("example.com", 443, false).await?;
+        assert_eq!(addrs[0].to_string(), "10.0.0.1:443");
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Embedding the proxies in an application on another async runtime,
+//! async-std or smol: they keep to a tokio runtime on a thread of their own,
+//! the tasks started on it are awaited from any executor.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+
+/// A tokio runtime running on its own thread until dropped, the tasks still
+/// running then are cancelled.
+pub struct ProxyRuntime {
+    handle: Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ProxyRuntime {
+    /// Start the runtime thread.
+    pub fn new() -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("kitty-proxy".to_string())
+            .spawn(move || {
+                runtime.block_on(async {
+                    let _ = shutdown_rx.await;
+                })
+            })?;
+        Ok(Self {
+            handle,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    /// Run `future` on the runtime, e.g. `HttpProxy::serve` or the calls of
+    /// a `ProxyManager`. Can be called from any thread.
+    pub fn spawn<F>(&self, future: F) -> RemoteTask<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.handle.spawn(async move {
+            let _ = tx.send(future.await);
+        });
+        RemoteTask(rx)
+    }
+
+    /// Handle of the runtime, for tokio aware code.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl Drop for ProxyRuntime {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Output of a task started with `ProxyRuntime::spawn`, a future any
+/// executor can poll. `None` when the runtime was dropped before the task
+/// finished.
+pub struct RemoteTask<T>(oneshot::Receiver<T>);
+
+impl<T> Future for RemoteTask<T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(Result::ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::time::Duration;
+
+    use super::*;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// The simplest executor there is, no tokio around.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn tasks_are_awaited_without_tokio() {
+        let runtime = ProxyRuntime::new().unwrap();
+        let task = runtime.spawn(async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            7
+        });
+        assert_eq!(block_on(task), Some(7));
+
+        let pending = runtime.spawn(std::future::pending::<()>());
+        drop(runtime);
+        assert_eq!(block_on(pending), None);
+    }
+}
@@ -1,4 +1,5 @@
-use kitty_proxy::{HttpProxy, MatchProxy, NodeInfo};
+use kitty_proxy::prelude::*;
+use log::LevelFilter;
 use std::{path::PathBuf, sync::Arc};
 use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
@@ -7,10 +8,16 @@ use tokio::signal;
 
 use anyhow::Ok;
 use anyhow::Result;
-use tokio::sync::{RwLock, watch};
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // KITTY_LOG_FORMAT=json for one JSON object per log line
+    let log_format = match std::env::var("KITTY_LOG_FORMAT") {
+        std::result::Result::Ok(format) => format.parse()?,
+        Err(_) => LogFormat::Pretty,
+    };
+    init_logging(log_format, LevelFilter::Info)?;
 
     let mut proxy = HttpProxy::new("127.0.0.1", 10089, None).await?;
         // let geoip_file = "/Users/hezhaozhao/myself/kitty/src-tauri/static/kitty_geoip.dat";
@@ -22,7 +29,7 @@ async fn main() -> Result<()> {
             Some(&PathBuf::from_str(geosite_file).unwrap()),
         )
             .unwrap();
-        let arc_match_proxy = Arc::new(RwLock::new(match_proxy));
+        let arc_match_proxy = Arc::new(SharedRules::new(match_proxy));
 
         let (http_kill_tx, mut http_kill_rx) = watch::channel(false);
         let mut http_vpn_node_infos = Vec::new();
@@ -0,0 +1,113 @@
+//! Rule decisions per second with many concurrent connections while the rules
+//! get reloaded, `RwLock<MatchProxy>` against `SharedRules`.
+//!
+//! cargo run --release --example rules_bench
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use kitty_proxy::{MatchProxy, SharedRules};
+use tokio::sync::RwLock;
+use url::Host;
+
+const TASKS: usize = 256;
+const RUN_FOR: Duration = Duration::from_secs(3);
+const RELOAD_EVERY: Duration = Duration::from_millis(100);
+
+fn rules() -> Result<MatchProxy> {
+    let mut content = String::new();
+    for i in 0..2000 {
+        content.push_str(&format!("DOMAIN,site{}.example.com,direct\n", i));
+        content.push_str(&format!("IP-CIDR,10.{}.{}.0/24,direct\n", i / 256, i % 256));
+    }
+    MatchProxy::from_rule_str(&content)
+}
+
+/// Run `decide` from `TASKS` tasks while `reload` runs every `RELOAD_EVERY`,
+/// returns the decisions made per second.
+async fn run<D, DF, U, UF>(decide: D, reload: U) -> f64
+where
+    D: Fn(Host) -> DF + Clone + Send + 'static,
+    DF: std::future::Future<Output = ()> + Send,
+    U: Fn() -> UF + Send + 'static,
+    UF: std::future::Future<Output = ()> + Send,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let decisions = Arc::new(AtomicU64::new(0));
+    let mut tasks = Vec::new();
+    for i in 0..TASKS {
+        let (decide, stop, decisions) = (decide.clone(), stop.clone(), decisions.clone());
+        tasks.push(tokio::spawn(async move {
+            let host = Host::Domain(format!("www.site{}.example.org", i));
+            while !stop.load(Ordering::Relaxed) {
+                decide(host.clone()).await;
+                decisions.fetch_add(1, Ordering::Relaxed);
+                // Decisions don't await without resolving, let the timer run
+                tokio::task::yield_now().await;
+            }
+        }));
+    }
+    let updater_stop = stop.clone();
+    let updater = tokio::spawn(async move {
+        while !updater_stop.load(Ordering::Relaxed) {
+            reload().await;
+            tokio::time::sleep(RELOAD_EVERY).await;
+        }
+    });
+    let started = Instant::now();
+    tokio::time::sleep(RUN_FOR).await;
+    stop.store(true, Ordering::Relaxed);
+    for task in tasks {
+        task.await.unwrap();
+    }
+    updater.await.unwrap();
+    decisions.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let locked = Arc::new(RwLock::new(rules()?));
+    let reload_locked = locked.clone();
+    let rwlock = run(
+        move |host| {
+            let locked = locked.clone();
+            async move {
+                let match_proxy = locked.read().await;
+                match_proxy.decide_resolving(None, None, None, &host, None).await;
+            }
+        },
+        move || {
+            let locked = reload_locked.clone();
+            async move {
+                // Parsed under the write lock, as `reload_layer` does
+                let mut match_proxy = locked.write().await;
+                *match_proxy = rules().unwrap();
+            }
+        },
+    )
+    .await;
+
+    let shared = Arc::new(SharedRules::new(rules()?));
+    let reload_shared = shared.clone();
+    let arc_swap = run(
+        move |host| {
+            let shared = shared.clone();
+            async move {
+                let match_proxy = shared.load();
+                match_proxy.decide_resolving(None, None, None, &host, None).await;
+            }
+        },
+        move || {
+            let shared = reload_shared.clone();
+            async move { shared.store(rules().unwrap()) }
+        },
+    )
+    .await;
+
+    println!("{} tasks, rules reloaded every {:?}", TASKS, RELOAD_EVERY);
+    println!("RwLock<MatchProxy>: {:>12.0} decisions/s", rwlock);
+    println!("SharedRules:        {:>12.0} decisions/s", arc_swap);
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! Soak run of both proxies against a local echo server through faulty
+//! upstreams: late, resetting and stalling ones, plus slow loris clients.
+//! Prints the outcome of every scenario and the listener statistics.
+//!
+//! The HTTP proxy reaches the echo server through two nodes, relays to a
+//! second HTTP proxy, one resetting every connection: hedging has to race
+//! the other one and win with it.
+//!
+//! cargo run --release --example soak [rounds]
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use kitty_proxy::fault::{FaultDialer, Faults};
+use kitty_proxy::{HttpProxy, MatchProxy, NodeInfo, ProxyConfigUpdate, SharedRules, SocksProxy};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+const PAYLOAD: usize = 4096;
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+const SCENARIO_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn echo_server() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Relay to `target` misbehaving as `faults` tell.
+async fn faulty_relay(target: SocketAddr, faults: Faults) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(FaultDialer::new(faults).relay(listener, target));
+    Ok(addr)
+}
+
+fn direct_rules(action: &str) -> Result<Arc<SharedRules>> {
+    let rules = format!("IP-CIDR,127.0.0.0/8,{}", action);
+    Ok(Arc::new(SharedRules::new(MatchProxy::from_rule_str(&rules)?)))
+}
+
+async fn socks_connect(proxy: SocketAddr, target: SocketAddr) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    let SocketAddr::V4(target) = target else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "IPv4 only"));
+    };
+    let mut request = vec![5, 1, 0, 1];
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!("SOCKS reply {}", reply[1])));
+    }
+    Ok(stream)
+}
+
+async fn http_connect<S>(mut stream: S, target: SocketAddr) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    stream.write_all(request.as_bytes()).await?;
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    if !head.starts_with("HTTP/1.1 200") {
+        let status = head.lines().next().unwrap_or_default().to_string();
+        return Err(io::Error::other(status));
+    }
+    Ok(stream)
+}
+
+async fn echo<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> io::Result<()> {
+    let payload: Vec<u8> = (0..PAYLOAD).map(|i| i as u8).collect();
+    stream.write_all(&payload).await?;
+    let mut received = vec![0; PAYLOAD];
+    stream.read_exact(&mut received).await?;
+    if received != payload {
+        return Err(io::Error::other("echo mismatch"));
+    }
+    Ok(())
+}
+
+const SCENARIOS: [&str; 6] = [
+    "socks clean",
+    "socks latency",
+    "socks reset",
+    "socks stall",
+    "http hedged",
+    "http slow loris",
+];
+
+struct Targets {
+    socks: SocketAddr,
+    http: SocketAddr,
+    echo: SocketAddr,
+    late_echo: SocketAddr,
+    resetting_echo: SocketAddr,
+    stalling_echo: SocketAddr,
+    slow_dialer: FaultDialer,
+}
+
+impl Targets {
+    async fn run(&self, scenario: &str) -> io::Result<()> {
+        let echo_target = match scenario {
+            "socks clean" => self.echo,
+            "socks latency" => self.late_echo,
+            "socks reset" => self.resetting_echo,
+            "socks stall" => self.stalling_echo,
+            "http hedged" => {
+                let stream = TcpStream::connect(self.http).await?;
+                return echo(http_connect(stream, self.echo).await?).await;
+            }
+            _ => {
+                // Trickle the request head only
+                let stream = self.slow_dialer.connect(self.http).await?;
+                return echo(http_connect(stream, self.echo).await?.into_inner()).await;
+            }
+        };
+        echo(socks_connect(self.socks, echo_target).await?).await
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    ok: usize,
+    failed: BTreeMap<String, usize>,
+    slowest: Duration,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let rounds: usize = match std::env::args().nth(1) {
+        Some(rounds) => rounds.parse()?,
+        None => 20,
+    };
+    let echo_addr = echo_server().await?;
+    let latency = Faults {
+        latency: Some(Duration::from_millis(200)),
+        ..Faults::default()
+    };
+    let resets = Faults {
+        reset_after: Some(PAYLOAD as u64 / 2),
+        ..Faults::default()
+    };
+    let stalls = Faults {
+        latency: Some(STALL_TIMEOUT * 3),
+        ..Faults::default()
+    };
+    let slow_loris = Faults {
+        trickle: Some((1, Duration::from_millis(20))),
+        ..Faults::default()
+    };
+    let late_echo = faulty_relay(echo_addr, latency.clone()).await?;
+    let resetting_echo = faulty_relay(echo_addr, resets).await?;
+    let stalling_echo = faulty_relay(echo_addr, stalls).await?;
+
+    let (shutdown, mut shutdown_rx) = watch::channel(false);
+    let stall_timeout = ProxyConfigUpdate {
+        stall_timeout: Some(Some(STALL_TIMEOUT)),
+        ..ProxyConfigUpdate::default()
+    };
+    let mut socks = SocksProxy::new("127.0.0.1", 0, None).await?;
+    socks.update_config(stall_timeout.clone()).await;
+    let serving = socks.serve(direct_rules("direct")?, &mut shutdown_rx, Vec::new()).await;
+    let socks_stopped = serving.stopped;
+    let socks_addr = socks.local_addr().expect("SOCKS proxy not serving");
+
+    let mut upstream = HttpProxy::new("127.0.0.1", 0, None).await?;
+    let serving = upstream.serve(direct_rules("direct")?, &mut shutdown_rx, Vec::new()).await;
+    let upstream_stopped = serving.stopped;
+    let upstream_addr = upstream.local_addr().expect("upstream HTTP proxy not serving");
+    let broken_node = faulty_relay(upstream_addr, Faults {
+        reset_after: Some(0),
+        ..Faults::default()
+    })
+    .await?;
+    let late_node = faulty_relay(upstream_addr, latency).await?;
+    let nodes = vec![
+        NodeInfo::new(broken_node.ip(), broken_node.port(), 1),
+        NodeInfo::new(late_node.ip(), late_node.port(), 2),
+    ];
+    let mut http = HttpProxy::new("127.0.0.1", 0, Some(Duration::from_secs(2))).await?;
+    http.update_config(ProxyConfigUpdate {
+        hedged_rules: Some(vec!["ip-cidr:".to_string()]),
+        ..stall_timeout
+    })
+    .await;
+    let serving = http.serve(direct_rules("proxy")?, &mut shutdown_rx, nodes).await;
+    let http_stopped = serving.stopped;
+    let http_addr = http.local_addr().expect("HTTP proxy not serving");
+
+    let targets = Arc::new(Targets {
+        socks: socks_addr,
+        http: http_addr,
+        echo: echo_addr,
+        late_echo,
+        resetting_echo,
+        stalling_echo,
+        slow_dialer: FaultDialer::new(slow_loris),
+    });
+    let mut tallies: BTreeMap<&str, Tally> = BTreeMap::new();
+    let started = Instant::now();
+    for _ in 0..rounds {
+        let mut runs = JoinSet::new();
+        for scenario in SCENARIOS {
+            let targets = Arc::clone(&targets);
+            runs.spawn(async move {
+                let started = Instant::now();
+                let res = match timeout(SCENARIO_TIMEOUT, targets.run(scenario)).await {
+                    Ok(res) => res,
+                    Err(_) => Err(io::ErrorKind::TimedOut.into()),
+                };
+                (scenario, res, started.elapsed())
+            });
+        }
+        while let Some(run) = runs.join_next().await {
+            let (scenario, res, elapsed) = run?;
+            let tally = tallies.entry(scenario).or_default();
+            tally.slowest = tally.slowest.max(elapsed);
+            match res {
+                Ok(()) => tally.ok += 1,
+                Err(e) => *tally.failed.entry(e.kind().to_string()).or_default() += 1,
+            }
+        }
+    }
+
+    println!("{} rounds in {:.1?}", rounds, started.elapsed());
+    for (name, tally) in &tallies {
+        println!(
+            "{:<16} ok {:>4}  slowest {:>8.1?}  failed {:?}",
+            name, tally.ok, tally.slowest, tally.failed
+        );
+    }
+    for snapshot in [http.config_snapshot().await, socks.config_snapshot().await] {
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+    }
+    shutdown.send(true)?;
+    tokio::join!(http_stopped, socks_stopped, upstream_stopped);
+    println!(
+        "stopped, active connections: http {} socks {}",
+        http.active_connections(),
+        socks.active_connections()
+    );
+    Ok(())
+}
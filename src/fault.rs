@@ -0,0 +1,271 @@
+//! Fault injection for tests and soak runs: connections that answer late,
+//! reset midway or trickle their writes like a slow loris client. The
+//! proxies apply a `ChaosConfig` with the same streams.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Sleep};
+
+use crate::traits::BoxedStream;
+
+/// Misbehaviour of a `FaultyStream`, none by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Delay of the connect and of every read
+    pub latency: Option<Duration>,
+    /// Bytes read and written before the connection fails with
+    /// `ErrorKind::ConnectionReset`
+    pub reset_after: Option<u64>,
+    /// At most this many bytes per write, each write after the first waiting
+    /// for the interval
+    pub trickle: Option<(usize, Duration)>,
+}
+
+/// `stream` misbehaving as its `Faults` tell.
+pub struct FaultyStream<S> {
+    stream: S,
+    faults: Faults,
+    transferred: u64,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    /// The latency of the pending read is already waited for
+    read_delayed: bool,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> FaultyStream<S> {
+    pub fn new(stream: S, faults: Faults) -> Self {
+        Self {
+            stream,
+            faults,
+            transferred: 0,
+            read_delay: None,
+            read_delayed: false,
+            write_delay: None,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn check_reset(&self) -> io::Result<()> {
+        match self.faults.reset_after {
+            Some(limit) if self.transferred >= limit => Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset by fault injection",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+        if let (Some(latency), false) = (this.faults.latency, this.read_delayed) {
+            let delay = this.read_delay.get_or_insert_with(|| Box::pin(sleep(latency)));
+            ready!(delay.as_mut().poll(cx));
+            this.read_delay = None;
+            this.read_delayed = true;
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        this.read_delayed = false;
+        this.transferred += (buf.filled().len() - filled) as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+        if let Some(delay) = &mut this.write_delay {
+            ready!(delay.as_mut().poll(cx));
+            this.write_delay = None;
+        }
+        let buf = match this.faults.trickle {
+            Some((chunk, _)) => &buf[..buf.len().min(chunk.max(1))],
+            None => buf,
+        };
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        if let Some((_, interval)) = this.faults.trickle {
+            this.write_delay = Some(Box::pin(sleep(interval)));
+        }
+        this.transferred += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Degraded conditions a listener simulates on its outbound connections, to
+/// see how applications cope with a bad proxy. Off by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChaosConfig {
+    /// Percentage (0-100) of connects to targets and VPN nodes failing as
+    /// refused without being attempted
+    pub drop_connect_percent: u8,
+    /// Delay of every read of the tunnels
+    pub tunnel_delay: Option<Duration>,
+    /// Percentage (0-100) of tunnels reset after a random number of bytes,
+    /// up to `reset_within`
+    pub reset_percent: u8,
+    pub reset_within: u64,
+}
+
+impl ChaosConfig {
+    /// Whether to fail the next connect.
+    pub(crate) fn drops_connect(&self) -> bool {
+        chance(self.drop_connect_percent)
+    }
+
+    /// `stream` delayed, and maybe doomed to be reset, as configured.
+    pub(crate) fn degrade(&self, stream: BoxedStream) -> BoxedStream {
+        let reset_after = chance(self.reset_percent).then(|| random() % self.reset_within.max(1));
+        if self.tunnel_delay.is_none() && reset_after.is_none() {
+            return stream;
+        }
+        let faults = Faults {
+            latency: self.tunnel_delay,
+            reset_after,
+            trickle: None,
+        };
+        Box::new(FaultyStream::new(stream, faults))
+    }
+}
+
+fn random() -> u64 {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(SEQUENCE.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn chance(percent: u8) -> bool {
+    percent > 0 && random() % 100 < u64::from(percent)
+}
+
+/// Opens TCP connections misbehaving as `faults` tell.
+#[derive(Clone, Debug, Default)]
+pub struct FaultDialer {
+    pub faults: Faults,
+}
+
+impl FaultDialer {
+    pub fn new(faults: Faults) -> Self {
+        Self { faults }
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<FaultyStream<TcpStream>> {
+        if let Some(latency) = self.faults.latency {
+            sleep(latency).await;
+        }
+        let stream = TcpStream::connect(addr).await?;
+        Ok(FaultyStream::new(stream, self.faults.clone()))
+    }
+
+    /// Relay every connection accepted on `listener` to `target` over a
+    /// faulty connection, an upstream to point the proxies at. Runs until
+    /// accepting fails.
+    pub async fn relay(self, listener: TcpListener, target: SocketAddr) -> io::Result<()> {
+        loop {
+            let (mut client, _) = listener.accept().await?;
+            let dialer = self.clone();
+            tokio::spawn(async move {
+                let res = match dialer.connect(target).await {
+                    Ok(mut upstream) => {
+                        tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    debug!("Faulty relay to {} ended: {}", target, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn trickles_then_resets() {
+        let (near, mut far) = duplex(64);
+        let faults = Faults {
+            latency: Some(Duration::from_millis(20)),
+            reset_after: Some(6),
+            trickle: Some((2, Duration::from_millis(10))),
+        };
+        let mut stream = FaultyStream::new(near, faults);
+        let started = Instant::now();
+        assert_eq!(stream.write(b"hello").await.unwrap(), 2);
+        stream.write_all(b"llo").await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        let mut received = [0; 5];
+        far.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        far.write_all(b"ok").await.unwrap();
+        let started = Instant::now();
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(&reply, b"ok");
+        let err = stream.write_all(b"more").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn chaos_resets_every_tunnel_at_100_percent() {
+        let chaos = ChaosConfig {
+            drop_connect_percent: 100,
+            reset_percent: 100,
+            reset_within: 4,
+            ..Default::default()
+        };
+        assert!(chaos.drops_connect());
+        assert!(!ChaosConfig::default().drops_connect());
+        let (near, _far) = duplex(64);
+        let mut stream = chaos.degrade(Box::new(near));
+        // Reset within the first 4 bytes, the first write may still pass
+        let first = stream.write_all(&[0; 8]).await;
+        let err = stream.write_all(&[0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        if let Err(e) = first {
+            assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        }
+    }
+}
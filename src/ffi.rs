@@ -0,0 +1,306 @@
+//! C ABI of the proxy manager for non-Rust host applications, behind the
+//! `ffi` feature. Build the shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Every function takes a NUL terminated UTF-8 string and returns a JSON
+//! string allocated by the library, `{"ok":true,"result":...}` or
+//! `{"ok":false,"error":"..."}`, to be released with
+//! `kitty_proxy_free_string`. The functions block until done, they must not
+//! be called from a tokio runtime thread.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+use crate::config::ProxyConfig;
+use crate::dns::dns_stats;
+use crate::http_proxy::HttpProxy;
+use crate::manager::{ProxyInstance, ProxyManager};
+use crate::relay::bandwidth_history;
+use crate::rules::SharedRules;
+use crate::socks_proxy::SocksProxy;
+use crate::traffic_diversion::MatchProxy;
+use crate::types::NodeInfo;
+
+/// Runtime errors kept per instance until read by `kitty_proxy_stats`.
+const MAX_PENDING_ERRORS: usize = 100;
+
+static RUNTIME: LazyLock<Result<Runtime, String>> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())
+});
+
+static MANAGER: LazyLock<ProxyManager> = LazyLock::new(ProxyManager::new);
+
+static ERRORS: LazyLock<Mutex<HashMap<String, Vec<String>>>> = LazyLock::new(Default::default);
+
+#[derive(Deserialize)]
+struct ListenAddr {
+    ip: String,
+    port: u16,
+    /// Required to listen on a non-loopback `ip`, the listeners have no
+    /// credentials
+    #[serde(default)]
+    allow_lan: bool,
+}
+
+impl ListenAddr {
+    fn config(&self, timeout: Option<Duration>) -> ProxyConfig {
+        ProxyConfig {
+            timeout,
+            allow_lan: self.allow_lan,
+            ..Default::default()
+        }
+    }
+}
+
+/// Rules as rule file text or a rule file path.
+#[derive(Deserialize)]
+struct RulesRequest {
+    rules: Option<String>,
+    rule_file: Option<PathBuf>,
+}
+
+impl RulesRequest {
+    fn load(&self) -> Result<Option<MatchProxy>> {
+        match (&self.rules, &self.rule_file) {
+            (Some(rules), None) => MatchProxy::from_rule_str(rules).map(Some),
+            (None, Some(path)) => MatchProxy::from_rule_file(path).map(Some),
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => Err(anyhow!("give either rules or rule_file")),
+        }
+    }
+}
+
+/// `{"name":"default","http":{"ip":"127.0.0.1","port":7890},"socks":null,
+/// "rules":"DOMAIN,*,proxy","nodes":[{"socket_addr":"10.0.0.1:1080",
+/// "node_number":1}],"timeout_ms":5000}`
+#[derive(Deserialize)]
+struct StartRequest {
+    name: String,
+    http: Option<ListenAddr>,
+    socks: Option<ListenAddr>,
+    #[serde(flatten)]
+    rules: RulesRequest,
+    #[serde(default)]
+    nodes: Vec<NodeInfo>,
+    timeout_ms: Option<u64>,
+}
+
+/// Fields left out are kept as is.
+#[derive(Deserialize)]
+struct ReloadRequest {
+    name: String,
+    #[serde(flatten)]
+    rules: RulesRequest,
+    nodes: Option<Vec<NodeInfo>>,
+}
+
+fn runtime() -> Result<&'static Runtime> {
+    RUNTIME
+        .as_ref()
+        .map_err(|e| anyhow!("failed to start the runtime: {}", e))
+}
+
+fn start(request: &str) -> Result<Value> {
+    let request: StartRequest = serde_json::from_str(request)?;
+    let match_proxy = request.rules.load()?.unwrap_or_default();
+    let timeout = request.timeout_ms.map(Duration::from_millis);
+    let runtime = runtime()?;
+    let mut errors = runtime.block_on(async {
+        let rules = Arc::new(SharedRules::new(match_proxy));
+        let mut instance = ProxyInstance::new(rules, request.nodes);
+        if let Some(http) = &request.http {
+            let config = http.config(timeout);
+            instance = instance.with_http(HttpProxy::with_config(&http.ip, http.port, config).await?);
+        }
+        if let Some(socks) = &request.socks {
+            let config = socks.config(timeout);
+            instance =
+                instance.with_socks(SocksProxy::with_config(&socks.ip, socks.port, config).await?);
+        }
+        MANAGER.start(&request.name, instance).await
+    })?;
+    let name = request.name;
+    ERRORS.lock().unwrap().insert(name.clone(), Vec::new());
+    runtime.spawn(async move {
+        while let Some(e) = errors.recv().await {
+            let mut pending = ERRORS.lock().unwrap();
+            let pending = pending.entry(name.clone()).or_default();
+            if pending.len() >= MAX_PENDING_ERRORS {
+                pending.remove(0);
+            }
+            pending.push(e.to_string());
+        }
+    });
+    Ok(Value::Null)
+}
+
+fn stop(name: &str) -> Result<Value> {
+    runtime()?.block_on(MANAGER.stop(name))?;
+    ERRORS.lock().unwrap().remove(name);
+    Ok(Value::Null)
+}
+
+fn reload(request: &str) -> Result<Value> {
+    let request: ReloadRequest = serde_json::from_str(request)?;
+    let match_proxy = request.rules.load()?;
+    runtime()?.block_on(async {
+        let instance = MANAGER
+            .get(&request.name)
+            .await
+            .ok_or_else(|| anyhow!("no proxy instance {}", request.name))?;
+        let mut instance = instance.lock().await;
+        if let Some(match_proxy) = match_proxy {
+            instance.rules().store(match_proxy);
+        }
+        if let Some(nodes) = request.nodes {
+            instance.replace_nodes(nodes).await;
+        }
+        Ok(Value::Null)
+    })
+}
+
+/// Listener snapshots, DNS counters, the process bandwidth of the last minutes
+/// and the runtime errors since the last call.
+fn stats(name: &str) -> Result<Value> {
+    let listeners = runtime()?.block_on(async {
+        let instance = MANAGER
+            .get(name)
+            .await
+            .ok_or_else(|| anyhow!("no proxy instance {}", name))?;
+        let instance = instance.lock().await;
+        Ok::<_, anyhow::Error>(instance.snapshots().await)
+    })?;
+    let errors = ERRORS.lock().unwrap().get_mut(name).map(std::mem::take);
+    Ok(json!({
+        "listeners": listeners,
+        "dns": dns_stats(),
+        "bandwidth": bandwidth_history(),
+        "errors": errors.unwrap_or_default(),
+    }))
+}
+
+/// Run `f` on the string `arg` points to and encode its outcome, panics
+/// included, they must not unwind into the host.
+unsafe fn call(arg: *const c_char, f: fn(&str) -> Result<Value>) -> *mut c_char {
+    let res = if arg.is_null() {
+        Err(anyhow!("null argument"))
+    } else {
+        CStr::from_ptr(arg)
+            .to_str()
+            .map_err(|e| anyhow!("argument isn't UTF-8: {}", e))
+            .and_then(|arg| {
+                panic::catch_unwind(AssertUnwindSafe(|| f(arg)))
+                    .unwrap_or_else(|_| Err(anyhow!("kitty_proxy panicked")))
+            })
+    };
+    let body = match res {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+    // JSON escapes NUL characters
+    CString::new(body.to_string()).unwrap_or_default().into_raw()
+}
+
+/// Start the proxy instance described by the JSON `request`.
+///
+/// # Safety
+///
+/// `request` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kitty_proxy_start(request: *const c_char) -> *mut c_char {
+    call(request, start)
+}
+
+/// Stop the instance `name`, waiting for its connections to end.
+///
+/// # Safety
+///
+/// `name` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kitty_proxy_stop(name: *const c_char) -> *mut c_char {
+    call(name, stop)
+}
+
+/// Replace the rules and/or the nodes of a running instance, e.g.
+/// `{"name":"default","rule_file":"/etc/kitty/rules.conf"}`.
+///
+/// # Safety
+///
+/// `request` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kitty_proxy_reload(request: *const c_char) -> *mut c_char {
+    call(request, reload)
+}
+
+/// Statistics of the instance `name`.
+///
+/// # Safety
+///
+/// `name` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kitty_proxy_stats(name: *const c_char) -> *mut c_char {
+    call(name, stats)
+}
+
+/// Release a string returned by the other functions.
+///
+/// # Safety
+///
+/// `s` must be null or returned by this library, and not released before.
+#[no_mangle]
+pub unsafe extern "C" fn kitty_proxy_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call_json(f: unsafe extern "C" fn(*const c_char) -> *mut c_char, arg: &str) -> Value {
+        let arg = CString::new(arg).unwrap();
+        unsafe {
+            let reply = f(arg.as_ptr());
+            let value = serde_json::from_str(CStr::from_ptr(reply).to_str().unwrap()).unwrap();
+            kitty_proxy_free_string(reply);
+            value
+        }
+    }
+
+    #[test]
+    fn start_stats_and_stop_over_json() {
+        let start = concat!(
+            r#"{"name":"ffi","http":{"ip":"127.0.0.1","port":0},"#,
+            r#""rules":"DOMAIN,*,direct"}"#
+        );
+        assert_eq!(call_json(kitty_proxy_start, start)["ok"], true);
+        let reload = r#"{"name":"ffi","nodes":[{"socket_addr":"127.0.0.1:1","node_number":1}]}"#;
+        assert_eq!(call_json(kitty_proxy_reload, reload)["ok"], true);
+        let stats = call_json(kitty_proxy_stats, "ffi");
+        let listener = &stats["result"]["listeners"][0];
+        assert_eq!(listener["kind"], "http");
+        assert_eq!(listener["serving"], true);
+        assert_eq!(listener["nodes"].as_array().unwrap().len(), 1);
+
+        assert_eq!(call_json(kitty_proxy_stop, "ffi")["ok"], true);
+        let stopped = call_json(kitty_proxy_stats, "ffi");
+        assert_eq!(stopped["ok"], false);
+        assert_eq!(stopped["error"], "no proxy instance ffi");
+        assert_eq!(call_json(kitty_proxy_start, "{")["ok"], false);
+        let reply = unsafe { kitty_proxy_stop(std::ptr::null()) };
+        unsafe { kitty_proxy_free_string(reply) };
+    }
+}
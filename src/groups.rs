@@ -0,0 +1,396 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use crate::banlancer::ConnectionStatsBanlancer;
+use crate::snapshot::GroupSnapshot;
+use crate::types::NodeInfo;
+
+/// How a proxy group picks its node, named as the Clash group types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupKind {
+    /// The node chosen with `ProxyGroups::select`, the first one until then
+    Select,
+    /// The node with the lowest delay measured by the last delay test
+    UrlTest,
+    /// The first available node, in the group order
+    Fallback,
+    /// The least connected node, as connections without group
+    LoadBalance,
+    /// Through every node in the group order, the last one reaching the
+    /// destination
+    Relay,
+}
+
+impl fmt::Display for GroupKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match self {
+            GroupKind::Select => "select",
+            GroupKind::UrlTest => "url-test",
+            GroupKind::Fallback => "fallback",
+            GroupKind::LoadBalance => "load-balance",
+            GroupKind::Relay => "relay",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl FromStr for GroupKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "select" => Ok(GroupKind::Select),
+            "url-test" => Ok(GroupKind::UrlTest),
+            "fallback" => Ok(GroupKind::Fallback),
+            "load-balance" => Ok(GroupKind::LoadBalance),
+            "relay" => Ok(GroupKind::Relay),
+            _ => Err(anyhow!("unknown proxy group type: {}", s)),
+        }
+    }
+}
+
+/// Named set of VPN nodes. Rules send connections to it with the group name as
+/// action, e.g. `DOMAIN-SUFFIX,netflix.com,streaming` after
+/// `PROXY-GROUP,streaming`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyGroup {
+    pub name: String,
+    pub kind: GroupKind,
+    /// Nodes of the listener in the group, by preference for `fallback`
+    pub nodes: Vec<SocketAddr>,
+}
+
+impl ProxyGroup {
+    pub fn new(name: &str, kind: GroupKind, nodes: Vec<SocketAddr>) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            nodes,
+        }
+    }
+}
+
+/// Nodes a connection of a group may use, resolved once per connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum GroupChoice {
+    /// The first available node of these
+    First(Vec<SocketAddr>),
+    /// The least connected available node of these
+    LeastConnected(Vec<SocketAddr>),
+    /// The last node, reached through the others in order, all available
+    Relay(Vec<SocketAddr>),
+}
+
+impl GroupChoice {
+    fn nodes(&self) -> &[SocketAddr] {
+        match self {
+            GroupChoice::First(nodes)
+            | GroupChoice::LeastConnected(nodes)
+            | GroupChoice::Relay(nodes) => nodes,
+        }
+    }
+
+    /// Node for a connection to the `destination_key` network, when latency
+    /// routing is enabled, `None` when no node of the group is available.
+    pub(crate) fn pick(
+        &self,
+        banlancer: &ConnectionStatsBanlancer,
+        destination: Option<&str>,
+    ) -> Option<NodeInfo> {
+        match self {
+            GroupChoice::First(nodes) => banlancer.pick_first(nodes),
+            GroupChoice::LeastConnected(nodes) => banlancer.pick_among(nodes, destination),
+            GroupChoice::Relay(nodes) => {
+                let (exit, hops) = nodes.split_last()?;
+                if !hops.iter().all(|hop| banlancer.is_healthy(hop)) {
+                    return None;
+                }
+                banlancer.pick_first(&[*exit])
+            }
+        }
+    }
+
+    /// Whether a node of the group is up, though maybe draining or at
+    /// capacity. For a relay every node has to be.
+    pub(crate) fn has_up(&self, banlancer: &ConnectionStatsBanlancer) -> bool {
+        match self {
+            GroupChoice::Relay(nodes) => {
+                !nodes.is_empty() && nodes.iter().all(|node| banlancer.is_up(node))
+            }
+            _ => self.nodes().iter().any(|node| banlancer.is_up(node)),
+        }
+    }
+
+    /// Nodes a connection goes through before the picked one, in order.
+    pub(crate) fn relay_hops(&self) -> &[SocketAddr] {
+        match self {
+            GroupChoice::Relay(nodes) => nodes.split_last().map_or(&[], |(_, hops)| hops),
+            _ => &[],
+        }
+    }
+}
+
+struct GroupState {
+    group: ProxyGroup,
+    /// Node chosen in a `select` group
+    selected: Option<SocketAddr>,
+}
+
+#[derive(Default)]
+struct State {
+    groups: HashMap<String, GroupState>,
+    /// Delays of the last test, `None` for the nodes that failed it
+    delays: HashMap<SocketAddr, Option<Duration>>,
+}
+
+impl State {
+    fn choice(&self, name: &str) -> Option<GroupChoice> {
+        let group = self.groups.get(name)?;
+        let nodes = &group.group.nodes;
+        let choice = match group.group.kind {
+            GroupKind::Select => {
+                GroupChoice::First(group.selected.or(nodes.first().copied()).into_iter().collect())
+            }
+            GroupKind::UrlTest => {
+                let mut nodes = nodes.clone();
+                // Untested nodes after the tested ones, failed ones last
+                nodes.sort_by_key(|node| match self.delays.get(node) {
+                    Some(Some(delay)) => (0, *delay),
+                    None => (1, Duration::ZERO),
+                    Some(None) => (2, Duration::ZERO),
+                });
+                GroupChoice::First(nodes)
+            }
+            GroupKind::Fallback => GroupChoice::First(nodes.clone()),
+            GroupKind::LoadBalance => GroupChoice::LeastConnected(nodes.clone()),
+            GroupKind::Relay => GroupChoice::Relay(nodes.clone()),
+        };
+        Some(choice)
+    }
+}
+
+/// Proxy groups of a listener with their runtime state: the node chosen in
+/// `select` groups and the delays measured for `url-test` groups.
+#[derive(Default)]
+pub struct ProxyGroups {
+    state: Mutex<State>,
+    /// Serializes `save_selections`, so the file ends with the latest choices
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl ProxyGroups {
+    /// Replace the groups, `select` groups keep their choice while the chosen
+    /// node stays in the group.
+    pub fn set_groups(&self, groups: Vec<ProxyGroup>) {
+        let mut state = self.state.lock().unwrap();
+        let groups = groups
+            .into_iter()
+            .map(|group| {
+                let selected = state
+                    .groups
+                    .get(&group.name)
+                    .and_then(|current| current.selected)
+                    .filter(|node| group.nodes.contains(node));
+                (group.name.clone(), GroupState { group, selected })
+            })
+            .collect();
+        state.groups = groups;
+    }
+
+    /// Choose the node of the `select` group `name`, used by its connections
+    /// from now on.
+    pub fn select(&self, name: &str, node: SocketAddr) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let group = state
+            .groups
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("unknown proxy group: {}", name))?;
+        if group.group.kind != GroupKind::Select {
+            return Err(anyhow!("proxy group {} is {}, not select", name, group.group.kind));
+        }
+        if !group.group.nodes.contains(&node) {
+            return Err(anyhow!("node {} is not in proxy group {}", node, name));
+        }
+        group.selected = Some(node);
+        Ok(())
+    }
+
+    /// Nodes chosen in `select` groups, by group name.
+    pub fn selections(&self) -> BTreeMap<String, SocketAddr> {
+        let state = self.state.lock().unwrap();
+        state
+            .groups
+            .iter()
+            .filter_map(|(name, group)| Some((name.clone(), group.selected?)))
+            .collect()
+    }
+
+    /// Write the `selections` to `path` as `group=node` lines, replacing the
+    /// file once complete.
+    pub async fn save_selections(&self, path: &Path) -> io::Result<()> {
+        let _saving = self.saving.lock().await;
+        let content: String = self
+            .selections()
+            .iter()
+            .map(|(group, node)| format!("{}={}\n", group, node))
+            .collect();
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, content).await?;
+        tokio::fs::rename(&partial, path).await
+    }
+
+    /// Choose the nodes saved by `save_selections` again. Groups and nodes no
+    /// longer configured are skipped, a missing file restores nothing.
+    pub async fn restore_selections(&self, path: &Path) -> io::Result<()> {
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let selection = line
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow!("expected group=node"))
+                .and_then(|(group, node)| Ok((group, node.trim().parse()?)))
+                .and_then(|(group, node)| self.select(group, node));
+            if let Err(e) = selection {
+                debug!("Not restoring selection {:?}: {}", line, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Nodes of the `url-test` groups, whose delays have to be measured.
+    pub fn url_test_nodes(&self) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let mut nodes: Vec<SocketAddr> = state
+            .groups
+            .values()
+            .filter(|state| state.group.kind == GroupKind::UrlTest)
+            .flat_map(|state| state.group.nodes.iter().copied())
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Store the results of a delay test, `None` for the nodes that failed,
+    /// which `url-test` groups then use last.
+    pub fn record_delays(&self, delays: Vec<(SocketAddr, Option<Duration>)>) {
+        self.state.lock().unwrap().delays.extend(delays);
+    }
+
+    /// Nodes a connection of the group `name` may use, `None` for unknown groups.
+    pub(crate) fn choice(&self, name: &str) -> Option<GroupChoice> {
+        self.state.lock().unwrap().choice(name)
+    }
+
+    pub(crate) fn snapshots(&self, banlancer: &ConnectionStatsBanlancer) -> Vec<GroupSnapshot> {
+        let state = self.state.lock().unwrap();
+        let mut snapshots: Vec<GroupSnapshot> = state
+            .groups
+            .iter()
+            .map(|(name, group)| GroupSnapshot {
+                name: name.clone(),
+                kind: group.group.kind.to_string(),
+                nodes: group.group.nodes.clone(),
+                current: state
+                    .choice(name)
+                    .and_then(|choice| choice.pick(banlancer, None))
+                    .map(|node_info| node_info.socket_addr),
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_pick_their_nodes() -> Result<()> {
+        let [a, b, c] = [1081, 1082, 1083].map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let banlancer = ConnectionStatsBanlancer::default()
+            .with_nodes(&[a, b, c].map(|node| NodeInfo::new(node.ip(), node.port(), 1)));
+        let groups = ProxyGroups::default();
+        groups.set_groups(vec![
+            ProxyGroup::new("manual", GroupKind::Select, vec![a, b]),
+            ProxyGroup::new("fastest", GroupKind::UrlTest, vec![a, b, c]),
+            ProxyGroup::new("backup", GroupKind::Fallback, vec![b, c]),
+        ]);
+        let pick = |name: &str| groups.choice(name).and_then(|c| c.pick(&banlancer, None));
+
+        assert_eq!(pick("manual").map(|n| n.socket_addr), Some(a));
+        groups.select("manual", b)?;
+        assert_eq!(pick("manual").map(|n| n.socket_addr), Some(b));
+        assert!(groups.select("manual", c).is_err());
+        assert!(groups.select("backup", c).is_err());
+
+        groups.record_delays(vec![(a, None), (c, Some(Duration::from_millis(20)))]);
+        assert_eq!(pick("fastest").map(|n| n.socket_addr), Some(c));
+
+        banlancer.set_node_healthy(b, false);
+        assert_eq!(pick("backup").map(|n| n.socket_addr), Some(c));
+        assert_eq!(pick("manual"), None);
+        assert!(groups.choice("missing").is_none());
+
+        // The selection survives a reload keeping the node
+        groups.set_groups(vec![ProxyGroup::new("manual", GroupKind::Select, vec![c, b])]);
+        assert_eq!(groups.choice("manual"), Some(GroupChoice::First(vec![b])));
+        Ok(())
+    }
+
+    #[test]
+    fn relays_go_through_every_node() {
+        let [a, b, c] = [1081, 1082, 1083].map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let banlancer = ConnectionStatsBanlancer::default()
+            .with_nodes(&[a, b, c].map(|node| NodeInfo::new(node.ip(), node.port(), 1)));
+        let groups = ProxyGroups::default();
+        groups.set_groups(vec![ProxyGroup::new("chain", GroupKind::Relay, vec![a, b, c])]);
+        let choice = groups.choice("chain").unwrap();
+        assert_eq!(choice.pick(&banlancer, None).map(|n| n.socket_addr), Some(c));
+        assert_eq!(choice.relay_hops(), [a, b]);
+
+        // A relay is down with any of its nodes
+        banlancer.set_node_healthy(a, false);
+        assert_eq!(choice.pick(&banlancer, None), None);
+        assert!(!choice.has_up(&banlancer));
+        assert_eq!("Relay".parse::<GroupKind>().unwrap(), GroupKind::Relay);
+    }
+
+    #[tokio::test]
+    async fn selections_survive_a_restart() -> Result<()> {
+        let [a, b] = [1081, 1082].map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let configured = || {
+            vec![
+                ProxyGroup::new("manual", GroupKind::Select, vec![a, b]),
+                ProxyGroup::new("other", GroupKind::Select, vec![a]),
+            ]
+        };
+        let path = std::env::temp_dir().join(format!("kitty_selections_{}", std::process::id()));
+        let groups = ProxyGroups::default();
+        groups.set_groups(configured());
+        groups.select("manual", b)?;
+        groups.save_selections(&path).await?;
+
+        let restarted = ProxyGroups::default();
+        restarted.set_groups(configured());
+        restarted.restore_selections(&path).await?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(restarted.selections(), BTreeMap::from([("manual".to_string(), b)]));
+        // Nothing saved yet
+        restarted.restore_selections(&path).await?;
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 use std::io;
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use http_body_util::{BodyExt, combinators::BoxBody};
 use hyper::{
     body,
@@ -14,23 +17,54 @@ use hyper::{
 };
 use hyper::body::Bytes;
 use hyper::client::conn::http1::Builder;
-use hyper::header::USER_AGENT;
+use hyper::header::{
+    ACCEPT, CONTENT_TYPE, IF_NONE_MATCH, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RETRY_AFTER,
+    USER_AGENT,
+};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::upgrade::Upgraded;
 use hyper_util::rt::TokioIo;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn, Level};
+use serde::Serialize;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tokio::sync::watch::Receiver;
+use tokio_util::sync::CancellationToken;
 use url::Host;
 
-use crate::banlancer::{ArcConnectionStatsBanlancer, ConnectionStatsBanlancer};
-use crate::MatchProxy;
-use crate::traffic_diversion::TrafficStreamRule;
-use crate::types::{Address, NodeInfo};
+use crate::banlancer::{self, NodeRegistry};
+use crate::cache::{Lookup, ResponseCache};
+use crate::decision_log::DecisionLog;
+use crate::fault::ChaosConfig;
+use crate::groups::ProxyGroup;
+use crate::learned::LearnedRoute;
+use crate::snapshot::{ListenerSnapshot, StartupReport};
+use crate::listener::{
+    accept, bind, client_keepalive, current_connection_id, drain, exposure_error, report,
+    restore_selections, route_hook_decision, runtime_error_channel, select_group_node,
+    set_connection_verbosity, spawn_connection, spawn_for_connection, test_group_delays,
+    validate_nodes, AcceptPacing, ConnectionId, RuntimeErrorSender, ServeHandle, Shutdown, Stopped,
+};
+use crate::config::{
+    ActiveConnections, ArcProxyConfig, Credentials, ProxyConfig, ProxyConfigUpdate, ServeState,
+};
+use crate::outbound::{self, NodeConnector, OutboundOptions, UpstreamHop};
+use crate::quota::{ProtocolCounts, ProtocolKey, ProxyUsage, QuotaState};
+use crate::relay::{
+    log_tunnel_closed, relay, track_tunnel, MeteredStream, Throughput, TrackedTunnel, TunnelBytes,
+    TunnelCloseReason, TunnelSide, TunnelSnapshot,
+};
+use crate::responder::{local_response, response_key, LocalResponse};
+use crate::sniff::{is_mismatch, read_client_hello, SniffConfig};
+use crate::rules::SharedRules;
+use crate::traffic_diversion::{RuleDecision, TrafficStreamRule};
+use crate::traits::{BoxedStream, RouteRequest};
+use crate::types::{
+    normalize_host, scoped_ipv6, Address, NodeInfo, ProxyRuntimeError, ResponseCode, RetryAdvice,
+    TargetAddr,
+};
 
 pub fn host_addr(uri: &Uri) -> Option<Address> {
     match uri.authority() {
@@ -52,7 +86,179 @@ fn make_bad_request() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::E
         .unwrap())
 }
 
+/// Body of the error replies of an HTTP listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPage {
+    /// Status line and headers only
+    Empty,
+    /// A small HTML page, for browsers
+    Html,
+    /// An `ErrorBody` object, for automated clients
+    Json,
+}
+
+impl From<bool> for ErrorPage {
+    fn from(error_page: bool) -> Self {
+        match error_page {
+            true => ErrorPage::Html,
+            false => ErrorPage::Empty,
+        }
+    }
+}
+
+impl ErrorPage {
+    /// Page asked for by the listener's `error_page`, as JSON for clients
+    /// listing `application/json` in their `Accept` header.
+    fn for_request(config: &ProxyConfig, req: &Request<body::Incoming>) -> Self {
+        let wants_json = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+        match ErrorPage::from(config.error_page) {
+            ErrorPage::Html if wants_json => ErrorPage::Json,
+            error_page => error_page,
+        }
+    }
+}
+
+/// JSON body of an error reply, e.g. `{"status":403,"reason":"Proxy Rule
+/// failure","rule_id":"domain-suffix:ads.com","connection":"01J9...","retry":"never"}`.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    reason: String,
+    /// Rule the request was rejected by
+    rule_id: Option<&'a str>,
+    /// Connection id of the matching log lines
+    connection: Option<String>,
+    retry: RetryAdvice,
+}
+
+/// HTTP counterpart of `SocksReply`, maps a `ResponseCode` to a real HTTP
+/// status so clients never see SOCKS reply codes as status lines.
+pub struct HttpReply {
+    status: StatusCode,
+    code: ResponseCode,
+    error_page: ErrorPage,
+    rule_id: Option<String>,
+    /// Sent as `Retry-After`, rounded up to whole seconds
+    retry_after: Option<Duration>,
+}
+
+impl HttpReply {
+    pub fn new(code: ResponseCode) -> Self {
+        let status = match code {
+            ResponseCode::Success => StatusCode::OK,
+            ResponseCode::RuleFailure => StatusCode::FORBIDDEN,
+            ResponseCode::HttpProxyAuthRequired => StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            ResponseCode::CommandNotSupported | ResponseCode::AddrTypeNotSupported => {
+                StatusCode::BAD_REQUEST
+            }
+            ResponseCode::TtlExpired => StatusCode::GATEWAY_TIMEOUT,
+            ResponseCode::NodesSaturated => StatusCode::SERVICE_UNAVAILABLE,
+            ResponseCode::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
+            ResponseCode::Failure
+            | ResponseCode::NetworkUnreachable
+            | ResponseCode::HostUnreachable
+            | ResponseCode::ConnectionRefused
+            | ResponseCode::HttpBadGateway => StatusCode::BAD_GATEWAY,
+        };
+        Self {
+            status,
+            code,
+            error_page: ErrorPage::Empty,
+            rule_id: None,
+            retry_after: None,
+        }
+    }
+
+    /// Tell the client how long to wait before trying again.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Describe the error in the body instead of leaving it empty, `true`
+    /// for an HTML page.
+    pub fn with_error_page(mut self, error_page: impl Into<ErrorPage>) -> Self {
+        self.error_page = error_page.into();
+        self
+    }
+
+    /// Rule the request was rejected by, listed in the JSON body.
+    pub fn with_rule_id(mut self, rule_id: &str) -> Self {
+        self.rule_id = Some(rule_id.to_string());
+        self
+    }
+
+    // Only read by users of the `internals` feature
+    #[cfg_attr(not(feature = "internals"), allow(dead_code))]
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn into_response(self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut builder = Response::builder().status(self.status);
+        if self.status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+            builder = builder.header(PROXY_AUTHENTICATE, "Basic realm=\"kitty_proxy\"");
+        }
+        if let Some(retry_after) = self.retry_after {
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            builder = builder.header(RETRY_AFTER, secs);
+        }
+        let page = match self.error_page {
+            _ if self.status.is_success() => None,
+            ErrorPage::Empty => None,
+            ErrorPage::Html => Some(("text/html; charset=utf-8", self.html_page())),
+            ErrorPage::Json => Some(("application/json", self.json_body())),
+        };
+        let response = match page {
+            Some((content_type, page)) => builder.header(CONTENT_TYPE, content_type).body(
+                http_body_util::Full::new(Bytes::from(page))
+                    .map_err(|never| match never {})
+                    .boxed(),
+            ),
+            None => builder.body(empty_body()),
+        };
+        response.unwrap()
+    }
+
+    fn html_page(&self) -> String {
+        let title = format!(
+            "{} {}",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or("Proxy Error")
+        );
+        // The connection id lets users point at the matching log lines
+        let connection = current_connection_id()
+            .map(|id| format!(" (connection {})", id))
+            .unwrap_or_default();
+        format!(
+            "<html><head><title>{title}</title></head><body><h1>{title}</h1><p>kitty_proxy: {}{}</p></body></html>",
+            self.code, connection
+        )
+    }
+
+    fn json_body(&self) -> String {
+        let body = ErrorBody {
+            status: self.status.as_u16(),
+            reason: self.code.to_string(),
+            rule_id: self.rule_id.as_deref(),
+            connection: current_connection_id().map(|id| id.to_string()),
+            retry: RetryAdvice::from(self.code),
+        };
+        serde_json::to_string(&body).unwrap_or_default()
+    }
+}
+
 pub fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
+    authority_target(scheme_str, authority).map(|target| target.to_address())
+}
+
+/// Target of a request URI or `Host` header authority, the port defaulting to
+/// the one of the scheme.
+pub fn authority_target(scheme_str: Option<&str>, authority: &Authority) -> Option<TargetAddr> {
     // RFC7230 indicates that we should ignore userinfo
     // https://tools.ietf.org/html/rfc7230#section-5.3.3
 
@@ -79,21 +285,42 @@ pub fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option
         // Must be a IPv6 address
         let addr = &host_str[1..host_str.len() - 1];
         match addr.parse::<Ipv6Addr>() {
-            Ok(a) => Some(Address::from((IpAddr::V6(a), port))),
-            // Ignore invalid IPv6 address
-            Err(..) => None,
+            Ok(a) => Some(TargetAddr::new(normalize_host(Host::Ipv6(a)), port)),
+            // A link-local address with a zone, `%` encoded as `%25`
+            // https://tools.ietf.org/html/rfc6874#section-2
+            Err(..) => {
+                let zoned = addr.replacen("%25", "%", 1);
+                scoped_ipv6(&zoned)?;
+                Some(TargetAddr::new(Host::Domain(zoned), port))
+            }
         }
     } else {
         // It must be a IPv4 address
         match host_str.parse::<Ipv4Addr>() {
-            Ok(a) => Some(Address::from((IpAddr::V4(a), port))),
+            Ok(a) => Some(TargetAddr::new(Host::Ipv4(a), port)),
             // Should be a domain name, or a invalid IP address.
             // Let DNS deal with it.
-            Err(..) => Some(Address::DomainNameAddress(host_str.to_owned(), port)),
+            Err(..) => Some(TargetAddr::new(Host::Domain(host_str.to_owned()), port)),
         }
     }
 }
 
+/// Point `req` and its target `host` to `port`, for `redirect-port=` rules.
+fn redirect_port<B>(req: &mut Request<B>, host: &mut Address, port: u16) {
+    match host {
+        Address::SocketAddress(addr) => addr.set_port(port),
+        Address::DomainNameAddress(_, host_port) => *host_port = port,
+    }
+    let mut parts = req.uri().clone().into_parts();
+    if parts.authority.is_some() {
+        let authority = TargetAddr::from(&*host).to_string();
+        parts.authority = Authority::from_str(&authority).ok();
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
 fn get_addr_from_header(req: &mut Request<body::Incoming>) -> Result<Address, ()> {
     // Try to be compatible as a transparent HTTP proxy
     match req.headers().get("Host") {
@@ -170,56 +397,326 @@ fn get_addr_from_header(req: &mut Request<body::Incoming>) -> Result<Address, ()
     }
 }
 
-async fn tunnel(
-    upgraded: Upgraded,
-    target_host: Address,
-    is_direct: bool,
-    req: Request<body::Incoming>,
-) -> std::io::Result<()> {
-    let mut upgraded = TokioIo::new(upgraded);
-    let mut target_stream = TcpStream::connect(target_host.to_string()).await.unwrap();
-    if !is_direct {
-        target_stream
-            .write_all(
-                format!(
-                    "CONNECT {} {:?}\r\nHost: {}\r\nUser-Agent: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
-                    req.uri().to_string(),
-                    req.version(),
-                    req.uri().to_string(),
-                    req.headers()
-                        .get(USER_AGENT)
-                        .and_then(|v| v.to_str().ok())
-                        .unwrap_or("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3")
-                )
-                    .as_bytes(),
+/// Upper bound of the CONNECT response head we accept from a VPN node.
+const MAX_CONNECT_REPLY_SIZE: usize = 8192;
+
+/// Headers of a VPN node reply not relayed to the client: hop-by-hop ones,
+/// RFC 9110 section 7.6.1, and the framing of the body, which is re-framed.
+const UNRELAYED_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "te",
+    "trailer",
+    "upgrade",
+    "transfer-encoding",
+    "content-length",
+];
+
+/// Status line and headers returned by the VPN node for a CONNECT request.
+struct ConnectReply {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    // Bytes received after the response head
+    remaining: Vec<u8>,
+}
+
+impl ConnectReply {
+    fn content_length(&self) -> usize {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+    }
+
+    /// Turn an error reply into a response relayed to the client, without
+    /// the hop-by-hop headers. The body read along with the head, at most
+    /// `MAX_CONNECT_REPLY_SIZE`, is sent with its own length, and dropped
+    /// when chunked.
+    fn into_response(mut self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        // Also hop-by-hop, the headers listed by `Connection`
+        let listed: Vec<String> = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| v.split(',').map(|name| name.trim().to_ascii_lowercase()))
+            .collect();
+        let relayed = |name: &str| {
+            let name = name.to_ascii_lowercase();
+            !UNRELAYED_HEADERS.contains(&name.as_str()) && !listed.contains(&name)
+        };
+        if self.is_chunked() {
+            self.remaining.clear();
+        }
+        self.remaining.truncate(self.content_length().min(MAX_CONNECT_REPLY_SIZE));
+        let mut builder = Response::builder().status(self.status);
+        for (k, v) in self.headers.iter().filter(|(k, _)| relayed(k)) {
+            builder = builder.header(k.as_str(), v.as_str());
+        }
+        builder
+            .body(
+                http_body_util::Full::new(Bytes::from(self.remaining))
+                    .map_err(|never| match never {})
+                    .boxed(),
             )
-            .await
-            .unwrap();
+            .unwrap_or_else(|_| {
+                let mut response = Response::new(empty_body());
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                response
+            })
+    }
+}
 
-        // 读取代理服务器响应
-        let mut resp_buf = [0; 1024];
-        let resp_len = target_stream.read(&mut resp_buf).await.unwrap();
-        let resp_str = String::from_utf8_lossy(&resp_buf[..resp_len]);
-        if !resp_str.starts_with("HTTP/1.1 200") {
-            eprintln!("Proxy server denied CONNECT request: {}", resp_str);
-            return Ok(());
-        }
-        // 确保代理服务器响应成功
-        let (from_client, from_server) =
-            tokio::io::copy_bidirectional(&mut upgraded, &mut target_stream).await?;
-        println!(
-            "client wrote {} bytes and received {} bytes",
-            from_client, from_server
-        );
-    } else {
-        let (from_client, from_server) =
-            tokio::io::copy_bidirectional(&mut upgraded, &mut target_stream).await?;
-        println!(
-            "client wrote {} bytes and received {} bytes",
-            from_client, from_server
-        );
+fn parse_connect_reply(head: &[u8], remaining: Vec<u8>) -> io::Result<ConnectReply> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let head = std::str::from_utf8(head).map_err(|_| invalid("CONNECT reply is not utf8"))?;
+    let mut lines = head.split("\r\n");
+    // e.g. "HTTP/1.1 407 Proxy Authentication Required"
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts.next().unwrap_or_default().starts_with("HTTP/") {
+        return Err(invalid("CONNECT reply has no HTTP status line"));
+    }
+    let status = parts
+        .next()
+        .and_then(|code| StatusCode::from_str(code).ok())
+        .ok_or_else(|| invalid("CONNECT reply has an invalid status code"))?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    Ok(ConnectReply {
+        status,
+        headers,
+        remaining,
+    })
+}
+
+async fn read_connect_reply(stream: &mut BoxedStream) -> io::Result<ConnectReply> {
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_CONNECT_REPLY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "CONNECT reply head too large",
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "node closed before answering CONNECT",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let remaining = buf.split_off(head_end + 4);
+    let mut reply = parse_connect_reply(&buf[..head_end], remaining)?;
+    if !reply.status.is_success() {
+        // Read the error body so it can be relayed to the client
+        let content_length = reply.content_length().min(MAX_CONNECT_REPLY_SIZE);
+        if reply.remaining.len() < content_length {
+            let mut rest = vec![0u8; content_length - reply.remaining.len()];
+            stream.read_exact(&mut rest).await?;
+            reply.remaining.extend_from_slice(&rest);
+        }
+    }
+    Ok(reply)
+}
+
+/// Node a proxied request would use, looked up without counting a connection.
+pub(crate) fn dry_run_node(
+    rule: &TrafficStreamRule,
+    arc_banlancer: &Arc<NodeRegistry>,
+) -> Option<SocketAddr> {
+    if *rule != TrafficStreamRule::Proxy {
+        return None;
+    }
+    arc_banlancer
+        .load()
+        .pick_node()
+        .map(|node_info| node_info.socket_addr)
+}
+
+/// Ask the VPN node to open a tunnel to the requested host.
+async fn connect_via_node(
+    target_stream: &mut BoxedStream,
+    req: &Request<body::Incoming>,
+) -> io::Result<ConnectReply> {
+    target_stream
+        .write_all(
+            format!(
+                "CONNECT {} {:?}\r\nHost: {}\r\nUser-Agent: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
+                req.uri(),
+                req.version(),
+                req.uri(),
+                req.headers()
+                    .get(USER_AGENT)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3")
+            )
+                .as_bytes(),
+        )
+        .await?;
+    // 读取代理服务器响应
+    read_connect_reply(target_stream).await
+}
+
+/// Decision forced with the `route_override_header`, honoured for clients on
+/// the loopback interface and authenticated ones. The header is removed
+/// either way.
+fn route_override(
+    req: &mut Request<body::Incoming>,
+    config: &ProxyConfig,
+    client_addr: SocketAddr,
+    authenticated: bool,
+) -> Option<RuleDecision> {
+    let header = config.route_override_header.as_ref()?;
+    let value = req.headers_mut().remove(header)?;
+    if !authenticated && !client_addr.ip().to_canonical().is_loopback() {
+        listener_log!(config, Level::Warn, "Ignoring {} from {}", header, client_addr);
+        return None;
+    }
+    let action = value.to_str().ok().map(str::trim).unwrap_or_default();
+    match RuleDecision::overridden(action) {
+        Ok(decision) => Some(decision),
+        Err(e) => {
+            listener_log!(config, Level::Warn, "Ignoring {} {:?}: {}", header, value, e);
+            None
+        }
+    }
+}
+
+/// User of the `Proxy-Authorization: Basic ...` header, `None` unless it
+/// carries the password of one of `credentials`.
+fn authorized_user<B>(req: &Request<B>, credentials: &Credentials) -> Option<String> {
+    let encoded = req
+        .headers()
+        .get(PROXY_AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))?;
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    let username = std::str::from_utf8(&decoded[..colon]).ok()?;
+    credentials
+        .check(username, &decoded[colon + 1..])
+        .then(|| username.to_string())
+}
+
+async fn connect_target(
+    target_host: &Address,
+    is_direct: bool,
+    config: &ProxyConfig,
+    outbound: &OutboundOptions,
+    node_connector: &NodeConnector,
+) -> Result<BoxedStream, ResponseCode> {
+    let connect = async {
+        if is_direct {
+            let stream: BoxedStream = Box::new(outbound::connect_direct(target_host, outbound).await?);
+            Ok(stream)
+        } else {
+            node_connector.connect(target_host, outbound).await
+        }
+    };
+    let adaptive = config.adaptive_timeout.as_ref();
+    let Some(res) = node_connector
+        .within_timeout(target_host, config.timeout, adaptive, connect)
+        .await
+    else {
+        listener_log!(config, Level::Error, "HTTP connect {} timed out", target_host);
+        return Err(ResponseCode::TtlExpired);
+    };
+    let stream = res.map_err(|e| {
+        listener_log!(config, Level::Error, "HTTP connect {} failed: {}", target_host, e);
+        ResponseCode::ConnectionRefused
+    })?;
+    Ok(match &config.chaos {
+        Some(chaos) => chaos.degrade(stream),
+        None => stream,
+    })
+}
+
+/// Open the tunnel of a CONNECT `req` to `target_host`, asking it for the
+/// requested host unless `direct`. Returns the stream and the bytes the node
+/// sent past its reply, or the response for the client and whether the target
+/// was refused: failing to reach a node tells nothing about the target.
+async fn connect_tunnel(
+    req: &Request<body::Incoming>,
+    target_host: &Address,
+    direct: bool,
+    config: &ProxyConfig,
+    outbound: &OutboundOptions,
+    node_connector: &NodeConnector,
+    error_page: ErrorPage,
+) -> Result<(BoxedStream, Vec<u8>), (Response<BoxBody<Bytes, hyper::Error>>, bool)> {
+    let connect = connect_target(target_host, direct, config, outbound, node_connector);
+    let mut target_stream = match connect.await {
+        Ok(stream) => stream,
+        Err(code) => {
+            let response = HttpReply::new(code).with_error_page(error_page).into_response();
+            return Err((response, direct));
+        }
+    };
+    if direct {
+        return Ok((target_stream, Vec::new()));
+    }
+    match connect_via_node(&mut target_stream, req).await {
+        Ok(reply) if reply.status.is_success() => Ok((target_stream, reply.remaining)),
+        Ok(reply) => {
+            listener_log!(
+                config,
+                Level::Error,
+                "Proxy server denied CONNECT {}: {}",
+                req.uri(),
+                reply.status
+            );
+            Err((reply.into_response(), true))
+        }
+        Err(e) => {
+            listener_log!(
+                config,
+                Level::Error,
+                "HTTP CONNECT {} via {} failed: {}",
+                req.uri(),
+                target_host,
+                e
+            );
+            let response = HttpReply::new(ResponseCode::HttpBadGateway)
+                .with_error_page(error_page)
+                .into_response();
+            Err((response, false))
+        }
     }
-    Ok(())
+}
+
+async fn tunnel(
+    mut upgraded: TokioIo<Upgraded>,
+    mut target_stream: BoxedStream,
+    early_data: Vec<u8>,
+    stall_timeout: Option<Duration>,
+    tracked: &TrackedTunnel,
+) -> std::io::Result<TunnelBytes> {
+    if !early_data.is_empty() {
+        upgraded.write_all(&early_data).await?;
+        tracked.record(TunnelSide::Upstream, early_data.len() as u64);
+    }
+    let throughput = Some(tracked.throughput());
+    let tunnel = relay(&mut upgraded, &mut target_stream, stall_timeout, throughput);
+    let mut bytes = tunnel.await?;
+    bytes.downloaded += early_data.len() as u64;
+    Ok(bytes)
 }
 
 async fn send_connect_req(
@@ -251,50 +748,313 @@ async fn send_connect_req(
 pub struct HttpProxy {
     ip: String,
     port: u16,
-    timeout: Option<Duration>,
-    banlancer: ArcConnectionStatsBanlancer,
-    is_serve: bool,
+    config: ArcProxyConfig,
+    connections: ActiveConnections,
+    usage: ProxyUsage,
+    cache: ResponseCache,
+    node_connector: NodeConnector,
+    banlancer: Arc<NodeRegistry>,
+    serve_state: ServeState,
+    runtime_errors: Option<RuntimeErrorSender>,
+    match_proxy: Option<Arc<SharedRules>>,
+    /// Address accepted on, the port may be a fallback one
+    local_addr: Option<SocketAddr>,
 }
 
 impl HttpProxy {
+    /// Fails with `ProxyRuntimeError::LanNotAllowed` as source for a
+    /// non-loopback `ip`, use `with_config` to set credentials or `allow_lan`.
     pub async fn new(ip: &str, port: u16, timeout: Option<Duration>) -> io::Result<Self> {
+        let config = ProxyConfig {
+            timeout,
+            ..Default::default()
+        };
+        Self::with_config(ip, port, config).await
+    }
+
+    /// Listener starting with `config`, which may expose it to the network
+    /// with `credentials` or `allow_lan`.
+    pub async fn with_config(ip: &str, port: u16, mut config: ProxyConfig) -> io::Result<Self> {
+        exposure_error(ip, &config)?;
         info!("Http proxy listening on {}:{}", ip, port);
+        config
+            .log_target
+            .get_or_insert_with(|| format!("kitty_proxy::http[{}]", port));
         Ok(Self {
             ip: ip.to_string(),
             port,
-            timeout,
-            banlancer: Arc::new(Mutex::new(None)),
-            is_serve: false,
+            config: Arc::new(RwLock::new(config)),
+            connections: ActiveConnections::default(),
+            usage: ProxyUsage::default(),
+            cache: ResponseCache::default(),
+            node_connector: NodeConnector::default(),
+            banlancer: Arc::default(),
+            serve_state: ServeState::default(),
+            runtime_errors: None,
+            match_proxy: None,
+            local_addr: None,
+        })
+    }
+
+    /// Multiplex tunnels over `sessions_per_node` yamux sessions per VPN node.
+    #[cfg(feature = "mux")]
+    pub fn enable_mux(&mut self, sessions_per_node: usize) {
+        self.node_connector = NodeConnector::with_mux(sessions_per_node);
+    }
+
+    /// Answer failed requests with an HTML error page instead of an empty body.
+    pub async fn set_error_page(&self, error_page: bool) {
+        self.update_config(ProxyConfigUpdate {
+            error_page: Some(error_page),
+            ..Default::default()
         })
+        .await;
     }
 
+    /// Apply `update` to connections accepted from now on, the listener keeps running.
+    pub async fn update_config(&self, update: ProxyConfigUpdate) {
+        let mut config = self.config.write().await;
+        config.apply(update);
+        info!("Http proxy {}:{} config updated: {:?}", self.ip, self.port, config);
+    }
+
+    pub async fn config(&self) -> ProxyConfig {
+        self.config.read().await.clone()
+    }
+
+    /// Answer every plain HTTP request for `host` (a domain or an IP, any
+    /// port) with `response` instead of forwarding it, e.g. a block page or
+    /// a PAC file. Replaces the response of the host.
+    pub async fn set_local_response(&self, host: &str, response: LocalResponse) {
+        let mut config = self.config.write().await;
+        config.local_responses.insert(response_key(host), response);
+    }
+
+    /// Forward the requests for `host` again, `false` if it had no response.
+    pub async fn remove_local_response(&self, host: &str) -> bool {
+        let mut config = self.config.write().await;
+        config.local_responses.remove(&response_key(host)).is_some()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.connections.count()
+    }
+
+    /// Close the connection `id`, as logged and listed in `tunnels()`: its
+    /// tasks are cancelled and its tunnels closed as `cancelled`. `false`
+    /// when the listener has no such connection open.
+    pub fn kill_connection(&self, id: ConnectionId) -> bool {
+        self.connections.kill(id)
+    }
+
+    /// Open tunnels with their throughput of the last seconds, e.g. for a live
+    /// speed display.
+    pub fn tunnels(&self) -> Vec<TunnelSnapshot> {
+        self.connections.tunnels()
+    }
+
+    /// Bandwidth used by every client seen within the quota window.
+    pub async fn client_usage(&self) -> HashMap<IpAddr, QuotaState> {
+        let config = self.config.read().await;
+        self.usage.clients.snapshot(config.quota.as_ref()).await
+    }
+
+    /// Bandwidth used by every authenticated user over the last day.
+    pub async fn user_usage(&self) -> HashMap<String, QuotaState> {
+        self.usage.users.snapshot(None).await
+    }
+
+    /// ALPN and TLS version counts of sniffed tunnels, per destination network
+    /// and node.
+    pub async fn protocol_usage(&self) -> HashMap<ProtocolKey, ProtocolCounts> {
+        self.usage.protocols.snapshot().await
+    }
+
+    /// Domains routed against their rule after failing, see
+    /// `ProxyConfig::learned_routes`.
+    pub fn learned_routes(&self) -> Vec<LearnedRoute> {
+        self.banlancer.learned().routes()
+    }
+
+    /// Route `domain` by the rules again, `false` when it had no learned route.
+    pub fn forget_learned_route(&self, domain: &str) -> bool {
+        self.banlancer.learned().forget(domain)
+    }
+
+    /// Take a node out of (or back into) rotation, e.g. from a health check.
+    pub async fn set_node_healthy(&self, socket_addr: SocketAddr, healthy: bool) {
+        self.banlancer.load().set_node_healthy(socket_addr, healthy);
+    }
+
+    /// Stop sending new connections to `socket_addr` and wait for the open
+    /// ones to end, at most until `deadline`, e.g. before rotating the node
+    /// server. Returns the connections still open, `None` for unknown nodes.
+    pub async fn drain_node(&self, socket_addr: SocketAddr, deadline: Instant) -> Option<usize> {
+        let remaining = self.banlancer.drain_node(socket_addr, deadline).await;
+        if let Some(remaining) = remaining {
+            info!("VPN node {} drained, {} connections left", socket_addr, remaining);
+        }
+        remaining
+    }
+
+    /// Send new connections to a node taken out by `drain_node` again.
+    pub fn resume_node(&self, socket_addr: SocketAddr) -> bool {
+        self.banlancer.resume_node(socket_addr)
+    }
+
+    /// Replace the proxy groups rules can name as action, `select` groups
+    /// keep their chosen node while it stays in the group. Choices saved to
+    /// the `selection_file` are restored.
+    pub async fn set_proxy_groups(&self, groups: Vec<ProxyGroup>) {
+        self.banlancer.groups().set_groups(groups);
+        let config = self.config.read().await.clone();
+        restore_selections(&config, self.banlancer.groups()).await;
+    }
+
+    /// Choose the node used from now on by the `select` group `group`, saved
+    /// to the `selection_file` when configured.
+    pub async fn select_group_node(&self, group: &str, node: SocketAddr) -> Result<()> {
+        let config = self.config.read().await.clone();
+        select_group_node(&config, self.banlancer.groups(), group, node).await
+    }
+
+    /// Measure the connect delays of the nodes of `url-test` groups, each
+    /// group then uses its fastest available node.
+    pub async fn test_group_delays(&self) {
+        let config = self.config.read().await.clone();
+        test_group_delays(&config, self.banlancer.groups()).await;
+    }
+
+    /// Everything this listener is running with, for UIs and support dumps.
+    pub async fn config_snapshot(&self) -> ListenerSnapshot {
+        let config = self.config.read().await;
+        let port = self.local_addr.map_or(self.port, |addr| addr.port());
+        let mut snapshot = ListenerSnapshot::new("http", &self.ip, port, &config);
+        snapshot.serving = self.is_serving();
+        snapshot.active_connections = self.connections.count();
+        snapshot.nodes = self.banlancer.load().node_snapshots();
+        snapshot.groups = self.banlancer.groups().snapshots(&self.banlancer.load());
+        if let Some(match_proxy) = &self.match_proxy {
+            snapshot.rules = Some(match_proxy.load().rule_counts());
+        }
+        snapshot
+    }
+
+    /// Number of VPN nodes not marked down.
+    pub async fn healthy_node_count(&self) -> usize {
+        self.banlancer.load().healthy_count()
+    }
+
+    /// Swap the VPN nodes of a running proxy, open connection counts are kept
+    /// for nodes with an unchanged address.
+    pub async fn replace_nodes(&self, vpn_node_infos: Vec<NodeInfo>) {
+        if let (true, Some(errors)) = (vpn_node_infos.is_empty(), &self.runtime_errors) {
+            report(errors, ProxyRuntimeError::NodePoolEmpty);
+        }
+        self.banlancer.replace_nodes(&vpn_node_infos);
+    }
+
+    /// Start accepting connections. Runtime errors (bind or accept failures, empty
+    /// node pool...) are sent to `errors` of the returned handle, which is closed
+    /// right away when the proxy is already serving.
+    ///
+    /// Sending `true` on `rx` stops the proxy: the listener closes, the tasks
+    /// of the open connections are cancelled and `is_serving()` turns false
+    /// once they all ended, when `stopped` of the returned handle resolves.
+    /// `false` is ignored, dropping the sender stops the proxy too, reported
+    /// as `ListenerClosed`.
     pub async fn serve(
         &mut self,
-        match_proxy: Arc<RwLock<MatchProxy>>,
+        match_proxy: Arc<SharedRules>,
         rx: &mut Receiver<bool>,
         vpn_node_infos: Vec<NodeInfo>,
-    ) {
-        let listener = TcpListener::bind((self.ip.clone(), self.port))
-            .await
-            .unwrap();
-        self.is_serve = true;
+    ) -> ServeHandle {
+        let (errors, errors_rx) = runtime_error_channel();
+        if self.serve_state.is_serving() {
+            warn!("Http proxy {}:{} already serving, only replacing nodes", self.ip, self.port);
+            self.replace_nodes(vpn_node_infos).await;
+            return ServeHandle::not_started(errors_rx);
+        }
+        let config = self.config.read().await.clone();
+        let listener = match bind(&self.ip, self.port, &config).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                report(&errors, e);
+                return ServeHandle::not_started(errors_rx);
+            }
+        };
+        self.local_addr = listener.local_addr().ok();
+        validate_nodes(&config, &vpn_node_infos, &errors).await;
+        let serving = self.serve_state.start();
+        self.match_proxy = Some(Arc::clone(&match_proxy));
+        self.runtime_errors = Some(errors.clone());
         let match_proxy_clone = Arc::clone(&match_proxy);
-        let mut rx_clone = rx.clone();
-        let mut banlancer = self.banlancer.lock().await;
-        *banlancer = Some(ConnectionStatsBanlancer::from_vec(&vpn_node_infos));
-        drop(banlancer);
+        let mut shutdown = Shutdown::new(rx);
+        self.replace_nodes(vpn_node_infos).await;
+        StartupReport::new(&self.config_snapshot().await).log();
         let banlancer_clone = Arc::clone(&self.banlancer);
-        tokio::task::spawn(async move {
+        let config_share = Arc::clone(&self.config);
+        let connections = self.connections.clone();
+        let usage = self.usage.clone();
+        let cache = self.cache.clone();
+        let node_connector = self.node_connector.clone();
+        let accept_task = tokio::task::spawn(async move {
+            let _serving = serving;
+            let cancel = CancellationToken::new();
         // loop {
         tokio::select! {
                     _ = async {
+                        let mut pacing = AcceptPacing::default();
                         loop {
-                            let (stream, _client_addr) = listener.accept().await.unwrap();
+                            let (stream, client_addr) = accept(&listener, &errors).await;
+                            let config = Arc::new(config_share.read().await.clone());
+                            pacing.accepted(&config, connections.count()).await;
+                            let guard = match connections.acquire(config.max_connections) {
+                                Some(guard) => guard,
+                                None => {
+                                    listener_log!(
+                                        config,
+                                        Level::Warn,
+                                        "HTTP connection limit reached, dropping {}",
+                                        client_addr
+                                    );
+                                    continue;
+                                }
+                            };
+                            client_keepalive(&config, &stream, client_addr);
+                            let rate_limited = config
+                                .connection_rate
+                                .and_then(|rate| usage.opens.open(client_addr.ip(), &rate).err());
+                            if let Some(retry_after) = rate_limited {
+                                listener_log!(
+                                    config,
+                                    Level::Warn,
+                                    "HTTP client {} opens connections too fast, refused for {:?}",
+                                    client_addr,
+                                    retry_after
+                                );
+                                let io = TokioIo::new(stream);
+                                spawn_connection(ConnectionId::new(), cancel.child_token(), guard, async move {
+                                    let refuse = service_fn(move |req| {
+                                        let reply = HttpReply::new(ResponseCode::TooManyConnections)
+                                            .with_retry_after(retry_after)
+                                            .with_error_page(ErrorPage::for_request(&config, &req));
+                                        async move { Ok::<_, hyper::Error>(reply.into_response()) }
+                                    });
+                                    let serve = http1::Builder::new().keep_alive(false).serve_connection(io, refuse);
+                                    let _ = serve.await;
+                                });
+                                continue;
+                            }
                             let match_proxy_clone = match_proxy_clone.clone();
                             let banlancer_clone = banlancer_clone.clone();
+                            let usage = usage.clone();
+                            let cache = cache.clone();
+                            let node_connector = node_connector.clone();
                             let io = TokioIo::new(stream);
 
-            tokio::task::spawn(async move {
+            spawn_connection(ConnectionId::new(), cancel.child_token(), guard, async move {
+                listener_log!(config, Level::Debug, "HTTP connection from {}", client_addr);
                 if let Err(err) = http1::Builder::new()
                     .preserve_header_case(true)
                     .title_case_headers(true)
@@ -302,7 +1062,16 @@ impl HttpProxy {
                         service_fn(move |req| {
                             let match_proxy_clone = match_proxy_clone.clone();
                             let banlancer_clone = banlancer_clone.clone();
-                            serve_connection(req, match_proxy_clone, banlancer_clone)
+                            serve_connection(
+                                req,
+                                match_proxy_clone,
+                                banlancer_clone,
+                                config.clone(),
+                                client_addr,
+                                usage.clone(),
+                                cache.clone(),
+                                node_connector.clone(),
+                            )
                         }
                     ))
                     .with_upgrades()
@@ -314,37 +1083,102 @@ impl HttpProxy {
                         }
                     } => {}
                     _ =  async {
-                            if rx_clone.changed().await.is_ok() {
+                            if shutdown.requested().await {
                                 return//该任务退出，别的也会停
                         }
+                        report(&errors, ProxyRuntimeError::ListenerClosed);
                     } => {}
                 }
         // }
+            cancel.cancel();
+            drop(listener);
+            drain(&connections).await;
         });
+        ServeHandle {
+            errors: errors_rx,
+            stopped: Stopped::new(accept_task),
+        }
+    }
+
+    /// Address accepted on since `serve()` bound it, with the port actually
+    /// used: a fallback port when the configured one was taken, the port
+    /// picked by the OS for port 0. `None` before the first bind.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
     }
 
     pub fn is_serving(&self) -> bool {
-        self.is_serve
+        self.serve_state.is_serving()
+    }
+
+    /// When the accept loop started, `None` when it isn't running.
+    pub fn serving_since(&self) -> Option<SystemTime> {
+        self.serve_state.serving_since()
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn serve_connection(
     mut req: Request<body::Incoming>,
-    match_proxy_share: Arc<RwLock<MatchProxy>>,
-    arc_banlancer: ArcConnectionStatsBanlancer,
+    match_proxy_share: Arc<SharedRules>,
+    arc_banlancer: Arc<NodeRegistry>,
+    config: Arc<ProxyConfig>,
+    client_addr: SocketAddr,
+    usage: ProxyUsage,
+    cache: ResponseCache,
+    node_connector: NodeConnector,
 ) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>> {
-    let host: Address = match host_addr(req.uri()) {
+    let error_page = ErrorPage::for_request(&config, &req);
+    let username = match &config.credentials {
+        Some(credentials) => match authorized_user(&req, credentials) {
+            Some(username) => Some(username),
+            None => {
+                listener_log!(
+                    config,
+                    Level::Debug,
+                    "HTTP {} URI {} proxy authentication failed",
+                    req.method(),
+                    req.uri()
+                );
+                return Ok(HttpReply::new(ResponseCode::HttpProxyAuthRequired)
+                    .with_error_page(error_page)
+                    .into_response());
+            }
+        },
+        None => None,
+    };
+    let username = username.as_deref();
+    req.headers_mut().remove(PROXY_AUTHORIZATION);
+    let route_override = route_override(&mut req, &config, client_addr, username.is_some());
+    if let Some(quota) = &config.quota {
+        if usage.clients.is_exceeded(&client_addr.ip(), quota).await {
+            listener_log!(
+                config,
+                Level::Warn,
+                "HTTP client {} is over its bandwidth quota",
+                client_addr
+            );
+            return Ok(HttpReply::new(quota.reject_code)
+                .with_error_page(error_page)
+                .into_response());
+        }
+    }
+    let mut host: Address = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
                 // URI has authority but invalid
-                error!(
+                listener_log!(
+                    config,
+                    Level::Error,
                     "HTTP {} URI {} doesn't have a valid host",
                     req.method(),
                     req.uri()
                 );
                 return make_bad_request();
             } else {
-                trace!(
+                listener_log!(
+                    config,
+                    Level::Trace,
                     "HTTP {} URI {} doesn't have a valid host",
                     req.method(),
                     req.uri()
@@ -358,85 +1192,545 @@ pub async fn serve_connection(
         }
         Some(h) => h,
     };
-    let match_proxy = match_proxy_share.read().await;
-
-    let rule = match_proxy.traffic_stream(&Host::from(&host));
+    if let Some(response) = local_response(&config, &req, &host) {
+        listener_log!(
+            config,
+            Level::Debug,
+            "HTTP {} {} answered locally: {}",
+            req.method(),
+            req.uri(),
+            response.status()
+        );
+        return Ok(response);
+    }
+    if let (true, Some(sniff)) = (req.method() == Method::CONNECT, config.sniff.clone()) {
+        let username = username.map(str::to_string);
+        spawn_for_connection(sniffed_connect(
+            req,
+            host,
+            match_proxy_share,
+            arc_banlancer,
+            config,
+            client_addr,
+            username,
+            route_override,
+            usage,
+            node_connector,
+            sniff,
+        ));
+        return Ok(Response::new(empty_body()));
+    }
+    let match_proxy = match_proxy_share.load();
+    let user_agent = req.headers().get(USER_AGENT).and_then(|v| v.to_str().ok());
+    let rule_host = Host::from(&host);
+    let decision = match route_override.filter(|_| !match_proxy.is_default_deny()) {
+        Some(decision) => decision,
+        None => {
+            match_proxy
+                .decide_resolving(user_agent, Some(&client_addr), username, &rule_host, Some(host.port()))
+                .await
+        }
+    };
     drop(match_proxy);
-    info!("HTTP [TCP] {} {} connect", host.to_string(), rule);
+    let learned = config.learned_routes.as_ref();
+    let decision = arc_banlancer.learned().apply(learned, &rule_host, decision);
+    let request = RouteRequest {
+        connection: current_connection_id(),
+        listener: "http",
+        source: Some(client_addr),
+        target: host.clone(),
+        rule_host: rule_host.to_string(),
+        username: username.map(str::to_string),
+        decision,
+    };
+    let decision = route_hook_decision(&config, request).await;
+    set_connection_verbosity(decision.verbosity);
+    if let Some(port) = decision.redirect_port {
+        redirect_port(&mut req, &mut host, port);
+    }
+    let rule = decision.rule.clone();
+    listener_log!(
+        config,
+        Level::Info,
+        "HTTP [TCP] {} {} connect, user {}",
+        host.to_string(),
+        rule,
+        username.unwrap_or("-")
+    );
+    let mut decision_log = DecisionLog {
+        connection: current_connection_id(),
+        network: "TCP",
+        source: Some(client_addr),
+        target: host.to_string(),
+        decision: &decision,
+        node: None,
+    };
     let is_direct = match rule {
+        _ if config.dry_run => {
+            let node_info = dry_run_node(&rule, &arc_banlancer);
+            listener_log!(
+                config,
+                Level::Info,
+                "HTTP [TCP] {} dry run: {} via {:?}, connecting direct",
+                host,
+                rule,
+                node_info
+            );
+            true
+        }
         TrafficStreamRule::Reject => {
-            return Ok(Response::new(empty_body()));
+            decision_log.log();
+            return Ok(HttpReply::new(ResponseCode::RuleFailure)
+                .with_rule_id(&decision.rule_id)
+                .with_error_page(error_page)
+                .into_response());
         }
         TrafficStreamRule::Direct => true,
         TrafficStreamRule::Proxy => false,
     };
-    let node_info = if !is_direct {
-        let banlancer = arc_banlancer.lock().await;
-        let banlancer_ref = banlancer.as_ref().unwrap();
-        Some(banlancer_ref.get_least_connected_node().await)
+    let destination = config.latency_routing.then(|| banlancer::destination_key(&host));
+    // Counted on their node from now on, until the connection ends or fails
+    let (node_info, counted) = if !is_direct {
+        let destination = destination.as_deref();
+        let group = decision.group.as_deref();
+        match arc_banlancer.select_node(config.no_node_policy, destination, group).await {
+            Ok(selected) => selected.unzip(),
+            Err(code) => {
+                listener_log!(config, Level::Error, "HTTP [TCP] {} no VPN node: {}", host, code);
+                return Ok(HttpReply::new(code)
+                    .with_error_page(error_page)
+                    .into_response());
+            }
+        }
     } else {
-        None
+        (None, None)
     };
-
-    let target_host = if is_direct {
-        host
-    } else {
-        Address::from(node_info.unwrap())
+    // Connections of a proxy group stick to the node it picked
+    let (runner_up, runner_up_counted) = match node_info {
+        Some(node_info) if decision.group.is_none() && config.is_hedged(&decision.rule_id) => {
+            let first = node_info.socket_addr;
+            arc_banlancer.select_runner_up(first, destination.as_deref()).unzip()
+        }
+        _ => (None, None),
     };
+    // The node losing a hedged race gets its slot back
+    let winner_counted = |winner: Option<NodeInfo>| match winner {
+        Some(winner) if Some(winner) == runner_up => runner_up_counted,
+        Some(_) => counted,
+        None => None,
+    };
+    // A hedged connection is logged once the node winning the race is known
+    if !config.dry_run && runner_up.is_none() {
+        decision_log.node = node_info.as_ref().map(|node_info| node_info.socket_addr);
+        decision_log.log();
+    }
+
+    let outbound = config.outbound_for(&decision.rule_id);
+    let (group, hop) = (decision.group.as_deref(), UpstreamHop::HttpConnect);
+    let outbound = arc_banlancer.relay_outbound(outbound, group, node_info, hop);
+    let connect_started = Instant::now();
+    // Dropped before the node or the learned routes see a failure
+    if config.chaos.as_ref().is_some_and(ChaosConfig::drops_connect) {
+        listener_log!(config, Level::Debug, "HTTP [TCP] {} connect dropped by chaos", host);
+        return Ok(HttpReply::new(ResponseCode::ConnectionRefused)
+            .with_error_page(error_page)
+            .into_response());
+    }
     if req.method() == Method::CONNECT {
-        tokio::task::spawn(async move {
+        let dial = |node_info: NodeInfo| {
+            let (req, config, node_connector) = (&req, &config, &node_connector);
+            let outbound = &outbound;
+            async move {
+                let target_host = Address::from(node_info);
+                let page = error_page;
+                connect_tunnel(req, &target_host, false, config, outbound, node_connector, page)
+                    .await
+            }
+        };
+        let (node_info, target_host, target_stream, early_data) = match node_info {
+            // NoNodePolicy::Direct
+            None => {
+                let connector = &node_connector;
+                let connect =
+                    connect_tunnel(&req, &host, true, &config, &outbound, connector, error_page);
+                let res = connect.await;
+                let learned_routes = arc_banlancer.learned();
+                learned_routes.record(learned, &rule_host, &decision, true, res.is_ok());
+                match res {
+                    Ok((stream, early_data)) => (None, host, stream, early_data),
+                    Err((response, _)) => return Ok(response),
+                }
+            }
+            Some(primary) => {
+                let res = outbound::hedged(primary, runner_up, dial).await;
+                if let Ok(_) | Err((_, true)) = &res {
+                    let learned_routes = arc_banlancer.learned();
+                    learned_routes.record(learned, &rule_host, &decision, false, res.is_ok());
+                }
+                let res = res.map_err(|(response, _)| response);
+                if res.is_err() {
+                    for node_info in std::iter::once(primary).chain(runner_up) {
+                        arc_banlancer.record_failure(node_info.socket_addr);
+                    }
+                }
+                let node_info = res.as_ref().map_or(primary, |(node_info, _)| *node_info);
+                if runner_up.is_some() {
+                    decision_log.node = Some(node_info.socket_addr);
+                    decision_log.log();
+                }
+                let (target_stream, early_data) = match res {
+                    Ok((_, connected)) => connected,
+                    Err(response) => return Ok(response),
+                };
+                if let Some(destination) = &destination {
+                    let latency = connect_started.elapsed();
+                    arc_banlancer.record_latency(node_info.socket_addr, destination, latency);
+                }
+                let target_host = Address::from(node_info);
+                (Some(node_info), target_host, target_stream, early_data)
+            }
+        };
+        let username = username.map(str::to_string);
+        let counted = winner_counted(node_info);
+        spawn_for_connection(async move {
+            // Counted on the node until the tunnel is closed
+            let _counted = counted;
             match hyper::upgrade::on(&mut req).await {
                 Ok(upgraded) => {
-                    if let Err(e) = tunnel(upgraded, target_host, is_direct, req).await {
-                        error!("server io error: {}", e);
+                    let upgraded = TokioIo::new(upgraded);
+                    let stall_timeout = config.stall_timeout;
+                    let tracked = track_tunnel(req.uri());
+                    let tunnel =
+                        tunnel(upgraded, target_stream, early_data, stall_timeout, &tracked);
+                    let (ip, throughput) = (client_addr.ip(), tracked.throughput());
+                    let res = usage.metered(ip, username.as_deref(), throughput, tunnel).await;
+                    match (log_tunnel_closed(req.uri(), &res), res) {
+                        (_, Ok(_)) => {}
+                        (TunnelCloseReason::Stalled, Err(e)) => {
+                            listener_log!(
+                                config,
+                                Level::Error,
+                                "HTTP CONNECT {} upstream stalled: {}",
+                                target_host,
+                                e
+                            )
+                        }
+                        (_, Err(e)) => {
+                            listener_log!(config, Level::Error, "server io error: {}", e)
+                        }
                     };
                 }
-                Err(e) => error!("upgrade error: {}", e),
+                Err(e) => listener_log!(config, Level::Error, "upgrade error: {}", e),
             }
         });
         let response = Response::new(empty_body());
         return Ok(response);
     }
-    let stream = TcpStream::connect(target_host.to_string()).await.unwrap();
-    let io = TokioIo::new(stream);
-    if !is_direct {
-        let mut banlancer = arc_banlancer.lock().await;
-        let banlancer_ref = banlancer.as_mut().unwrap();
-        banlancer_ref.incre_count_by_node_info(&node_info.unwrap());
+    let cache_key = config.response_cache.as_ref().and_then(|_| ResponseCache::key(&req));
+    if let Some(key) = &cache_key {
+        match cache.lookup(key) {
+            Lookup::Hit(response) => {
+                listener_log!(config, Level::Debug, "HTTP cache hit {}", key);
+                return Ok(response);
+            }
+            Lookup::Revalidate(etag) => {
+                req.headers_mut().insert(IF_NONE_MATCH, etag);
+            }
+            Lookup::Miss => {}
+        }
     }
+    let (node_info, stream) = match node_info {
+        // NoNodePolicy::Direct
+        None => {
+            let res = connect_target(&host, true, &config, &outbound, &node_connector).await;
+            let learned_routes = arc_banlancer.learned();
+            learned_routes.record(learned, &rule_host, &decision, true, res.is_ok());
+            match res {
+                Ok(stream) => (None, stream),
+                Err(code) => {
+                    return Ok(HttpReply::new(code)
+                        .with_error_page(error_page)
+                        .into_response());
+                }
+            }
+        }
+        Some(primary) => {
+            let dial = |node_info: NodeInfo| {
+                let (config, outbound, node_connector) = (&config, &outbound, &node_connector);
+                async move {
+                    let target_host = Address::from(node_info);
+                    connect_target(&target_host, false, config, outbound, node_connector).await
+                }
+            };
+            let res = outbound::hedged(primary, runner_up, dial).await;
+            // Failing to reach a node tells nothing about the target
+            if res.is_ok() {
+                let learned_routes = arc_banlancer.learned();
+                learned_routes.record(learned, &rule_host, &decision, false, true);
+            }
+            if res.is_err() {
+                for node_info in std::iter::once(primary).chain(runner_up) {
+                    arc_banlancer.record_failure(node_info.socket_addr);
+                }
+            }
+            let node_info = res.as_ref().map_or(primary, |(node_info, _)| *node_info);
+            if runner_up.is_some() {
+                decision_log.node = Some(node_info.socket_addr);
+                decision_log.log();
+            }
+            let stream = match res {
+                Ok((_, stream)) => stream,
+                Err(code) => {
+                    return Ok(HttpReply::new(code)
+                        .with_error_page(error_page)
+                        .into_response());
+                }
+            };
+            if let Some(destination) = &destination {
+                let latency = connect_started.elapsed();
+                let node = node_info.socket_addr;
+                arc_banlancer.record_latency(node, destination, latency);
+            }
+            (Some(node_info), stream)
+        }
+    };
+    // The request and its response count against the quota like tunnels do
+    let throughput = Arc::new(Throughput::default());
+    let io = TokioIo::new(MeteredStream::new(stream, Arc::clone(&throughput)));
+    let counted = winner_counted(node_info);
     let (mut sender, conn) = Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
         .handshake(io)
         .await?;
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            error!("Connection failed: {:?}", err);
-        }
+    let conn_config = Arc::clone(&config);
+    let username = username.map(str::to_string);
+    spawn_for_connection(async move {
+        let conn = async {
+            if let Err(err) = conn.await {
+                listener_log!(conn_config, Level::Error, "Connection failed: {:?}", err);
+            }
+        };
+        usage.metered(client_addr.ip(), username.as_deref(), &throughput, conn).await
     });
 
     let resp = sender.send_request(req).await?;
-    if !is_direct {
-        let mut banlancer = arc_banlancer.lock().await;
-        let banlancer_ref = banlancer.as_mut().unwrap();
-        banlancer_ref.decre_count_by_node_info(&node_info.unwrap());
+    drop(counted);
+    let (Some(key), Some(cache_config)) = (cache_key, &config.response_cache) else {
+        return Ok(resp.map(|b| b.boxed()));
+    };
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        if let Some(response) = cache.revalidated(&key, resp.headers()) {
+            listener_log!(config, Level::Debug, "HTTP cache revalidated {}", key);
+            return Ok(response);
+        }
+    }
+    cache.store(key, resp, cache_config).await
+}
+
+/// CONNECT with SNI sniffing: the tunnel is accepted right away and routed once
+/// the TLS ClientHello told the host name, failures can only close it.
+#[allow(clippy::too_many_arguments)]
+async fn sniffed_connect(
+    mut req: Request<body::Incoming>,
+    mut host: Address,
+    match_proxy_share: Arc<SharedRules>,
+    arc_banlancer: Arc<NodeRegistry>,
+    config: Arc<ProxyConfig>,
+    client_addr: SocketAddr,
+    username: Option<String>,
+    route_override: Option<RuleDecision>,
+    usage: ProxyUsage,
+    node_connector: NodeConnector,
+    sniff: SniffConfig,
+) {
+    let mut upgraded = match hyper::upgrade::on(&mut req).await {
+        Ok(upgraded) => TokioIo::new(upgraded),
+        Err(e) => {
+            listener_log!(config, Level::Error, "upgrade error: {}", e);
+            return;
+        }
+    };
+    let (first_bytes, hello) = match read_client_hello(&mut upgraded, sniff.timeout).await {
+        Ok(sniffed) => sniffed,
+        Err(e) => {
+            listener_log!(config, Level::Error, "HTTP CONNECT {} sniffing failed: {}", host, e);
+            return;
+        }
+    };
+    let declared = Host::from(&host);
+    let mut rule_host = declared.clone();
+    let mut destination = host.clone();
+    if let Some(server_name) = hello.as_ref().and_then(|hello| hello.server_name.clone()) {
+        if sniff.reject_mismatch && is_mismatch(&declared, &server_name) {
+            listener_log!(
+                config,
+                Level::Warn,
+                "HTTP [TCP] {} SNI {} mismatch, closing",
+                host,
+                server_name
+            );
+            return;
+        }
+        destination = Address::from((server_name.as_str(), host.port()));
+        rule_host = Host::Domain(server_name);
+    }
+
+    let match_proxy = match_proxy_share.load();
+    let user_agent = req.headers().get(USER_AGENT).and_then(|v| v.to_str().ok());
+    let decision = match route_override.filter(|_| !match_proxy.is_default_deny()) {
+        Some(decision) => decision,
+        None => {
+            match_proxy
+                .decide_resolving(
+                    user_agent,
+                    Some(&client_addr),
+                    username.as_deref(),
+                    &rule_host,
+                    Some(destination.port()),
+                )
+                .await
+        }
+    };
+    drop(match_proxy);
+    let learned = config.learned_routes.as_ref();
+    let decision = arc_banlancer.learned().apply(learned, &rule_host, decision);
+    let request = RouteRequest {
+        connection: current_connection_id(),
+        listener: "http",
+        source: Some(client_addr),
+        target: host.clone(),
+        rule_host: rule_host.to_string(),
+        username: username.clone(),
+        decision,
+    };
+    let decision = route_hook_decision(&config, request).await;
+    set_connection_verbosity(decision.verbosity);
+    if let Some(port) = decision.redirect_port {
+        redirect_port(&mut req, &mut host, port);
+    }
+    listener_log!(
+        config,
+        Level::Info,
+        "HTTP [TCP] {} {} connect, user {}",
+        rule_host,
+        decision.rule,
+        username.as_deref().unwrap_or("-")
+    );
+    let mut decision_log = DecisionLog {
+        connection: current_connection_id(),
+        network: "TCP",
+        source: Some(client_addr),
+        target: host.to_string(),
+        decision: &decision,
+        node: None,
+    };
+    // Counted on its node until the tunnel is closed
+    let selected = match decision.rule {
+        _ if config.dry_run => {
+            listener_log!(config, Level::Info, "HTTP [TCP] {} dry run, connecting direct", host);
+            None
+        }
+        TrafficStreamRule::Reject => {
+            decision_log.log();
+            return;
+        }
+        TrafficStreamRule::Direct => None,
+        TrafficStreamRule::Proxy => {
+            let group = decision.group.as_deref();
+            match arc_banlancer.select_node(config.no_node_policy, None, group).await {
+                Ok(selected) => selected,
+                Err(code) => {
+                    let message = format!("HTTP [TCP] {} no VPN node: {}", host, code);
+                    listener_log!(config, Level::Error, "{}", message);
+                    return;
+                }
+            }
+        }
+    };
+    let (node_info, _counted) = selected.unzip();
+    if !config.dry_run {
+        decision_log.node = node_info.as_ref().map(|node_info| node_info.socket_addr);
+        decision_log.log();
+    }
+
+    let target_host = node_info.map(Address::from).unwrap_or_else(|| host.clone());
+    let direct = node_info.is_none();
+    let record = |connected| {
+        let learned_routes = arc_banlancer.learned();
+        learned_routes.record(learned, &rule_host, &decision, direct, connected);
+    };
+    let outbound = config.outbound_for(&decision.rule_id);
+    let (group, hop) = (decision.group.as_deref(), UpstreamHop::HttpConnect);
+    let outbound = arc_banlancer.relay_outbound(outbound, group, node_info, hop);
+    if config.chaos.as_ref().is_some_and(ChaosConfig::drops_connect) {
+        listener_log!(config, Level::Debug, "HTTP [TCP] {} connect dropped by chaos", host);
+        return;
+    }
+    // The client was answered before sniffing, failures are only logged
+    let error_page = ErrorPage::Empty;
+    let connector = &node_connector;
+    let connect =
+        connect_tunnel(&req, &target_host, direct, &config, &outbound, connector, error_page);
+    let (mut target_stream, early_data) = match connect.await {
+        Ok(connected) => connected,
+        Err((_, target_refused)) => {
+            if target_refused {
+                record(false);
+            }
+            if let Some(node_info) = node_info {
+                arc_banlancer.record_failure(node_info.socket_addr);
+            }
+            return;
+        }
+    };
+    record(true);
+    if let Err(e) = target_stream.write_all(&first_bytes).await {
+        listener_log!(config, Level::Error, "HTTP CONNECT {} replay failed: {}", host, e);
+        return;
+    }
+    if let Some(hello) = &hello {
+        let node = node_info.map(|node_info| node_info.socket_addr);
+        usage
+            .protocols
+            .record(banlancer::destination_key(&destination), node, hello)
+            .await;
+    }
+
+    let tracked = track_tunnel(&host);
+    tracked.record(TunnelSide::Client, first_bytes.len() as u64);
+    let stall_timeout = config.stall_timeout;
+    let tunnel = tunnel(upgraded, target_stream, early_data, stall_timeout, &tracked);
+    let res = usage
+        .metered(client_addr.ip(), username.as_deref(), tracked.throughput(), tunnel)
+        .await;
+    let res = res.map(|mut bytes| {
+        bytes.uploaded += first_bytes.len() as u64;
+        bytes
+    });
+    log_tunnel_closed(&host, &res);
+    if let Err(e) = res {
+        listener_log!(config, Level::Error, "HTTP CONNECT {} io error: {}", host, e);
     }
-    Ok(resp.map(|b| b.boxed()))
 }
 
 #[cfg(test)]
 mod tests {
     use std::{path::PathBuf, sync::Arc};
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV6};
     use std::str::FromStr;
     use std::time::Duration;
 
     use anyhow::Ok;
     use anyhow::Result;
-    use tokio::sync::{RwLock, watch};
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
     use tokio::time;
 
     use super::*;
+    use crate::listener::assert_stops_and_drains;
+    use crate::quota::{ConnectionOpens, ConnectionRateConfig, QuotaConfig};
+    use crate::MatchProxy;
 
     #[tokio::test]
     async fn it_works() -> Result<()> {
@@ -450,7 +1744,7 @@ mod tests {
             Some(&PathBuf::from_str(geosite_file).unwrap()),
         )
             .unwrap();
-        let arc_match_proxy = Arc::new(RwLock::new(match_proxy));
+        let arc_match_proxy = Arc::new(SharedRules::new(match_proxy));
 
         let (http_kill_tx, mut http_kill_rx) = watch::channel(false);
         let mut http_vpn_node_infos = Vec::new();
@@ -465,4 +1759,246 @@ mod tests {
         time::sleep(Duration::from_secs(1000000000)).await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn plain_http_post_body_is_forwarded() -> Result<()> {
+        // Origin answering with the size of the body it received
+        let origin = TcpListener::bind("127.0.0.1:0").await?;
+        let origin_addr = origin.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let service = service_fn(|req: Request<body::Incoming>| async move {
+                let body = req.into_body().collect().await?.to_bytes();
+                let reply = http_body_util::Full::new(Bytes::from(body.len().to_string()));
+                std::result::Result::Ok::<_, hyper::Error>(Response::new(reply))
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let mut proxy = HttpProxy::new("127.0.0.1", port, None).await?;
+        let match_proxy = MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (_kill_tx, mut kill_rx) = watch::channel(false);
+        let _ = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await?;
+        let chunk = vec![b'x'; 64 * 1024];
+        client
+            .write_all(
+                format!(
+                    "POST http://{origin_addr}/upload HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                     Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await?;
+        for _ in 0..4 {
+            client.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+            client.write_all(&chunk).await?;
+            client.write_all(b"\r\n").await?;
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        client.write_all(b"0\r\n\r\n").await?;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(&(4 * chunk.len()).to_string()), "{}", response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plain_http_requests_count_against_the_quota() -> Result<()> {
+        let origin = TcpListener::bind("127.0.0.1:0").await?;
+        let origin_addr = origin.local_addr()?;
+        tokio::spawn(async move {
+            while let std::result::Result::Ok((stream, _)) = origin.accept().await {
+                let service = service_fn(|_req: Request<body::Incoming>| async move {
+                    let reply = http_body_util::Full::new(Bytes::from(vec![b'x'; 1000]));
+                    std::result::Result::Ok::<_, hyper::Error>(Response::new(reply))
+                });
+                tokio::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let config = ProxyConfig {
+            quota: Some(QuotaConfig::per_day(1000)),
+            ..Default::default()
+        };
+        let mut proxy = HttpProxy::with_config("127.0.0.1", port, config).await?;
+        let match_proxy = MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (_kill_tx, mut kill_rx) = watch::channel(false);
+        let _ = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+
+        let get = || async move {
+            let mut client = TcpStream::connect(("127.0.0.1", port)).await?;
+            let request = format!(
+                "GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\
+                 Connection: close\r\n\r\n"
+            );
+            client.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            client.read_to_string(&mut response).await?;
+            Ok(response)
+        };
+        let response = get().await?;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // Charged once the upstream connection is done
+        let charged = async {
+            loop {
+                match proxy.client_usage().await.get(&localhost) {
+                    Some(state) if state.exceeded => break state.clone(),
+                    _ => time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        let used = time::timeout(Duration::from_secs(5), charged).await?;
+        assert!(used.downloaded_bytes > 1000 && used.uploaded_bytes > 0, "{:?}", used);
+        let response = get().await?;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn true_stops_and_drains_the_listener() -> Result<()> {
+        let mut proxy = HttpProxy::new("127.0.0.1", 0, None).await?;
+        let match_proxy = MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (kill_tx, mut kill_rx) = watch::channel(false);
+        let handle = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+        let addr = proxy.local_addr().unwrap();
+        assert_stops_and_drains(addr, &kill_tx, handle, || proxy.is_serving()).await
+    }
+
+    #[test]
+    fn parse_connect_reply_works() {
+        let reply = parse_connect_reply(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic",
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(reply.status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert_eq!(
+            reply.headers,
+            vec![("Proxy-Authenticate".to_string(), "Basic".to_string())]
+        );
+        assert!(parse_connect_reply(b"SSH-2.0-OpenSSH", Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn relayed_connect_replies_are_reframed() {
+        let head = concat!(
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 5\r\nConnection: close, X-Node\r\n",
+            "X-Node: hk-1\r\nContent-Type: text/plain"
+        );
+        let reply = parse_connect_reply(head.as_bytes(), b"deniedHTTP/1.1".to_vec()).unwrap();
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let names: Vec<_> = response.headers().keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, ["content-type"]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "denie");
+
+        let chunked = "HTTP/1.1 502 Bad Gateway\r\nTransfer-Encoding: chunked";
+        let reply = parse_connect_reply(chunked.as_bytes(), b"5\r\nerror\r\n".to_vec()).unwrap();
+        let response = reply.into_response();
+        assert!(response.headers().is_empty());
+    }
+
+    #[test]
+    fn redirected_authorities_keep_their_host() {
+        for (uri, redirected) in [
+            ("http://example.com/a", "http://example.com:8443/a"),
+            ("http://10.0.0.1:80/", "http://10.0.0.1:8443/"),
+            ("http://[2001:db8::1]/", "http://[2001:db8::1]:8443/"),
+        ] {
+            let mut req = Request::get(uri).body(()).unwrap();
+            let mut host = host_addr(req.uri()).unwrap();
+            redirect_port(&mut req, &mut host, 8443);
+            assert_eq!(req.uri().to_string(), redirected);
+            assert_eq!(host.port(), 8443);
+        }
+    }
+
+    #[test]
+    fn zoned_ipv6_authority() {
+        let uri: Uri = "http://[fe80::1%253]:8080/".parse().unwrap();
+        let expected: SocketAddr = SocketAddrV6::new("fe80::1".parse().unwrap(), 8080, 0, 3).into();
+        assert_eq!(host_addr(&uri), Some(Address::SocketAddress(expected)));
+        let uri: Uri = "http://[fe80::1%25no-such-interface0]/".parse().unwrap();
+        assert_eq!(host_addr(&uri), None);
+    }
+
+    #[test]
+    fn authority_targets() {
+        let target = |scheme, authority| {
+            let authority = Authority::from_static(authority);
+            authority_target(scheme, &authority).map(|target| target.to_string())
+        };
+        assert_eq!(target(Some("https"), "example.com").as_deref(), Some("example.com:443"));
+        assert_eq!(target(None, "[::ffff:10.0.0.1]").as_deref(), Some("10.0.0.1:80"));
+        assert_eq!(target(Some("http"), "[fe80::1%253]:8080").as_deref(), Some("[fe80::1%3]:8080"));
+        assert_eq!(target(Some("ftp"), "example.com"), None);
+    }
+
+    #[test]
+    fn each_user_authenticates_with_its_password() {
+        let credentials = Credentials::new("alice", "a-secret").with_user("bob", "b:secret");
+        let user = |value: &str| {
+            let req = Request::builder()
+                .header(PROXY_AUTHORIZATION, format!("Basic {}", BASE64.encode(value)))
+                .body(())
+                .unwrap();
+            authorized_user(&req, &credentials)
+        };
+        assert_eq!(user("alice:a-secret").as_deref(), Some("alice"));
+        assert_eq!(user("bob:b:secret").as_deref(), Some("bob"));
+        assert_eq!(user("alice:b:secret"), None);
+        assert_eq!(user("carol:"), None);
+    }
+
+    #[test]
+    fn json_error_body() {
+        let reply = HttpReply::new(ResponseCode::RuleFailure)
+            .with_rule_id("domain-suffix:ads.com")
+            .with_error_page(ErrorPage::Json);
+        assert_eq!(
+            reply.json_body(),
+            concat!(
+                r#"{"status":403,"reason":"Proxy Rule failure","#,
+                r#""rule_id":"domain-suffix:ads.com","connection":null,"retry":"never"}"#
+            )
+        );
+        let response = HttpReply::new(ResponseCode::NodesSaturated)
+            .with_error_page(ErrorPage::Json)
+            .into_response();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn connection_rate_refusal() {
+        let rate = ConnectionRateConfig::per_second(2);
+        let opens = ConnectionOpens::default();
+        let (client, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(opens.open(client, &rate).is_ok());
+        assert!(opens.open(client, &rate).is_ok());
+        let retry_after = opens.open(client, &rate).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= rate.window);
+        assert!(opens.open(other, &rate).is_ok());
+        let response = HttpReply::new(ResponseCode::TooManyConnections)
+            .with_retry_after(Duration::from_millis(400))
+            .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
 }
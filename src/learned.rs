@@ -0,0 +1,323 @@
+//! Routes learned from failed connects: a domain failing again and again
+//! through the way its rule sends it is tried once the other way, and sent
+//! that way for a while when the trial connects.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use url::Host;
+
+use crate::traffic_diversion::{RuleDecision, TrafficStreamRule};
+
+/// Domains with failures counted, the least recently failed is forgotten first.
+const MAX_FAILING_DOMAINS: usize = 4096;
+
+/// Error budget of the domains before their route is learned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LearnedRouteConfig {
+    /// Failed connects of a domain within `window` turning its route around
+    pub failures: u32,
+    pub window: Duration,
+    /// Time a learned route is used, unless it fails itself before
+    pub ttl: Duration,
+}
+
+impl Default for LearnedRouteConfig {
+    fn default() -> Self {
+        Self {
+            failures: 3,
+            window: Duration::from_secs(60),
+            ttl: Duration::from_secs(1800),
+        }
+    }
+}
+
+/// A domain sent `DIRECT` or `PROXY` against its rule.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LearnedRoute {
+    pub domain: String,
+    /// `direct` or `proxy`
+    pub action: String,
+    /// Id of the rule whose connects failed
+    pub failed_rule_id: String,
+    pub expires_in_secs: u64,
+}
+
+struct Learned {
+    rule: TrafficStreamRule,
+    failed_rule_id: String,
+    expires: Instant,
+}
+
+/// The other way of a domain out of error budget, taken by one connection
+/// before it is learned.
+struct Trial {
+    rule: TrafficStreamRule,
+    failed_rule_id: String,
+    /// When a connection took the trial, `None` until one did
+    taken: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Failures within the window and when it started
+    failures: HashMap<String, (u32, Instant)>,
+    trials: HashMap<String, Trial>,
+    routes: HashMap<String, Learned>,
+}
+
+/// Learned routes of a listener, kept across node and rule reloads.
+#[derive(Default)]
+pub struct LearnedRoutes(Mutex<State>);
+
+impl LearnedRoutes {
+    /// `decision` for `host`, turned around when the domain learned a route
+    /// or for the one connection trying the other way. Decisions forced by
+    /// the client and rejections are kept.
+    pub(crate) fn apply(
+        &self,
+        config: Option<&LearnedRouteConfig>,
+        host: &Host,
+        decision: RuleDecision,
+    ) -> RuleDecision {
+        let (Some(config), Host::Domain(domain)) = (config, host) else {
+            return decision;
+        };
+        if decision.rule == TrafficStreamRule::Reject || decision.rule_id.starts_with("override:") {
+            return decision;
+        }
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+        let (rule, rule_id) = match state.routes.get(domain) {
+            Some(learned) if learned.expires > now => {
+                (learned.rule.clone(), format!("learned:{}", learned.rule))
+            }
+            Some(_) => {
+                state.routes.remove(domain);
+                return decision;
+            }
+            None => {
+                let Some(trial) = state.trials.get_mut(domain) else {
+                    return decision;
+                };
+                // A trial whose connection never told how it went is retaken
+                if trial.taken.is_some_and(|taken| now - taken < config.window) {
+                    return decision;
+                }
+                trial.taken = Some(now);
+                (trial.rule.clone(), format!("trial:{}", trial.rule))
+            }
+        };
+        // Proxied connections keep to the group of the rule
+        let group = match rule {
+            TrafficStreamRule::Proxy => decision.group,
+            _ => None,
+        };
+        RuleDecision {
+            rule,
+            rule_id,
+            redirect_port: decision.redirect_port,
+            group,
+            verbosity: decision.verbosity,
+        }
+    }
+
+    /// Count the outcome of a connect of `host`, made `direct` or through a
+    /// node. Connects not going the way `decision` tells, as in a dry run or
+    /// without a healthy node, aren't counted. Callers don't report proxied
+    /// connects failing for the node itself, unreachable or saturated: they
+    /// tell nothing about the domain.
+    pub(crate) fn record(
+        &self,
+        config: Option<&LearnedRouteConfig>,
+        host: &Host,
+        decision: &RuleDecision,
+        direct: bool,
+        connected: bool,
+    ) {
+        let (Some(config), Host::Domain(domain)) = (config, host) else {
+            return;
+        };
+        let other = match decision.rule {
+            TrafficStreamRule::Direct if direct => TrafficStreamRule::Proxy,
+            TrafficStreamRule::Proxy if !direct => TrafficStreamRule::Direct,
+            _ => return,
+        };
+        let mut state = self.0.lock().unwrap();
+        if decision.rule_id.starts_with("trial:") {
+            let Some(trial) = state.trials.remove(domain) else {
+                return;
+            };
+            if !connected {
+                info!("{} failed {} as well, keeping its rule", domain, trial.rule);
+                return;
+            }
+            info!(
+                "Learned route {} for {} after failed connects by {}, for {:?}",
+                trial.rule, domain, trial.failed_rule_id, config.ttl
+            );
+            let learned = Learned {
+                rule: trial.rule,
+                failed_rule_id: trial.failed_rule_id,
+                expires: Instant::now() + config.ttl,
+            };
+            state.routes.insert(domain.clone(), learned);
+            return;
+        }
+        if decision.rule_id.starts_with("learned:") {
+            // The other way doesn't work either, back to the rules
+            if !connected && state.routes.remove(domain).is_some() {
+                warn!("Learned route of {} failed, using the rules again", domain);
+            }
+            return;
+        }
+        if connected || decision.rule_id.starts_with("override:") {
+            state.failures.remove(domain);
+            return;
+        }
+        let now = Instant::now();
+        if !state.failures.contains_key(domain) && state.failures.len() >= MAX_FAILING_DOMAINS {
+            let stalest = state
+                .failures
+                .iter()
+                .min_by_key(|(_, (_, since))| *since)
+                .map(|(domain, _)| domain.clone());
+            if let Some(stalest) = stalest {
+                state.failures.remove(&stalest);
+            }
+        }
+        let (failures, since) = state.failures.entry(domain.clone()).or_insert((0, now));
+        if now - *since > config.window {
+            *failures = 0;
+            *since = now;
+        }
+        *failures += 1;
+        if *failures < config.failures {
+            return;
+        }
+        state.failures.remove(domain);
+        info!(
+            "Trying {} for {} after {} failed connects by {}",
+            other, domain, config.failures, decision.rule_id
+        );
+        let trial = Trial {
+            rule: other,
+            failed_rule_id: decision.rule_id.clone(),
+            taken: None,
+        };
+        state.trials.insert(domain.clone(), trial);
+    }
+
+    /// Routes in use, by domain.
+    pub fn routes(&self) -> Vec<LearnedRoute> {
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+        state.routes.retain(|_, learned| learned.expires > now);
+        let mut routes: Vec<LearnedRoute> = state
+            .routes
+            .iter()
+            .map(|(domain, learned)| LearnedRoute {
+                domain: domain.clone(),
+                action: learned.rule.to_string(),
+                failed_rule_id: learned.failed_rule_id.clone(),
+                expires_in_secs: (learned.expires - now).as_secs(),
+            })
+            .collect();
+        routes.sort_by(|a, b| a.domain.cmp(&b.domain));
+        routes
+    }
+
+    /// Drop the learned route of `domain`, `false` when it had none.
+    pub fn forget(&self, domain: &str) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.failures.remove(domain);
+        state.trials.remove(domain);
+        state.routes.remove(domain).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_domains_learn_the_other_way() {
+        let learned = LearnedRoutes::default();
+        let config = LearnedRouteConfig::default();
+        let host = Host::Domain("example.com".to_string());
+        let proxied = RuleDecision {
+            rule: TrafficStreamRule::Proxy,
+            rule_id: "domain-suffix:example.com".to_string(),
+            redirect_port: None,
+            group: Some("streaming".to_string()),
+            verbosity: None,
+        };
+        for connected in [false, false, true, false, false] {
+            learned.record(Some(&config), &host, &proxied, false, connected);
+        }
+        assert!(learned.routes().is_empty());
+        learned.record(Some(&config), &host, &proxied, true, false);
+        assert!(learned.routes().is_empty());
+        learned.record(Some(&config), &host, &proxied, false, false);
+        // Nothing is learned before the other way connected
+        assert!(learned.routes().is_empty());
+        let trial = learned.apply(Some(&config), &host, proxied.clone());
+        assert_eq!(trial.rule_id, "trial:direct");
+        // One connection takes the trial, the others follow the rule
+        assert_eq!(learned.apply(Some(&config), &host, proxied.clone()), proxied);
+        learned.record(Some(&config), &host, &trial, true, true);
+        let routes = learned.routes();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].domain, "example.com");
+        assert_eq!(routes[0].action, "direct");
+
+        let decision = learned.apply(Some(&config), &host, proxied.clone());
+        assert_eq!(decision.rule, TrafficStreamRule::Direct);
+        assert_eq!(decision.rule_id, "learned:direct");
+        assert_eq!(decision.group, None);
+        let other = Host::Domain("example.org".to_string());
+        assert_eq!(learned.apply(Some(&config), &other, proxied.clone()), proxied);
+        assert_eq!(learned.apply(None, &host, proxied.clone()), proxied);
+
+        // The learned way failing too goes back to the rules
+        learned.record(Some(&config), &host, &decision, true, false);
+        assert!(learned.routes().is_empty());
+        assert!(!learned.forget("example.com"));
+
+        // A failed trial learns nothing
+        for _ in 0..config.failures {
+            learned.record(Some(&config), &host, &proxied, false, false);
+        }
+        let trial = learned.apply(Some(&config), &host, proxied.clone());
+        learned.record(Some(&config), &host, &trial, true, false);
+        assert!(learned.routes().is_empty());
+        assert_eq!(learned.apply(Some(&config), &host, proxied.clone()), proxied);
+    }
+
+    #[test]
+    fn learned_proxy_routes_keep_the_group() {
+        let learned = LearnedRoutes::default();
+        let config = LearnedRouteConfig::default();
+        let host = Host::Domain("example.com".to_string());
+        let direct = RuleDecision {
+            rule: TrafficStreamRule::Direct,
+            rule_id: "domain-suffix:example.com".to_string(),
+            redirect_port: None,
+            group: None,
+            verbosity: None,
+        };
+        for _ in 0..config.failures {
+            learned.record(Some(&config), &host, &direct, true, false);
+        }
+        let grouped = RuleDecision {
+            group: Some("streaming".to_string()),
+            ..direct
+        };
+        let trial = learned.apply(Some(&config), &host, grouped);
+        assert_eq!(trial.rule, TrafficStreamRule::Proxy);
+        assert_eq!(trial.group.as_deref(), Some("streaming"));
+    }
+}
@@ -1,13 +1,140 @@
+//! HTTP and SOCKS5 proxies dividing traffic between direct connections and
+//! VPN nodes by rules. Built for wasm32, the crate only has the rule engine:
+//! `MatchProxy` parsing rules and deciding, without the proxies, the rule
+//! downloads and the lookups of the OS.
+
+// The rule engine, also built for wasm32
+mod asn;
+mod ports;
+mod rule_plan;
+mod rules;
+mod traffic_diversion;
+mod v2ray_config;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[macro_use]
+mod config;
+#[cfg(not(target_arch = "wasm32"))]
 mod http_proxy;
+#[cfg(not(target_arch = "wasm32"))]
 mod socks_proxy;
+#[cfg(not(target_arch = "wasm32"))]
 mod types;
-mod v2ray_config;
-mod traffic_diversion;
+#[cfg(not(target_arch = "wasm32"))]
 mod traits;
+#[cfg(not(target_arch = "wasm32"))]
 mod banlancer;
+#[cfg(not(target_arch = "wasm32"))]
+mod cache;
+#[cfg(all(not(target_arch = "wasm32"), feature = "mux"))]
+mod mux;
+#[cfg(not(target_arch = "wasm32"))]
+mod outbound;
+#[cfg(not(target_arch = "wasm32"))]
+mod netif;
+#[cfg(not(target_arch = "wasm32"))]
+mod process;
+#[cfg(not(target_arch = "wasm32"))]
+mod quota;
+#[cfg(not(target_arch = "wasm32"))]
+mod relay;
+#[cfg(not(target_arch = "wasm32"))]
+mod responder;
+#[cfg(not(target_arch = "wasm32"))]
+mod decision_log;
+#[cfg(not(target_arch = "wasm32"))]
+mod dns;
+#[cfg(not(target_arch = "wasm32"))]
+mod groups;
+#[cfg(not(target_arch = "wasm32"))]
+mod learned;
+#[cfg(not(target_arch = "wasm32"))]
+mod providers;
+#[cfg(not(target_arch = "wasm32"))]
+mod listener;
+#[cfg(not(target_arch = "wasm32"))]
+mod logging;
+#[cfg(not(target_arch = "wasm32"))]
+mod manager;
+#[cfg(not(target_arch = "wasm32"))]
+mod snapshot;
+#[cfg(not(target_arch = "wasm32"))]
+mod sniff;
+#[cfg(not(target_arch = "wasm32"))]
+mod daemon;
+#[cfg(not(target_arch = "wasm32"))]
+mod embed;
+#[cfg(not(target_arch = "wasm32"))]
+mod udp_over_tcp;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fault;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod prelude;
+#[cfg(all(windows, feature = "windows-service"))]
+pub mod win_service;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
-pub use http_proxy::HttpProxy;
-pub use socks_proxy::SocksProxy;
+pub use asn::load_asn_database;
+pub use rule_plan::{DomainStage, RuleCounts, RuleIssue, RuleIssueKind, RuleReport, StageReport};
+pub use rules::SharedRules;
 pub use traffic_diversion::MatchProxy;
-pub use types::NodeInfo;
-pub use traffic_diversion::TrafficStreamRule;
+pub use traffic_diversion::{
+    DomainResolve, RuleDecision, RuleSource, RuleVerbosity, TrafficStreamRule,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use proxies::*;
+
+/// Everything but the rule engine, left out of wasm32 builds.
+#[cfg(not(target_arch = "wasm32"))]
+mod proxies {
+    pub use crate::cache::CacheConfig;
+    pub use crate::config::{
+        Credentials, NoNodePolicy, ProxyConfig, ProxyConfigUpdate, SharedRouteHook,
+    };
+    pub use crate::daemon::{serve_until_shutdown, Listener};
+    pub use crate::decision_log::{DecisionLog, PinnedLog, DECISION_LOG_TARGET};
+    pub use crate::dns::{dns_stats, DnsStats};
+    pub use crate::embed::{ProxyRuntime, RemoteTask};
+    pub use crate::fault::ChaosConfig;
+    pub use crate::groups::{GroupKind, ProxyGroup};
+    pub use crate::learned::{LearnedRoute, LearnedRouteConfig};
+    pub use crate::http_proxy::{ErrorPage, HttpProxy};
+    pub use crate::listener::{AcceptBackoff, ConnectionId, ServeHandle, Stopped};
+    pub use crate::logging::{init_logging, LogFormat};
+    pub use crate::manager::{ProxyInstance, ProxyManager};
+    pub use crate::outbound::{
+        AdaptiveTimeout, IpPreference, Keepalive, NodeChain, OutboundOptions, UpstreamHop,
+    };
+    pub use crate::providers::{spawn_provider_updates, ProviderBehavior, RuleProvider};
+    pub use crate::responder::LocalResponse;
+    pub use crate::relay::{
+        bandwidth_history, ThroughputSample, TunnelBytes, TunnelCloseReason, TunnelSide,
+        TunnelSnapshot, BANDWIDTH_HISTORY_SECONDS, THROUGHPUT_SECONDS, TUNNEL_LOG_TARGET,
+    };
+    pub use crate::quota::{
+        ConnectionRateConfig, ProtocolCounts, ProtocolKey, QuotaConfig, QuotaState,
+    };
+    pub use crate::snapshot::{
+        GroupSnapshot, ListenerSnapshot, NodeSnapshot, StartupReport, STARTUP_LOG_TARGET,
+    };
+    pub use crate::sniff::SniffConfig;
+    pub use crate::socks_proxy::{SocksProxy, SocksUpstreamHandshake};
+    pub use crate::types::{
+        KittyProxyError, NodeInfo, ProxyRuntimeError, ResponseCode, RetryAdvice, TargetAddr,
+    };
+    pub use crate::traits::{
+        AsyncStream, BoxedStream, HandshakeFuture, RouteDecision, RouteFuture, RouteHook,
+        RouteRequest, UpstreamHandshake,
+    };
+}
+
+/// Requests and replies as the proxies parse and send them, for tests and
+/// tools working at the protocol level. Unlike the `prelude`, they change
+/// with the implementation, minor releases included.
+#[cfg(all(not(target_arch = "wasm32"), feature = "internals"))]
+pub mod internals {
+    pub use crate::http_proxy::HttpReply;
+    pub use crate::socks_proxy::{SOCKSReq, SockCommand, SocksReply};
+}
@@ -0,0 +1,649 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+
+use anyhow::anyhow;
+use log::{debug, error, warn, Level, LevelFilter};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{ActiveConnections, ConnectionGuard, ProxyConfig};
+use crate::groups::ProxyGroups;
+use crate::outbound;
+use crate::relay::Tunnels;
+use crate::traffic_diversion::{RuleDecision, RuleVerbosity};
+use crate::traits::{RouteDecision, RouteRequest};
+use crate::types::{NodeInfo, ProxyRuntimeError};
+
+/// Errors buffered for the embedding application, newer ones are dropped
+/// (and only logged) while the channel is full.
+const RUNTIME_ERROR_CAPACITY: usize = 64;
+
+/// Pause after resource errors such as EMFILE, retrying at once would spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Connections accepted in a row before the accept loop lets the other tasks
+/// run, a burst would otherwise starve the relays of a `current_thread`
+/// runtime.
+const ACCEPT_BATCH: usize = 16;
+
+/// Connect timeout of node tests when the listener has none configured.
+const TEST_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a stopped listener checks whether its connections ended.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub type RuntimeErrorSender = mpsc::Sender<ProxyRuntimeError>;
+pub type RuntimeErrorReceiver = mpsc::Receiver<ProxyRuntimeError>;
+
+pub fn runtime_error_channel() -> (RuntimeErrorSender, RuntimeErrorReceiver) {
+    mpsc::channel(RUNTIME_ERROR_CAPACITY)
+}
+
+pub fn report(errors: &RuntimeErrorSender, runtime_error: ProxyRuntimeError) {
+    error!("{}", runtime_error);
+    if errors.try_send(runtime_error).is_err() {
+        warn!("Runtime error channel full or closed");
+    }
+}
+
+/// What `serve()` hands back.
+pub struct ServeHandle {
+    /// Runtime errors of the listener: bind or accept failures, empty node
+    /// pool...
+    pub errors: RuntimeErrorReceiver,
+    /// Resolves once the listener stopped and its connections ended
+    pub stopped: Stopped,
+}
+
+impl ServeHandle {
+    /// Handle of a `serve()` call that didn't start an accept loop, the
+    /// listener already served or failed to bind.
+    pub(crate) fn not_started(errors: RuntimeErrorReceiver) -> Self {
+        Self {
+            errors,
+            stopped: Stopped(None),
+        }
+    }
+}
+
+/// Future resolving once a listener stopped and drained its connections,
+/// at once when `serve()` didn't start it.
+pub struct Stopped(Option<JoinHandle<()>>);
+
+impl Stopped {
+    pub(crate) fn new(accept_task: JoinHandle<()>) -> Self {
+        Self(Some(accept_task))
+    }
+}
+
+impl Future for Stopped {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(accept_task) = &mut self.0 {
+            // A panicking accept task stopped the listener as well
+            let _ = ready!(Pin::new(accept_task).poll(cx));
+            self.0 = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Check that `false` on `kill_tx` is ignored by the listener serving at
+/// `addr`, and that `true` stops it and closes its open connections.
+#[cfg(test)]
+pub(crate) async fn assert_stops_and_drains(
+    addr: SocketAddr,
+    kill_tx: &watch::Sender<bool>,
+    handle: ServeHandle,
+    is_serving: impl Fn() -> bool,
+) -> anyhow::Result<()> {
+    use tokio::io::AsyncReadExt;
+    use tokio::time;
+
+    let mut open = TcpStream::connect(addr).await?;
+    kill_tx.send(false)?;
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(is_serving());
+    TcpStream::connect(addr).await?;
+
+    kill_tx.send(true)?;
+    time::timeout(Duration::from_secs(5), handle.stopped).await?;
+    assert!(!is_serving());
+    assert_eq!(open.read(&mut [0; 16]).await?, 0);
+    assert!(TcpStream::connect(addr).await.is_err());
+    Ok(())
+}
+
+/// Stop signal of a listener, the receiver given to `serve()`: sending `true`
+/// stops the listener, `false` is ignored and dropping the sender stops it
+/// too.
+pub(crate) struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub(crate) fn new(rx: &watch::Receiver<bool>) -> Self {
+        Self(rx.clone())
+    }
+
+    /// Wait for the listener to be stopped, `false` when the sender was
+    /// dropped rather than sending `true`.
+    pub(crate) async fn requested(&mut self) -> bool {
+        self.0.wait_for(|stop| *stop).await.is_ok()
+    }
+}
+
+/// Wait for the connections of a stopped listener to end, once their tokens
+/// were cancelled.
+pub(crate) async fn drain(connections: &ActiveConnections) {
+    while connections.count() > 0 {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Decision of the `route_hook` of `config` for `request`, the one of the
+/// request without hook.
+pub(crate) async fn route_hook_decision(config: &ProxyConfig, request: RouteRequest) -> RuleDecision {
+    let Some(hook) = &config.route_hook else {
+        return request.decision;
+    };
+    let decided = request.decision.clone();
+    match hook.0.on_route(request).await {
+        RouteDecision::Keep => decided,
+        RouteDecision::Replace(decision) => {
+            debug!("Route hook replaced {} with {}", decided.rule_id, decision.rule_id);
+            decision
+        }
+    }
+}
+
+/// Refuse to listen on `ip` when it is reachable from other hosts while
+/// clients don't have to authenticate, unless `allow_lan` is set. Host names
+/// other than `localhost` count as reachable.
+pub(crate) fn check_exposure(ip: &str, config: &ProxyConfig) -> Result<(), ProxyRuntimeError> {
+    let is_loopback = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip.to_canonical().is_loopback(),
+        Err(_) => ip.eq_ignore_ascii_case("localhost"),
+    };
+    if is_loopback || config.credentials.is_some() || config.allow_lan {
+        return Ok(());
+    }
+    Err(ProxyRuntimeError::LanNotAllowed(ip.to_string()))
+}
+
+/// `check_exposure` for the constructors of the listeners, the typed error
+/// is kept as the source of the `io::Error`.
+pub(crate) fn exposure_error(ip: &str, config: &ProxyConfig) -> io::Result<()> {
+    check_exposure(ip, config).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))
+}
+
+/// Bind `ip:port`, or while it is taken the first free port among the
+/// `fallback_ports` of `config`. Fails with the error of `port`, or as
+/// `check_exposure` since the config may have changed after `new()`.
+pub(crate) async fn bind(
+    ip: &str,
+    port: u16,
+    config: &ProxyConfig,
+) -> Result<TcpListener, ProxyRuntimeError> {
+    check_exposure(ip, config)?;
+    let e = match TcpListener::bind((ip, port)).await {
+        Ok(listener) => return Ok(listener),
+        Err(e) => e,
+    };
+    let fallback_ports = match (e.kind(), &config.fallback_ports) {
+        (io::ErrorKind::AddrInUse, Some(ports)) => ports.clone(),
+        _ => return Err(ProxyRuntimeError::Bind(format!("{}:{}", ip, port), e)),
+    };
+    for fallback in fallback_ports.filter(|fallback| *fallback != port) {
+        match TcpListener::bind((ip, fallback)).await {
+            Ok(listener) => {
+                warn!("Port {} of {} in use, listening on {} instead", port, ip, fallback);
+                return Ok(listener);
+            }
+            Err(e) => debug!("Fallback port {} of {} unavailable: {}", fallback, ip, e),
+        }
+    }
+    Err(ProxyRuntimeError::Bind(format!("{}:{}", ip, port), e))
+}
+
+/// Report the nodes failing a test connect when `validate_nodes` is enabled,
+/// so misconfigured nodes show up at startup rather than as slow connections.
+pub(crate) async fn validate_nodes(
+    config: &ProxyConfig,
+    nodes: &[NodeInfo],
+    errors: &RuntimeErrorSender,
+) {
+    if !config.validate_nodes {
+        return;
+    }
+    let timeout = config.timeout.unwrap_or(TEST_CONNECT_TIMEOUT);
+    for (node, e) in outbound::unreachable_nodes(nodes, &config.outbound, timeout).await {
+        report(errors, ProxyRuntimeError::NodeUnreachable(node, e));
+    }
+}
+
+/// Choose the nodes saved to the `selection_file` again, e.g. after a restart.
+pub(crate) async fn restore_selections(config: &ProxyConfig, groups: &ProxyGroups) {
+    let Some(path) = &config.selection_file else {
+        return;
+    };
+    if let Err(e) = groups.restore_selections(path).await {
+        warn!("Failed to restore node selections from {:?}: {}", path, e);
+    }
+}
+
+/// Choose `node` in the `select` group `group` and save the choice to the
+/// `selection_file`. The node stays chosen when saving fails.
+pub(crate) async fn select_group_node(
+    config: &ProxyConfig,
+    groups: &ProxyGroups,
+    group: &str,
+    node: SocketAddr,
+) -> anyhow::Result<()> {
+    groups.select(group, node)?;
+    if let Some(path) = &config.selection_file {
+        groups
+            .save_selections(path)
+            .await
+            .map_err(|e| anyhow!("failed to save node selections to {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Measure the connect delays of the nodes of `url-test` proxy groups.
+pub(crate) async fn test_group_delays(config: &ProxyConfig, groups: &ProxyGroups) {
+    let timeout = config.timeout.unwrap_or(TEST_CONNECT_TIMEOUT);
+    let nodes = groups.url_test_nodes();
+    let delays = outbound::node_delays(&nodes, &config.outbound, timeout).await;
+    let delays = delays
+        .into_iter()
+        .map(|(node, res)| match res {
+            Ok(delay) => (node, Some(delay)),
+            Err(e) => {
+                warn!("Delay test of node {} failed: {}", node, e);
+                (node, None)
+            }
+        })
+        .collect();
+    groups.record_delays(delays);
+}
+
+/// Crockford base32, as used by ULIDs.
+const ID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Short ULID given to every accepted connection: 48 bits of milliseconds
+/// since the epoch then 32 random bits, 16 characters sorting by time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u128);
+
+impl ConnectionId {
+    pub fn new() -> Self {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(SEQUENCE.fetch_add(1, Ordering::Relaxed));
+        let random = hasher.finish() as u32;
+        Self(((millis & 0xFFFF_FFFF_FFFF) << 32) | random as u128)
+    }
+}
+
+impl Default for ConnectionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.len() != 16 {
+            return Err(anyhow!("connection id {} isn't 16 characters long", s));
+        }
+        s.bytes()
+            .try_fold(0u128, |id, c| {
+                let digit = ID_ALPHABET.iter().position(|d| *d == c.to_ascii_uppercase());
+                let digit = digit.ok_or_else(|| anyhow!("invalid connection id {}", s))?;
+                Ok((id << 5) | digit as u128)
+            })
+            .map(Self)
+    }
+}
+
+/// As displayed, a `u128` doesn't survive JSON numbers.
+impl Serialize for ConnectionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in (0..16).rev() {
+            let digit = (self.0 >> (i * 5)) & 0x1F;
+            write!(f, "{}", ID_ALPHABET[digit as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the tasks serving one connection share.
+#[derive(Clone)]
+struct ConnectionContext {
+    id: ConnectionId,
+    cancel: CancellationToken,
+    /// Counts the connection as active until its last task ended
+    guard: Arc<ConnectionGuard>,
+    /// `RuleVerbosity` of its rule as `verbosity_code`, 0 until decided
+    verbosity: Arc<AtomicU8>,
+}
+
+fn verbosity_code(verbosity: Option<RuleVerbosity>) -> u8 {
+    match verbosity {
+        None => 0,
+        Some(RuleVerbosity::Silent) => 1,
+        Some(RuleVerbosity::Verbose) => 2,
+    }
+}
+
+tokio::task_local! {
+    /// Connection served by the current task, see `spawn_for_connection`.
+    static CONNECTION: ConnectionContext;
+}
+
+/// Id of the connection served by the current task.
+pub fn current_connection_id() -> Option<ConnectionId> {
+    CONNECTION.try_with(|context| context.id).ok()
+}
+
+/// Log the connection served by the current task as its rule wants, see
+/// `connection_log_level`.
+pub(crate) fn set_connection_verbosity(verbosity: Option<RuleVerbosity>) {
+    let _ = CONNECTION.try_with(|context| {
+        context.verbosity.store(verbosity_code(verbosity), Ordering::Relaxed)
+    });
+}
+
+/// Level to log a message of `level` about the connection served by the
+/// current task at, `None` to drop it. A `silent` rule keeps warnings and
+/// errors only, a `verbose` one everything, debug messages raised to `Info`
+/// so they show without debug logging. Otherwise `log_level` filters.
+pub(crate) fn connection_log_level(log_level: Option<LevelFilter>, level: Level) -> Option<Level> {
+    let verbosity = CONNECTION.try_with(|context| context.verbosity.load(Ordering::Relaxed));
+    match verbosity.unwrap_or_default() {
+        1 if level > Level::Warn => None,
+        1 => Some(level),
+        2 => Some(level.min(Level::Info)),
+        _ if level <= log_level.unwrap_or(LevelFilter::Trace) => Some(level),
+        _ => None,
+    }
+}
+
+/// Tunnels of the listener serving the current task.
+pub(crate) fn current_tunnels() -> Option<Tunnels> {
+    CONNECTION.try_with(|context| context.guard.tunnels().clone()).ok()
+}
+
+fn spawn_in<F>(context: ConnectionContext, future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let cancel = context.cancel.clone();
+    tokio::spawn(CONNECTION.scope(context, async move {
+        // Handlers watching the token themselves get to wrap up first
+        tokio::select! {
+            biased;
+            output = future => Some(output),
+            _ = cancel.cancelled() => None,
+        }
+    }))
+}
+
+/// Spawn `future` serving the connection `id` of a listener, counted by
+/// `guard`, until `cancel` fires: `None` when it was cancelled first, by a
+/// shutdown of the listener or the connection alone, see
+/// `ActiveConnections::kill`.
+pub(crate) fn spawn_connection<F>(
+    id: ConnectionId,
+    cancel: CancellationToken,
+    mut guard: ConnectionGuard,
+    future: F,
+) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    guard.register(id, cancel.clone());
+    let context = ConnectionContext {
+        id,
+        cancel,
+        guard: Arc::new(guard),
+        verbosity: Arc::default(),
+    };
+    spawn_in(context, future)
+}
+
+/// Spawn `future` as part of the connection of the current task: it keeps
+/// its id, is cancelled along with it and keeps it counted until it ends.
+pub fn spawn_for_connection<F>(future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match CONNECTION.try_with(ConnectionContext::clone) {
+        Ok(context) => spawn_in(context, future),
+        Err(_) => tokio::spawn(async move { Some(future.await) }),
+    }
+}
+
+/// Accept the next connection, reporting failures instead of giving up.
+pub async fn accept(
+    listener: &TcpListener,
+    errors: &RuntimeErrorSender,
+) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            // the peer went away before we got to it
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {}
+            Err(e) => {
+                report(errors, ProxyRuntimeError::Accept(e));
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Slowing down of the accept loop while a listener is busy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcceptBackoff {
+    /// Open connections from which each batch of accepts is followed by `pause`
+    pub busy_connections: usize,
+    pub pause: Duration,
+}
+
+/// Paces the accept loop of a listener, in batches of `ACCEPT_BATCH`.
+#[derive(Default)]
+pub(crate) struct AcceptPacing {
+    accepted: usize,
+}
+
+impl AcceptPacing {
+    /// Count a connection accepted with `active` connections open. At the
+    /// end of a batch yield to the other tasks, or pause with the
+    /// `accept_backoff` of `config` when the listener is busy.
+    pub(crate) async fn accepted(&mut self, config: &ProxyConfig, active: usize) {
+        self.accepted += 1;
+        if self.accepted < ACCEPT_BATCH {
+            return;
+        }
+        self.accepted = 0;
+        match config.accept_backoff {
+            Some(backoff) if active >= backoff.busy_connections => {
+                debug!("{} connections open, pausing accepts for {:?}", active, backoff.pause);
+                tokio::time::sleep(backoff.pause).await;
+            }
+            _ => tokio::task::yield_now().await,
+        }
+    }
+}
+
+/// Probe idle clients with the outbound keepalive settings, so tunnels of
+/// clients that vanished get closed too.
+pub fn client_keepalive(config: &ProxyConfig, stream: &TcpStream, client_addr: SocketAddr) {
+    if let Some(keepalive) = &config.outbound.keepalive {
+        if let Err(e) = keepalive.apply(stream) {
+            warn!("Failed to enable keepalive for {}: {}", client_addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Credentials;
+
+    #[test]
+    fn open_listeners_stay_on_loopback() {
+        let mut config = ProxyConfig::default();
+        for ip in ["127.0.0.1", "::1", "::ffff:127.0.0.1", "localhost"] {
+            assert!(check_exposure(ip, &config).is_ok(), "{}", ip);
+        }
+        for ip in ["0.0.0.0", "::", "192.168.1.2", "proxy.lan"] {
+            let res = check_exposure(ip, &config);
+            assert!(matches!(res, Err(ProxyRuntimeError::LanNotAllowed(_))), "{}", ip);
+        }
+        let e = exposure_error("0.0.0.0", &config).unwrap_err();
+        let source = e.get_ref().and_then(|e| e.downcast_ref::<ProxyRuntimeError>());
+        assert!(matches!(source, Some(ProxyRuntimeError::LanNotAllowed(_))));
+        config.credentials = Some(Credentials::new("user", "secret"));
+        assert!(check_exposure("0.0.0.0", &config).is_ok());
+        config.credentials = None;
+        config.allow_lan = true;
+        assert!(check_exposure("0.0.0.0", &config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn connection_tasks_share_id_and_cancellation() {
+        let shutdown = CancellationToken::new();
+        let connections = ActiveConnections::default();
+        let id = ConnectionId::new();
+        assert_eq!(id.to_string().len(), 16);
+        assert_ne!(id, ConnectionId::new());
+        assert_eq!(id.to_string().to_lowercase().parse::<ConnectionId>().unwrap(), id);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id));
+        assert_eq!(serde_json::from_str::<ConnectionId>(&json).unwrap(), id);
+        let guard = connections.acquire(None).unwrap();
+        let seen = spawn_connection(id, shutdown.child_token(), guard, async {
+            // The rule of the connection decides how much of it is logged
+            let debug = Some(LevelFilter::Debug);
+            assert_eq!(connection_log_level(debug, Level::Trace), None);
+            set_connection_verbosity(Some(RuleVerbosity::Silent));
+            assert_eq!(connection_log_level(debug, Level::Info), None);
+            assert_eq!(connection_log_level(debug, Level::Warn), Some(Level::Warn));
+            set_connection_verbosity(Some(RuleVerbosity::Verbose));
+            let verbose = spawn_for_connection(async {
+                connection_log_level(Some(LevelFilter::Error), Level::Trace)
+            });
+            assert_eq!(verbose.await.unwrap().flatten(), Some(Level::Info));
+            spawn_for_connection(async { current_connection_id() }).await.unwrap().flatten()
+        });
+        assert_eq!(seen.await.unwrap(), Some(Some(id)));
+        assert_eq!(current_connection_id(), None);
+        assert_eq!(connection_log_level(None, Level::Trace), Some(Level::Trace));
+
+        // Tasks spawned by a connection keep it counted until cancelled
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            let guard = connections.acquire(None).unwrap();
+            let cancel = shutdown.child_token();
+            tokens.push(cancel.clone());
+            let opened = spawn_connection(ConnectionId::new(), cancel, guard, async {
+                spawn_for_connection(std::future::pending::<()>());
+            });
+            opened.await.unwrap().unwrap();
+        }
+        assert_eq!(connections.count(), 2);
+        tokens[0].cancel();
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        assert_eq!(connections.count(), 1);
+
+        // Killed by id, the others keep running
+        let guard = connections.acquire(None).unwrap();
+        let killed = ConnectionId::new();
+        let pending = std::future::pending::<()>();
+        let task = spawn_connection(killed, shutdown.child_token(), guard, pending);
+        assert!(connections.kill(killed));
+        assert_eq!(task.await.unwrap(), None);
+        assert!(!connections.kill(killed));
+        assert!(!connections.kill(id));
+        assert_eq!(connections.count(), 1);
+        shutdown.cancel();
+        drain(&connections).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn accepts_yield_between_batches() {
+        let ran = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&ran);
+        tokio::spawn(async move { counter.fetch_add(1, Ordering::Relaxed) });
+        let mut config = ProxyConfig::default();
+        let mut pacing = AcceptPacing::default();
+        for _ in 0..ACCEPT_BATCH - 1 {
+            pacing.accepted(&config, 0).await;
+        }
+        assert_eq!(ran.load(Ordering::Relaxed), 0);
+        pacing.accepted(&config, 0).await;
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+
+        let pause = Duration::from_millis(50);
+        config.accept_backoff = Some(AcceptBackoff {
+            busy_connections: 100,
+            pause,
+        });
+        let started = std::time::Instant::now();
+        for _ in 0..ACCEPT_BATCH {
+            pacing.accepted(&config, 99).await;
+        }
+        assert!(started.elapsed() < pause);
+        for _ in 0..ACCEPT_BATCH {
+            pacing.accepted(&config, 100).await;
+        }
+        assert!(started.elapsed() >= pause);
+    }
+
+    #[tokio::test]
+    async fn bind_falls_back_to_free_ports() -> io::Result<()> {
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        let port = taken.local_addr()?.port();
+        let mut config = ProxyConfig::default();
+        assert!(bind("127.0.0.1", port, &config).await.is_err());
+        config.fallback_ports = Some(port..=port.saturating_add(16));
+        let listener = bind("127.0.0.1", port, &config).await.unwrap();
+        assert_ne!(listener.local_addr()?.port(), port);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::{LevelFilter, Record, SetLoggerError};
+use serde_json::json;
+
+use crate::listener::current_connection_id;
+
+/// Environment variable with the log filters, as read by `env_logger`.
+const LOG_FILTER_ENV: &str = "RUST_LOG";
+
+/// How `init_logging` writes log events to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored lines for people, from `pretty_env_logger`
+    #[default]
+    Pretty,
+    /// One JSON object per line for log pipelines such as Loki or Elastic:
+    /// `{"ts":"2026-01-02T03:04:05.678Z","level":"INFO","target":"kitty_proxy::decision",
+    /// "connection":"01J9Z3K8Q2M4X7AB","message":"..."}`. The field names
+    /// are stable, `connection` is `null` outside of connections
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Install the process wide logger, for binaries letting the crate own
+/// their logging. `RUST_LOG` filters when set, `level` otherwise. Fails
+/// when a logger is already installed.
+pub fn init_logging(format: LogFormat, level: LevelFilter) -> Result<(), SetLoggerError> {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if format == LogFormat::Json {
+        builder.format(|f, record| {
+            let ts = f.timestamp_millis().to_string();
+            writeln!(f, "{}", json_line(&ts, record))
+        });
+    }
+    match std::env::var(LOG_FILTER_ENV) {
+        Ok(filters) => builder.parse_filters(&filters),
+        Err(_) => builder.filter_level(level),
+    };
+    builder.try_init()
+}
+
+fn json_line(ts: &str, record: &Record) -> String {
+    json!({
+        "ts": ts,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "connection": current_connection_id().map(|id| id.to_string()),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use serde_json::Value;
+
+    #[test]
+    fn json_events_have_stable_fields() {
+        let record = Record::builder()
+            .args(format_args!("Socks5 \"proxy\" started"))
+            .level(Level::Warn)
+            .target("kitty_proxy::socks[1080]")
+            .build();
+        let line = json_line("2026-01-02T03:04:05.678Z", &record);
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["ts"], "2026-01-02T03:04:05.678Z");
+        assert_eq!(event["level"], "WARN");
+        assert_eq!(event["target"], "kitty_proxy::socks[1080]");
+        assert!(event["connection"].is_null());
+        assert_eq!(event["message"], "Socks5 \"proxy\" started");
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+    }
+}
@@ -0,0 +1,269 @@
+//! Independent proxy instances in one process, each with its own listeners,
+//! rules, node pool, statistics and runtime error stream.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::sync::{watch, Mutex};
+
+use crate::http_proxy::HttpProxy;
+use crate::listener::{runtime_error_channel, RuntimeErrorReceiver, Stopped};
+use crate::rules::SharedRules;
+use crate::snapshot::ListenerSnapshot;
+use crate::socks_proxy::SocksProxy;
+use crate::types::NodeInfo;
+
+/// Time the listeners of a stopped instance get to stop.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The listeners of one instance with the rules and nodes they serve.
+pub struct ProxyInstance {
+    http: Option<HttpProxy>,
+    socks: Option<SocksProxy>,
+    rules: Arc<SharedRules>,
+    nodes: Vec<NodeInfo>,
+    shutdown: Option<watch::Sender<bool>>,
+    /// One per listener started by `serve`
+    stopped: Vec<Stopped>,
+}
+
+impl ProxyInstance {
+    /// Instance serving `rules` through `nodes`. Rule hit counts live in the
+    /// rules, so instances shouldn't share them.
+    pub fn new(rules: Arc<SharedRules>, nodes: Vec<NodeInfo>) -> Self {
+        Self {
+            http: None,
+            socks: None,
+            rules,
+            nodes,
+            shutdown: None,
+            stopped: Vec::new(),
+        }
+    }
+
+    pub fn with_http(mut self, http: HttpProxy) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    pub fn with_socks(mut self, socks: SocksProxy) -> Self {
+        self.socks = Some(socks);
+        self
+    }
+
+    pub fn http(&self) -> Option<&HttpProxy> {
+        self.http.as_ref()
+    }
+
+    pub fn socks(&self) -> Option<&SocksProxy> {
+        self.socks.as_ref()
+    }
+
+    pub fn rules(&self) -> &Arc<SharedRules> {
+        &self.rules
+    }
+
+    /// Give both listeners a new node pool.
+    pub async fn replace_nodes(&mut self, nodes: Vec<NodeInfo>) {
+        if let Some(http) = &self.http {
+            http.replace_nodes(nodes.clone()).await;
+        }
+        if let Some(socks) = &self.socks {
+            socks.replace_nodes(nodes.clone()).await;
+        }
+        self.nodes = nodes;
+    }
+
+    pub async fn snapshots(&self) -> Vec<ListenerSnapshot> {
+        let mut snapshots = Vec::new();
+        if let Some(http) = &self.http {
+            snapshots.push(http.config_snapshot().await);
+        }
+        if let Some(socks) = &self.socks {
+            snapshots.push(socks.config_snapshot().await);
+        }
+        snapshots
+    }
+
+    pub fn is_serving(&self) -> bool {
+        self.http.as_ref().is_some_and(HttpProxy::is_serving)
+            || self.socks.as_ref().is_some_and(SocksProxy::is_serving)
+    }
+
+    /// Start both listeners, the runtime errors of both go to the returned
+    /// channel. When one fails to start the other is stopped again.
+    async fn serve(&mut self) -> Result<RuntimeErrorReceiver> {
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+        self.shutdown = Some(shutdown);
+        let mut listener_errors = Vec::new();
+        if let Some(http) = &mut self.http {
+            let rules = Arc::clone(&self.rules);
+            let handle = http.serve(rules, &mut shutdown_rx, self.nodes.clone()).await;
+            self.stopped.push(handle.stopped);
+            listener_errors.push((handle.errors, http.is_serving()));
+        }
+        if let Some(socks) = &mut self.socks {
+            let rules = Arc::clone(&self.rules);
+            let handle = socks.serve(rules, &mut shutdown_rx, self.nodes.clone()).await;
+            self.stopped.push(handle.stopped);
+            listener_errors.push((handle.errors, socks.is_serving()));
+        }
+        if listener_errors.is_empty() {
+            return Err(anyhow!("no listener"));
+        }
+        for (errors, is_serving) in listener_errors.iter_mut() {
+            if !*is_serving {
+                self.stop().await;
+                return Err(match errors.try_recv() {
+                    Ok(e) => e.into(),
+                    Err(_) => anyhow!("listener closed"),
+                });
+            }
+        }
+        let (errors, errors_rx) = runtime_error_channel();
+        for (mut listener_errors, _) in listener_errors {
+            let errors = errors.clone();
+            tokio::spawn(async move {
+                while let Some(e) = listener_errors.recv().await {
+                    if errors.send(e).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(errors_rx)
+    }
+
+    /// Stop accepting and wait up to `STOP_TIMEOUT` for both listeners to
+    /// stop, `false` when one is still serving then.
+    async fn stop(&mut self) -> bool {
+        if let Some(shutdown) = &self.shutdown {
+            let _ = shutdown.send(true);
+        }
+        let stopped = async {
+            for stopped in self.stopped.iter_mut() {
+                stopped.await;
+            }
+        };
+        if tokio::time::timeout(STOP_TIMEOUT, stopped).await.is_err() {
+            return false;
+        }
+        self.stopped.clear();
+        self.shutdown = None;
+        true
+    }
+}
+
+/// Proxy instances keyed by name, started and stopped independently.
+#[derive(Default)]
+pub struct ProxyManager {
+    /// Only locked to look instances up, add or remove them
+    instances: std::sync::Mutex<BTreeMap<String, Arc<Mutex<ProxyInstance>>>>,
+}
+
+impl ProxyManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start serving `instance` under `name`, returns the runtime errors of
+    /// its listeners. Fails when the name is taken or a listener can't bind.
+    /// Other instances are started and stopped meanwhile, `get(name)` waits
+    /// for the start to end.
+    pub async fn start(&self, name: &str, instance: ProxyInstance) -> Result<RuntimeErrorReceiver> {
+        let instance = Arc::new(Mutex::new(instance));
+        let mut starting = Arc::clone(&instance).lock_owned().await;
+        {
+            let mut instances = self.instances.lock().unwrap();
+            if instances.contains_key(name) {
+                return Err(anyhow!("proxy instance {} already exists", name));
+            }
+            instances.insert(name.to_string(), instance);
+        }
+        match starting.serve().await {
+            Ok(errors) => {
+                info!("Proxy instance {} started", name);
+                Ok(errors)
+            }
+            Err(e) => {
+                self.instances.lock().unwrap().remove(name);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop the instance `name` and hand it back, its statistics can still
+    /// be read. Listeners still serving after `STOP_TIMEOUT` are left to
+    /// stop on their own.
+    pub async fn stop(&self, name: &str) -> Result<Arc<Mutex<ProxyInstance>>> {
+        let instance = self.instances.lock().unwrap().remove(name);
+        let instance = instance.ok_or_else(|| anyhow!("no proxy instance {}", name))?;
+        Self::stop_instance(name, &instance).await;
+        Ok(instance)
+    }
+
+    pub async fn stop_all(&self) {
+        let instances = std::mem::take(&mut *self.instances.lock().unwrap());
+        for (name, instance) in instances {
+            Self::stop_instance(&name, &instance).await;
+        }
+    }
+
+    async fn stop_instance(name: &str, instance: &Mutex<ProxyInstance>) {
+        if instance.lock().await.stop().await {
+            info!("Proxy instance {} stopped", name);
+        } else {
+            warn!("Proxy instance {} still serving after {:?}", name, STOP_TIMEOUT);
+        }
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        self.instances.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The instance `name`. Holding its lock only keeps the instance itself
+    /// from being stopped.
+    pub async fn get(&self, name: &str) -> Option<Arc<Mutex<ProxyInstance>>> {
+        self.instances.lock().unwrap().get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MatchProxy;
+
+    #[tokio::test]
+    async fn instances_are_independent() -> Result<()> {
+        let manager = ProxyManager::new();
+        for name in ["a", "b"] {
+            let rules = Arc::new(SharedRules::new(MatchProxy::default()));
+            let http = HttpProxy::new("127.0.0.1", 0, None).await?;
+            let instance = ProxyInstance::new(rules, Vec::new()).with_http(http);
+            manager.start(name, instance).await?;
+        }
+        let rules = Arc::new(SharedRules::new(MatchProxy::default()));
+        let taken = ProxyInstance::new(rules, Vec::new());
+        assert!(manager.start("a", taken).await.is_err());
+        assert_eq!(manager.names().await, ["a", "b"]);
+
+        let stopped = manager.stop("a").await?;
+        assert!(!stopped.lock().await.is_serving());
+        let b = manager.get("b").await.unwrap();
+        let b = b.lock().await;
+        assert!(b.is_serving());
+        assert_eq!(b.snapshots().await.len(), 1);
+        // Holding an instance doesn't block the others
+        let rules = Arc::new(SharedRules::new(MatchProxy::default()));
+        let http = HttpProxy::new("127.0.0.1", 0, None).await?;
+        manager.start("c", ProxyInstance::new(rules, Vec::new()).with_http(http)).await?;
+        manager.stop("c").await?;
+        drop(b);
+        manager.stop_all().await;
+        assert!(manager.names().await.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::task::Poll;
+
+use log::{debug, error, info};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use yamux::{Config, Connection, Mode};
+
+use crate::outbound::{self, OutboundOptions};
+use crate::traits::BoxedStream;
+use crate::types::Address;
+
+type OpenRequest = oneshot::Sender<io::Result<yamux::Stream>>;
+
+fn mux_error(e: yamux::ConnectionError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// A long-lived yamux connection to a VPN node.
+#[derive(Clone)]
+struct MuxSession {
+    opener: mpsc::UnboundedSender<OpenRequest>,
+}
+
+impl MuxSession {
+    async fn connect(node: &Address, options: &OutboundOptions) -> io::Result<Self> {
+        let stream = outbound::connect_node(node, options).await?;
+        let mut connection = Connection::new(stream.compat(), Config::default(), Mode::Client);
+        let (opener, mut requests) = mpsc::unbounded_channel::<OpenRequest>();
+        let node = node.clone();
+        info!("Mux session to {} established", node);
+        tokio::spawn(async move {
+            let mut pending: Vec<OpenRequest> = Vec::new();
+            let res: Result<(), yamux::ConnectionError> = poll_fn(|cx| {
+                while let Poll::Ready(Some(request)) = requests.poll_recv(cx) {
+                    pending.push(request);
+                }
+                while !pending.is_empty() {
+                    match connection.poll_new_outbound(cx) {
+                        Poll::Ready(res) => {
+                            let _ = pending.remove(0).send(res.map_err(mux_error));
+                        }
+                        Poll::Pending => break,
+                    }
+                }
+                // Drives the connection, inbound streams are not expected from a node
+                loop {
+                    match connection.poll_next_inbound(cx) {
+                        Poll::Ready(Some(Ok(_))) => debug!("Mux dropping inbound stream"),
+                        Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                        Poll::Ready(None) => return Poll::Ready(Ok(())),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            })
+            .await;
+            if let Err(e) = res {
+                error!("Mux session to {} failed: {}", node, e);
+            }
+        });
+        Ok(Self { opener })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.opener.is_closed()
+    }
+
+    async fn open_stream(&self) -> io::Result<yamux::Stream> {
+        let (tx, rx) = oneshot::channel();
+        self.opener
+            .send(tx)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))?;
+        rx.await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))?
+    }
+}
+
+/// Sessions of a node, and those being connected.
+#[derive(Default)]
+struct NodeSessions {
+    sessions: Vec<MuxSession>,
+    connecting: usize,
+}
+
+/// What an `open` does with the pool as it is.
+enum Pick {
+    Session(MuxSession),
+    /// Connect a new session, counted in `connecting`
+    Connect,
+    /// Every session wanted is being connected, wait for one
+    Wait,
+}
+
+/// Keeps up to `sessions_per_node` mux sessions per VPN node and spreads new
+/// streams over them.
+pub struct MuxPool {
+    sessions_per_node: usize,
+    sessions: Mutex<HashMap<Address, NodeSessions>>,
+    /// Woken when a connect ends, with a session or not
+    connected: Notify,
+    next: AtomicUsize,
+}
+
+impl MuxPool {
+    pub fn new(sessions_per_node: usize) -> Self {
+        Self {
+            sessions_per_node: sessions_per_node.max(1),
+            sessions: Mutex::new(HashMap::new()),
+            connected: Notify::new(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Open a stream to `node`. The pool is only locked to pick or add a
+    /// session, connecting and opening streams go on without it.
+    pub async fn open(&self, node: &Address, options: &OutboundOptions) -> io::Result<BoxedStream> {
+        let session = loop {
+            // Registered before the lock is released, so no wakeup is missed
+            let connected = self.connected.notified();
+            match self.pick(node) {
+                Pick::Session(session) => break session,
+                Pick::Wait => connected.await,
+                Pick::Connect => {
+                    let slot = ConnectSlot { pool: self, node };
+                    let session = MuxSession::connect(node, options).await?;
+                    slot.add(session.clone());
+                    break session;
+                }
+            }
+        };
+        let stream = session.open_stream().await?;
+        Ok(Box::new(stream.compat()))
+    }
+
+    fn pick(&self, node: &Address) -> Pick {
+        let mut sessions = self.sessions.lock().unwrap();
+        let node_sessions = sessions.entry(node.clone()).or_default();
+        node_sessions.sessions.retain(|session| !session.is_closed());
+        let count = node_sessions.sessions.len();
+        if count + node_sessions.connecting < self.sessions_per_node {
+            node_sessions.connecting += 1;
+            return Pick::Connect;
+        }
+        if count == 0 {
+            return Pick::Wait;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        Pick::Session(node_sessions.sessions[index].clone())
+    }
+}
+
+/// A connect counted by `pick`, given back when done or cancelled.
+struct ConnectSlot<'a> {
+    pool: &'a MuxPool,
+    node: &'a Address,
+}
+
+impl ConnectSlot<'_> {
+    fn add(self, session: MuxSession) {
+        let mut sessions = self.pool.sessions.lock().unwrap();
+        if let Some(node_sessions) = sessions.get_mut(self.node) {
+            node_sessions.sessions.push(session);
+        }
+    }
+}
+
+impl Drop for ConnectSlot<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.pool.sessions.lock().unwrap();
+        if let Some(node_sessions) = sessions.get_mut(self.node) {
+            node_sessions.connecting -= 1;
+        }
+        drop(sessions);
+        self.pool.connected.notify_waiters();
+    }
+}
+
+// Opens racing on worker threads, the runtime needs `rt-multi-thread`
+#[cfg(all(test, feature = "rt-multi-thread"))]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_opens_share_the_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Address::SocketAddress(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut connection =
+                    Connection::new(stream.compat(), Config::default(), Mode::Server);
+                tokio::spawn(async move {
+                    let mut streams = Vec::new();
+                    while let Some(Ok(stream)) =
+                        poll_fn(|cx| connection.poll_next_inbound(cx)).await
+                    {
+                        streams.push(stream);
+                    }
+                });
+            }
+        });
+
+        let pool = Arc::new(MuxPool::new(2));
+        let options = OutboundOptions::default();
+        let opens: Vec<_> = (0..8)
+            .map(|_| {
+                let (pool, node, options) = (pool.clone(), node.clone(), options.clone());
+                tokio::spawn(async move { pool.open(&node, &options).await.map(drop) })
+            })
+            .collect();
+        for open in opens {
+            open.await.unwrap().unwrap();
+        }
+        let sessions = pool.sessions.lock().unwrap();
+        assert_eq!(sessions[&node].sessions.len(), 2);
+        assert_eq!(sessions[&node].connecting, 0);
+    }
+}
@@ -0,0 +1,41 @@
+//! Network interface names, for the zones of link-local IPv6 targets
+//! (`fe80::1%eth0`).
+
+/// Index of the interface called `name`, the scope id of link-local
+/// addresses reached through it. `None` when there is no such interface.
+pub(crate) fn interface_index(name: &str) -> Option<u32> {
+    if name.is_empty() || name.contains(['/', '\0']) {
+        return None;
+    }
+    platform::interface_index(name)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs;
+
+    pub fn interface_index(name: &str) -> Option<u32> {
+        let index = fs::read_to_string(format!("/sys/class/net/{}/ifindex", name)).ok()?;
+        index.trim_end().parse().ok()
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::CString;
+
+    use windows_sys::Win32::NetworkManagement::IpHelper::if_nametoindex;
+
+    pub fn interface_index(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        let index = unsafe { if_nametoindex(name.as_ptr().cast()) };
+        (index != 0).then_some(index)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    pub fn interface_index(_name: &str) -> Option<u32> {
+        None
+    }
+}
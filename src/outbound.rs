@@ -0,0 +1,901 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::decision_log::PinnedLog;
+use crate::dns;
+use crate::listener::current_connection_id;
+use crate::traits::BoxedStream;
+use crate::types::{Address, NodeInfo};
+
+/// Upper bound of the CONNECT response head accepted from an HTTP hop.
+const MAX_HOP_REPLY_SIZE: usize = 8192;
+/// Targets with a connect time history, the least recently used is forgotten first.
+const MAX_CONNECT_TIME_TARGETS: usize = 4096;
+/// Weight of a new sample in the moving average of a target's connect time.
+const CONNECT_TIME_EWMA_WEIGHT: f64 = 0.3;
+
+/// Intermediate proxy a connection to a VPN node is tunneled through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamHop {
+    /// SOCKS5 proxy without authentication
+    Socks5(Address),
+    /// HTTP proxy accepting CONNECT
+    HttpConnect(Address),
+}
+
+/// Hops, in order, used to reach a group of VPN nodes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeChain {
+    /// Nodes this chain applies to, every node when empty
+    pub nodes: Vec<SocketAddr>,
+    pub hops: Vec<UpstreamHop>,
+}
+
+/// Socket options applied to connections opened towards targets and VPN nodes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutboundOptions {
+    /// IP_TTL for IPv4, IPV6_UNICAST_HOPS for IPv6
+    pub ttl: Option<u32>,
+    /// Upstream proxies to go through before reaching a VPN node, the first
+    /// matching chain is used
+    pub chains: Vec<NodeChain>,
+    /// Nodes connected with TCP Fast Open, saving a round trip once the node
+    /// handed out a cookie. Only supported on Linux, ignored elsewhere
+    pub fast_open: Vec<SocketAddr>,
+    /// TCP keepalive of tunnels, enabled on the connections to targets and
+    /// VPN nodes and on the client ones
+    pub keepalive: Option<Keepalive>,
+    /// DSCP value (0-63) marked in the IP header, e.g. 46 (EF) for
+    /// interactive traffic, for the QoS of routers on the way. Set per rule
+    /// by `ProxyConfig::dscp_rules`
+    pub dscp: Option<u8>,
+    /// Direct connections to a domain with several addresses take turns
+    /// starting with each of them, spreading them over the origin servers.
+    /// Otherwise they all try the addresses in the resolver order. The address
+    /// each connection took is logged, see `PinnedLog`
+    pub dns_round_robin: bool,
+    /// Address family of direct connections to domains, the resolver order
+    /// when `None`
+    pub ip_preference: Option<IpPreference>,
+}
+
+/// Address family used for direct connections, for users whose IPv6 (or
+/// IPv4) route bypasses the VPN.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpPreference {
+    /// IPv4 addresses first, then IPv6 ones
+    PreferV4,
+    /// IPv6 addresses first, then IPv4 ones
+    PreferV6,
+    /// IPv4 addresses only, IPv6 targets fail
+    OnlyV4,
+    /// IPv6 addresses only, IPv4 targets fail
+    OnlyV6,
+}
+
+impl IpPreference {
+    /// Order `addrs` by preference, dropping the excluded family. The
+    /// resolver order is kept within a family.
+    pub fn apply(&self, addrs: &mut Vec<SocketAddr>) {
+        match self {
+            IpPreference::PreferV4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpPreference::PreferV6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            IpPreference::OnlyV4 => addrs.retain(|addr| addr.is_ipv4()),
+            IpPreference::OnlyV6 => addrs.retain(|addr| addr.is_ipv6()),
+        }
+    }
+}
+
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match self {
+            IpPreference::PreferV4 => "prefer-v4",
+            IpPreference::PreferV6 => "prefer-v6",
+            IpPreference::OnlyV4 => "only-v4",
+            IpPreference::OnlyV6 => "only-v6",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "prefer-v4" => Ok(IpPreference::PreferV4),
+            "prefer-v6" => Ok(IpPreference::PreferV6),
+            "only-v4" => Ok(IpPreference::OnlyV4),
+            "only-v6" => Ok(IpPreference::OnlyV6),
+            _ => Err(anyhow!("unknown IP preference: {}", s)),
+        }
+    }
+}
+
+/// TCP keepalive probing, a peer not answering is detected as dead after
+/// `idle + interval * retries`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Silence before the first probe
+    pub idle: Duration,
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped, the system
+    /// default on Windows
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 6,
+        }
+    }
+}
+
+impl Keepalive {
+    /// Enable the probing on `socket`, e.g. a connected `TcpStream`.
+    pub(crate) fn apply<'s, S>(&self, socket: &'s S) -> io::Result<()>
+    where
+        SockRef<'s>: From<&'s S>,
+    {
+        let keepalive = TcpKeepalive::new()
+            .with_time(self.idle)
+            .with_interval(self.interval);
+        #[cfg(not(windows))]
+        let keepalive = keepalive.with_retries(self.retries);
+        SockRef::from(socket).set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// Connect timeout following how long connecting to each target or VPN node
+/// took before, so slow but working paths aren't cut off by a timeout made
+/// for the fast ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveTimeout {
+    /// Multiple of the average connect time a connect may take
+    pub factor: f64,
+    pub min: Duration,
+    /// Also the timeout of targets connected to for the first time
+    pub max: Duration,
+}
+
+impl Default for AdaptiveTimeout {
+    fn default() -> Self {
+        Self {
+            factor: 3.0,
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl AdaptiveTimeout {
+    /// Timeout for a target connecting in `average`, `max` for unknown
+    /// targets and for a `factor` giving no valid duration, e.g. negative.
+    fn timeout(&self, average: Option<Duration>) -> Duration {
+        let Some(average) = average else {
+            return self.max;
+        };
+        match Duration::try_from_secs_f64(average.as_secs_f64() * self.factor) {
+            Ok(timeout) => timeout.clamp(self.min, self.max.max(self.min)),
+            Err(_) => self.max,
+        }
+    }
+}
+
+/// Moving average of the connect times of each target, a timed out connect
+/// counting as long as its timeout so the timeout backs off toward `max`.
+/// The targets last recorded longest ago are forgotten first.
+#[derive(Default)]
+struct ConnectTimes(Mutex<HashMap<String, (Duration, Instant)>>);
+
+impl ConnectTimes {
+    fn average(&self, target: &str) -> Option<Duration> {
+        let times = self.0.lock().unwrap();
+        times.get(target).map(|(average, _)| *average)
+    }
+
+    fn record(&self, target: &str, elapsed: Duration) {
+        let now = Instant::now();
+        let mut times = self.0.lock().unwrap();
+        if !times.contains_key(target) && times.len() >= MAX_CONNECT_TIME_TARGETS {
+            let stalest = times
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(target, _)| target.clone());
+            if let Some(stalest) = stalest {
+                times.remove(&stalest);
+            }
+        }
+        times
+            .entry(target.to_string())
+            .and_modify(|(average, last_used)| {
+                *average = average.mul_f64(1.0 - CONNECT_TIME_EWMA_WEIGHT)
+                    + elapsed.mul_f64(CONNECT_TIME_EWMA_WEIGHT);
+                *last_used = now;
+            })
+            .or_insert((elapsed, now));
+    }
+}
+
+impl OutboundOptions {
+    fn is_default(&self) -> bool {
+        self.ttl.is_none()
+            && self.fast_open.is_empty()
+            && self.keepalive.is_none()
+            && self.dscp.is_none()
+    }
+
+    fn chain_for(&self, node: &Address) -> &[UpstreamHop] {
+        let applies = |chain: &&NodeChain| match node {
+            _ if chain.nodes.is_empty() => true,
+            Address::SocketAddress(addr) => chain.nodes.contains(addr),
+            Address::DomainNameAddress(..) => false,
+        };
+        self.chains
+            .iter()
+            .find(applies)
+            .map(|chain| chain.hops.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Options reaching `exit` through `relay`, the other nodes of a relay
+    /// group in order, each tunneling to the next with `hop`. The chain of
+    /// the first relay node still leads to it.
+    pub(crate) fn relayed(
+        &self,
+        exit: SocketAddr,
+        relay: &[SocketAddr],
+        hop: fn(Address) -> UpstreamHop,
+    ) -> Self {
+        let Some(first) = relay.first() else {
+            return self.clone();
+        };
+        let mut hops = self.chain_for(&Address::from(*first)).to_vec();
+        hops.extend(relay.iter().map(|node| hop(Address::from(*node))));
+        let mut options = self.clone();
+        options.chains.insert(0, NodeChain { nodes: vec![exit], hops });
+        options
+    }
+
+    fn apply(&self, socket: &TcpSocket, addr: &SocketAddr) -> io::Result<()> {
+        let sock_ref = SockRef::from(socket);
+        if let Some(ttl) = self.ttl {
+            match addr {
+                SocketAddr::V4(_) => sock_ref.set_ttl(ttl)?,
+                SocketAddr::V6(_) => sock_ref.set_unicast_hops_v6(ttl)?,
+            }
+        }
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.apply(socket)?;
+        }
+        if let Some(dscp) = self.dscp {
+            // DSCP is the upper 6 bits of the TOS / traffic class byte
+            let tos = u32::from(dscp & 0x3f) << 2;
+            let res = match addr {
+                SocketAddr::V4(_) => sock_ref.set_tos(tos),
+                SocketAddr::V6(_) => set_traffic_class_v6(socket, tos),
+            };
+            if let Err(e) = res {
+                debug!("DSCP {} unavailable for {}: {}", dscp, addr, e);
+            }
+        }
+        if self.fast_open.contains(addr) {
+            // Fall back to a regular handshake when the kernel refuses
+            if let Err(e) = set_fast_open_connect(socket) {
+                debug!("TCP Fast Open unavailable for {}: {}", addr, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(socket: &TcpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    // SAFETY: the fd is owned by `socket` and `enable` outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_traffic_class_v6(socket: &TcpSocket, tclass: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let tclass = tclass as libc::c_int;
+    // SAFETY: the fd is owned by `socket` and `tclass` outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&tclass) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_traffic_class_v6(_socket: &TcpSocket, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 traffic class is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open_connect(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on Linux",
+    ))
+}
+
+async fn connect_socket_addr(addr: SocketAddr, options: &OutboundOptions) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    options.apply(&socket, &addr)?;
+    socket.connect(addr).await
+}
+
+async fn resolve(addr: &Address, options: &OutboundOptions) -> io::Result<Vec<SocketAddr>> {
+    match addr {
+        Address::SocketAddress(socket_addr) => Ok(vec![*socket_addr]),
+        Address::DomainNameAddress(host, port) if options.dns_round_robin => {
+            dns::resolve_rotated(host, *port).await
+        }
+        Address::DomainNameAddress(host, port) => dns::resolve(host, *port).await,
+    }
+}
+
+/// Open a TCP connection to `addr`, a VPN node or upstream hop, trying every
+/// resolved address in order. With `dns_round_robin` the address of a domain
+/// connected to is logged to the decision log, the connection sticks to it.
+pub async fn connect(addr: &Address, options: &OutboundOptions) -> io::Result<TcpStream> {
+    let socket_addrs = resolve(addr, options).await?;
+    connect_first(addr, socket_addrs, options).await
+}
+
+/// Open a direct TCP connection to the target `addr`, as `connect` with the
+/// addresses ordered or filtered by the `ip_preference`.
+pub async fn connect_direct(addr: &Address, options: &OutboundOptions) -> io::Result<TcpStream> {
+    let mut socket_addrs = resolve(addr, options).await?;
+    if let Some(ip_preference) = options.ip_preference {
+        ip_preference.apply(&mut socket_addrs);
+        if socket_addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} has no address allowed by {}", addr, ip_preference),
+            ));
+        }
+    }
+    connect_first(addr, socket_addrs, options).await
+}
+
+async fn connect_first(
+    addr: &Address,
+    socket_addrs: Vec<SocketAddr>,
+    options: &OutboundOptions,
+) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for socket_addr in socket_addrs {
+        let connect = if options.is_default() {
+            TcpStream::connect(socket_addr).await
+        } else {
+            connect_socket_addr(socket_addr, options).await
+        };
+        match connect {
+            Ok(stream) => {
+                // Without round robin the connections keep to the resolver
+                // order, a line each would only flood the decision log
+                if let (Address::DomainNameAddress(..), true) = (addr, options.dns_round_robin) {
+                    let connection = current_connection_id();
+                    let target = addr;
+                    PinnedLog { connection, target, addr: socket_addr }.log();
+                }
+                return Ok(stream);
+            }
+            Err(e) => {
+                debug!("connect {} ({}) failed: {}", addr, socket_addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("could not resolve {}", addr),
+        )
+    }))
+}
+
+impl UpstreamHop {
+    fn addr(&self) -> &Address {
+        match self {
+            UpstreamHop::Socks5(addr) | UpstreamHop::HttpConnect(addr) => addr,
+        }
+    }
+
+    /// Ask the hop at the other end of `stream` to connect to `next`.
+    async fn tunnel(&self, stream: &mut BoxedStream, next: &Address) -> io::Result<()> {
+        match self {
+            UpstreamHop::Socks5(_) => socks5_connect(stream, next).await,
+            UpstreamHop::HttpConnect(_) => http_connect(stream, next).await,
+        }
+    }
+}
+
+fn hop_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+async fn socks5_connect(stream: &mut BoxedStream, next: &Address) -> io::Result<()> {
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        return Err(hop_error(format!(
+            "socks5 hop refused no auth: {:?}",
+            method
+        )));
+    }
+    let mut request = vec![5, 1, 0];
+    let port = match next {
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Address::DomainNameAddress(domain, _) if domain.len() > 255 => {
+            return Err(hop_error(format!("domain too long for socks5: {}", domain)));
+        }
+        Address::DomainNameAddress(domain, port) => {
+            request.push(3);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    read_socks5_reply(stream, next).await
+}
+
+/// Read the SOCKS5 reply to a CONNECT to `next`, failing unless it succeeded.
+pub(crate) async fn read_socks5_reply(stream: &mut BoxedStream, next: &Address) -> io::Result<()> {
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(hop_error(format!(
+            "socks5 upstream failed to reach {}: {}",
+            next, reply[1]
+        )));
+    }
+    // BND.ADDR and BND.PORT
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        _ => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(stream: &mut BoxedStream, next: &Address) -> io::Result<()> {
+    stream
+        .write_all(format!("CONNECT {next} HTTP/1.1\r\nHost: {next}\r\n\r\n").as_bytes())
+        .await?;
+    // Read byte by byte, nothing past the response head belongs to us
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HOP_REPLY_SIZE {
+            return Err(hop_error("http hop reply head too large".to_string()));
+        }
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    let status_line = String::from_utf8_lossy(&head);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(hop_error(format!(
+            "http hop failed to reach {}: {}",
+            next, status_line
+        ))),
+    }
+}
+
+/// Connect to `node`, through its upstream chain when one is configured.
+pub async fn connect_node(node: &Address, options: &OutboundOptions) -> io::Result<BoxedStream> {
+    let chain = options.chain_for(node);
+    let Some(first) = chain.first() else {
+        return Ok(Box::new(connect(node, options).await?));
+    };
+    let mut stream: BoxedStream = Box::new(connect(first.addr(), options).await?);
+    for (i, hop) in chain.iter().enumerate() {
+        let next = chain.get(i + 1).map(UpstreamHop::addr).unwrap_or(node);
+        debug!("upstream hop {} -> {}", hop.addr(), next);
+        hop.tunnel(&mut stream, next).await?;
+    }
+    Ok(stream)
+}
+
+/// Run `dial` against `primary` and `secondary` at once and return the node
+/// that succeeded first, the other attempt is dropped. Without `secondary`
+/// only `primary` is dialed. Fails with the error of the last attempt.
+pub async fn hedged<T, E, F, Fut>(
+    primary: NodeInfo,
+    secondary: Option<NodeInfo>,
+    dial: F,
+) -> Result<(NodeInfo, T), E>
+where
+    F: Fn(NodeInfo) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let Some(secondary) = secondary else {
+        return dial(primary).await.map(|t| (primary, t));
+    };
+    let first = dial(primary);
+    let second = dial(secondary);
+    tokio::pin!(first, second);
+    tokio::select! {
+        res = &mut first => match res {
+            Ok(t) => Ok((primary, t)),
+            Err(_) => second.await.map(|t| (secondary, t)),
+        },
+        res = &mut second => match res {
+            Ok(t) => Ok((secondary, t)),
+            Err(_) => first.await.map(|t| (primary, t)),
+        },
+    }
+}
+
+/// Test connect every node at once, through its upstream chain, each attempt
+/// giving up after `timeout`. Returns how long each node took to connect.
+pub async fn node_delays(
+    nodes: &[SocketAddr],
+    options: &OutboundOptions,
+    timeout: Duration,
+) -> Vec<(SocketAddr, io::Result<Duration>)> {
+    let probes: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            let (node, options) = (Address::from(*node), options.clone());
+            tokio::spawn(async move {
+                let started = Instant::now();
+                match tokio::time::timeout(timeout, connect_node(&node, &options)).await {
+                    Ok(res) => res.map(|_| started.elapsed()),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+                }
+            })
+        })
+        .collect();
+    let mut delays = Vec::new();
+    for (node, probe) in nodes.iter().zip(probes) {
+        let res = probe.await.unwrap_or_else(|e| Err(io::Error::other(e)));
+        delays.push((*node, res));
+    }
+    delays
+}
+
+/// Nodes failing a test connect, see `node_delays`.
+pub async fn unreachable_nodes(
+    nodes: &[NodeInfo],
+    options: &OutboundOptions,
+    timeout: Duration,
+) -> Vec<(SocketAddr, io::Error)> {
+    let nodes: Vec<SocketAddr> = nodes.iter().map(|node| node.socket_addr).collect();
+    node_delays(&nodes, options, timeout)
+        .await
+        .into_iter()
+        .filter_map(|(node, res)| res.err().map(|e| (node, e)))
+        .collect()
+}
+
+/// Opens connections to VPN nodes, through shared mux sessions when enabled,
+/// and keeps the connect times of the listener's targets.
+#[derive(Clone, Default)]
+pub struct NodeConnector {
+    #[cfg(feature = "mux")]
+    mux: Option<Arc<crate::mux::MuxPool>>,
+    connect_times: Arc<ConnectTimes>,
+}
+
+impl NodeConnector {
+    /// Share `sessions_per_node` long-lived connections per node between all tunnels.
+    #[cfg(feature = "mux")]
+    pub fn with_mux(sessions_per_node: usize) -> Self {
+        Self {
+            mux: Some(Arc::new(crate::mux::MuxPool::new(sessions_per_node))),
+            connect_times: Arc::default(),
+        }
+    }
+
+    /// Run `connect` to `target` within `timeout`, or with `adaptive` within
+    /// the timeout following the target's connect times, which a success or
+    /// a timeout adds to. `None` when it timed out.
+    pub async fn within_timeout<T>(
+        &self,
+        target: &Address,
+        timeout: Option<Duration>,
+        adaptive: Option<&AdaptiveTimeout>,
+        connect: impl Future<Output = io::Result<T>>,
+    ) -> Option<io::Result<T>> {
+        let Some(adaptive) = adaptive else {
+            return match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect).await.ok(),
+                None => Some(connect.await),
+            };
+        };
+        let target = target.to_string();
+        let timeout = adaptive.timeout(self.connect_times.average(&target));
+        let started = Instant::now();
+        let Ok(res) = tokio::time::timeout(timeout, connect).await else {
+            self.connect_times.record(&target, timeout);
+            return None;
+        };
+        if res.is_ok() {
+            self.connect_times.record(&target, started.elapsed());
+        }
+        Some(res)
+    }
+
+    pub async fn connect(
+        &self,
+        node: &Address,
+        options: &OutboundOptions,
+    ) -> io::Result<BoxedStream> {
+        #[cfg(feature = "mux")]
+        if let Some(mux) = &self.mux {
+            return mux.open(node, options).await;
+        }
+        connect_node(node, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_node_through_socks5_hop() -> io::Result<()> {
+        let hop = TcpListener::bind("127.0.0.1:0").await?;
+        let hop_addr = hop.local_addr()?;
+        let node: SocketAddr = "10.1.2.3:1080".parse().unwrap();
+        tokio::spawn(async move {
+            let (mut client, _) = hop.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            client.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 1, 2, 3, 0x04, 0x38]);
+            client
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            client.write_all(b"from node").await.unwrap();
+        });
+
+        let options = OutboundOptions {
+            chains: vec![NodeChain {
+                nodes: vec![node],
+                hops: vec![UpstreamHop::Socks5(Address::from(hop_addr))],
+            }],
+            ..Default::default()
+        };
+        let mut stream = connect_node(&Address::from(node), &options).await?;
+        let mut buf = [0u8; 9];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"from node");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_unreachable_nodes() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let up = NodeInfo::new(listener.local_addr()?.ip(), listener.local_addr()?.port(), 1);
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let down = NodeInfo::new(closed.ip(), closed.port(), 1);
+
+        let options = OutboundOptions::default();
+        let unreachable = unreachable_nodes(&[up, down], &options, Duration::from_secs(1)).await;
+        assert_eq!(unreachable.len(), 1);
+        assert_eq!(unreachable[0].0, closed);
+        assert_eq!(unreachable[0].1.kind(), io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+
+    #[test]
+    fn relays_keep_the_chain_of_their_first_node() {
+        let [proxy, a, b, exit] = [3128, 1081, 1082, 1083]
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let options = OutboundOptions {
+            chains: vec![NodeChain {
+                nodes: vec![a],
+                hops: vec![UpstreamHop::HttpConnect(Address::from(proxy))],
+            }],
+            ..Default::default()
+        };
+        let relayed = options.relayed(exit, &[a, b], UpstreamHop::Socks5);
+        assert_eq!(
+            relayed.chain_for(&Address::from(exit)),
+            [
+                UpstreamHop::HttpConnect(Address::from(proxy)),
+                UpstreamHop::Socks5(Address::from(a)),
+                UpstreamHop::Socks5(Address::from(b)),
+            ]
+        );
+        assert_eq!(options.relayed(exit, &[], UpstreamHop::Socks5), options);
+    }
+
+    #[test]
+    fn ip_preference_orders_addresses() -> Result<()> {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let applied = |preference: &str| -> Result<Vec<String>> {
+            let mut addrs = addrs.clone();
+            preference.parse::<IpPreference>()?.apply(&mut addrs);
+            Ok(addrs.iter().map(|addr| addr.to_string()).collect())
+        };
+        assert_eq!(applied("prefer-v4")?, ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80", "[::2]:80"]);
+        assert_eq!(applied("prefer-v6")?, ["[::1]:80", "[::2]:80", "10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(applied("only-v4")?, ["10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(applied("ONLY-V6")?, ["[::1]:80", "[::2]:80"]);
+        assert!("v4".parse::<IpPreference>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_node_with_fast_open() -> io::Result<()> {
+        let node = TcpListener::bind("127.0.0.1:0").await?;
+        let node_addr = node.local_addr()?;
+        tokio::spawn(async move {
+            let (mut client, _) = node.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            client.write_all(&buf).await.unwrap();
+        });
+
+        let options = OutboundOptions {
+            fast_open: vec![node_addr],
+            ..Default::default()
+        };
+        let mut stream = connect_node(&Address::from(node_addr), &options).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ip_preference_only_applies_to_direct_connections() -> io::Result<()> {
+        let node = TcpListener::bind("127.0.0.1:0").await?;
+        let node_addr = Address::from(node.local_addr()?);
+        let options = OutboundOptions {
+            ip_preference: Some(IpPreference::OnlyV6),
+            ..Default::default()
+        };
+        assert!(connect_direct(&node_addr, &options).await.is_err());
+        connect_node(&node_addr, &options).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_marks_dscp_by_rule() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = crate::ProxyConfig {
+            dscp_rules: vec![("games/".to_string(), 46)],
+            ..Default::default()
+        };
+        assert_eq!(config.outbound_for("domain-full:example.com").dscp, None);
+        let options = config.outbound_for("games/domain-suffix:steam.com");
+        let stream = connect(&Address::from(listener.local_addr()?), &options).await?;
+        assert_eq!(SockRef::from(&stream).tos()?, 46 << 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hedged_keeps_first_success() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let slow = NodeInfo::new(ip, 1080, 1);
+        let fast = NodeInfo::new(ip, 1081, 1);
+        let dial = |node: NodeInfo| async move {
+            if node == slow {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+            Ok::<_, io::Error>(node.socket_addr.port())
+        };
+        let (node, port) = hedged(slow, Some(fast), dial).await.unwrap();
+        assert_eq!((node, port), (fast, 1081));
+    }
+
+    #[tokio::test]
+    async fn timeout_follows_connect_times() {
+        let connector = NodeConnector::default();
+        let adaptive = AdaptiveTimeout {
+            factor: 2.0,
+            min: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+        };
+        let target = Address::from(("example.com", 443));
+        let connect = |delay| async move {
+            tokio::time::sleep(delay).await;
+            Ok::<_, io::Error>(())
+        };
+        // Unknown targets get the maximum
+        let first = connect(Duration::from_millis(200));
+        let res = connector.within_timeout(&target, None, Some(&adaptive), first).await;
+        assert!(res.is_some());
+        let slower = connect(Duration::from_millis(1000));
+        let res = connector.within_timeout(&target, None, Some(&adaptive), slower).await;
+        assert!(res.is_none());
+        let other = Address::from(("example.org", 443));
+        let slower = connect(Duration::from_millis(1000));
+        let res = connector.within_timeout(&other, None, Some(&adaptive), slower).await;
+        assert!(res.is_some());
+        // The timeout grows back after timing out
+        let timed_out = connector.connect_times.average(&target.to_string()).unwrap();
+        assert!(adaptive.timeout(Some(timed_out)) > Duration::from_millis(400));
+    }
+
+    #[test]
+    fn invalid_factors_time_out_at_max() {
+        let average = Some(Duration::from_millis(100));
+        for factor in [-1.0, f64::NAN, f64::INFINITY, 1e300] {
+            let adaptive = AdaptiveTimeout {
+                factor,
+                ..Default::default()
+            };
+            assert_eq!(adaptive.timeout(average), adaptive.max, "{}", factor);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Destination port ranges of `DST-PORT` rules.
+
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, Result};
+
+/// `443`, `6881-6889` or several of them separated by `/`:
+/// `80/443/8000-8999`.
+pub(crate) fn parse_port_ranges(value: &str) -> Result<Vec<RangeInclusive<u16>>> {
+    value
+        .split('/')
+        .map(|range| {
+            let range = range.trim();
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse = |port: &str| {
+                port.trim()
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("invalid port range: {}", range))
+            };
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(anyhow!("invalid port range: {}", range));
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+/// Port ranges with a value each, possibly overlapping. An interval tree
+/// laid out in a vector sorted by range start: the subtree of `ranges[lo..hi]`
+/// has its root in the middle, `max_end` keeps the highest end of each
+/// subtree so lookups skip the subtrees ending below the port.
+#[derive(Clone, Debug)]
+pub(crate) struct PortRanges<T> {
+    ranges: Vec<(RangeInclusive<u16>, T)>,
+    max_end: Vec<u16>,
+}
+
+impl<T> Default for PortRanges<T> {
+    fn default() -> Self {
+        Self {
+            ranges: Vec::new(),
+            max_end: Vec::new(),
+        }
+    }
+}
+
+impl<T> PortRanges<T> {
+    pub fn insert(&mut self, range: RangeInclusive<u16>, value: T) {
+        let at = self
+            .ranges
+            .partition_point(|(r, _)| r.start() <= range.start());
+        self.ranges.insert(at, (range, value));
+        self.rebuild();
+    }
+
+    /// Drop the ranges whose value `keep` refuses.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.ranges.retain(|(_, value)| keep(value));
+        self.rebuild();
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.ranges.iter().map(|(_, value)| value)
+    }
+
+    /// Value of the narrowest range containing `port`, the first one by
+    /// start among equally narrow ones.
+    pub fn get(&self, port: u16) -> Option<&T> {
+        let mut best: Option<&(RangeInclusive<u16>, T)> = None;
+        self.visit(0, self.ranges.len(), port, &mut |entry| {
+            let width = |(r, _): &(RangeInclusive<u16>, T)| r.end() - r.start();
+            if best.is_none_or(|best| width(entry) < width(best)) {
+                best = Some(entry);
+            }
+        });
+        best.map(|(_, value)| value)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        lo: usize,
+        hi: usize,
+        port: u16,
+        f: &mut impl FnMut(&'a (RangeInclusive<u16>, T)),
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        if self.max_end[mid] < port {
+            return;
+        }
+        self.visit(lo, mid, port, f);
+        let entry = &self.ranges[mid];
+        // the ranges right of `mid` start after it
+        if *entry.0.start() > port {
+            return;
+        }
+        if entry.0.contains(&port) {
+            f(entry);
+        }
+        self.visit(mid + 1, hi, port, f);
+    }
+
+    fn rebuild(&mut self) {
+        self.max_end = vec![0; self.ranges.len()];
+        self.build(0, self.ranges.len());
+    }
+
+    fn build(&mut self, lo: usize, hi: usize) -> u16 {
+        if lo >= hi {
+            return 0;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let end = *self.ranges[mid].0.end();
+        let max_end = end.max(self.build(lo, mid)).max(self.build(mid + 1, hi));
+        self.max_end[mid] = max_end;
+        max_end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrowest_range_wins() {
+        let mut ranges = PortRanges::default();
+        ranges.insert(1024..=65535, "high");
+        ranges.insert(6881..=6889, "bittorrent");
+        ranges.insert(443..=443, "https");
+        ranges.insert(6885..=6885, "one");
+        ranges.insert(1..=1023, "low");
+        assert_eq!(ranges.get(443), Some(&"https"));
+        assert_eq!(ranges.get(80), Some(&"low"));
+        assert_eq!(ranges.get(6881), Some(&"bittorrent"));
+        assert_eq!(ranges.get(6885), Some(&"one"));
+        assert_eq!(ranges.get(8080), Some(&"high"));
+        assert_eq!(ranges.get(0), None);
+        ranges.retain(|value| *value != "high");
+        assert_eq!(ranges.get(8080), None);
+        assert_eq!(ranges.len(), 4);
+
+        assert_eq!(
+            parse_port_ranges("80/ 443 /6881-6889").unwrap(),
+            [80..=80, 443..=443, 6881..=6889]
+        );
+        for invalid in ["", "http", "6889-6881", "1-70000", "80/"] {
+            assert!(parse_port_ranges(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! The embedding surface of the crate: `use kitty_proxy::prelude::*;`.
+//!
+//! Names exported here follow semver, they are only removed or changed in
+//! a breaking release. The rest of the crate root may still move between
+//! minor releases, wire level types are only exported with the `internals`
+//! feature.
+
+pub use crate::config::{
+    Credentials, NoNodePolicy, ProxyConfig, ProxyConfigUpdate, SharedRouteHook,
+};
+pub use crate::daemon::{serve_until_shutdown, Listener};
+pub use crate::decision_log::{DecisionLog, DECISION_LOG_TARGET};
+pub use crate::embed::{ProxyRuntime, RemoteTask};
+pub use crate::http_proxy::HttpProxy;
+pub use crate::listener::{ServeHandle, Stopped};
+pub use crate::logging::{init_logging, LogFormat};
+pub use crate::manager::{ProxyInstance, ProxyManager};
+pub use crate::relay::{TunnelCloseReason, TunnelSnapshot, TUNNEL_LOG_TARGET};
+pub use crate::rules::SharedRules;
+pub use crate::snapshot::{ListenerSnapshot, StartupReport};
+pub use crate::socks_proxy::SocksProxy;
+pub use crate::traffic_diversion::{MatchProxy, RuleDecision, RuleSource, TrafficStreamRule};
+pub use crate::traits::{
+    HandshakeFuture, RouteDecision, RouteFuture, RouteHook, RouteRequest, UpstreamHandshake,
+};
+pub use crate::types::{
+    KittyProxyError, NodeInfo, ProxyRuntimeError, ResponseCode, RetryAdvice, TargetAddr,
+};
+
+#[cfg(test)]
+mod tests {
+    use std::any::type_name;
+
+    use super::*;
+
+    /// Renaming or dropping a name of the prelude is a breaking change, this
+    /// list has to be changed along with it.
+    #[test]
+    fn prelude_names_stay_exported() {
+        let names = [
+            type_name::<Credentials>(),
+            type_name::<NoNodePolicy>(),
+            type_name::<ProxyConfig>(),
+            type_name::<ProxyConfigUpdate>(),
+            type_name::<SharedRouteHook>(),
+            type_name::<Listener<'static>>(),
+            type_name::<DecisionLog>(),
+            type_name::<ProxyRuntime>(),
+            type_name::<RemoteTask<()>>(),
+            type_name::<HttpProxy>(),
+            type_name::<ServeHandle>(),
+            type_name::<Stopped>(),
+            type_name::<LogFormat>(),
+            type_name::<ProxyInstance>(),
+            type_name::<ProxyManager>(),
+            type_name::<TunnelCloseReason>(),
+            type_name::<TunnelSnapshot>(),
+            type_name::<SharedRules>(),
+            type_name::<ListenerSnapshot>(),
+            type_name::<StartupReport>(),
+            type_name::<SocksProxy>(),
+            type_name::<MatchProxy>(),
+            type_name::<RuleDecision>(),
+            type_name::<RuleSource>(),
+            type_name::<TrafficStreamRule>(),
+            type_name::<RouteDecision>(),
+            type_name::<dyn RouteHook>(),
+            type_name::<RouteRequest>(),
+            type_name::<dyn UpstreamHandshake>(),
+            type_name::<KittyProxyError>(),
+            type_name::<NodeInfo>(),
+            type_name::<ProxyRuntimeError>(),
+            type_name::<ResponseCode>(),
+            type_name::<RetryAdvice>(),
+            type_name::<TargetAddr>(),
+        ];
+        assert!(names.iter().all(|name| name.contains("kitty_proxy::")));
+        let _ = (serve_until_shutdown::<fn()>, init_logging);
+        let _ = (DECISION_LOG_TARGET, TUNNEL_LOG_TARGET);
+        // Aliases of std types, their names don't tell the crate
+        let _: Option<(RouteFuture<'static>, HandshakeFuture<'static>)> = None;
+    }
+}
@@ -0,0 +1,374 @@
+//! Name of the local process owning a TCP connection, for `PROCESS-NAME`
+//! rules on clients connecting from the same host.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Executable name (`firefox`, `chrome.exe`...) of the process whose TCP
+/// socket is bound to `local`, the source address of a connection accepted
+/// from the loopback interface. Blocks on the platform socket tables.
+pub(crate) fn process_name(local: SocketAddr) -> Option<String> {
+    let local = SocketAddr::new(normalize_ip(local.ip()), local.port());
+    platform::process_name(local)
+}
+
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::normalize_ip;
+
+    pub fn process_name(local: SocketAddr) -> Option<String> {
+        let inode = socket_inode(local)?;
+        let pid = socket_owner(inode)?;
+        let name = fs::read_link(format!("/proc/{}/exe", pid))
+            .ok()
+            .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()));
+        // Only the process' own user may read `exe`
+        name.or_else(|| {
+            let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+            Some(comm.trim_end().to_string())
+        })
+    }
+
+    /// Inode of the socket bound to `local`, from `/proc/net/tcp{,6}`.
+    fn socket_inode(local: SocketAddr) -> Option<u64> {
+        ["/proc/net/tcp", "/proc/net/tcp6"].iter().find_map(|table| {
+            let content = fs::read_to_string(table).ok()?;
+            content.lines().skip(1).find_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let address = parse_address(fields.get(1)?)?;
+                let inode = fields.get(9)?.parse().ok()?;
+                (address == local && inode != 0).then_some(inode)
+            })
+        })
+    }
+
+    /// `0100007F:1F90`, the IP as 32 bit words in host byte order.
+    fn parse_address(field: &str) -> Option<SocketAddr> {
+        let (ip, port) = field.split_once(':')?;
+        let words = (0..ip.len() / 8)
+            .map(|i| u32::from_str_radix(&ip[i * 8..i * 8 + 8], 16).ok())
+            .collect::<Option<Vec<u32>>>()?;
+        let ip = match words[..] {
+            [word] => IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes())),
+            [a, b, c, d] => {
+                let bytes = [a, b, c, d].map(u32::to_ne_bytes).concat();
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?))
+            }
+            _ => return None,
+        };
+        let port = u16::from_str_radix(port, 16).ok()?;
+        Some(SocketAddr::new(normalize_ip(ip), port))
+    }
+
+    /// Process holding a file descriptor of the socket `inode`.
+    fn socket_owner(inode: u64) -> Option<u32> {
+        let link = format!("socket:[{}]", inode);
+        fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let mut fds = fs::read_dir(entry.path().join("fd")).ok()?.flatten();
+            fds.any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target.as_os_str() == &*link))
+                .then_some(pid)
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parses_proc_net_addresses() {
+            let v4 = parse_address("0100007F:1F90").unwrap();
+            assert_eq!(v4, SocketAddr::from(([127, 0, 0, 1], 8080)));
+            let mapped = parse_address("0000000000000000FFFF00000100007F:0050").unwrap();
+            assert_eq!(mapped, SocketAddr::from(([127, 0, 0, 1], 80)));
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::c_void;
+    use std::mem::{size_of, MaybeUninit};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::path::Path;
+
+    use libc::{c_int, proc_fdinfo, PROC_PIDLISTFDS, PROX_FDTYPE_SOCKET};
+
+    use super::normalize_ip;
+
+    /// `proc_pidfdinfo` flavor and socket kind of `<sys/proc_info.h>`, libc
+    /// has neither them nor the structs below.
+    const PROC_PIDFDSOCKETINFO: c_int = 3;
+    const SOCKINFO_TCP: c_int = 2;
+    const INI_IPV4: u8 = 0x1;
+    const INI_IPV6: u8 = 0x2;
+
+    #[repr(C)]
+    struct ProcFileinfo {
+        fi_openflags: u32,
+        fi_status: u32,
+        fi_offset: i64,
+        fi_type: i32,
+        fi_guardflags: u32,
+    }
+
+    #[repr(C)]
+    struct SockbufInfo {
+        sbi_cc: u32,
+        sbi_hiwat: u32,
+        sbi_mbcnt: u32,
+        sbi_mbmax: u32,
+        sbi_lowat: u32,
+        sbi_flags: i16,
+        sbi_timeo: i16,
+    }
+
+    /// `in_sockinfo`, leading `tcp_sockinfo` too. Addresses are 16 bytes,
+    /// IPv4 ones in the last 4.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct InSockinfo {
+        insi_fport: c_int,
+        insi_lport: c_int,
+        insi_gencnt: u64,
+        insi_flags: u32,
+        insi_flow: u32,
+        insi_vflag: u8,
+        insi_ip_ttl: u8,
+        rfu_1: u32,
+        insi_faddr: [u8; 16],
+        insi_laddr: [u8; 16],
+        insi_v4: u8,
+        insi_v6: [u32; 3],
+    }
+
+    /// `soi_proto`, sized by its largest member, `un_sockinfo`.
+    #[repr(C)]
+    union SocketProto {
+        pri_in: InSockinfo,
+        size: [u64; 66],
+    }
+
+    #[repr(C)]
+    struct SocketInfo {
+        /// `vinfo_stat`
+        soi_stat: [u64; 17],
+        soi_so: u64,
+        soi_pcb: u64,
+        soi_type: c_int,
+        soi_protocol: c_int,
+        soi_family: c_int,
+        soi_options: i16,
+        soi_linger: i16,
+        soi_state: i16,
+        soi_qlen: i16,
+        soi_incqlen: i16,
+        soi_qlimit: i16,
+        soi_timeo: i16,
+        soi_error: u16,
+        soi_oobmark: u32,
+        soi_rcv: SockbufInfo,
+        soi_snd: SockbufInfo,
+        soi_kind: c_int,
+        rfu_1: u32,
+        soi_proto: SocketProto,
+    }
+
+    #[repr(C)]
+    struct SocketFdinfo {
+        pfi: ProcFileinfo,
+        psi: SocketInfo,
+    }
+
+    // The kernel only fills a buffer of exactly its size
+    const _: () = assert!(size_of::<SocketFdinfo>() == 792);
+
+    pub fn process_name(local: SocketAddr) -> Option<String> {
+        let pid = all_pids()?
+            .into_iter()
+            .filter(|pid| *pid > 0)
+            .find(|pid| owns_socket(*pid, local))?;
+        image_name(pid)
+    }
+
+    fn all_pids() -> Option<Vec<c_int>> {
+        let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+        if count <= 0 {
+            return None;
+        }
+        // Room for processes started meanwhile
+        let mut pids = vec![0 as c_int; count as usize + 64];
+        let size = (pids.len() * size_of::<c_int>()) as c_int;
+        let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut c_void, size) };
+        if count <= 0 {
+            return None;
+        }
+        pids.truncate(count as usize);
+        Some(pids)
+    }
+
+    fn owns_socket(pid: c_int, local: SocketAddr) -> bool {
+        let size = unsafe { libc::proc_pidinfo(pid, PROC_PIDLISTFDS, 0, std::ptr::null_mut(), 0) };
+        if size <= 0 {
+            return false;
+        }
+        let empty = proc_fdinfo { proc_fd: 0, proc_fdtype: 0 };
+        let mut fds = vec![empty; size as usize / size_of::<proc_fdinfo>()];
+        let size = unsafe {
+            libc::proc_pidinfo(pid, PROC_PIDLISTFDS, 0, fds.as_mut_ptr() as *mut c_void, size)
+        };
+        if size <= 0 {
+            return false;
+        }
+        fds.truncate(size as usize / size_of::<proc_fdinfo>());
+        fds.iter()
+            .filter(|fd| fd.proc_fdtype == PROX_FDTYPE_SOCKET as u32)
+            .any(|fd| socket_local_addr(pid, fd.proc_fd) == Some(local))
+    }
+
+    /// Local address of the TCP socket `fd` of `pid`.
+    fn socket_local_addr(pid: c_int, fd: c_int) -> Option<SocketAddr> {
+        let mut info = MaybeUninit::<SocketFdinfo>::zeroed();
+        let size = size_of::<SocketFdinfo>() as c_int;
+        let buffer = info.as_mut_ptr() as *mut c_void;
+        let res = unsafe { libc::proc_pidfdinfo(pid, fd, PROC_PIDFDSOCKETINFO, buffer, size) };
+        if res != size {
+            return None;
+        }
+        let info = unsafe { info.assume_init() };
+        if info.psi.soi_kind != SOCKINFO_TCP {
+            return None;
+        }
+        let ini = unsafe { info.psi.soi_proto.pri_in };
+        let ip = if ini.insi_vflag & INI_IPV4 != 0 {
+            let [_, _, _, _, _, _, _, _, _, _, _, _, a, b, c, d] = ini.insi_laddr;
+            IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+        } else if ini.insi_vflag & INI_IPV6 != 0 {
+            IpAddr::V6(Ipv6Addr::from(ini.insi_laddr))
+        } else {
+            return None;
+        };
+        // The port in network byte order in the low 16 bits
+        let port = u16::from_be(ini.insi_lport as u16);
+        Some(SocketAddr::new(normalize_ip(ip), port))
+    }
+
+    fn image_name(pid: c_int) -> Option<String> {
+        let mut path = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+        let len = unsafe {
+            libc::proc_pidpath(pid, path.as_mut_ptr() as *mut c_void, path.len() as u32)
+        };
+        if len <= 0 {
+            return None;
+        }
+        let path = String::from_utf8_lossy(&path[..len as usize]).into_owned();
+        Some(Path::new(&path).file_name()?.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::path::Path;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, NO_ERROR};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetExtendedTcpTable, MIB_TCP6ROW_OWNER_PID, MIB_TCPROW_OWNER_PID,
+        TCP_TABLE_OWNER_PID_CONNECTIONS,
+    };
+    use windows_sys::Win32::Networking::WinSock::{AF_INET, AF_INET6};
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    use super::normalize_ip;
+
+    pub fn process_name(local: SocketAddr) -> Option<String> {
+        let pid = match local.ip() {
+            IpAddr::V4(_) => tcp_table::<MIB_TCPROW_OWNER_PID>(AF_INET as u32)?
+                .iter()
+                .find(|row| {
+                    let ip = Ipv4Addr::from(row.dwLocalAddr.to_ne_bytes());
+                    SocketAddr::new(IpAddr::V4(ip), port(row.dwLocalPort)) == local
+                })
+                .map(|row| row.dwOwningPid),
+            IpAddr::V6(_) => tcp_table::<MIB_TCP6ROW_OWNER_PID>(AF_INET6 as u32)?
+                .iter()
+                .find(|row| {
+                    let ip = normalize_ip(IpAddr::V6(Ipv6Addr::from(row.ucLocalAddr)));
+                    SocketAddr::new(ip, port(row.dwLocalPort)) == local
+                })
+                .map(|row| row.dwOwningPid),
+        }?;
+        image_name(pid)
+    }
+
+    /// The port is in network byte order in the low 16 bits.
+    fn port(dw_port: u32) -> u16 {
+        u16::from_be(dw_port as u16)
+    }
+
+    /// Connection rows of `GetExtendedTcpTable`: a `u32` count then the rows.
+    fn tcp_table<Row: Copy>(family: u32) -> Option<Vec<Row>> {
+        let mut size = 0u32;
+        let mut buf: Vec<u32> = Vec::new();
+        for _ in 0..3 {
+            let res = unsafe {
+                GetExtendedTcpTable(
+                    buf.as_mut_ptr() as *mut c_void,
+                    &mut size,
+                    0,
+                    family,
+                    TCP_TABLE_OWNER_PID_CONNECTIONS,
+                    0,
+                )
+            };
+            if res == NO_ERROR {
+                let count = buf[0] as usize;
+                let rows = unsafe { buf.as_ptr().add(1) as *const Row };
+                return Some((0..count).map(|i| unsafe { *rows.add(i) }).collect());
+            }
+            // ERROR_INSUFFICIENT_BUFFER, the table grew since asked
+            buf = vec![0u32; (size as usize).div_ceil(4)];
+        }
+        None
+    }
+
+    fn image_name(pid: u32) -> Option<String> {
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if process == 0 {
+            return None;
+        }
+        let mut path = [0u16; 1024];
+        let mut len = path.len() as u32;
+        let res = unsafe {
+            QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, path.as_mut_ptr(), &mut len)
+        };
+        unsafe { CloseHandle(process) };
+        if res == 0 {
+            return None;
+        }
+        let path = String::from_utf16_lossy(&path[..len as usize]);
+        Some(Path::new(&path).file_name()?.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use std::net::SocketAddr;
+
+    pub fn process_name(_local: SocketAddr) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::client::conn::http1;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::rules::SharedRules;
+use crate::traffic_diversion::MatchProxy;
+use crate::traits::BoxedStream;
+
+/// Time allowed to download a rule provider or an included rule file.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest rule download accepted.
+const MAX_FETCH_SIZE: usize = 16 * 1024 * 1024;
+/// Pause before downloading a provider again after a failure, unless its
+/// interval is shorter.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How the entries of a rule provider are read, as in Clash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProviderBehavior {
+    /// Domains, `+.example.com` also matching the subdomains
+    Domain,
+    /// IPv4 and IPv6 CIDRs
+    IpCidr,
+    /// `TYPE,VALUE[,OPTION]` rules without action
+    Classical,
+}
+
+impl FromStr for ProviderBehavior {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "domain" => Ok(ProviderBehavior::Domain),
+            "ipcidr" => Ok(ProviderBehavior::IpCidr),
+            "classical" => Ok(ProviderBehavior::Classical),
+            _ => Err(anyhow!("unknown rule provider behavior: {}", s)),
+        }
+    }
+}
+
+/// Rules downloaded from `url` and kept in `path`, Clash rule-providers of
+/// type `http`. The entries are read as the `text` format, or as the `yaml`
+/// one when they are listed under `payload:`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleProvider {
+    pub url: String,
+    /// Copy of the last download, used until the next one succeeds
+    pub path: PathBuf,
+    /// How often the rules are downloaded again
+    pub interval: Duration,
+    pub behavior: ProviderBehavior,
+    /// Action of every entry, `DIRECT`, `PROXY`, `REJECT` or a proxy group
+    pub action: String,
+}
+
+impl RuleProvider {
+    /// The provider's entries as rule file lines, downloaded first when
+    /// `path` doesn't exist yet.
+    pub(crate) fn rule_lines(&self) -> Result<String> {
+        if !self.path.exists() {
+            let payload = fetch_blocking(&self.url)?;
+            self.save(&payload)?;
+        }
+        let payload = fs::read_to_string(&self.path)?;
+        Ok(rule_lines(self.behavior, &payload, &self.action))
+    }
+
+    /// Download the entries again and store them in `path`. The file is
+    /// kept as is when the download fails or holds invalid rules.
+    pub async fn fetch(&self) -> Result<()> {
+        let payload = fetch(&self.url).await?;
+        MatchProxy::from_rule_str(&rule_lines(self.behavior, &payload, &self.action))?;
+        self.save(&payload)
+    }
+
+    fn save(&self, payload: &str) -> Result<()> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        fs::write(&partial, payload)?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+
+    /// Time left until the copy in `path` is `interval` old.
+    fn next_refresh(&self) -> Duration {
+        let age = fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        match age {
+            Some(age) => self.interval.saturating_sub(age),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Turn provider entries into `TYPE,VALUE,ACTION` rule lines.
+fn rule_lines(behavior: ProviderBehavior, payload: &str, action: &str) -> String {
+    let mut lines = String::new();
+    for entry in payload.lines() {
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with('#') || entry == "payload:" {
+            continue;
+        }
+        let entry = entry.strip_prefix('-').unwrap_or(entry).trim();
+        let entry = entry.trim_matches(|c| c == '\'' || c == '"');
+        let line = match behavior {
+            ProviderBehavior::Domain => match entry.strip_prefix("+.") {
+                Some(suffix) => format!("DOMAIN-SUFFIX,{},{}", suffix, action),
+                // Clash leaves out the domain itself, close enough
+                None if entry.starts_with('.') => {
+                    format!("DOMAIN-SUFFIX,{},{}", &entry[1..], action)
+                }
+                None => format!("DOMAIN,{},{}", entry, action),
+            },
+            ProviderBehavior::IpCidr if entry.contains(':') => {
+                format!("IP-CIDR6,{},{}", entry, action)
+            }
+            ProviderBehavior::IpCidr => format!("IP-CIDR,{},{}", entry, action),
+            ProviderBehavior::Classical => {
+                let mut parts: Vec<&str> = entry.split(',').collect();
+                parts.insert(parts.len().min(2), action);
+                parts.join(",")
+            }
+        };
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Download the rule providers of `rules` again once they are `interval`
+/// old, each on its own schedule. A task ends once its layer is removed.
+pub fn spawn_provider_updates(rules: &Arc<SharedRules>) -> Vec<JoinHandle<()>> {
+    let names = rules.load().provider_layers();
+    names
+        .into_iter()
+        .map(|name| {
+            let rules = Arc::clone(rules);
+            tokio::spawn(async move {
+                while let Some(provider) = rules.load().provider(&name) {
+                    tokio::time::sleep(provider.next_refresh()).await;
+                    if let Err(e) = provider.fetch().await {
+                        warn!("Failed to download rule provider {}: {}", name, e);
+                        tokio::time::sleep(provider.interval.min(RETRY_INTERVAL)).await;
+                        continue;
+                    }
+                    // Loading reads files and may download `INCLUDE`s
+                    let reload = {
+                        let (rules, name) = (Arc::clone(&rules), name.clone());
+                        tokio::task::spawn_blocking(move || {
+                            rules.update(|match_proxy| match_proxy.reload_layer(&name))
+                        })
+                    };
+                    match reload.await {
+                        Ok(Ok(())) => info!("Rule provider {} updated", name),
+                        Ok(Err(e)) => warn!("Failed to load rule provider {}: {}", name, e),
+                        Err(e) => warn!("Loading rule provider {} panicked: {}", name, e),
+                    }
+                }
+                debug!("Rule provider {} removed, no longer updated", name);
+            })
+        })
+        .collect()
+}
+
+/// GET `url` as text, over https only with the `provider-tls` feature.
+pub(crate) async fn fetch(url: &str) -> Result<String> {
+    tokio::time::timeout(FETCH_TIMEOUT, fetch_url(url))
+        .await
+        .map_err(|_| anyhow!("downloading {} timed out", url))?
+}
+
+/// `fetch` for the synchronous rule loading, the download gets a runtime of
+/// its own. Blocks the calling thread, on a runtime run it through
+/// `spawn_blocking`.
+pub(crate) fn fetch_blocking(url: &str) -> Result<String> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(fetch(url))
+            })
+            .join()
+            .map_err(|_| anyhow!("downloading {} panicked", url))?
+    })
+}
+
+async fn fetch_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("no host in {}", url))?
+        .to_string();
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("no port in {}", url))?;
+    let tcp = TcpStream::connect((host.trim_matches(|c| c == '[' || c == ']'), port)).await?;
+    let stream: BoxedStream = match parsed.scheme() {
+        "http" => Box::new(tcp),
+        "https" => Box::new(tls_connect(&host, tcp).await?),
+        scheme => return Err(anyhow!("unsupported scheme {} in {}", scheme, url)),
+    };
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!("Rule download connection closed: {}", e);
+        }
+    });
+    let authority = match parsed.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    let path = &parsed[url::Position::BeforePath..url::Position::AfterQuery];
+    let req = Request::get(path)
+        .header(hyper::header::HOST, authority)
+        .header(hyper::header::USER_AGENT, "kitty_proxy")
+        .body(Empty::<Bytes>::new())?;
+    let res = sender.send_request(req).await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("{} answered {}", url, res.status()));
+    }
+    let body = Limited::new(res.into_body(), MAX_FETCH_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow!("downloading {}: {}", url, e))?
+        .to_bytes();
+    Ok(String::from_utf8(body.to_vec())?)
+}
+
+#[cfg(feature = "provider-tls")]
+async fn tls_connect(
+    host: &str,
+    tcp: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore};
+
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())?;
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    Ok(connector.connect(server_name, tcp).await?)
+}
+
+#[cfg(not(feature = "provider-tls"))]
+async fn tls_connect(host: &str, _tcp: TcpStream) -> Result<TcpStream> {
+    Err(anyhow!("https download from {} needs the provider-tls feature", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_entries_become_rules() {
+        let domains = "payload:\n  - '+.google.com'\n  - 'www.example.com'\n";
+        assert_eq!(
+            rule_lines(ProviderBehavior::Domain, domains, "proxy"),
+            "DOMAIN-SUFFIX,google.com,proxy\nDOMAIN,www.example.com,proxy\n"
+        );
+        let cidrs = "# text format\n10.0.0.0/8\n2001:db8::/32\n";
+        assert_eq!(
+            rule_lines(ProviderBehavior::IpCidr, cidrs, "direct"),
+            "IP-CIDR,10.0.0.0/8,direct\nIP-CIDR6,2001:db8::/32,direct\n"
+        );
+        let classical = "DOMAIN-KEYWORD,ads\nIP-CIDR,1.1.1.1/32,no-resolve\n";
+        assert_eq!(
+            rule_lines(ProviderBehavior::Classical, classical, "reject"),
+            "DOMAIN-KEYWORD,ads,reject\nIP-CIDR,1.1.1.1/32,reject,no-resolve\n"
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::relay::{Throughput, TunnelBytes};
+use crate::sniff::ClientHello;
+use crate::types::ResponseCode;
+
+/// How long usage is remembered when no quota is configured.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// Destination and node pairs with protocol counts, the least recently seen
+/// one is forgotten first.
+const MAX_PROTOCOL_ENTRIES: usize = 1024;
+/// Client IPs tracked by `ConnectionOpens` before idle ones are forgotten.
+const MAX_RATE_CLIENTS: usize = 4096;
+/// How often the bytes moved by an open tunnel are charged to its client.
+const CHARGE_INTERVAL: Duration = Duration::from_secs(1);
+/// Bytes charged to a key this soon after its last entry are added to it,
+/// so long tunnels charged every second don't pile up entries.
+const USAGE_GRANULARITY: Duration = Duration::from_secs(60);
+
+/// Bandwidth allowed to a single client IP over a rolling window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Bytes (both directions) a client may transfer within `window`
+    pub limit_bytes: u64,
+    pub window: Duration,
+    /// Error returned to clients over quota
+    pub reject_code: ResponseCode,
+}
+
+impl QuotaConfig {
+    pub fn per_day(limit_bytes: u64) -> Self {
+        Self {
+            limit_bytes,
+            window: DEFAULT_WINDOW,
+            reject_code: ResponseCode::RuleFailure,
+        }
+    }
+}
+
+/// Connections a single client IP may open over a sliding window, against
+/// apps opening connections in a loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionRateConfig {
+    /// Connections accepted from a client within `window`
+    pub max_opens: usize,
+    pub window: Duration,
+}
+
+impl ConnectionRateConfig {
+    pub fn per_second(max_opens: usize) -> Self {
+        Self {
+            max_opens,
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Times of the connections opened per client IP within the rate window.
+/// Checked from the accept loop, so it locks without awaiting.
+#[derive(Clone, Default)]
+pub struct ConnectionOpens(Arc<SyncMutex<HashMap<IpAddr, VecDeque<Instant>>>>);
+
+impl ConnectionOpens {
+    /// Count a connection opened by `ip`, or refuse it with the time until
+    /// the client may open another one when it is over `rate`. Refused
+    /// connections are not counted, a client backing off gets through.
+    pub fn open(&self, ip: IpAddr, rate: &ConnectionRateConfig) -> Result<(), Duration> {
+        let now = Instant::now();
+        let in_window = |at: &Instant| now.duration_since(*at) < rate.window;
+        let mut opens = self.0.lock().unwrap();
+        if !opens.contains_key(&ip) && opens.len() >= MAX_RATE_CLIENTS {
+            opens.retain(|_, times| times.back().is_some_and(in_window));
+        }
+        let times = opens.entry(ip).or_default();
+        while times.front().is_some_and(|at| !in_window(at)) {
+            times.pop_front();
+        }
+        if times.len() >= rate.max_opens {
+            let retry_after = match times.front() {
+                Some(oldest) => rate.window - now.duration_since(*oldest),
+                None => rate.window,
+            };
+            return Err(retry_after);
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+/// Usage of a client as reported by `ClientUsage::snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaState {
+    /// Both directions
+    pub used_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub limit_bytes: Option<u64>,
+    pub exceeded: bool,
+}
+
+/// Bytes uploaded and downloaded since the time of the entry.
+type UsageMap<K> = HashMap<K, VecDeque<(Instant, u64, u64)>>;
+
+/// Bytes transferred per key, one entry per `USAGE_GRANULARITY` at most.
+pub struct Usage<K>(Arc<Mutex<UsageMap<K>>>);
+
+/// Usage per client IP, the unit quotas are enforced on.
+pub type ClientUsage = Usage<IpAddr>;
+
+/// Usage per authenticated username.
+pub type UserUsage = Usage<String>;
+
+impl<K> Clone for Usage<K> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<K> Default for Usage<K> {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+fn used_within(entries: &mut VecDeque<(Instant, u64, u64)>, window: Duration) -> u64 {
+    while let Some((at, _, _)) = entries.front() {
+        if at.elapsed() > window {
+            entries.pop_front();
+        } else {
+            break;
+        }
+    }
+    entries.iter().map(|(_, up, down)| up + down).sum()
+}
+
+impl<K: Eq + Hash + Clone> Usage<K> {
+    pub async fn record(&self, key: K, bytes: &TunnelBytes) {
+        if bytes.uploaded + bytes.downloaded == 0 {
+            return;
+        }
+        let mut usage = self.0.lock().await;
+        let entries = usage.entry(key).or_default();
+        match entries.back_mut() {
+            Some((at, uploaded, downloaded)) if at.elapsed() < USAGE_GRANULARITY => {
+                *uploaded += bytes.uploaded;
+                *downloaded += bytes.downloaded;
+            }
+            _ => entries.push_back((Instant::now(), bytes.uploaded, bytes.downloaded)),
+        }
+    }
+
+    pub async fn used(&self, key: &K, window: Duration) -> u64 {
+        let mut usage = self.0.lock().await;
+        usage
+            .get_mut(key)
+            .map(|entries| used_within(entries, window))
+            .unwrap_or(0)
+    }
+
+    pub async fn is_exceeded(&self, key: &K, quota: &QuotaConfig) -> bool {
+        self.used(key, quota.window).await >= quota.limit_bytes
+    }
+
+    pub async fn snapshot(&self, quota: Option<&QuotaConfig>) -> HashMap<K, QuotaState> {
+        let window = quota.map(|q| q.window).unwrap_or(DEFAULT_WINDOW);
+        let mut usage = self.0.lock().await;
+        usage.retain(|_, entries| used_within(entries, window) > 0);
+        usage
+            .iter()
+            .map(|(key, entries)| {
+                let uploaded_bytes = entries.iter().map(|(_, up, _)| up).sum::<u64>();
+                let downloaded_bytes = entries.iter().map(|(_, _, down)| down).sum::<u64>();
+                let used_bytes = uploaded_bytes + downloaded_bytes;
+                let state = QuotaState {
+                    used_bytes,
+                    uploaded_bytes,
+                    downloaded_bytes,
+                    limit_bytes: quota.map(|q| q.limit_bytes),
+                    exceeded: quota.map(|q| used_bytes >= q.limit_bytes).unwrap_or(false),
+                };
+                (key.clone(), state)
+            })
+            .collect()
+    }
+}
+
+/// TLS hints of the sniffed tunnels to a destination network through a node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolCounts {
+    /// Tunnels per protocol preferred through ALPN, `none` without ALPN
+    pub alpn: BTreeMap<String, u64>,
+    /// Tunnels per highest TLS version offered
+    pub tls_versions: BTreeMap<String, u64>,
+}
+
+/// `destination_key` and node, `None` for direct tunnels.
+pub type ProtocolKey = (String, Option<SocketAddr>);
+
+/// Protocol counts of sniffed tunnels.
+#[derive(Clone, Default)]
+pub struct ProtocolUsage(Arc<Mutex<HashMap<ProtocolKey, (Instant, ProtocolCounts)>>>);
+
+impl ProtocolUsage {
+    pub async fn record(&self, destination: String, node: Option<SocketAddr>, hello: &ClientHello) {
+        let key = (destination, node);
+        let mut usage = self.0.lock().await;
+        if !usage.contains_key(&key) && usage.len() >= MAX_PROTOCOL_ENTRIES {
+            let stalest = usage
+                .iter()
+                .min_by_key(|(_, (last_seen, _))| *last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(stalest) = stalest {
+                usage.remove(&stalest);
+            }
+        }
+        let (last_seen, counts) = usage
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), ProtocolCounts::default()));
+        *last_seen = Instant::now();
+        let alpn = hello.alpn.first().map_or("none", String::as_str);
+        *counts.alpn.entry(alpn.to_string()).or_default() += 1;
+        *counts.tls_versions.entry(hello.version_name()).or_default() += 1;
+    }
+
+    pub async fn snapshot(&self) -> HashMap<ProtocolKey, ProtocolCounts> {
+        let usage = self.0.lock().await;
+        usage
+            .iter()
+            .map(|(key, (_, counts))| (key.clone(), counts.clone()))
+            .collect()
+    }
+}
+
+/// Usage recorded by a listener, per client IP and per authenticated user.
+#[derive(Clone, Default)]
+pub struct ProxyUsage {
+    pub clients: ClientUsage,
+    pub users: UserUsage,
+    pub protocols: ProtocolUsage,
+    /// Connections recently opened per client IP, see `connection_rate`
+    pub opens: ConnectionOpens,
+}
+
+impl ProxyUsage {
+    pub async fn record(&self, ip: IpAddr, username: Option<&str>, bytes: &TunnelBytes) {
+        self.clients.record(ip, bytes).await;
+        if let Some(username) = username {
+            self.users.record(username.to_string(), bytes).await;
+        }
+    }
+
+    /// Run `tunnel`, charging the bytes `throughput` counts to `ip` and
+    /// `username` while they flow: a long download counts against the quota
+    /// before it ends, and so does a tunnel failing midway.
+    pub(crate) async fn metered<F: Future>(
+        &self,
+        ip: IpAddr,
+        username: Option<&str>,
+        throughput: &Throughput,
+        tunnel: F,
+    ) -> F::Output {
+        let mut tunnel = std::pin::pin!(tunnel);
+        let start = tokio::time::Instant::now() + CHARGE_INTERVAL;
+        let mut ticks = tokio::time::interval_at(start, CHARGE_INTERVAL);
+        let mut charged = TunnelBytes::default();
+        loop {
+            tokio::select! {
+                output = &mut tunnel => {
+                    self.charge(ip, username, throughput, &mut charged).await;
+                    return output;
+                }
+                _ = ticks.tick() => self.charge(ip, username, throughput, &mut charged).await,
+            }
+        }
+    }
+
+    /// Charge what `throughput` counted since `charged`.
+    async fn charge(
+        &self,
+        ip: IpAddr,
+        username: Option<&str>,
+        throughput: &Throughput,
+        charged: &mut TunnelBytes,
+    ) {
+        let total = throughput.total();
+        let bytes = TunnelBytes {
+            uploaded: total.uploaded - charged.uploaded,
+            downloaded: total.downloaded - charged.downloaded,
+            closed_first: None,
+        };
+        charged.uploaded = total.uploaded;
+        charged.downloaded = total.downloaded;
+        self.record(ip, username, &bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::{ProxyUsage, QuotaConfig};
+    use crate::relay::{track_tunnel, TunnelBytes, TunnelSide};
+    use crate::types::ResponseCode;
+
+    #[tokio::test]
+    async fn quota_is_exceeded_until_the_window_passes() {
+        let usage = ProxyUsage::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let quota = QuotaConfig {
+            limit_bytes: 1000,
+            window: Duration::from_millis(200),
+            reject_code: ResponseCode::RuleFailure,
+        };
+        let bytes = TunnelBytes {
+            uploaded: 400,
+            downloaded: 500,
+            closed_first: None,
+        };
+        usage.record(ip, None, &bytes).await;
+        assert!(!usage.clients.is_exceeded(&ip, &quota).await);
+        usage.record(ip, None, &TunnelBytes { uploaded: 100, ..bytes }).await;
+        assert!(usage.clients.is_exceeded(&ip, &quota).await);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!usage.clients.is_exceeded(&ip, &quota).await);
+        assert_eq!(usage.clients.used(&ip, quota.window).await, 0);
+    }
+
+    #[tokio::test]
+    async fn metered_tunnels_are_charged_while_open() {
+        let usage = ProxyUsage::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let tracked = track_tunnel(&"example.com:443");
+        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let tunnel = async {
+            tracked.record(TunnelSide::Client, 100);
+            tracked.record(TunnelSide::Upstream, 1000);
+            let _ = close_rx.await;
+            tracked.record(TunnelSide::Upstream, 24);
+        };
+        let check = async {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            let open = usage.clients.snapshot(None).await[&ip].clone();
+            assert_eq!((open.uploaded_bytes, open.downloaded_bytes), (100, 1000));
+            close_tx.send(()).unwrap();
+        };
+        tokio::join!(usage.metered(ip, Some("alice"), tracked.throughput(), tunnel), check);
+
+        let closed = usage.clients.snapshot(None).await[&ip].clone();
+        assert_eq!((closed.uploaded_bytes, closed.downloaded_bytes), (100, 1024));
+        assert_eq!(usage.users.snapshot(None).await["alice"].used_bytes, 1124);
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use log::{log, Level};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::listener::{
+    connection_log_level, current_connection_id, current_tunnels, ConnectionId,
+};
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Seconds of throughput samples kept per tunnel.
+pub const THROUGHPUT_SECONDS: usize = 60;
+
+/// Seconds of process wide bandwidth samples kept, see `bandwidth_history`.
+pub const BANDWIDTH_HISTORY_SECONDS: usize = 10 * 60;
+
+static BANDWIDTH: LazyLock<Throughput> =
+    LazyLock::new(|| Throughput::new(BANDWIDTH_HISTORY_SECONDS));
+
+/// Log target of tunnel close events, so dead peers can be alerted on.
+pub const TUNNEL_LOG_TARGET: &str = "kitty_proxy::tunnel";
+
+/// Why a tunnel ended, as logged under `TUNNEL_LOG_TARGET`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TunnelCloseReason {
+    /// Both sides closed their end
+    Completed,
+    /// The upstream stayed silent past the `stall_timeout`
+    Stalled,
+    /// A peer stopped answering TCP keepalive probes
+    PeerDead,
+    /// A peer reset the connection
+    Reset,
+    /// Interrupted by the proxy, see `cancellable`
+    Cancelled,
+    Error,
+}
+
+impl TunnelCloseReason {
+    /// Reason of a tunnel ending with `res`, e.g. the result of `relay`.
+    pub fn of<T>(res: &io::Result<T>) -> Self {
+        let Err(e) = res else {
+            return TunnelCloseReason::Completed;
+        };
+        match e.kind() {
+            // Failed keepalive probes are reported by the socket itself, the
+            // stall watchdog raises its own error
+            io::ErrorKind::TimedOut | io::ErrorKind::HostUnreachable
+                if e.raw_os_error().is_some() =>
+            {
+                TunnelCloseReason::PeerDead
+            }
+            io::ErrorKind::TimedOut => TunnelCloseReason::Stalled,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => TunnelCloseReason::Reset,
+            io::ErrorKind::Interrupted => TunnelCloseReason::Cancelled,
+            _ => TunnelCloseReason::Error,
+        }
+    }
+
+    /// Reason code of the close event.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TunnelCloseReason::Completed => "completed",
+            TunnelCloseReason::Stalled => "stalled",
+            TunnelCloseReason::PeerDead => "peer_dead",
+            TunnelCloseReason::Reset => "reset",
+            TunnelCloseReason::Cancelled => "cancelled",
+            TunnelCloseReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for TunnelCloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// End of a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelSide {
+    Client,
+    Upstream,
+}
+
+impl fmt::Display for TunnelSide {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TunnelSide::Client => write!(f, "client"),
+            TunnelSide::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+/// Bytes a tunnel moved in each direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelBytes {
+    /// From the client to the upstream
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Side that finished sending first, `None` when unknown
+    pub closed_first: Option<TunnelSide>,
+}
+
+impl fmt::Display for TunnelBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} B up, {} B down", self.uploaded, self.downloaded)?;
+        match self.closed_first {
+            Some(side) => write!(f, ", {} closed first", side),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Bytes a tunnel moved in each direction during one second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThroughputSample {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+struct Samples {
+    /// Second since the start of the running sample, the last one
+    second: u64,
+    ring: VecDeque<ThroughputSample>,
+    total: ThroughputSample,
+}
+
+/// Per second throughput of a tunnel over the last `THROUGHPUT_SECONDS`.
+pub struct Throughput {
+    started: Instant,
+    /// Complete seconds kept
+    seconds: usize,
+    samples: Mutex<Samples>,
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Self::new(THROUGHPUT_SECONDS)
+    }
+}
+
+impl Throughput {
+    fn new(seconds: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            seconds,
+            samples: Mutex::new(Samples {
+                second: 0,
+                ring: VecDeque::from([ThroughputSample::default()]),
+                total: ThroughputSample::default(),
+            }),
+        }
+    }
+
+    /// Start a sample for every second passed since the running one.
+    fn advance(&self, samples: &mut Samples) {
+        let second = self.started.elapsed().as_secs();
+        let passed = (second - samples.second).min(self.seconds as u64 + 1);
+        for _ in 0..passed {
+            samples.ring.push_back(ThroughputSample::default());
+        }
+        samples.second = second;
+        // The complete seconds and the running one
+        while samples.ring.len() > self.seconds + 1 {
+            samples.ring.pop_front();
+        }
+    }
+
+    pub(crate) fn record(&self, side: TunnelSide, bytes: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        self.advance(&mut samples);
+        let Samples { ring, total, .. } = &mut *samples;
+        let running = ring.back_mut().expect("throughput ring has a running sample");
+        match side {
+            TunnelSide::Client => {
+                running.uploaded += bytes;
+                total.uploaded += bytes;
+            }
+            TunnelSide::Upstream => {
+                running.downloaded += bytes;
+                total.downloaded += bytes;
+            }
+        }
+    }
+
+    /// The complete seconds kept, oldest first: the last one is the current
+    /// speed.
+    pub fn samples(&self) -> Vec<ThroughputSample> {
+        let mut samples = self.samples.lock().unwrap();
+        self.advance(&mut samples);
+        let complete = samples.ring.len() - 1;
+        samples.ring.iter().take(complete).copied().collect()
+    }
+
+    /// Bytes moved since the tunnel opened.
+    pub fn total(&self) -> ThroughputSample {
+        self.samples.lock().unwrap().total
+    }
+}
+
+/// Bytes moved by all the tunnels of the process each second over the last
+/// `BANDWIDTH_HISTORY_SECONDS`, oldest first, e.g. for a traffic graph.
+pub fn bandwidth_history() -> Vec<ThroughputSample> {
+    BANDWIDTH.samples()
+}
+
+/// Count `bytes` sent by `side` into the process wide bandwidth and the
+/// `throughput` of its tunnel.
+fn count_bytes(throughput: Option<&Throughput>, side: TunnelSide, bytes: u64) {
+    BANDWIDTH.record(side, bytes);
+    if let Some(throughput) = throughput {
+        throughput.record(side, bytes);
+    }
+}
+
+/// `stream` to an upstream with the bytes written to it counted as uploaded
+/// and the ones read as downloaded, e.g. for the quota of plain HTTP requests.
+pub(crate) struct MeteredStream<S> {
+    stream: S,
+    throughput: Arc<Throughput>,
+}
+
+impl<S> MeteredStream<S> {
+    pub(crate) fn new(stream: S, throughput: Arc<Throughput>) -> Self {
+        Self { stream, throughput }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - filled) as u64;
+        this.throughput.record(TunnelSide::Upstream, read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        this.throughput.record(TunnelSide::Client, written as u64);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// An open tunnel of a listener, as listed by `HttpProxy::tunnels`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelSnapshot {
+    pub connection: Option<ConnectionId>,
+    pub target: String,
+    pub open_secs: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes moved each second, oldest first, see `Throughput::samples`
+    pub samples: Vec<ThroughputSample>,
+}
+
+struct OpenTunnel {
+    connection: Option<ConnectionId>,
+    target: String,
+    throughput: Throughput,
+}
+
+#[derive(Default)]
+struct OpenTunnels {
+    last_key: u64,
+    open: BTreeMap<u64, Arc<OpenTunnel>>,
+}
+
+/// Open tunnels of a listener in the order they opened.
+#[derive(Clone, Default)]
+pub(crate) struct Tunnels(Arc<Mutex<OpenTunnels>>);
+
+impl Tunnels {
+    pub(crate) fn snapshots(&self) -> Vec<TunnelSnapshot> {
+        let open: Vec<Arc<OpenTunnel>> = self.0.lock().unwrap().open.values().cloned().collect();
+        open.iter()
+            .map(|tunnel| {
+                let total = tunnel.throughput.total();
+                TunnelSnapshot {
+                    connection: tunnel.connection,
+                    target: tunnel.target.clone(),
+                    open_secs: tunnel.throughput.started.elapsed().as_secs(),
+                    uploaded: total.uploaded,
+                    downloaded: total.downloaded,
+                    samples: tunnel.throughput.samples(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A tunnel listed by its listener until dropped.
+pub(crate) struct TrackedTunnel {
+    tunnels: Option<Tunnels>,
+    key: u64,
+    tunnel: Arc<OpenTunnel>,
+}
+
+impl TrackedTunnel {
+    pub(crate) fn throughput(&self) -> &Throughput {
+        &self.tunnel.throughput
+    }
+
+    /// Count bytes moved outside of `relay`, e.g. replayed ones.
+    pub(crate) fn record(&self, side: TunnelSide, bytes: u64) {
+        count_bytes(Some(self.throughput()), side, bytes);
+    }
+}
+
+impl Drop for TrackedTunnel {
+    fn drop(&mut self) {
+        if let Some(tunnels) = &self.tunnels {
+            tunnels.0.lock().unwrap().open.remove(&self.key);
+        }
+    }
+}
+
+/// List the tunnel to `target` of the connection served by the current task
+/// with the other tunnels of its listener. Outside of a connection it is
+/// measured all the same, listed nowhere.
+pub(crate) fn track_tunnel(target: &dyn fmt::Display) -> TrackedTunnel {
+    let tunnel = Arc::new(OpenTunnel {
+        connection: current_connection_id(),
+        target: target.to_string(),
+        throughput: Throughput::default(),
+    });
+    let tunnels = current_tunnels();
+    let mut key = 0;
+    if let Some(tunnels) = &tunnels {
+        let mut tunnels = tunnels.0.lock().unwrap();
+        tunnels.last_key += 1;
+        key = tunnels.last_key;
+        tunnels.open.insert(key, Arc::clone(&tunnel));
+    }
+    TrackedTunnel {
+        tunnels,
+        key,
+        tunnel,
+    }
+}
+
+/// Log the end of the tunnel to `target` under `TUNNEL_LOG_TARGET`, e.g.
+/// `[01J9Z3K8Q2M4X7AB] tunnel to example.com:22 closed: peer_dead`, with the
+/// bytes moved when it completed.
+pub fn log_tunnel_closed(
+    target: &dyn fmt::Display,
+    res: &io::Result<TunnelBytes>,
+) -> TunnelCloseReason {
+    let reason = TunnelCloseReason::of(res);
+    let level = match reason {
+        TunnelCloseReason::Completed => Level::Debug,
+        _ => Level::Info,
+    };
+    let Some(level) = connection_log_level(None, level) else {
+        return reason;
+    };
+    let bytes = match res {
+        Ok(bytes) => format!(" ({})", bytes),
+        Err(_) => String::new(),
+    };
+    match current_connection_id() {
+        Some(id) => log!(
+            target: TUNNEL_LOG_TARGET,
+            level,
+            "[{}] tunnel to {} closed: {}{}",
+            id,
+            target,
+            reason,
+            bytes
+        ),
+        None => log!(
+            target: TUNNEL_LOG_TARGET,
+            level,
+            "tunnel to {} closed: {}{}",
+            target,
+            reason,
+            bytes
+        ),
+    }
+    reason
+}
+
+/// Last time each direction moved data, in milliseconds since the relay started
+/// (0 means never), and the side reaching EOF first.
+struct Activity {
+    started: Instant,
+    a_to_b: AtomicU64,
+    b_to_a: AtomicU64,
+    /// 0 until a side closed, 1 for `a`, 2 for `b`
+    closed_first: AtomicU8,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            a_to_b: AtomicU64::new(0),
+            b_to_a: AtomicU64::new(0),
+            closed_first: AtomicU8::new(0),
+        }
+    }
+
+    fn close(&self, side: TunnelSide) {
+        let side = match side {
+            TunnelSide::Client => 1,
+            TunnelSide::Upstream => 2,
+        };
+        let _ = self
+            .closed_first
+            .compare_exchange(0, side, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    fn closed_first(&self) -> Option<TunnelSide> {
+        match self.closed_first.load(Ordering::Relaxed) {
+            1 => Some(TunnelSide::Client),
+            2 => Some(TunnelSide::Upstream),
+            _ => None,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64 + 1
+    }
+
+    fn touch(&self, direction: &AtomicU64) {
+        direction.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// `b` has not sent anything for `stall_timeout` although `a` sent data.
+    fn is_stalled(&self, stall_timeout: Duration) -> bool {
+        let a_to_b = self.a_to_b.load(Ordering::Relaxed);
+        let b_to_a = self.b_to_a.load(Ordering::Relaxed);
+        a_to_b > b_to_a && self.now() - a_to_b >= stall_timeout.as_millis() as u64
+    }
+}
+
+/// Copy `reader`, the `side` end, into `writer` until EOF, then forward the
+/// FIN by shutting down the write half of `writer`.
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    side: TunnelSide,
+    activity: &Activity,
+    direction: &AtomicU64,
+    throughput: Option<&Throughput>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            activity.close(side);
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        activity.touch(direction);
+        count_bytes(throughput, side, n as u64);
+        total += n as u64;
+    }
+    match writer.shutdown().await {
+        // the peer is already gone, nothing left to propagate
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => {}
+        Err(e) => return Err(e),
+        Ok(()) => {}
+    }
+    Ok(total)
+}
+
+/// Relay bytes between the client `a` and the upstream `b`, returning the
+/// bytes sent each way and the side closing first.
+///
+/// Unlike `tokio::io::copy_bidirectional` every direction runs to its own EOF:
+/// a half-close of one side only shuts down the write half of the other one,
+/// the relay ends once both directions are finished.
+///
+/// With `stall_timeout`, the relay fails with `ErrorKind::TimedOut` when `b`
+/// stays silent that long after `a` sent data, e.g. a blackholing upstream.
+///
+/// The bytes moved each way are sampled into `throughput` as they go.
+pub async fn relay<A, B>(
+    a: &mut A,
+    b: &mut B,
+    stall_timeout: Option<Duration>,
+    throughput: Option<&Throughput>,
+) -> io::Result<TunnelBytes>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let activity = Activity::new();
+    let copy = async {
+        let (uploaded, downloaded) = tokio::try_join!(
+            copy_half(
+                &mut a_read,
+                &mut b_write,
+                TunnelSide::Client,
+                &activity,
+                &activity.a_to_b,
+                throughput
+            ),
+            copy_half(
+                &mut b_read,
+                &mut a_write,
+                TunnelSide::Upstream,
+                &activity,
+                &activity.b_to_a,
+                throughput
+            )
+        )?;
+        Ok(TunnelBytes {
+            uploaded,
+            downloaded,
+            closed_first: activity.closed_first(),
+        })
+    };
+    let stall_timeout = match stall_timeout {
+        Some(stall_timeout) => stall_timeout,
+        None => return copy.await,
+    };
+    let watchdog = async {
+        let check_interval = (stall_timeout / 4).max(Duration::from_millis(100));
+        loop {
+            tokio::time::sleep(check_interval).await;
+            if activity.is_stalled(stall_timeout) {
+                return io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("upstream stalled for {:?}", stall_timeout),
+                );
+            }
+        }
+    };
+    tokio::select! {
+        res = copy => res,
+        e = watchdog => Err(e),
+    }
+}
+
+/// Run `tunnel` until `cancel` fires, which fails it with
+/// `ErrorKind::Interrupted` (closed as `cancelled`), e.g. on shutdown.
+pub async fn cancellable<T>(
+    cancel: &CancellationToken,
+    tunnel: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::select! {
+        res = tunnel => res,
+        _ = cancel.cancelled() => {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "tunnel cancelled"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_propagates_half_close() {
+        let (mut client, mut proxy_client) = duplex(64);
+        let (mut proxy_target, mut target) = duplex(64);
+        let relay_task = tokio::spawn(async move {
+            relay(&mut proxy_client, &mut proxy_target, None, None).await
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // the target can still answer after the client sent its FIN
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let bytes = relay_task.await.unwrap().unwrap();
+        assert_eq!((bytes.uploaded, bytes.downloaded), (7, 8));
+        assert_eq!(bytes.closed_first, Some(TunnelSide::Client));
+    }
+
+    #[tokio::test]
+    async fn relay_detects_stalled_upstream() {
+        let (mut client, mut proxy_client) = duplex(64);
+        let (mut proxy_target, _target) = duplex(64);
+        client.write_all(b"request").await.unwrap();
+        let err = relay(
+            &mut proxy_client,
+            &mut proxy_target,
+            Some(Duration::from_millis(200)),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(TunnelCloseReason::of::<()>(&Err(err)), TunnelCloseReason::Stalled);
+        #[cfg(target_os = "linux")]
+        {
+            let keepalive_failed = io::Error::from_raw_os_error(libc::ETIMEDOUT);
+            let reason = TunnelCloseReason::of::<()>(&Err(keepalive_failed));
+            assert_eq!(reason, TunnelCloseReason::PeerDead);
+        }
+    }
+
+    #[test]
+    fn throughput_is_sampled_per_second() {
+        let mut throughput = Throughput::default();
+        throughput.record(TunnelSide::Client, 10);
+        throughput.record(TunnelSide::Upstream, 20);
+        assert!(throughput.samples().is_empty());
+        throughput.started -= Duration::from_secs(2);
+        throughput.record(TunnelSide::Upstream, 5);
+        let second = |uploaded, downloaded| ThroughputSample {
+            uploaded,
+            downloaded,
+        };
+        assert_eq!(throughput.samples(), [second(10, 20), second(0, 0)]);
+        assert_eq!(throughput.total(), second(10, 25));
+        throughput.started -= Duration::from_secs(100);
+        assert_eq!(throughput.samples(), [second(0, 0); THROUGHPUT_SECONDS]);
+
+        // Tunnels of every listener add up, whatever else runs meanwhile
+        let before = BANDWIDTH.total();
+        count_bytes(None, TunnelSide::Client, 7);
+        assert!(BANDWIDTH.total().uploaded >= before.uploaded + 7);
+        assert!(bandwidth_history().len() <= BANDWIDTH_HISTORY_SECONDS);
+    }
+
+    #[tokio::test]
+    async fn cancelled_relay_ends() {
+        let (_client, mut proxy_client) = duplex(64);
+        let (mut proxy_target, _target) = duplex(64);
+        let cancel = CancellationToken::new();
+        let tunnel = relay(&mut proxy_client, &mut proxy_target, None, None);
+        let (res, _) = tokio::join!(cancellable(&cancel, tunnel), async { cancel.cancel() });
+        assert_eq!(TunnelCloseReason::of(&res), TunnelCloseReason::Cancelled);
+    }
+}
@@ -0,0 +1,149 @@
+//! Responses the HTTP proxy serves itself for some hosts, without touching
+//! the network: a block page, a PAC file, connectivity checks.
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::config::ProxyConfig;
+use crate::types::Address;
+
+/// Connectivity check endpoints answering 204 No Content, by host and path,
+/// see `ProxyConfig::local_connectivity_checks`.
+const CONNECTIVITY_CHECKS: [(&str, &str); 7] = [
+    ("connectivitycheck.gstatic.com", "/generate_204"),
+    ("connectivitycheck.android.com", "/generate_204"),
+    ("www.gstatic.com", "/generate_204"),
+    ("clients3.google.com", "/generate_204"),
+    ("www.google.com", "/generate_204"),
+    ("cp.cloudflare.com", "/"),
+    ("edge-http.microsoft.com", "/captiveportal/generate_204"),
+];
+
+/// Static response to every plain HTTP request for a host, registered with
+/// `HttpProxy::set_local_response`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl LocalResponse {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// `body` sent as `content_type`, e.g. `text/html; charset=utf-8` or
+    /// `application/x-ns-proxy-autoconfig`.
+    pub fn with_body(mut self, content_type: HeaderValue, body: impl Into<Bytes>) -> Self {
+        self.headers.insert(CONTENT_TYPE, content_type);
+        self.body = body.into();
+        self
+    }
+
+    fn to_response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let body = Full::new(self.body.clone()).map_err(|never| match never {}).boxed();
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// Host of `host` as the responses are keyed: lowercase, no trailing dot.
+pub(crate) fn response_key(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// Response served by the proxy itself to the plain HTTP request `req` for
+/// `host`, a registered one first. `None` for requests to forward.
+pub(crate) fn local_response<B>(
+    config: &ProxyConfig,
+    req: &Request<B>,
+    host: &Address,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    if req.method() == Method::CONNECT {
+        return None;
+    }
+    let key = match host {
+        Address::SocketAddress(addr) => addr.ip().to_string(),
+        Address::DomainNameAddress(domain, _) => response_key(domain),
+    };
+    if let Some(response) = config.local_responses.get(&key) {
+        return Some(response.to_response());
+    }
+    if config.local_connectivity_checks && is_connectivity_check(req, host) {
+        return Some(LocalResponse::new(StatusCode::NO_CONTENT).to_response());
+    }
+    None
+}
+
+/// Whether `req` to `host` is a plain HTTP connectivity check.
+fn is_connectivity_check<B>(req: &Request<B>, host: &Address) -> bool {
+    let Address::DomainNameAddress(domain, 80) = host else {
+        return false;
+    };
+    let domain = domain.trim_end_matches('.');
+    (req.method() == Method::GET || req.method() == Method::HEAD)
+        && CONNECTIVITY_CHECKS
+            .iter()
+            .any(|(check, path)| domain.eq_ignore_ascii_case(check) && req.uri().path() == *path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_proxy::host_addr;
+
+    fn respond(config: &ProxyConfig, method: Method, uri: &str) -> Option<StatusCode> {
+        let req = Request::builder().method(method).uri(uri).body(()).unwrap();
+        let host = host_addr(req.uri()).unwrap();
+        local_response(config, &req, &host).map(|response| response.status())
+    }
+
+    #[test]
+    fn connectivity_checks_are_recognized() {
+        let config = ProxyConfig {
+            local_connectivity_checks: true,
+            ..Default::default()
+        };
+        let check = |method, uri| respond(&config, method, uri) == Some(StatusCode::NO_CONTENT);
+        assert!(check(Method::GET, "http://connectivitycheck.gstatic.com/generate_204"));
+        assert!(check(Method::HEAD, "http://WWW.GSTATIC.COM./generate_204"));
+        assert!(check(Method::GET, "http://cp.cloudflare.com/"));
+        assert!(!check(Method::POST, "http://www.gstatic.com/generate_204"));
+        assert!(!check(Method::GET, "http://www.gstatic.com:8080/generate_204"));
+        assert!(!check(Method::GET, "http://www.gstatic.com/search"));
+        assert!(!check(Method::GET, "http://example.com/generate_204"));
+        let off = ProxyConfig::default();
+        assert_eq!(respond(&off, Method::GET, "http://cp.cloudflare.com/"), None);
+    }
+
+    #[test]
+    fn registered_hosts_get_their_response() {
+        let blocked = LocalResponse::new(StatusCode::FORBIDDEN)
+            .with_body(HeaderValue::from_static("text/html"), "<h1>Blocked</h1>");
+        let mut config = ProxyConfig::default();
+        config.local_responses.insert(response_key("Ads.Example.com."), blocked.clone());
+        config.local_responses.insert("10.0.0.1".to_string(), blocked);
+        assert_eq!(
+            respond(&config, Method::POST, "http://ads.example.com:8080/x"),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(respond(&config, Method::GET, "http://10.0.0.1/"), Some(StatusCode::FORBIDDEN));
+        assert_eq!(respond(&config, Method::CONNECT, "ads.example.com:443"), None);
+        assert_eq!(respond(&config, Method::GET, "http://example.com/"), None);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use addr::parse_domain_name;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::traffic_diversion::TrafficStreamRule;
+
+/// Issues listed by a `RuleReport`, the others are only counted.
+const MAX_REPORTED_ISSUES: usize = 1000;
+/// Cost of matching one domain regex, against 1 for a hash lookup.
+const REGEX_COST: usize = 8;
+
+/// A kind of domain rule, the domain rules are tried one kind after the
+/// other in the order of the evaluation plan, the first match wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum DomainStage {
+    /// `DOMAIN,*.example.com`
+    Wildcard,
+    Suffix,
+    Keyword,
+    Full,
+    Root,
+    /// Regexes of geosite files
+    Regex,
+}
+
+impl DomainStage {
+    /// Order the domain rules are tried in when not configured.
+    pub const DEFAULT_PLAN: [DomainStage; 6] = [
+        DomainStage::Wildcard,
+        DomainStage::Suffix,
+        DomainStage::Keyword,
+        DomainStage::Full,
+        DomainStage::Root,
+        DomainStage::Regex,
+    ];
+
+    /// Id of the rule with `value`, as counted by `MatchProxy::rule_stats`.
+    pub(crate) fn rule_id(&self, value: &str) -> String {
+        match self {
+            DomainStage::Wildcard => format!("domain-wildcard:*.{}", value),
+            DomainStage::Suffix => format!("domain-suffix:{}", value),
+            DomainStage::Keyword => format!("domain-prefix:{}", value),
+            DomainStage::Full => format!("domain-full:{}", value),
+            DomainStage::Root => format!("domain-root:{}", value),
+            DomainStage::Regex => format!("domain-regex:{}", value),
+        }
+    }
+
+    /// Rough number of comparisons to rule out a domain with `rules` rules.
+    fn cost(&self, rules: usize) -> usize {
+        match self {
+            _ if rules == 0 => 0,
+            // The parents of `a.example.com`
+            DomainStage::Wildcard => 2,
+            DomainStage::Full | DomainStage::Root => 1,
+            DomainStage::Suffix | DomainStage::Keyword => rules,
+            DomainStage::Regex => rules * REGEX_COST,
+        }
+    }
+}
+
+impl fmt::Display for DomainStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DomainStage::Wildcard => "DOMAIN-WILDCARD",
+            DomainStage::Suffix => "DOMAIN-SUFFIX",
+            DomainStage::Keyword => "DOMAIN-KEYWORD",
+            DomainStage::Full => "DOMAIN",
+            DomainStage::Root => "DOMAIN-ROOT",
+            DomainStage::Regex => "DOMAIN-REGEX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DomainStage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "DOMAIN-WILDCARD" => Ok(DomainStage::Wildcard),
+            "DOMAIN-SUFFIX" => Ok(DomainStage::Suffix),
+            "DOMAIN-KEYWORD" => Ok(DomainStage::Keyword),
+            "DOMAIN" => Ok(DomainStage::Full),
+            "DOMAIN-ROOT" => Ok(DomainStage::Root),
+            "DOMAIN-REGEX" => Ok(DomainStage::Regex),
+            _ => Err(anyhow!("unknown domain rule kind: {}", s)),
+        }
+    }
+}
+
+/// Check `plan` names every domain stage exactly once.
+pub(crate) fn validate_plan(plan: &[DomainStage]) -> Result<()> {
+    for stage in DomainStage::DEFAULT_PLAN {
+        if plan.iter().filter(|s| **s == stage).count() != 1 {
+            return Err(anyhow!("evaluation plan must list {} exactly once", stage));
+        }
+    }
+    if plan.len() != DomainStage::DEFAULT_PLAN.len() {
+        return Err(anyhow!("evaluation plan has unknown stages: {:?}", plan));
+    }
+    Ok(())
+}
+
+/// Why a rule is listed by a `RuleReport`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum RuleIssueKind {
+    /// Defined again later in the same rules, the last definition is used
+    Duplicate,
+    /// Every domain it matches is decided before by the other rule
+    Shadowed,
+    /// Overlaps another rule of the same kind with another action, which
+    /// one matches a domain matching both is unspecified
+    Ambiguous,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RuleIssue {
+    pub kind: RuleIssueKind,
+    /// Rule id, prefixed by its layer as in `MatchProxy::rule_stats`
+    pub rule_id: String,
+    /// Rule shadowing or overlapping it
+    pub other: Option<String>,
+}
+
+/// Number of loaded rules per kind, layers included.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuleCounts {
+    pub domain_full: usize,
+    pub domain_suffix: usize,
+    pub domain_keyword: usize,
+    pub domain_root: usize,
+    pub domain_regex: usize,
+    pub domain_wildcard: usize,
+    pub ip_cidr: usize,
+    /// IP CIDRs merged into adjacent or overlapping ones while loading
+    #[serde(default)]
+    pub ip_cidr_merged: usize,
+    pub ip_asn: usize,
+    pub user_agent: usize,
+    pub user: usize,
+    pub client: usize,
+    /// `DST-PORT` ranges
+    #[serde(default)]
+    pub dst_port: usize,
+    pub layers: Vec<String>,
+}
+
+/// Domain rules of one kind.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StageReport {
+    pub stage: DomainStage,
+    pub rules: usize,
+    /// Rough number of comparisons to rule out a domain
+    pub cost: usize,
+}
+
+/// How the rules are evaluated and the rules that can never decide.
+#[derive(Clone, Debug, Serialize)]
+pub struct RuleReport {
+    pub plan: Vec<DomainStage>,
+    pub counts: RuleCounts,
+    /// Domain rules per kind, layers included, in plan order
+    pub stages: Vec<StageReport>,
+    /// Rough number of comparisons of a domain no rule matches
+    pub estimated_cost: usize,
+    /// The first `MAX_REPORTED_ISSUES` issues
+    pub issues: Vec<RuleIssue>,
+    pub issue_count: usize,
+}
+
+/// Domain rules of one layer, or of the rules themselves.
+pub(crate) struct RuleSetView {
+    /// `layer/`, empty for the own rules
+    pub prefix: String,
+    pub stages: Vec<(DomainStage, Vec<(String, TrafficStreamRule)>)>,
+    /// Rule ids defined more than once
+    pub duplicates: Vec<String>,
+}
+
+/// Domain rules tried so far, by how they match.
+#[derive(Default)]
+struct Earlier {
+    full: HashMap<String, String>,
+    wildcard: HashMap<String, String>,
+    root: HashMap<String, String>,
+    /// Suffix and keyword rules, both match domains containing them
+    contains: HashMap<String, String>,
+    regexes: Vec<(Regex, String)>,
+}
+
+impl Earlier {
+    /// Id of an earlier rule matching every domain `value` matches.
+    fn covering(&self, stage: DomainStage, value: &str) -> Option<&String> {
+        match stage {
+            DomainStage::Full => self
+                .full
+                .get(value)
+                .or_else(|| substrings(value).find_map(|s| self.contains.get(s)))
+                .or_else(|| parents(value).find_map(|p| self.wildcard.get(p)))
+                .or_else(|| self.root.get(root(value)?))
+                .or_else(|| {
+                    let mut regexes = self.regexes.iter();
+                    regexes.find(|(regex, _)| regex.is_match(value)).map(|(_, id)| id)
+                }),
+            DomainStage::Suffix | DomainStage::Keyword => {
+                substrings(value).find_map(|s| self.contains.get(s))
+            }
+            DomainStage::Wildcard => {
+                let subdomain = format!(".{}", value);
+                let contained = substrings(&subdomain).find_map(|s| self.contains.get(s));
+                contained
+                    .or_else(|| self.wildcard.get(value))
+                    .or_else(|| parents(value).find_map(|p| self.wildcard.get(p)))
+                    .or_else(|| self.root.get(value))
+                    .or_else(|| parents(value).find_map(|p| self.root.get(p)))
+            }
+            DomainStage::Root => self
+                .root
+                .get(value)
+                .or_else(|| substrings(value).find_map(|s| self.contains.get(s))),
+            DomainStage::Regex => None,
+        }
+    }
+
+    fn add(&mut self, stage: DomainStage, value: &str, rule_id: String) {
+        let map = match stage {
+            DomainStage::Full => &mut self.full,
+            DomainStage::Wildcard => &mut self.wildcard,
+            DomainStage::Root => &mut self.root,
+            DomainStage::Suffix | DomainStage::Keyword => &mut self.contains,
+            DomainStage::Regex => {
+                if let Ok(regex) = Regex::new(value) {
+                    self.regexes.push((regex, rule_id));
+                }
+                return;
+            }
+        };
+        map.entry(value.to_string()).or_insert(rule_id);
+    }
+}
+
+/// Non empty substrings of `value`, longest first.
+fn substrings(value: &str) -> impl Iterator<Item = &str> {
+    let len = value.len();
+    (1..=len).rev().flat_map(move |size| {
+        (0..=len - size)
+            .filter(move |&i| value.is_char_boundary(i) && value.is_char_boundary(i + size))
+            .map(move |i| &value[i..i + size])
+    })
+}
+
+/// `b.example.com`, `example.com` then `com` for `a.b.example.com`.
+fn parents(value: &str) -> impl Iterator<Item = &str> {
+    value.match_indices('.').map(move |(i, _)| &value[i + 1..])
+}
+
+fn root(value: &str) -> Option<&str> {
+    parse_domain_name(value).ok()?.root()
+}
+
+/// Report of rule sets evaluated one after the other, each following `plan`.
+pub(crate) fn report(plan: &[DomainStage], counts: RuleCounts, sets: &[RuleSetView]) -> RuleReport {
+    let mut issues = Vec::new();
+    let mut stage_rules: HashMap<DomainStage, usize> = HashMap::new();
+    let mut estimated_cost = 0;
+    let mut earlier = Earlier::default();
+    for set in sets {
+        for rule_id in &set.duplicates {
+            issues.push(RuleIssue {
+                kind: RuleIssueKind::Duplicate,
+                rule_id: format!("{}{}", set.prefix, rule_id),
+                other: None,
+            });
+        }
+        for (stage, rules) in &set.stages {
+            *stage_rules.entry(*stage).or_default() += rules.len();
+            estimated_cost += stage.cost(rules.len());
+            let rule_id = |value: &str| format!("{}{}", set.prefix, stage.rule_id(value));
+            for (value, _) in rules {
+                if let Some(other) = earlier.covering(*stage, value) {
+                    issues.push(RuleIssue {
+                        kind: RuleIssueKind::Shadowed,
+                        rule_id: rule_id(value),
+                        other: Some(other.clone()),
+                    });
+                }
+            }
+            // Rules of one kind are tried in no particular order
+            if matches!(stage, DomainStage::Suffix | DomainStage::Keyword) {
+                let actions: HashMap<&str, &TrafficStreamRule> =
+                    rules.iter().map(|(value, rule)| (value.as_str(), rule)).collect();
+                for (value, rule) in rules {
+                    let overlapping = substrings(value)
+                        .filter(|s| s != value)
+                        .find(|s| actions.get(s).is_some_and(|other| *other != rule));
+                    if let Some(other) = overlapping {
+                        issues.push(RuleIssue {
+                            kind: RuleIssueKind::Ambiguous,
+                            rule_id: rule_id(value),
+                            other: Some(rule_id(other)),
+                        });
+                    }
+                }
+            }
+            for (value, _) in rules {
+                earlier.add(*stage, value, rule_id(value));
+            }
+        }
+    }
+    let issue_count = issues.len();
+    issues.truncate(MAX_REPORTED_ISSUES);
+    let stages = plan
+        .iter()
+        .map(|stage| {
+            let rules = stage_rules.get(stage).copied().unwrap_or_default();
+            StageReport {
+                stage: *stage,
+                rules,
+                cost: stage.cost(rules),
+            }
+        })
+        .collect();
+    RuleReport {
+        plan: plan.to_vec(),
+        counts,
+        stages,
+        estimated_cost,
+        issues,
+        issue_count,
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+use crate::traffic_diversion::MatchProxy;
+
+/// Rules shared by the listeners. Connections load the current rules without
+/// locking, updates are applied to a copy swapped in once complete, so a
+/// reload never stalls connections and they never see half applied changes.
+pub struct SharedRules {
+    current: ArcSwap<MatchProxy>,
+    /// Serializes updates, so none is lost to a concurrent one
+    updates: Mutex<()>,
+}
+
+impl SharedRules {
+    pub fn new(match_proxy: MatchProxy) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(match_proxy),
+            updates: Mutex::new(()),
+        }
+    }
+
+    /// Rules as of now, later updates don't affect the returned ones.
+    pub fn load(&self) -> Arc<MatchProxy> {
+        self.current.load_full()
+    }
+
+    /// Replace the rules, e.g. after reloading them from their files. The
+    /// allowlist mode of the current rules is kept, it is a setting of the
+    /// listeners rather than of the rule files, see `set_default_deny`.
+    pub fn store(&self, mut match_proxy: MatchProxy) {
+        let _updates = self.updates.lock().unwrap();
+        match_proxy.set_default_deny(self.current.load().is_default_deny());
+        self.current.store(Arc::new(match_proxy));
+    }
+
+    /// Turn the allowlist mode of `MatchProxy::set_default_deny` on or off,
+    /// for the current rules and those stored later.
+    pub fn set_default_deny(&self, default_deny: bool) {
+        let _updates = self.updates.lock().unwrap();
+        let mut match_proxy = MatchProxy::clone(&self.current.load());
+        match_proxy.set_default_deny(default_deny);
+        self.current.store(Arc::new(match_proxy));
+    }
+
+    /// Change the rules with `f`, applied to a copy of the current ones. The
+    /// copy is only swapped in when `f` returns `Ok`.
+    pub fn update<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut MatchProxy) -> Result<T, E>,
+    {
+        let _updates = self.updates.lock().unwrap();
+        let mut match_proxy = MatchProxy::clone(&self.current.load());
+        let res = f(&mut match_proxy)?;
+        self.current.store(Arc::new(match_proxy));
+        Ok(res)
+    }
+}
+
+impl From<MatchProxy> for SharedRules {
+    fn from(match_proxy: MatchProxy) -> Self {
+        Self::new(match_proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traffic_diversion::TrafficStreamRule;
+    use url::Host;
+
+    #[test]
+    fn update_swaps_a_copy() -> anyhow::Result<()> {
+        let rules = SharedRules::new(MatchProxy::from_rule_str("DOMAIN,a.com,direct")?);
+        let before = rules.load();
+        let a = Host::Domain("a.com".to_string());
+        rules.update(|m| m.add_rule_line("DOMAIN,a.com,reject"))?;
+        assert_eq!(before.traffic_stream(&a), TrafficStreamRule::Direct);
+        assert_eq!(rules.load().traffic_stream(&a), TrafficStreamRule::Reject);
+
+        assert!(rules.update(|m| m.add_rule_line("DOMAIN,a.com")).is_err());
+        assert_eq!(rules.load().traffic_stream(&a), TrafficStreamRule::Reject);
+        // hit counters are shared with the copy
+        assert_eq!(rules.load().rule_stats().get("domain-full:a.com"), Some(&3));
+        Ok(())
+    }
+
+    #[test]
+    fn default_deny_survives_stores() -> anyhow::Result<()> {
+        let rules = SharedRules::new(MatchProxy::from_rule_str("DOMAIN,a.com,direct")?);
+        rules.set_default_deny(true);
+        rules.store(MatchProxy::from_rule_str("DOMAIN,b.com,direct")?);
+        assert!(rules.load().is_default_deny());
+        rules.update(|m| m.add_rule_line("DOMAIN,c.com,direct"))?;
+        assert!(rules.load().is_default_deny());
+        let other = Host::Domain("example.com".to_string());
+        assert_eq!(rules.load().traffic_stream(&other), TrafficStreamRule::Reject);
+        rules.set_default_deny(false);
+        assert_eq!(rules.load().traffic_stream(&other), TrafficStreamRule::Proxy);
+        Ok(())
+    }
+}
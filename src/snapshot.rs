@@ -0,0 +1,246 @@
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ProxyConfig;
+use crate::rule_plan::RuleCounts;
+
+/// Log target of the startup reports, see `StartupReport`.
+pub const STARTUP_LOG_TARGET: &str = "kitty_proxy::startup";
+
+/// Serializable view of the settings a listener is running with.
+#[derive(Clone, Debug, Serialize)]
+pub struct ListenerSnapshot {
+    /// `http` or `socks5`
+    pub kind: &'static str,
+    pub ip: String,
+    pub port: u16,
+    pub serving: bool,
+    pub active_connections: usize,
+    pub timeout_ms: Option<u64>,
+    pub stall_timeout_ms: Option<u64>,
+    /// Minimum and maximum of the adaptive connect timeout
+    pub adaptive_timeout_ms: Option<(u64, u64)>,
+    pub max_connections: Option<usize>,
+    /// Whether clients have to authenticate, credentials are never exposed
+    pub auth: bool,
+    pub error_page: bool,
+    pub dry_run: bool,
+    pub quota_limit_bytes: Option<u64>,
+    pub quota_window_secs: Option<u64>,
+    pub outbound_ttl: Option<u32>,
+    pub upstream_chains: usize,
+    pub no_node_policy: String,
+    pub latency_routing: bool,
+    pub hedged_rules: Vec<String>,
+    pub response_cache_entries: Option<usize>,
+    pub sniff: bool,
+    pub validate_nodes: bool,
+    pub selection_file: Option<PathBuf>,
+    pub udp_over_tcp: bool,
+    pub route_override_header: Option<String>,
+    pub socks_route_hints: bool,
+    pub fallback_ports: Option<RangeInclusive<u16>>,
+    pub learned_routes: bool,
+    pub outbound_dscp: Option<u8>,
+    pub dscp_rules: Vec<(String, u8)>,
+    pub dns_round_robin: bool,
+    pub ip_preference: Option<String>,
+    /// Open connections from which accepts are paused, and the pause
+    pub accept_backoff_ms: Option<(usize, u64)>,
+    /// Connections a client may open, and the window they are counted over
+    pub connection_rate_ms: Option<(usize, u64)>,
+    pub allow_lan: bool,
+    pub route_hook: bool,
+    pub chaos: bool,
+    pub local_connectivity_checks: bool,
+    /// Hosts answered with a `LocalResponse`
+    pub local_responses: Vec<String>,
+    pub log_target: Option<String>,
+    pub nodes: Vec<NodeSnapshot>,
+    pub groups: Vec<GroupSnapshot>,
+    /// `None` until `serve()` got its rules
+    pub rules: Option<RuleCounts>,
+}
+
+impl ListenerSnapshot {
+    pub(crate) fn new(kind: &'static str, ip: &str, port: u16, config: &ProxyConfig) -> Self {
+        Self {
+            kind,
+            ip: ip.to_string(),
+            port,
+            serving: false,
+            active_connections: 0,
+            timeout_ms: config.timeout.map(|t| t.as_millis() as u64),
+            stall_timeout_ms: config.stall_timeout.map(|t| t.as_millis() as u64),
+            adaptive_timeout_ms: config
+                .adaptive_timeout
+                .map(|t| (t.min.as_millis() as u64, t.max.as_millis() as u64)),
+            max_connections: config.max_connections,
+            auth: config.credentials.is_some(),
+            error_page: config.error_page,
+            dry_run: config.dry_run,
+            quota_limit_bytes: config.quota.as_ref().map(|q| q.limit_bytes),
+            quota_window_secs: config.quota.as_ref().map(|q| q.window.as_secs()),
+            outbound_ttl: config.outbound.ttl,
+            upstream_chains: config.outbound.chains.len(),
+            no_node_policy: format!("{:?}", config.no_node_policy),
+            latency_routing: config.latency_routing,
+            hedged_rules: config.hedged_rules.clone(),
+            response_cache_entries: config.response_cache.as_ref().map(|c| c.max_entries),
+            sniff: config.sniff.is_some(),
+            validate_nodes: config.validate_nodes,
+            selection_file: config.selection_file.clone(),
+            udp_over_tcp: config.udp_over_tcp,
+            route_override_header: config.route_override_header.as_ref().map(|h| h.to_string()),
+            socks_route_hints: config.socks_route_hints,
+            fallback_ports: config.fallback_ports.clone(),
+            learned_routes: config.learned_routes.is_some(),
+            outbound_dscp: config.outbound.dscp,
+            dscp_rules: config.dscp_rules.clone(),
+            dns_round_robin: config.outbound.dns_round_robin,
+            ip_preference: config.outbound.ip_preference.map(|p| p.to_string()),
+            accept_backoff_ms: config
+                .accept_backoff
+                .map(|b| (b.busy_connections, b.pause.as_millis() as u64)),
+            connection_rate_ms: config
+                .connection_rate
+                .map(|r| (r.max_opens, r.window.as_millis() as u64)),
+            allow_lan: config.allow_lan,
+            route_hook: config.route_hook.is_some(),
+            chaos: config.chaos.is_some(),
+            local_connectivity_checks: config.local_connectivity_checks,
+            local_responses: {
+                let mut hosts: Vec<_> = config.local_responses.keys().cloned().collect();
+                hosts.sort();
+                hosts
+            },
+            log_target: config.log_target.clone(),
+            nodes: Vec::new(),
+            groups: Vec::new(),
+            rules: None,
+        }
+    }
+}
+
+/// What a listener started serving with, logged by `serve()` as one JSON
+/// line under `STARTUP_LOG_TARGET` so bug reports carry the configuration.
+#[derive(Clone, Debug, Serialize)]
+pub struct StartupReport {
+    pub version: &'static str,
+    pub kind: &'static str,
+    /// Bound address, with the port actually used
+    pub addr: String,
+    pub auth: bool,
+    pub udp_over_tcp: bool,
+    pub sniff: bool,
+    pub dry_run: bool,
+    /// Cargo features the crate was built with, e.g. `mux`, `provider-tls`
+    pub build_features: Vec<&'static str>,
+    pub nodes: usize,
+    pub healthy_nodes: usize,
+    pub groups: usize,
+    pub rules: Option<RuleCounts>,
+}
+
+impl StartupReport {
+    pub fn new(snapshot: &ListenerSnapshot) -> Self {
+        let build_features = [
+            ("rt-multi-thread", cfg!(feature = "rt-multi-thread")),
+            ("mux", cfg!(feature = "mux")),
+            ("provider-tls", cfg!(feature = "provider-tls")),
+            ("windows-service", cfg!(feature = "windows-service")),
+            ("ffi", cfg!(feature = "ffi")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            kind: snapshot.kind,
+            addr: format!("{}:{}", snapshot.ip, snapshot.port),
+            auth: snapshot.auth,
+            udp_over_tcp: snapshot.udp_over_tcp,
+            sniff: snapshot.sniff,
+            dry_run: snapshot.dry_run,
+            build_features: build_features
+                .into_iter()
+                .filter_map(|(name, enabled)| enabled.then_some(name))
+                .collect(),
+            nodes: snapshot.nodes.len(),
+            healthy_nodes: snapshot.nodes.iter().filter(|node| node.healthy).count(),
+            groups: snapshot.groups.len(),
+            rules: snapshot.rules.clone(),
+        }
+    }
+
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => info!(target: STARTUP_LOG_TARGET, "{}", json),
+            Err(e) => info!(target: STARTUP_LOG_TARGET, "{:?} ({})", self, e),
+        }
+    }
+}
+
+/// A VPN node as seen by the balancer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub addr: SocketAddr,
+    pub weight: i8,
+    pub connections: usize,
+    pub max_connections: Option<usize>,
+    pub healthy: bool,
+    /// Taken out of rotation by `NodeRegistry::drain_node`
+    #[serde(default)]
+    pub draining: bool,
+    /// Recent connect failures, decaying, see `NodeRegistry::record_failure`
+    pub failure_penalty: f64,
+}
+
+/// A proxy group with the node its new connections would use now.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupSnapshot {
+    pub name: String,
+    /// `select`, `url-test`, `fallback` or `load-balance`
+    pub kind: String,
+    pub nodes: Vec<SocketAddr>,
+    /// `None` when no node of the group is available
+    pub current: Option<SocketAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Credentials;
+
+    #[test]
+    fn startup_report_fingerprint() {
+        let config = ProxyConfig {
+            credentials: Some(Credentials::new("user", "secret")),
+            udp_over_tcp: true,
+            ..Default::default()
+        };
+        let mut snapshot = ListenerSnapshot::new("socks5", "127.0.0.1", 1080, &config);
+        snapshot.nodes = ["10.0.0.1:1080", "10.0.0.2:1080"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| NodeSnapshot {
+                addr: addr.parse().unwrap(),
+                weight: 1,
+                connections: 0,
+                max_connections: None,
+                healthy: i == 0,
+                draining: false,
+                failure_penalty: 0.0,
+            })
+            .collect();
+        let report = StartupReport::new(&snapshot);
+        assert_eq!(report.addr, "127.0.0.1:1080");
+        assert!(report.auth && report.udp_over_tcp && !report.sniff);
+        assert_eq!((report.nodes, report.healthy_nodes), (2, 1));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["kind"], "socks5");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["rules"].is_null());
+    }
+}
@@ -0,0 +1,310 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+use url::Host;
+
+/// Most bytes read from a tunnel while looking for a ClientHello.
+const MAX_SNIFF_SIZE: usize = 16 * 1024;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Settings of TLS ClientHello sniffing in CONNECT and SOCKS tunnels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SniffConfig {
+    /// How long to wait for the client to speak first, protocols where the
+    /// server talks first (SSH, SMTP...) are delayed by this much
+    pub timeout: Duration,
+    /// Close tunnels whose SNI differs from the host name they were opened for
+    pub reject_mismatch: bool,
+}
+
+impl Default for SniffConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            reject_mismatch: false,
+        }
+    }
+}
+
+/// What was learned from a TLS ClientHello.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    /// Protocols offered through ALPN, in the client's order of preference
+    pub alpn: Vec<String>,
+    /// Highest TLS version offered, `supported_versions` included
+    pub version: u16,
+}
+
+impl ClientHello {
+    /// Name of the offered TLS `version`, such as `TLS 1.3`.
+    pub fn version_name(&self) -> String {
+        match self.version {
+            0x0300 => "SSL 3.0".to_string(),
+            0x0301 => "TLS 1.0".to_string(),
+            0x0302 => "TLS 1.1".to_string(),
+            0x0303 => "TLS 1.2".to_string(),
+            0x0304 => "TLS 1.3".to_string(),
+            version => format!("0x{:04x}", version),
+        }
+    }
+}
+
+/// GREASE values (RFC 8701) clients sprinkle in their version and ALPN lists.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+enum Parsed {
+    Hello(ClientHello),
+    NotTls,
+    Incomplete,
+}
+
+/// Read the first bytes the client sends through a tunnel. Returns them, to be
+/// replayed upstream, with the ClientHello when they start a TLS handshake.
+pub async fn read_client_hello<S>(
+    stream: &mut S,
+    wait: Duration,
+) -> io::Result<(Vec<u8>, Option<ClientHello>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + wait;
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    while buf.len() < MAX_SNIFF_SIZE {
+        let n = match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+            Ok(n) => n?,
+            Err(_) => break,
+        };
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        match parse(&buf) {
+            Parsed::Hello(hello) => return Ok((buf, Some(hello))),
+            Parsed::NotTls => break,
+            Parsed::Incomplete => {}
+        }
+    }
+    Ok((buf, None))
+}
+
+/// Whether `server_name` contradicts the host name a tunnel was opened for,
+/// tunnels opened for an IP address never mismatch.
+pub fn is_mismatch(declared: &Host, server_name: &str) -> bool {
+    match declared {
+        Host::Domain(domain) => !domain.eq_ignore_ascii_case(server_name),
+        Host::Ipv4(_) | Host::Ipv6(_) => false,
+    }
+}
+
+fn parse(buf: &[u8]) -> Parsed {
+    // The ClientHello may be split over several handshake records
+    let mut handshake = Vec::new();
+    let mut records = buf;
+    loop {
+        match records.first() {
+            None => return Parsed::Incomplete,
+            Some(&RECORD_HANDSHAKE) => {}
+            Some(_) => return Parsed::NotTls,
+        }
+        if records.len() < 5 {
+            return Parsed::Incomplete;
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records.len() < 5 + len {
+            return Parsed::Incomplete;
+        }
+        handshake.extend_from_slice(&records[5..5 + len]);
+        records = &records[5 + len..];
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return Parsed::NotTls;
+        }
+        let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() >= 4 + len {
+            return match parse_client_hello(&handshake[4..4 + len]) {
+                Some(hello) => Parsed::Hello(hello),
+                None => Parsed::NotTls,
+            };
+        }
+    }
+}
+
+/// Big endian reader over a ClientHello body, `None` once out of bytes.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Bytes prefixed by their length on `width` bytes.
+    fn vec(&mut self, width: usize) -> Option<Reader<'a>> {
+        let len = match width {
+            1 => self.u8()? as usize,
+            _ => self.u16()? as usize,
+        };
+        self.bytes(len).map(Reader)
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut hello = Reader(body);
+    let mut result = ClientHello {
+        version: hello.u16()?,
+        ..Default::default()
+    };
+    hello.bytes(32)?; // random
+    hello.vec(1)?; // session id
+    hello.vec(2)?; // cipher suites
+    hello.vec(1)?; // compression methods
+    // Extensions are optional in TLS 1.0/1.1 hellos
+    let Some(mut extensions) = hello.vec(2) else {
+        return Some(result);
+    };
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vec(2)?;
+        match kind {
+            EXTENSION_SERVER_NAME => {
+                let mut names = data.vec(2)?;
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec(2)?.0;
+                    if name_type == 0 {
+                        result.server_name = std::str::from_utf8(name).ok().map(str::to_string);
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let mut protocols = data.vec(2)?;
+                while !protocols.0.is_empty() {
+                    let protocol = protocols.vec(1)?.0;
+                    let is_grease = protocol.len() == 2
+                        && is_grease(u16::from_be_bytes([protocol[0], protocol[1]]));
+                    if let (Ok(protocol), false) = (std::str::from_utf8(protocol), is_grease) {
+                        result.alpn.push(protocol.to_string());
+                    }
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let mut versions = data.vec(1)?;
+                while !versions.0.is_empty() {
+                    let version = versions.u16()?;
+                    if !is_grease(version) {
+                        result.version = result.version.max(version);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length prefixed `data` on `width` bytes.
+    fn vec(width: usize, data: &[u8]) -> Vec<u8> {
+        let mut vec = (data.len() as u16).to_be_bytes()[2 - width..].to_vec();
+        vec.extend_from_slice(data);
+        vec
+    }
+
+    /// ClientHello record with a server_name extension for `host` and `others`.
+    fn client_hello(host: &str, others: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut sni = vec![0];
+        sni.extend_from_slice(&vec(2, host.as_bytes()));
+        let mut extensions = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        extensions.extend_from_slice(&vec(2, &vec(2, &sni)));
+        for (kind, data) in others {
+            extensions.extend_from_slice(&kind.to_be_bytes());
+            extensions.extend_from_slice(&vec(2, data));
+        }
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&vec(2, &extensions));
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&vec(2, &body));
+        let mut record = vec![RECORD_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&vec(2, &handshake));
+        record
+    }
+
+    #[tokio::test]
+    async fn reads_server_name() -> io::Result<()> {
+        let hello = client_hello("www.example.com", &[]);
+        let (client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            tokio::io::AsyncWriteExt::write_all(&mut client, &hello).await?;
+            io::Result::Ok(client)
+        });
+        let (bytes, sniffed) = read_client_hello(&mut server, Duration::from_secs(5)).await?;
+        let _client = writer.await??;
+        assert_eq!(bytes, client_hello("www.example.com", &[]));
+        let sniffed = sniffed.unwrap();
+        assert_eq!(sniffed.server_name.as_deref(), Some("www.example.com"));
+        assert_eq!(sniffed.version_name(), "TLS 1.2");
+
+        let (bytes, sniffed) =
+            read_client_hello(&mut &b"SSH-2.0-OpenSSH\r\n"[..], Duration::from_secs(5)).await?;
+        assert_eq!((bytes.len(), sniffed), (17, None));
+
+        let declared = Host::Domain("example.com".to_string());
+        assert!(is_mismatch(&declared, "www.example.com"));
+        assert!(!is_mismatch(&Host::Ipv4("1.2.3.4".parse().unwrap()), "example.com"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_alpn_and_version() -> io::Result<()> {
+        let mut protocols = Vec::new();
+        for protocol in ["\x3a\x3a", "h2", "http/1.1"] {
+            protocols.extend_from_slice(&vec(1, protocol.as_bytes()));
+        }
+        let versions = [0x7a, 0x7a, 0x03, 0x04, 0x03, 0x03];
+        let hello = client_hello(
+            "example.com",
+            &[
+                (EXTENSION_ALPN, vec(2, &protocols)),
+                (EXTENSION_SUPPORTED_VERSIONS, vec(1, &versions)),
+            ],
+        );
+        let (_, sniffed) = read_client_hello(&mut &hello[..], Duration::from_secs(5)).await?;
+        let sniffed = sniffed.unwrap();
+        assert_eq!(sniffed.alpn, ["h2", "http/1.1"]);
+        assert_eq!(sniffed.version_name(), "TLS 1.3");
+        Ok(())
+    }
+}
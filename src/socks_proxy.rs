@@ -1,29 +1,63 @@
 use anyhow::{anyhow, Result};
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, trace, warn, Level};
 use tokio::sync::watch::Receiver;
 use url::Host;
 
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tokio::time::timeout;
 
-use crate::banlancer::{ArcConnectionStatsBanlancer, ConnectionStatsBanlancer};
-use crate::traffic_diversion::TrafficStreamRule;
-use crate::types::{KittyProxyError, NodeInfo, ResponseCode};
-use crate::MatchProxy;
+use crate::banlancer::{self, NodeRegistry};
+use crate::decision_log::DecisionLog;
+use crate::fault::ChaosConfig;
+use crate::dns;
+use crate::groups::ProxyGroup;
+use crate::learned::LearnedRoute;
+use crate::http_proxy::dry_run_node;
+use crate::sniff::{is_mismatch, read_client_hello};
+use crate::snapshot::{ListenerSnapshot, StartupReport};
+use crate::listener::{
+    accept, bind, client_keepalive, current_connection_id, drain, exposure_error, report,
+    restore_selections, route_hook_decision, runtime_error_channel, select_group_node,
+    set_connection_verbosity, spawn_connection, test_group_delays, validate_nodes, AcceptPacing,
+    ConnectionId, RuntimeErrorSender, ServeHandle, Shutdown, Stopped,
+};
+use crate::config::{
+    ActiveConnections, ArcProxyConfig, Credentials, ProxyConfig, ProxyConfigUpdate, ServeState,
+};
+use crate::traffic_diversion::{RuleDecision, TrafficStreamRule};
+use crate::traits::{BoxedStream, HandshakeFuture, RouteRequest, UpstreamHandshake};
+use crate::outbound::{self, read_socks5_reply, NodeConnector, UpstreamHop};
+use crate::quota::{ProtocolCounts, ProtocolKey, ProxyUsage, QuotaState};
+use crate::relay::{
+    cancellable, log_tunnel_closed, relay, track_tunnel, TunnelBytes, TunnelCloseReason,
+    TunnelSnapshot,
+};
+use crate::types::{
+    host_port_to_socketaddr, socks_domain, Address, KittyProxyError, NodeInfo, ProxyRuntimeError,
+    ResponseCode, TargetAddr,
+};
+use crate::rules::SharedRules;
+use crate::udp_over_tcp::{read_frame, write_frame, Frame, UdpSockets};
 
 /// Version of socks
 const SOCKS_VERSION: u8 = 0x05;
 
 const RESERVED: u8 = 0x00;
 
+/// Version of the username/password sub-negotiation
+const USER_PASS_VERSION: u8 = 0x01;
+
+/// Targets of a UDP association whose route is remembered, the routes are
+/// decided again once there are more.
+const MAX_UDP_ROUTES: usize = 1024;
+
 pub struct SocksReply {
     // From rfc 1928 (S6),
     // the server evaluates the request, and returns a reply formed as follows:
@@ -61,11 +95,16 @@ pub struct SocksReply {
 
 impl SocksReply {
     pub fn new(status: ResponseCode) -> Self {
+        let rep = match status {
+            // SOCKS has no code for it, refused as by a rule
+            ResponseCode::TooManyConnections => ResponseCode::RuleFailure as u8,
+            status => status as u8,
+        };
         let buf = [
             // VER
             SOCKS_VERSION,
             // REP
-            status as u8,
+            rep,
             // RSV
             RESERVED,
             // ATYP
@@ -125,10 +164,13 @@ impl AddrType {
 
 /// SOCK5 CMD Type
 #[derive(Debug)]
-enum SockCommand {
+pub enum SockCommand {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssosiate = 0x3,
+    /// Not in RFC 1928: UDP ASSOCIATE with the datagrams framed on the control
+    /// connection, see `udp_over_tcp`
+    UdpOverTcp = 0x05,
 }
 
 impl SockCommand {
@@ -138,94 +180,386 @@ impl SockCommand {
             1 => Some(SockCommand::Connect),
             2 => Some(SockCommand::Bind),
             3 => Some(SockCommand::UdpAssosiate),
+            5 => Some(SockCommand::UdpOverTcp),
             _ => None,
         }
     }
 }
 
+/// Default upstream framing: replay the client's SOCKS5 bytes to the node and
+/// swallow its 2-byte method selection reply.
+pub struct SocksUpstreamHandshake;
+
+impl UpstreamHandshake for SocksUpstreamHandshake {
+    fn handshake<'a>(
+        &'a self,
+        stream: &'a mut BoxedStream,
+        _host: &'a Host,
+        _port: u16,
+        request: &'a [u8],
+    ) -> HandshakeFuture<'a> {
+        Box::pin(async move {
+            stream.write_all(request).await?;
+            let mut _header = [0u8; 2];
+            stream.read_exact(&mut _header).await?;
+            Ok(())
+        })
+    }
+}
+
 pub struct SocksProxy {
-    // Timeout for connections
     ip: String,
     port: u16,
-    timeout: Option<Duration>,
-    balancer: ArcConnectionStatsBanlancer,
-    is_serve: bool,
+    // Timeout for connections, limits and auth settings
+    config: ArcProxyConfig,
+    connections: ActiveConnections,
+    usage: ProxyUsage,
+    balancer: Arc<NodeRegistry>,
+    upstream_handshake: Arc<dyn UpstreamHandshake>,
+    node_connector: NodeConnector,
+    serve_state: ServeState,
+    runtime_errors: Option<RuntimeErrorSender>,
+    match_proxy: Option<Arc<SharedRules>>,
+    /// Address accepted on, the port may be a fallback one
+    local_addr: Option<SocketAddr>,
 }
 
 impl SocksProxy {
-    /// Create a new Merino instance
+    /// Create a new Merino instance. Fails with `ProxyRuntimeError::LanNotAllowed`
+    /// as source for a non-loopback `ip`, use `with_config` to set credentials
+    /// or `allow_lan`.
     pub async fn new(ip: &str, port: u16, timeout: Option<Duration>) -> io::Result<Self> {
+        let config = ProxyConfig {
+            timeout,
+            ..Default::default()
+        };
+        Self::with_config(ip, port, config).await
+    }
+
+    /// Listener starting with `config`, which may expose it to the network
+    /// with `credentials` or `allow_lan`.
+    pub async fn with_config(ip: &str, port: u16, mut config: ProxyConfig) -> io::Result<Self> {
+        exposure_error(ip, &config)?;
         info!("Socks5 proxy listening on {}:{}", ip, port);
+        config
+            .log_target
+            .get_or_insert_with(|| format!("kitty_proxy::socks[{}]", port));
         Ok(Self {
             ip: ip.to_string(),
             port,
-            timeout,
-            balancer: Arc::new(Mutex::new(None)),
-            is_serve: false,
+            config: Arc::new(RwLock::new(config)),
+            connections: ActiveConnections::default(),
+            usage: ProxyUsage::default(),
+            balancer: Arc::default(),
+            upstream_handshake: Arc::new(SocksUpstreamHandshake),
+            node_connector: NodeConnector::default(),
+            serve_state: ServeState::default(),
+            runtime_errors: None,
+            match_proxy: None,
+            local_addr: None,
         })
     }
 
+    /// Multiplex tunnels over `sessions_per_node` yamux sessions per VPN node.
+    #[cfg(feature = "mux")]
+    pub fn enable_mux(&mut self, sessions_per_node: usize) {
+        self.node_connector = NodeConnector::with_mux(sessions_per_node);
+    }
+
+    /// Apply `update` to connections accepted from now on, the listener keeps running.
+    pub async fn update_config(&self, update: ProxyConfigUpdate) {
+        let mut config = self.config.write().await;
+        config.apply(update);
+        info!("Socks5 proxy {}:{} config updated: {:?}", self.ip, self.port, config);
+    }
+
+    pub async fn config(&self) -> ProxyConfig {
+        self.config.read().await.clone()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.connections.count()
+    }
+
+    /// Close the connection `id`, as logged and listed in `tunnels()`: its
+    /// tasks are cancelled and its tunnels closed as `cancelled`. `false`
+    /// when the listener has no such connection open.
+    pub fn kill_connection(&self, id: ConnectionId) -> bool {
+        self.connections.kill(id)
+    }
+
+    /// Open tunnels with their throughput of the last seconds.
+    pub fn tunnels(&self) -> Vec<TunnelSnapshot> {
+        self.connections.tunnels()
+    }
+
+    /// Bandwidth used by every client seen within the quota window.
+    pub async fn client_usage(&self) -> HashMap<IpAddr, QuotaState> {
+        let config = self.config.read().await;
+        self.usage.clients.snapshot(config.quota.as_ref()).await
+    }
+
+    /// Bandwidth used by every authenticated user over the last day.
+    pub async fn user_usage(&self) -> HashMap<String, QuotaState> {
+        self.usage.users.snapshot(None).await
+    }
+
+    /// ALPN and TLS version counts of sniffed tunnels, per destination network
+    /// and node.
+    pub async fn protocol_usage(&self) -> HashMap<ProtocolKey, ProtocolCounts> {
+        self.usage.protocols.snapshot().await
+    }
+
+    /// Domains routed against their rule after failing, see
+    /// `ProxyConfig::learned_routes`.
+    pub fn learned_routes(&self) -> Vec<LearnedRoute> {
+        self.balancer.learned().routes()
+    }
+
+    /// Route `domain` by the rules again, `false` when it had no learned route.
+    pub fn forget_learned_route(&self, domain: &str) -> bool {
+        self.balancer.learned().forget(domain)
+    }
+
+    /// Replace the framing used to open connections on the VPN node.
+    pub fn set_upstream_handshake(&mut self, handshake: Arc<dyn UpstreamHandshake>) {
+        self.upstream_handshake = handshake;
+    }
+
+    /// Take a node out of (or back into) rotation, e.g. from a health check.
+    pub async fn set_node_healthy(&self, socket_addr: SocketAddr, healthy: bool) {
+        self.balancer.load().set_node_healthy(socket_addr, healthy);
+    }
+
+    /// Stop sending new connections to `socket_addr` and wait for the open
+    /// ones to end, at most until `deadline`, e.g. before rotating the node
+    /// server. Returns the connections still open, `None` for unknown nodes.
+    pub async fn drain_node(&self, socket_addr: SocketAddr, deadline: Instant) -> Option<usize> {
+        let remaining = self.balancer.drain_node(socket_addr, deadline).await;
+        if let Some(remaining) = remaining {
+            info!("VPN node {} drained, {} connections left", socket_addr, remaining);
+        }
+        remaining
+    }
+
+    /// Send new connections to a node taken out by `drain_node` again.
+    pub fn resume_node(&self, socket_addr: SocketAddr) -> bool {
+        self.balancer.resume_node(socket_addr)
+    }
+
+    /// Replace the proxy groups rules can name as action, `select` groups
+    /// keep their chosen node while it stays in the group. Choices saved to
+    /// the `selection_file` are restored.
+    pub async fn set_proxy_groups(&self, groups: Vec<ProxyGroup>) {
+        self.balancer.groups().set_groups(groups);
+        let config = self.config.read().await.clone();
+        restore_selections(&config, self.balancer.groups()).await;
+    }
+
+    /// Choose the node used from now on by the `select` group `group`, saved
+    /// to the `selection_file` when configured.
+    pub async fn select_group_node(&self, group: &str, node: SocketAddr) -> Result<()> {
+        let config = self.config.read().await.clone();
+        select_group_node(&config, self.balancer.groups(), group, node).await
+    }
+
+    /// Measure the connect delays of the nodes of `url-test` groups, each
+    /// group then uses its fastest available node.
+    pub async fn test_group_delays(&self) {
+        let config = self.config.read().await.clone();
+        test_group_delays(&config, self.balancer.groups()).await;
+    }
+
+    /// Everything this listener is running with, for UIs and support dumps.
+    pub async fn config_snapshot(&self) -> ListenerSnapshot {
+        let config = self.config.read().await;
+        let port = self.local_addr.map_or(self.port, |addr| addr.port());
+        let mut snapshot = ListenerSnapshot::new("socks5", &self.ip, port, &config);
+        snapshot.serving = self.is_serving();
+        snapshot.active_connections = self.connections.count();
+        snapshot.nodes = self.balancer.load().node_snapshots();
+        snapshot.groups = self.balancer.groups().snapshots(&self.balancer.load());
+        if let Some(match_proxy) = &self.match_proxy {
+            snapshot.rules = Some(match_proxy.load().rule_counts());
+        }
+        snapshot
+    }
+
+    /// Number of VPN nodes not marked down.
+    pub async fn healthy_node_count(&self) -> usize {
+        self.balancer.load().healthy_count()
+    }
+
+    /// Swap the VPN nodes of a running proxy, open connection counts are kept
+    /// for nodes with an unchanged address.
+    pub async fn replace_nodes(&self, vpn_node_infos: Vec<NodeInfo>) {
+        if let (true, Some(errors)) = (vpn_node_infos.is_empty(), &self.runtime_errors) {
+            report(errors, ProxyRuntimeError::NodePoolEmpty);
+        }
+        self.balancer.replace_nodes(&vpn_node_infos);
+    }
+
+    /// Start accepting connections. Runtime errors (bind or accept failures, empty
+    /// node pool...) are sent to `errors` of the returned handle, which is closed
+    /// right away when the proxy is already serving.
+    ///
+    /// Sending `true` on `rx` stops the proxy: the listener closes, the tasks
+    /// of the open connections are cancelled and `is_serving()` turns false
+    /// once they all ended, when `stopped` of the returned handle resolves.
+    /// `false` is ignored, dropping the sender stops the proxy too, reported
+    /// as `ListenerClosed`.
     pub async fn serve(
         &mut self,
-        match_proxy: Arc<RwLock<MatchProxy>>,
+        match_proxy: Arc<SharedRules>,
         rx: &mut Receiver<bool>,
         vpn_node_infos: Vec<NodeInfo>,
-    ) {
-        let listener = TcpListener::bind((self.ip.clone(), self.port))
-            .await
-            .unwrap();
-        self.is_serve = true;
-        let timeout = self.timeout.clone();
+    ) -> ServeHandle {
+        let (errors, errors_rx) = runtime_error_channel();
+        if self.serve_state.is_serving() {
+            warn!("Socks5 proxy {}:{} already serving, only replacing nodes", self.ip, self.port);
+            self.replace_nodes(vpn_node_infos).await;
+            return ServeHandle::not_started(errors_rx);
+        }
+        let config = self.config.read().await.clone();
+        let listener = match bind(&self.ip, self.port, &config).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                report(&errors, e);
+                return ServeHandle::not_started(errors_rx);
+            }
+        };
+        self.local_addr = listener.local_addr().ok();
+        validate_nodes(&config, &vpn_node_infos, &errors).await;
+        let serving = self.serve_state.start();
+        self.match_proxy = Some(Arc::clone(&match_proxy));
+        self.runtime_errors = Some(errors.clone());
+        let config_share = Arc::clone(&self.config);
+        let connections = self.connections.clone();
+        let usage = self.usage.clone();
         let match_proxy_clone = Arc::clone(&match_proxy);
-        let mut rx_clone = rx.clone();
-        let mut balancer = self.balancer.lock().await;
-        *balancer = Some(ConnectionStatsBanlancer::from_vec(&vpn_node_infos));
-        drop(balancer);
+        let mut shutdown = Shutdown::new(rx);
+        self.replace_nodes(vpn_node_infos).await;
+        StartupReport::new(&self.config_snapshot().await).log();
         let balancer = Arc::clone(&self.balancer);
+        let upstream_handshake = Arc::clone(&self.upstream_handshake);
+        let node_connector = self.node_connector.clone();
 
-        tokio::spawn(async move {
+        let accept_task = tokio::spawn(async move {
+            let _serving = serving;
+            let cancel = CancellationToken::new();
             tokio::select! {
                 _ = async {
+                    let mut pacing = AcceptPacing::default();
                     loop {
-                        let (stream, client_addr) = listener.accept().await.unwrap();
+                        let (stream, client_addr) = accept(&listener, &errors).await;
+                        let config = config_share.read().await.clone();
+                        pacing.accepted(&config, connections.count()).await;
+                        let guard = match connections.acquire(config.max_connections) {
+                            Some(guard) => guard,
+                            None => {
+                                listener_log!(
+                                    config,
+                                    Level::Warn,
+                                    "Socks5 connection limit reached, dropping {}",
+                                    client_addr
+                                );
+                                continue;
+                            }
+                        };
+                        client_keepalive(&config, &stream, client_addr);
                         let match_proxy_clone = match_proxy_clone.clone();
                         let statistics_map_clone = balancer.clone();
-                        let mut client = SOCKClient::new(stream, timeout);
-            match client
-                .handle_client(match_proxy_clone, statistics_map_clone)
-                .await
-            {
-                Ok(_) => {}
-                Err(error) => {
-                    debug!("Error {:?}, client: {:?}", error, client_addr);
-                    if let Err(e) = SocksReply::new(error.into()).send(&mut client.stream).await
-                    {
-                        warn!("Failed to send error code: {:?}", e);
-                    }
+                        let upstream_handshake = upstream_handshake.clone();
+                        let mut client = SOCKClient::new(stream, config.timeout)
+                            .with_config(config)
+                            .with_usage(usage.clone())
+                            .with_node_connector(node_connector.clone())
+                            .with_client_addr(client_addr);
+                        let token = cancel.child_token();
+            spawn_connection(ConnectionId::new(), token.clone(), guard, async move {
+                let config = client.config.clone();
+                listener_log!(config, Level::Debug, "Socks5 connection from {}", client_addr);
+                let handled = client.handle_client(
+                    match_proxy_clone,
+                    statistics_map_clone,
+                    upstream_handshake,
+                    token,
+                );
+                match handled.await
+                {
+                    Ok(_) => {}
+                    Err(error) => {
+                        listener_log!(
+                            config,
+                            Level::Debug,
+                            "Error {:?}, client: {:?}",
+                            error,
+                            client_addr
+                        );
+                        // Once answered the stream belongs to the tunnel
+                        if !client.answered {
+                            let reply = SocksReply::new(error.into());
+                            if let Err(e) = reply.send(&mut client.stream).await {
+                                listener_log!(
+                                    config,
+                                    Level::Warn,
+                                    "Failed to send error code: {:?}",
+                                    e
+                                )
+                            }
+                        }
 
-                    if let Err(e) = client.shutdown().await {
-                        warn!("Failed to shutdown TcpStream: {:?}", e);
-                    };
-                }
-            };
+                        if let Err(e) = client.shutdown().await {
+                            listener_log!(config, Level::Warn, "Failed to shutdown: {:?}", e);
+                        };
+                    }
+                };
+            });
                     }
                 } => {}
                 _ =  async {
-                        if rx_clone.changed().await.is_ok() {
+                        if shutdown.requested().await {
                             return//该任务退出，别的也会停
                     }
+                    report(&errors, ProxyRuntimeError::ListenerClosed);
                 } => {}
             }
+            cancel.cancel();
+            drop(listener);
+            drain(&connections).await;
         });
+        ServeHandle {
+            errors: errors_rx,
+            stopped: Stopped::new(accept_task),
+        }
     }
+
+    /// Address accepted on since `serve()` bound it, with the port actually
+    /// used: a fallback port when the configured one was taken, the port
+    /// picked by the OS for port 0. `None` before the first bind.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
     pub fn is_serving(&self) -> bool {
-        self.is_serve
+        self.serve_state.is_serving()
+    }
+
+    /// When the accept loop started, `None` when it isn't running.
+    pub fn serving_since(&self) -> Option<SystemTime> {
+        self.serve_state.serving_since()
     }
 }
 
 pub struct SOCKClient<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> {
     stream: T,
     timeout: Option<Duration>,
+    config: ProxyConfig,
+    client_addr: Option<SocketAddr>,
+    usage: ProxyUsage,
+    node_connector: NodeConnector,
+    /// The client got its reply, errors can't be reported to it anymore
+    answered: bool,
 }
 
 impl<T> SOCKClient<T>
@@ -234,7 +568,43 @@ where
 {
     /// Create a new SOCKClient
     pub fn new(stream: T, timeout: Option<Duration>) -> Self {
-        SOCKClient { stream, timeout }
+        SOCKClient {
+            stream,
+            timeout,
+            config: ProxyConfig {
+                timeout,
+                ..Default::default()
+            },
+            client_addr: None,
+            usage: ProxyUsage::default(),
+            node_connector: NodeConnector::default(),
+            answered: false,
+        }
+    }
+
+    /// Listener settings (auth, quota, socket options...) for this client
+    pub fn with_config(mut self, config: ProxyConfig) -> Self {
+        self.timeout = config.timeout;
+        self.config = config;
+        self
+    }
+
+    /// How connections to VPN nodes are opened
+    pub fn with_node_connector(mut self, node_connector: NodeConnector) -> Self {
+        self.node_connector = node_connector;
+        self
+    }
+
+    /// Where transferred bytes are recorded for quotas and per user statistics
+    pub fn with_usage(mut self, usage: ProxyUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Address of the client, used by client based rules
+    pub fn with_client_addr(mut self, client_addr: SocketAddr) -> Self {
+        self.client_addr = Some(client_addr);
+        self
     }
 
     /// Shutdown a client
@@ -243,13 +613,71 @@ where
         Ok(())
     }
 
-    /// Handles a client
+    /// Tell the client its request succeeded
+    async fn reply_success(&mut self) -> io::Result<()> {
+        SocksReply::new(ResponseCode::Success).send(&mut self.stream).await?;
+        self.answered = true;
+        Ok(())
+    }
+
+    /// Decision forced by the route hint of the username, honoured for
+    /// clients on the loopback interface and authenticated ones.
+    fn route_override(&self, req: &SOCKSReq) -> Option<RuleDecision> {
+        let route = req.route.as_deref()?;
+        let is_local = self.client_addr.is_some_and(|addr| addr.ip().to_canonical().is_loopback());
+        if req.username.is_none() && !is_local {
+            listener_log!(self.config, Level::Warn, "Ignoring route hint {}", route);
+            return None;
+        }
+        match RuleDecision::overridden(route) {
+            Ok(decision) => Some(decision),
+            Err(e) => {
+                listener_log!(self.config, Level::Warn, "Ignoring route hint {}: {}", route, e);
+                None
+            }
+        }
+    }
+
+    /// Handles a client, its tunnel is closed once `cancel` fires.
     pub async fn handle_client(
         &mut self,
-        match_proxy_share: Arc<RwLock<MatchProxy>>,
-        arc_banlancer: ArcConnectionStatsBanlancer,
+        match_proxy_share: Arc<SharedRules>,
+        arc_banlancer: Arc<NodeRegistry>,
+        upstream_handshake: Arc<dyn UpstreamHandshake>,
+        cancel: CancellationToken,
     ) -> Result<usize, KittyProxyError> {
-        let req = SOCKSReq::from_stream(&mut self.stream).await?;
+        let credentials = self.config.credentials.as_ref();
+        let mut req =
+            SOCKSReq::from_stream(&mut self.stream, credentials, self.config.socks_route_hints)
+                .await?;
+        if let (Some(rate), Some(client_addr)) = (&self.config.connection_rate, self.client_addr) {
+            if let Err(retry_after) = self.usage.opens.open(client_addr.ip(), rate) {
+                listener_log!(
+                    self.config,
+                    Level::Warn,
+                    "Socks5 client {} opens connections too fast, refused for {:?}",
+                    client_addr,
+                    retry_after
+                );
+                return Err(KittyProxyError::Proxy(ResponseCode::TooManyConnections));
+            }
+        }
+        if let (Some(quota), Some(client_addr)) = (&self.config.quota, self.client_addr) {
+            if self
+                .usage
+                .clients
+                .is_exceeded(&client_addr.ip(), quota)
+                .await
+            {
+                listener_log!(
+                    self.config,
+                    Level::Warn,
+                    "Socks5 client {} is over its bandwidth quota",
+                    client_addr
+                );
+                return Err(KittyProxyError::Proxy(quota.reject_code));
+            }
+        }
 
         // Respond
         match req.command {
@@ -260,96 +688,601 @@ where
                 } else {
                     Duration::from_millis(1000)
                 };
-                let match_proxy = match_proxy_share.read().await;
-                let rule = match_proxy.traffic_stream(&req.host);
+                // With sniffing the client is answered before connecting, so the
+                // rules can see the host name of its TLS ClientHello
+                let mut sniffed = None;
+                let mut tls_hello = None;
+                let mut rule_host = req.target.host.clone();
+                if let Some(sniff) = self.config.sniff.clone() {
+                    self.reply_success().await?;
+                    let (first_bytes, hello) =
+                        read_client_hello(&mut self.stream, sniff.timeout).await?;
+                    let server_name = hello.as_ref().and_then(|hello| hello.server_name.clone());
+                    if let Some(server_name) = server_name {
+                        if sniff.reject_mismatch && is_mismatch(&req.target.host, &server_name) {
+                            listener_log!(
+                                self.config,
+                                Level::Warn,
+                                "Socks5 [TCP] {} SNI {} mismatch, closing",
+                                req.target,
+                                server_name
+                            );
+                            self.shutdown().await?;
+                            return Ok(0);
+                        }
+                        rule_host = Host::Domain(server_name);
+                    }
+                    sniffed = Some(first_bytes);
+                    tls_hello = hello;
+                }
+                let match_proxy = match_proxy_share.load();
+                let username = req.username.as_deref();
+                let route_override = self.route_override(&req);
+                let decision = match route_override.filter(|_| !match_proxy.is_default_deny()) {
+                    Some(decision) => decision,
+                    None => {
+                        match_proxy
+                            .decide_resolving(
+                                None,
+                                self.client_addr.as_ref(),
+                                username,
+                                &rule_host,
+                                Some(req.target.port),
+                            )
+                            .await
+                    }
+                };
                 drop(match_proxy);
-                info!("Socks5 [TCP] {}:{} {} connect", req.host, req.port, rule);
+                let learned = self.config.learned_routes.as_ref();
+                let decision = arc_banlancer.learned().apply(learned, &rule_host, decision);
+                let request = RouteRequest {
+                    connection: current_connection_id(),
+                    listener: "socks5",
+                    source: self.client_addr,
+                    target: req.target.to_address(),
+                    rule_host: rule_host.to_string(),
+                    username: username.map(str::to_string),
+                    decision,
+                };
+                let decision = route_hook_decision(&self.config, request).await;
+                set_connection_verbosity(decision.verbosity);
+                if let Some(port) = decision.redirect_port {
+                    // DST.PORT ends the request replayed to VPN nodes
+                    let len = req.readed_buffer.len();
+                    req.readed_buffer[len - 2..].copy_from_slice(&port.to_be_bytes());
+                    req.target.port = port;
+                }
+                let rule = decision.rule.clone();
+                listener_log!(
+                    self.config,
+                    Level::Info,
+                    "Socks5 [TCP] {}:{} {} connect, user {}",
+                    rule_host,
+                    req.target.port,
+                    rule,
+                    username.unwrap_or("-")
+                );
+                let mut decision_log = DecisionLog {
+                    connection: current_connection_id(),
+                    network: "TCP",
+                    source: self.client_addr,
+                    target: req.target.to_string(),
+                    decision: &decision,
+                    node: None,
+                };
                 let is_direct = match rule {
+                    _ if self.config.dry_run => {
+                        let node_info = dry_run_node(&rule, &arc_banlancer);
+                        listener_log!(
+                            self.config,
+                            Level::Info,
+                            "Socks5 [TCP] {} dry run: {} via {:?}, connecting direct",
+                            req.target,
+                            rule,
+                            node_info
+                        );
+                        true
+                    }
                     TrafficStreamRule::Reject => {
+                        decision_log.log();
+                        // X'02' connection not allowed by ruleset, unless already answered
+                        if sniffed.is_none() {
+                            SocksReply::new(ResponseCode::RuleFailure)
+                                .send(&mut self.stream)
+                                .await?;
+                        }
                         self.shutdown().await?;
                         return Ok(0 as usize);
                     }
                     TrafficStreamRule::Direct => true,
                     TrafficStreamRule::Proxy => false,
                 };
-                let node_info = if !is_direct {
-                    let banlancer = arc_banlancer.lock().await;
-                    let banlancer_ref = banlancer.as_ref().unwrap();
-                    Some(banlancer_ref.get_least_connected_node().await)
+                let destination = self.config.latency_routing.then(|| {
+                    banlancer::destination_key(&req.target.to_address())
+                });
+                // Counted on their node from now on, until the connection ends or fails
+                let (node_info, counted) = if !is_direct {
+                    let group = decision.group.as_deref();
+                    arc_banlancer
+                        .select_node(self.config.no_node_policy, destination.as_deref(), group)
+                        .await?
+                        .unzip()
                 } else {
-                    None
+                    (None, None)
                 };
-                let target_server = if is_direct {
-                    format!("{}:{}", req.host, req.port)
-                } else {
-                    node_info.unwrap().socket_addr.to_string()
+                // Connections of a proxy group stick to the node it picked
+                let hedged = decision.group.is_none() && self.config.is_hedged(&decision.rule_id);
+                let (runner_up, runner_up_counted) = match node_info {
+                    Some(node_info) if hedged => {
+                        let first = node_info.socket_addr;
+                        let destination = destination.as_deref();
+                        arc_banlancer.select_runner_up(first, destination).unzip()
+                    }
+                    _ => (None, None),
                 };
-                debug!("req.target_server: {}", target_server);
-                let mut target_stream =
-                    timeout(
-                        time_out,
-                        async move { TcpStream::connect(target_server).await },
-                    )
-                    .await
-                    .map_err(|_| {
-                        error!("Socks5 error {}:{} connect timeout", req.host, req.port);
-                        KittyProxyError::Proxy(ResponseCode::ConnectionRefused)
-                    })??;
-                if !is_direct {
-                    let mut banlancer = arc_banlancer.lock().await;
-                    let banlancer_ref = banlancer.as_mut().unwrap();
-                    banlancer_ref.incre_count_by_node_info(&node_info.unwrap());
+                // A hedged connection is logged once the node winning the race is known
+                if !self.config.dry_run && runner_up.is_none() {
+                    decision_log.node = node_info.as_ref().map(|node_info| node_info.socket_addr);
+                    decision_log.log();
                 }
-                if !is_direct {
-                    target_stream.write_all(&req.readed_buffer).await?;
-                    let mut _header = [0u8; 2];
-                    target_stream.read_exact(&mut _header).await?;
-                } else {
-                    SocksReply::new(ResponseCode::Success)
-                        .send(&mut self.stream)
-                        .await?;
+                let connect_timeout_error = || {
+                    listener_log!(
+                        self.config,
+                        Level::Error,
+                        "Socks5 error {} connect timeout",
+                        req.target,
+                    );
+                    KittyProxyError::Proxy(ResponseCode::ConnectionRefused)
+                };
+                let adaptive = self.config.adaptive_timeout.as_ref();
+                let outbound = self.config.outbound_for(&decision.rule_id);
+                let outbound = arc_banlancer.relay_outbound(
+                    outbound,
+                    decision.group.as_deref(),
+                    node_info,
+                    UpstreamHop::Socks5,
+                );
+                let connect_started = Instant::now();
+                if self.config.chaos.as_ref().is_some_and(ChaosConfig::drops_connect) {
+                    listener_log!(
+                        self.config,
+                        Level::Debug,
+                        "Socks5 [TCP] {} connect dropped by chaos",
+                        req.target
+                    );
+                    return Err(KittyProxyError::Proxy(ResponseCode::ConnectionRefused));
                 }
-
-                let return_value =
-                    match tokio::io::copy_bidirectional(&mut self.stream, &mut target_stream).await
-                    {
-                        // ignore not connected for shutdown error
-                        Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
-                            error!("Socks5 error {}:{} {}", req.host, req.port, e);
-                            Ok(0)
+                let (node_info, target_stream) = match node_info {
+                    // NoNodePolicy::Direct
+                    None => {
+                        let target_server = req.target.to_address();
+                        listener_log!(
+                            self.config,
+                            Level::Debug,
+                            "req.target_server: {}",
+                            target_server
+                        );
+                        let connect = outbound::connect_direct(&target_server, &outbound);
+                        let res = self
+                            .node_connector
+                            .within_timeout(&target_server, Some(time_out), adaptive, connect)
+                            .await;
+                        let connected = matches!(res, Some(Ok(_)));
+                        let learned_routes = arc_banlancer.learned();
+                        learned_routes.record(learned, &rule_host, &decision, true, connected);
+                        let stream = res.ok_or_else(connect_timeout_error)??;
+                        if sniffed.is_none() {
+                            self.reply_success().await?;
+                        }
+                        (None, Box::new(stream) as BoxedStream)
+                    }
+                    Some(primary) => {
+                        let dial = |node_info: NodeInfo| {
+                            let upstream_handshake = &upstream_handshake;
+                            let (config, node_connector) = (&self.config, &self.node_connector);
+                            let outbound = &outbound;
+                            let (req, connect_timeout_error) = (&req, &connect_timeout_error);
+                            let answered = sniffed.is_some();
+                            async move {
+                                let target_server = Address::from(node_info);
+                                listener_log!(
+                                    config,
+                                    Level::Debug,
+                                    "req.target_server: {}",
+                                    target_server
+                                );
+                                let connect = node_connector.connect(&target_server, outbound);
+                                let within = node_connector.within_timeout(
+                                    &target_server,
+                                    Some(time_out),
+                                    adaptive,
+                                    connect,
+                                );
+                                let mut target_stream = within
+                                    .await
+                                    .ok_or_else(connect_timeout_error)??;
+                                upstream_handshake
+                                    .handshake(
+                                        &mut target_stream,
+                                        &req.target.host,
+                                        req.target.port,
+                                        &req.readed_buffer,
+                                    )
+                                    .await?;
+                                if answered {
+                                    // The client already got a reply, drop the node's
+                                    read_socks5_reply(&mut target_stream, &target_server).await?;
+                                }
+                                Ok::<_, KittyProxyError>(target_stream)
+                            }
+                        };
+                        let res = outbound::hedged(primary, runner_up, dial).await;
+                        // Failing to reach a node tells nothing about the target
+                        if res.is_ok() {
+                            let learned_routes = arc_banlancer.learned();
+                            learned_routes.record(learned, &rule_host, &decision, false, true);
                         }
-                        Err(e) => {
-                            error!("Socks5 error {}:{} {}", req.host, req.port, e);
-                            Err(KittyProxyError::Io(e))
+                        if res.is_err() {
+                            for node_info in std::iter::once(primary).chain(runner_up) {
+                                arc_banlancer.record_failure(node_info.socket_addr);
+                            }
                         }
-                        Ok((_s_to_t, t_to_s)) => Ok(t_to_s as usize),
-                    };
-                if !is_direct {
-                    let mut banlancer = arc_banlancer.lock().await;
-                    let banlancer_ref = banlancer.as_mut().unwrap();
-                    banlancer_ref.decre_count_by_node_info(&node_info.unwrap());
+                        let node_info = res.as_ref().map_or(primary, |(node_info, _)| *node_info);
+                        if runner_up.is_some() {
+                            decision_log.node = Some(node_info.socket_addr);
+                            decision_log.log();
+                        }
+                        let (_, target_stream) = res?;
+                        if let Some(destination) = &destination {
+                            arc_banlancer.record_latency(
+                                node_info.socket_addr,
+                                destination,
+                                connect_started.elapsed(),
+                            );
+                        }
+                        (Some(node_info), target_stream)
+                    }
+                };
+                let mut target_stream = match &self.config.chaos {
+                    Some(chaos) => chaos.degrade(target_stream),
+                    None => target_stream,
+                };
+                if let Some(first_bytes) = &sniffed {
+                    target_stream.write_all(first_bytes).await?;
+                }
+                if let Some(hello) = &tls_hello {
+                    let destination = host_port_to_socketaddr(&rule_host, req.target.port);
+                    let node = node_info.map(|node_info| node_info.socket_addr);
+                    self.usage
+                        .protocols
+                        .record(banlancer::destination_key(&destination), node, hello)
+                        .await;
                 }
+                // The node losing a hedged race gets its slot back
+                let _counted = match node_info {
+                    Some(winner) if Some(winner) == runner_up => runner_up_counted,
+                    Some(_) => counted,
+                    None => None,
+                };
+
+                // Without a reply of ours, the node's reaches the client through the tunnel
+                self.answered = true;
+                let stall_timeout = self.config.stall_timeout;
+                let target = req.target.to_address();
+                let tracked = track_tunnel(&target);
+                let throughput = Some(tracked.throughput());
+                let tunnel = relay(&mut self.stream, &mut target_stream, stall_timeout, throughput);
+                let tunnel = cancellable(&cancel, tunnel);
+                let res = match self.client_addr {
+                    Some(client_addr) => {
+                        let throughput = tracked.throughput();
+                        let ip = client_addr.ip();
+                        self.usage.metered(ip, username, throughput, tunnel).await
+                    }
+                    None => tunnel.await,
+                };
+                let return_value = match (log_tunnel_closed(&target, &res), res) {
+                    // ignore not connected for shutdown error
+                    (_, Err(e)) if e.kind() == std::io::ErrorKind::NotConnected => {
+                        listener_log!(
+                            self.config,
+                            Level::Error,
+                            "Socks5 error {} {}",
+                            req.target,
+                            e
+                        );
+                        Ok(0)
+                    }
+                    (TunnelCloseReason::Stalled, Err(e)) => {
+                        listener_log!(
+                            self.config,
+                            Level::Error,
+                            "Socks5 error {} {}",
+                            req.target,
+                            e
+                        );
+                        Err(KittyProxyError::UpstreamStalled(
+                            self.config.stall_timeout.unwrap_or_default(),
+                        ))
+                    }
+                    // Already logged, the client is only disconnected
+                    (TunnelCloseReason::Cancelled, Err(_)) => Ok(0),
+                    (_, Err(e)) => {
+                        listener_log!(
+                            self.config,
+                            Level::Error,
+                            "Socks5 error {} {}",
+                            req.target,
+                            e
+                        );
+                        Err(KittyProxyError::Io(e))
+                    }
+                    (_, Ok(bytes)) => Ok(bytes.downloaded as usize),
+                };
                 return_value
             }
             SockCommand::Bind => Err(KittyProxyError::Io(std::io::Error::new(
                 std::io::ErrorKind::Unsupported,
                 "Bind not supported",
             ))),
+            SockCommand::UdpOverTcp if self.config.udp_over_tcp => {
+                let route_override = self.route_override(&req);
+                let username = req.username.as_deref();
+                self.udp_over_tcp(
+                    match_proxy_share,
+                    arc_banlancer,
+                    route_override,
+                    username,
+                    &cancel,
+                )
+                .await
+            }
+            // Lets the client fall back to another transport
+            SockCommand::UdpOverTcp => {
+                Err(KittyProxyError::Proxy(ResponseCode::CommandNotSupported))
+            }
             SockCommand::UdpAssosiate => Err(KittyProxyError::Io(std::io::Error::new(
                 std::io::ErrorKind::Unsupported,
                 "UdpAssosiate not supported",
             ))),
         }
     }
+
+    /// Relay the datagrams framed on the control connection until the client
+    /// closes it or `cancel` fires. Each target is routed like a TCP connect,
+    /// route hook included, but only direct routes are relayed: UDP isn't sent
+    /// through the VPN nodes.
+    async fn udp_over_tcp(
+        &mut self,
+        match_proxy_share: Arc<SharedRules>,
+        arc_banlancer: Arc<NodeRegistry>,
+        route_override: Option<RuleDecision>,
+        username: Option<&str>,
+        cancel: &CancellationToken,
+    ) -> Result<usize, KittyProxyError> {
+        let sockets = UdpSockets::bind().await?;
+        self.reply_success().await?;
+        listener_log!(
+            self.config,
+            Level::Info,
+            "Socks5 [UDP] association over TCP, user {}",
+            username.unwrap_or("-")
+        );
+        let (config, client_addr) = (&self.config, self.client_addr);
+        let (mut reader, mut writer) = tokio::io::split(&mut self.stream);
+        let (mut sent, mut received) = (0u64, 0u64);
+        let outbound = async {
+            let mut routes: HashMap<String, Option<SocketAddr>> = HashMap::new();
+            while let Some(mut frame) = read_frame(&mut reader).await? {
+                if frame.frag != 0 {
+                    continue;
+                }
+                let target = frame.target.to_string();
+                let route = match routes.get(&target) {
+                    Some(route) => *route,
+                    None => {
+                        let rules = match_proxy_share.load();
+                        let host = &frame.target.host;
+                        let port = Some(frame.target.port);
+                        let source = client_addr.as_ref();
+                        let decision = match route_override.clone() {
+                            Some(decision) if !rules.is_default_deny() => decision,
+                            _ => rules.decide_resolving(None, source, username, host, port).await,
+                        };
+                        drop(rules);
+                        let learned = config.learned_routes.as_ref();
+                        let decision = arc_banlancer.learned().apply(learned, host, decision);
+                        let request = RouteRequest {
+                            connection: current_connection_id(),
+                            listener: "socks5",
+                            source: client_addr,
+                            target: frame.target.to_address(),
+                            rule_host: host.to_string(),
+                            username: username.map(str::to_string),
+                            decision,
+                        };
+                        let decision = route_hook_decision(config, request).await;
+                        if let Some(port) = decision.redirect_port {
+                            frame.target.port = port;
+                        }
+                        let route = udp_route(config, &frame, &target, &decision).await;
+                        if routes.len() >= MAX_UDP_ROUTES {
+                            routes.clear();
+                        }
+                        routes.insert(target, route);
+                        route
+                    }
+                };
+                let Some(route) = route else {
+                    continue;
+                };
+                match sockets.send_to(&frame.data, route).await {
+                    Ok(len) => sent += len as u64,
+                    Err(e) => {
+                        listener_log!(config, Level::Debug, "Socks5 [UDP] {} {}", route, e);
+                    }
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+        let inbound = async {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            loop {
+                let (len, from) = sockets.recv_from(&mut buf).await?;
+                write_frame(&mut writer, from, &buf[..len]).await?;
+                received += len as u64;
+            }
+        };
+        let res: io::Result<()> = tokio::select! {
+            res = outbound => res,
+            res = inbound => res,
+            _ = cancel.cancelled() => Ok(()),
+        };
+        if let Some(client_addr) = self.client_addr {
+            let bytes = TunnelBytes {
+                uploaded: sent,
+                downloaded: received,
+                closed_first: None,
+            };
+            self.usage.record(client_addr.ip(), username, &bytes).await;
+        }
+        res?;
+        Ok(received as usize)
+    }
 }
 
+/// Where datagrams to `frame`'s target are sent, `None` to drop them.
+async fn udp_route(
+    config: &ProxyConfig,
+    frame: &Frame,
+    target: &str,
+    decision: &RuleDecision,
+) -> Option<SocketAddr> {
+    let decision_log = DecisionLog {
+        connection: current_connection_id(),
+        network: "UDP",
+        source: None,
+        target: target.to_string(),
+        decision,
+        node: None,
+    };
+    match decision.rule {
+        _ if config.dry_run => {}
+        TrafficStreamRule::Direct => {}
+        TrafficStreamRule::Reject => {
+            decision_log.log();
+            return None;
+        }
+        TrafficStreamRule::Proxy => {
+            listener_log!(
+                config,
+                Level::Warn,
+                "Socks5 [UDP] {} is proxied, UDP is only relayed direct, dropping",
+                target
+            );
+            return None;
+        }
+    }
+    decision_log.log();
+    let mut addrs = match frame.target.to_address() {
+        Address::SocketAddress(addr) => vec![addr],
+        Address::DomainNameAddress(domain, port) => match dns::resolve(&domain, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                listener_log!(config, Level::Warn, "Socks5 [UDP] {} {}", target, e);
+                return None;
+            }
+        },
+    };
+    if let Some(ip_preference) = config.outbound.ip_preference {
+        ip_preference.apply(&mut addrs);
+        if addrs.is_empty() {
+            listener_log!(
+                config,
+                Level::Warn,
+                "Socks5 [UDP] {} has no address allowed by {}, dropping",
+                target,
+                ip_preference
+            );
+        }
+    }
+    addrs.first().copied()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AuthMethod {
     /// No Authentication
     NoAuth = 0x00,
+    /// Username/Password, RFC 1929
+    UserPass = 0x02,
     /// Cannot authenticate
     NoMethod = 0xFF,
 }
 
+impl AuthMethod {
+    /// Method picked by the listener among the `offered` ones: username and
+    /// password when it has credentials, no authentication otherwise. Which
+    /// methods the client offers, or in which order, doesn't matter. With
+    /// `route_hints` the username is asked for whenever offered.
+    fn negotiate(offered: &[u8], credentials: Option<&Credentials>, route_hints: bool) -> Self {
+        let offers_user_pass = offered.contains(&(AuthMethod::UserPass as u8));
+        let required = match credentials {
+            Some(_) => AuthMethod::UserPass,
+            None if route_hints && offers_user_pass => AuthMethod::UserPass,
+            None => AuthMethod::NoAuth,
+        };
+        if offered.contains(&(required as u8)) {
+            required
+        } else {
+            AuthMethod::NoMethod
+        }
+    }
+}
+
+/// Methods offered by a client greeting, RFC 1928 section 3:
+///
+///    +----+----------+----------+
+///    |VER | NMETHODS | METHODS  |
+///    +----+----------+----------+
+///    | 1  |    1     | 1 to 255 |
+///    +----+----------+----------+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MethodSelection {
+    /// Method codes in the order offered, unknown ones included
+    methods: Vec<u8>,
+}
+
+impl MethodSelection {
+    /// Read the greeting, failing on another version than SOCKS5 or a
+    /// connection closed before NMETHODS methods. No method offered at all
+    /// reads fine, no method can be selected then.
+    async fn read<T>(stream: &mut T) -> Result<Self, KittyProxyError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let version = stream.read_u8().await?;
+        if version != SOCKS_VERSION {
+            debug!("Init: Unsupported version: SOCKS{}", version);
+            stream.shutdown().await?;
+            return Err(anyhow!("Not support version: {}.", version).into());
+        }
+        let count = stream.read_u8().await?;
+        let mut methods = vec![0u8; count as usize];
+        stream.read_exact(&mut methods).await?;
+        Ok(Self { methods })
+    }
+
+    /// Method of the listener among the offered ones, see `AuthMethod::negotiate`.
+    fn select(&self, credentials: Option<&Credentials>, route_hints: bool) -> AuthMethod {
+        AuthMethod::negotiate(&self.methods, credentials, route_hints)
+    }
+
+    /// Reply selecting `method`, `X'FF'` telling the client to close.
+    fn reply(method: AuthMethod) -> [u8; 2] {
+        [SOCKS_VERSION, method as u8]
+    }
+}
+
 async fn addr_to_host(addr_type: &AddrType, addr: &[u8]) -> io::Result<Host> {
     match addr_type {
         AddrType::V6 => {
@@ -374,26 +1307,83 @@ async fn addr_to_host(addr_type: &AddrType, addr: &[u8]) -> io::Result<Host> {
         AddrType::V4 => Ok(Host::Ipv4(Ipv4Addr::new(
             addr[0], addr[1], addr[2], addr[3],
         ))),
-        AddrType::Domain => {
-            let domain = std::str::from_utf8(addr).expect("parse domain failed from u8!");
-            Ok(Host::Domain(domain.to_string()))
-        }
+        AddrType::Domain => Ok(Host::Domain(socks_domain(addr)?)),
     }
 }
 
+/// `alice+route:hk` as (`alice`, `hk`), `route:direct` as (``, `direct`).
+fn split_route_hint(username: &str) -> (&str, Option<&str>) {
+    if let Some(route) = username.strip_prefix("route:") {
+        return ("", Some(route));
+    }
+    match username.rsplit_once("+route:") {
+        Some((user, route)) => (user, Some(route)),
+        None => (username, None),
+    }
+}
+
+/// Username/password sub-negotiation, RFC 1929. Any username and password
+/// pass without `credentials`, only asked for the route hint. Returns the
+/// authenticated user and the route hint.
+async fn authenticate<T>(
+    stream: &mut T,
+    credentials: Option<&Credentials>,
+    route_hints: bool,
+) -> Result<(Option<String>, Option<String>), KittyProxyError>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    //    +----+------+----------+------+----------+
+    //    |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    //    +----+------+----------+------+----------+
+    //    | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    //    +----+------+----------+------+----------+
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut username = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut plen = [0u8; 1];
+    stream.read_exact(&mut plen).await?;
+    let mut password = vec![0u8; plen[0] as usize];
+    stream.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let (user, route) = match route_hints {
+        true => split_route_hint(&username),
+        false => (username.as_ref(), None),
+    };
+    let is_valid = header[0] == USER_PASS_VERSION
+        && credentials.is_none_or(|credentials| credentials.check(user, &password));
+    let status = if is_valid { 0x00 } else { 0x01 };
+    stream.write_all(&[USER_PASS_VERSION, status]).await?;
+    if !is_valid {
+        stream.shutdown().await?;
+        return Err(anyhow!("Socks auth failed.").into());
+    }
+    let user = credentials.map(|_| user.to_string());
+    Ok((user, route.map(str::to_string)))
+}
+
 /// Proxy User Request
 #[allow(dead_code)]
-struct SOCKSReq {
+pub struct SOCKSReq {
     pub version: u8,
     pub command: SockCommand,
-    pub host: Host,
-    pub port: u16,
+    pub target: TargetAddr,
     pub readed_buffer: Vec<u8>,
+    /// Username the client authenticated with
+    pub username: Option<String>,
+    /// Route hint of the username, see `ProxyConfig::socks_route_hints`
+    pub route: Option<String>,
 }
 
 impl SOCKSReq {
     /// Parse a SOCKS Req from a TcpStream
-    async fn from_stream<T>(stream: &mut T) -> Result<Self, KittyProxyError>
+    async fn from_stream<T>(
+        stream: &mut T,
+        credentials: Option<&Credentials>,
+        route_hints: bool,
+    ) -> Result<Self, KittyProxyError>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
@@ -420,38 +1410,20 @@ impl SOCKSReq {
         //      o  DST.ADDR       desired destination address
         //      o  DST.PORT desired destination port in network octet
         //         order
-        let mut readed_buffer: Vec<u8> = Vec::new();
-        let mut header = [0u8; 2];
-        // Read a byte from the stream and determine the version being requested
-        stream.read_exact(&mut header).await?;
-        readed_buffer.extend_from_slice(&header);
-
-        let socks_version = header[0];
-        let auth_method = header[1] as usize;
-
-        debug!("socks version: {}", socks_version);
-
-        if socks_version != SOCKS_VERSION {
-            debug!("Init: Unsupported version: SOCKS{}", socks_version);
-            stream.shutdown().await?;
-            return Err(anyhow!(format!("Not support version: {}.", socks_version)).into());
-        }
-        let mut method = vec![0u8; auth_method];
-        stream.read_exact(&mut method).await?;
-        readed_buffer.extend_from_slice(&method);
-
-        let no_auth = AuthMethod::NoAuth as u8;
-        let mut auth_response = [0u8, 2];
-        auth_response[0] = SOCKS_VERSION;
-        if method.contains(&no_auth) {
-            auth_response[1] = no_auth;
-            stream.write_all(&auth_response).await?;
-        } else {
-            auth_response[1] = AuthMethod::NoMethod as u8;
-            stream.write_all(&auth_response).await?;
-            stream.shutdown().await?;
-            return Err(anyhow!("Socks auth failed.").into());
-        }
+        let selection = MethodSelection::read(stream).await?;
+        let auth_method = selection.select(credentials, route_hints);
+        stream.write_all(&MethodSelection::reply(auth_method)).await?;
+        let (username, route) = match auth_method {
+            AuthMethod::UserPass => authenticate(stream, credentials, route_hints).await?,
+            AuthMethod::NoAuth => (None, None),
+            AuthMethod::NoMethod => {
+                stream.shutdown().await?;
+                return Err(anyhow!("Socks auth failed.").into());
+            }
+        };
+        // The VPN node only gets to see a no auth greeting, whatever the
+        // client offered, so it can't pick a method the client then skips
+        let mut readed_buffer = vec![SOCKS_VERSION, 1, AuthMethod::NoAuth as u8];
 
         let mut packet = [0u8; 4];
         // Read a byte from the stream and determine the version being requested
@@ -473,7 +1445,7 @@ impl SOCKSReq {
             }
         }?;
 
-        let addr_type = match AddrType::from(packet[3] as usize) {
+        let mut addr_type = match AddrType::from(packet[3] as usize) {
             Some(addr) => Ok(addr),
             None => {
                 error!("No Addr");
@@ -502,8 +1474,20 @@ impl SOCKSReq {
             AddrType::V6 => {
                 let mut addr = [0u8; 16];
                 stream.read_exact(&mut addr).await?;
-                readed_buffer.extend_from_slice(&addr);
-                addr.to_vec()
+                match Ipv6Addr::from(addr).to_ipv4_mapped() {
+                    // Matched by rules and sent on to the node as IPv4
+                    Some(ip) => {
+                        let atyp = readed_buffer.len() - 1;
+                        readed_buffer[atyp] = AddrType::V4 as u8;
+                        readed_buffer.extend_from_slice(&ip.octets());
+                        addr_type = AddrType::V4;
+                        ip.octets().to_vec()
+                    }
+                    None => {
+                        readed_buffer.extend_from_slice(&addr);
+                        addr.to_vec()
+                    }
+                }
             }
         };
         // read DST.port
@@ -517,9 +1501,302 @@ impl SOCKSReq {
         Ok(SOCKSReq {
             version: packet[0],
             command,
-            host,
-            port,
+            target: TargetAddr::new(host, port),
             readed_buffer,
+            username,
+            route,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::traits::{RouteDecision, RouteFuture, RouteHook, RouteRequest};
+
+    /// Listener routing 127.0.0.0/8 direct, serving until the runtime of the
+    /// test shuts down.
+    async fn serve_socks(config: ProxyConfig) -> Result<SocketAddr> {
+        let mut proxy = SocksProxy::with_config("127.0.0.1", 0, config).await?;
+        let match_proxy = crate::MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (kill_tx, mut kill_rx) = watch::channel(false);
+        let _ = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+        let addr = proxy.local_addr().unwrap();
+        // Dropping the sender would stop the proxy
+        tokio::spawn(async move {
+            let _serving = (proxy, kill_tx);
+            std::future::pending::<()>().await
+        });
+        Ok(addr)
+    }
+
+    /// Offer no authentication, as the listeners of the tests expect.
+    async fn greet(client: &mut TcpStream) -> Result<()> {
+        client.write_all(&[SOCKS_VERSION, 1, AuthMethod::NoAuth as u8]).await?;
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await?;
+        assert_eq!(method, [SOCKS_VERSION, AuthMethod::NoAuth as u8]);
+        Ok(())
+    }
+
+    /// Vetoes the connections to a port.
+    struct RejectPort(u16);
+
+    impl RouteHook for RejectPort {
+        fn on_route(&self, request: RouteRequest) -> RouteFuture<'_> {
+            Box::pin(async move {
+                match request.target {
+                    Address::SocketAddress(addr) if addr.port() == self.0 => {
+                        RouteDecision::Replace(RuleDecision::overridden("reject").unwrap())
+                    }
+                    _ => RouteDecision::Keep,
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn auth_method_follows_the_credentials() {
+        let credentials = Credentials::new("user", "secret");
+        let both = [AuthMethod::NoAuth as u8, AuthMethod::UserPass as u8];
+        let negotiate = |offered, credentials| AuthMethod::negotiate(offered, credentials, false);
+        assert_eq!(negotiate(&both, Some(&credentials)), AuthMethod::UserPass);
+        assert_eq!(negotiate(&both, None), AuthMethod::NoAuth);
+        let no_auth = [AuthMethod::NoAuth as u8];
+        assert_eq!(negotiate(&no_auth, Some(&credentials)), AuthMethod::NoMethod);
+        let user_pass = [AuthMethod::UserPass as u8];
+        assert_eq!(negotiate(&user_pass, None), AuthMethod::NoMethod);
+        // the username carries the route hint
+        assert_eq!(AuthMethod::negotiate(&both, None, true), AuthMethod::UserPass);
+        assert_eq!(AuthMethod::negotiate(&no_auth, None, true), AuthMethod::NoAuth);
+    }
+
+    #[tokio::test]
+    async fn method_selection_reads_offers() {
+        async fn read(greeting: &[u8]) -> Result<MethodSelection, KittyProxyError> {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            client.write_all(greeting).await.unwrap();
+            drop(client);
+            MethodSelection::read(&mut server).await
+        }
+        let credentials = Credentials::new("user", "secret");
+        // GSSAPI, a private method and username/password, in any order
+        let offers = read(&[SOCKS_VERSION, 3, 0x01, 0x80, 0x02]).await.unwrap();
+        assert_eq!(offers.methods, [0x01, 0x80, 0x02]);
+        assert_eq!(offers.select(Some(&credentials), false), AuthMethod::UserPass);
+        assert_eq!(offers.select(None, false), AuthMethod::NoMethod);
+        assert_eq!(MethodSelection::reply(AuthMethod::NoMethod), [SOCKS_VERSION, 0xFF]);
+
+        let none = read(&[SOCKS_VERSION, 0]).await.unwrap();
+        assert!(none.methods.is_empty());
+        assert_eq!(none.select(None, false), AuthMethod::NoMethod);
+        // NMETHODS announcing more methods than sent, or nothing at all
+        assert!(read(&[SOCKS_VERSION, 3, 0x00]).await.is_err());
+        assert!(read(&[SOCKS_VERSION]).await.is_err());
+        assert!(read(&[4, 1, 0x00]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn each_user_authenticates_with_its_password() {
+        async fn auth(credentials: &Credentials, user: &str, password: &str) -> Option<String> {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            let mut request = vec![USER_PASS_VERSION, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            client.write_all(&request).await.unwrap();
+            let (user, _) = authenticate(&mut server, Some(credentials), false).await.ok()?;
+            user
+        }
+        let credentials = Credentials::new("alice", "a-secret").with_user("bob", "b-secret");
+        assert_eq!(auth(&credentials, "alice", "a-secret").await.as_deref(), Some("alice"));
+        assert_eq!(auth(&credentials, "bob", "b-secret").await.as_deref(), Some("bob"));
+        assert_eq!(auth(&credentials, "bob", "a-secret").await, None);
+        assert_eq!(auth(&credentials, "carol", "").await, None);
+    }
+
+    #[test]
+    fn route_hints_in_usernames() {
+        assert_eq!(split_route_hint("route:direct"), ("", Some("direct")));
+        assert_eq!(split_route_hint("alice+route:hk"), ("alice", Some("hk")));
+        assert_eq!(split_route_hint("alice"), ("alice", None));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn tunnels_on_a_current_thread_runtime() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        let origin = TcpListener::bind("127.0.0.1:0").await?;
+        let origin_port = origin.local_addr()?.port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+        let mut proxy = SocksProxy::new("127.0.0.1", 0, None).await?;
+        let match_proxy = crate::MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (_kill_tx, mut kill_rx) = watch::channel(false);
+        let _ = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+
+        let mut client = TcpStream::connect(proxy.local_addr().unwrap()).await?;
+        greet(&mut client).await?;
+        let mut request = vec![SOCKS_VERSION, 1, RESERVED, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&origin_port.to_be_bytes());
+        client.write_all(&request).await?;
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply[1], ResponseCode::Success as u8);
+        client.write_all(b"ping").await?;
+        let mut echo = [0; 4];
+        client.read_exact(&mut echo).await?;
+        assert_eq!(&echo, b"ping");
+        let tunnels = proxy.tunnels();
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].target, format!("127.0.0.1:{}", origin_port));
+        assert_eq!(tunnels[0].uploaded, 4);
+        drop(client);
+        while !proxy.tunnels().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_tunnels_close_without_a_reply() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        let origin = TcpListener::bind("127.0.0.1:0").await?;
+        let origin_port = origin.local_addr()?.port();
+        tokio::spawn(async move {
+            // Reads the request but never answers it
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+        });
+        let config = ProxyConfig {
+            stall_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let mut client = TcpStream::connect(serve_socks(config).await?).await?;
+        greet(&mut client).await?;
+        let mut request = vec![SOCKS_VERSION, 1, RESERVED, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&origin_port.to_be_bytes());
+        client.write_all(&request).await?;
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply[1], ResponseCode::Success as u8);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty(), "got {:?} after the tunnel stalled", rest);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_over_tcp_is_asked_for_by_the_client() -> Result<()> {
+        use tokio::net::UdpSocket;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], from).await.unwrap();
+        });
+        let config = ProxyConfig {
+            udp_over_tcp: true,
+            ..Default::default()
+        };
+        let proxy_addr = serve_socks(config).await?;
+        let associate = |command: u8| async move {
+            let mut client = TcpStream::connect(proxy_addr).await?;
+            greet(&mut client).await?;
+            client.write_all(&[SOCKS_VERSION, command, RESERVED, 1, 0, 0, 0, 0, 0, 0]).await?;
+            let mut reply = [0; 10];
+            client.read_exact(&mut reply).await?;
+            anyhow::Ok((client, reply[1]))
+        };
+        // Plain UDP ASSOCIATE clients aren't sent frames they can't read
+        let (_, status) = associate(SockCommand::UdpAssosiate as u8).await?;
+        assert_ne!(status, ResponseCode::Success as u8);
+
+        let (mut client, status) = associate(SockCommand::UdpOverTcp as u8).await?;
+        assert_eq!(status, ResponseCode::Success as u8);
+        write_frame(&mut client, echo_addr, b"ping").await?;
+        let frame = read_frame(&mut client).await?.unwrap();
+        assert_eq!(frame.target.to_string(), echo_addr.to_string());
+        assert_eq!(&frame.data[..], b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_hook_vetoes_udp_over_tcp_targets() -> Result<()> {
+        use crate::config::SharedRouteHook;
+        use tokio::net::UdpSocket;
+
+        let vetoed = UdpSocket::bind("127.0.0.1:0").await?;
+        let echo = UdpSocket::bind("127.0.0.1:0").await?;
+        let (vetoed_addr, echo_addr) = (vetoed.local_addr()?, echo.local_addr()?);
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], from).await.unwrap();
+        });
+        let config = ProxyConfig {
+            udp_over_tcp: true,
+            route_hook: Some(SharedRouteHook(Arc::new(RejectPort(vetoed_addr.port())))),
+            ..Default::default()
+        };
+        let mut client = TcpStream::connect(serve_socks(config).await?).await?;
+        greet(&mut client).await?;
+        let command = SockCommand::UdpOverTcp as u8;
+        client.write_all(&[SOCKS_VERSION, command, RESERVED, 1, 0, 0, 0, 0, 0, 0]).await?;
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply[1], ResponseCode::Success as u8);
+        write_frame(&mut client, vetoed_addr, b"vetoed").await?;
+        write_frame(&mut client, echo_addr, b"ping").await?;
+        let frame = read_frame(&mut client).await?.unwrap();
+        assert_eq!(frame.target.to_string(), echo_addr.to_string());
+        let err = vetoed.try_recv_from(&mut [0; 64]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_hook_vetoes_connections() -> Result<()> {
+        use crate::config::SharedRouteHook;
+
+        let config = ProxyConfig {
+            route_hook: Some(SharedRouteHook(Arc::new(RejectPort(9)))),
+            ..Default::default()
+        };
+        let mut client = TcpStream::connect(serve_socks(config).await?).await?;
+        greet(&mut client).await?;
+        client.write_all(&[SOCKS_VERSION, 1, RESERVED, 1, 127, 0, 0, 1, 0, 9]).await?;
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply[1], ResponseCode::RuleFailure as u8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn true_stops_and_drains_the_listener() -> Result<()> {
+        use crate::listener::assert_stops_and_drains;
+
+        let mut proxy = SocksProxy::new("127.0.0.1", 0, None).await?;
+        let match_proxy = crate::MatchProxy::from_rule_str("IP-CIDR,127.0.0.0/8,direct")?;
+        let (kill_tx, mut kill_rx) = watch::channel(false);
+        let handle = proxy
+            .serve(Arc::new(match_proxy.into()), &mut kill_rx, Vec::new())
+            .await;
+        let addr = proxy.local_addr().unwrap();
+        assert_stops_and_drains(addr, &kill_tx, handle, || proxy.is_serving()).await
+    }
+}
@@ -1,21 +1,63 @@
+use crate::asn::lookup_asn;
+use crate::ports::{parse_port_ranges, PortRanges};
+use crate::rule_plan::{self, DomainStage, RuleCounts, RuleReport, RuleSetView};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::providers::RuleProvider;
 use crate::v2ray_config::domain::Type;
-use crate::v2ray_config::{Cidr, GeoIpList, GeoSiteList};
+use crate::v2ray_config::{Cidr, GeoCache, GeoIp, GeoIpList, GeoSite, GeoSiteList};
 
 use addr::parse_domain_name;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr};
 use cidr_utils::combiner::{Ipv4CidrCombiner, Ipv6CidrCombiner};
+use log::{debug, warn};
 use prost::Message;
 use regex::Regex;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use url::Host;
 
+mod loaders;
+
+/// Whether `cidrs`, sorted and disjoint as merged by the combiners, contain
+/// `ip`. A binary search, the combiners' own `contains` tries every CIDR.
+fn cidrs_contain<C: cidr::Cidr>(cidrs: &[C], ip: &C::Address) -> bool
+where
+    C::Address: Ord,
+{
+    let next = cidrs.partition_point(|cidr| cidr.first_address() <= *ip);
+    next > 0 && cidrs[next - 1].contains(ip)
+}
+
+/// `13335` or `AS13335`.
+fn parse_asn(value: &str) -> Result<u32> {
+    let number = value.strip_prefix("AS").unwrap_or(value);
+    number.parse().map_err(|_| anyhow!("invalid ASN: {}", value))
+}
+
+/// How deep `INCLUDE`s may nest, deeper ones are taken for a cycle.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Where a rule file was read from, which decides what it may `INCLUDE`.
+#[derive(Clone, Copy, Debug)]
+enum RuleOrigin<'a> {
+    /// Read locally, relative paths are relative to the directory, to the
+    /// working directory without one
+    Local(Option<&'a Path>),
+    /// Downloaded, only URLs may be included so a remote file never reads
+    /// local ones
+    Remote,
+}
+
 impl fmt::Display for Cidr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -38,7 +80,8 @@ enum SiteIp {
     UnknownSite(String),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TrafficStreamRule {
     Direct,
     Proxy,
@@ -57,6 +100,110 @@ impl fmt::Display for TrafficStreamRule {
     }
 }
 
+/// How much a rule wants its connections logged, from a `silent` or
+/// `verbose` rule option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleVerbosity {
+    /// Warnings and errors only, e.g. for chatty telemetry endpoints
+    Silent,
+    /// Everything, debug messages included, whatever the listener's
+    /// `log_level`
+    Verbose,
+}
+
+/// Outcome of the rules for one connection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleDecision {
+    pub rule: TrafficStreamRule,
+    /// Id of the matching rule, as counted by `MatchProxy::rule_stats`
+    pub rule_id: String,
+    /// Port to connect to instead of the requested one, from a
+    /// `redirect-port=` rule option
+    pub redirect_port: Option<u16>,
+    /// Proxy group named as action by the rule, its connections only use
+    /// the nodes of the group
+    pub group: Option<String>,
+    /// From a `silent` or `verbose` rule option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verbosity: Option<RuleVerbosity>,
+}
+
+impl RuleDecision {
+    /// Rule type as named by Clash (`DomainSuffix`, `IPCIDR`, `Match`...).
+    pub fn clash_rule_type(&self) -> &'static str {
+        let (kind, _) = self.split_rule_id();
+        match kind {
+            "domain-suffix" | "domain-root" | "domain-wildcard" => "DomainSuffix",
+            "domain-prefix" => "DomainKeyword",
+            "domain-full" => "Domain",
+            "domain-regex" => "DomainRegex",
+            "ip-cidr" => "IPCIDR",
+            "ip-asn" => "IPASN",
+            "user-agent" => "UserAgent",
+            "client-port" => "SrcPort",
+            "dst-port" => "DstPort",
+            "client-cidr" => "SrcIPCIDR",
+            "process-name" => "ProcessName",
+            "user" => "InUser",
+            _ => "Match",
+        }
+    }
+
+    /// Rule payload as shown by Clash, empty for the final rule.
+    pub fn clash_payload(&self) -> &str {
+        match self.split_rule_id() {
+            ("final", _) => "",
+            (_, payload) => payload,
+        }
+    }
+
+    /// Decision forced by a client for one connection, e.g. through the
+    /// `route_override_header` of the HTTP listener. `action` as in rules:
+    /// `DIRECT`, `PROXY`, `REJECT` or a proxy group.
+    pub fn overridden(action: &str) -> Result<Self> {
+        let (rule, group) = parse_action(action)?;
+        Ok(RuleDecision {
+            rule,
+            rule_id: format!("override:{}", action),
+            redirect_port: None,
+            group,
+            verbosity: None,
+        })
+    }
+
+    fn split_rule_id(&self) -> (&str, &str) {
+        let (kind, payload) = self.rule_id.split_once(':').unwrap_or((&self.rule_id, ""));
+        // drop the `layer/` prefix of layered rules
+        let kind = kind.rsplit('/').next().unwrap_or(kind);
+        (kind, payload)
+    }
+}
+
+/// Rule action: `DIRECT`, `PROXY`, `REJECT`, or else the proxy group the
+/// connections use, as `PROXY`. Rules only name groups declared with
+/// `PROXY-GROUP`, see `MatchProxy::add_proxy_group`.
+fn parse_action(action: &str) -> Result<(TrafficStreamRule, Option<String>)> {
+    match TrafficStreamRule::from_str(action) {
+        Ok(rule) => Ok((rule, None)),
+        Err(e) if action.is_empty() => Err(e),
+        Err(_) => Ok((TrafficStreamRule::Proxy, Some(action.to_string()))),
+    }
+}
+
+impl FromStr for TrafficStreamRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "direct" => Ok(TrafficStreamRule::Direct),
+            "proxy" => Ok(TrafficStreamRule::Proxy),
+            "reject" => Ok(TrafficStreamRule::Reject),
+            _ => Err(anyhow!("unknown rule action: {}", s)),
+        }
+    }
+}
+
 impl SiteIp {
     fn from_str(input: &str) -> SiteIp {
         let res = if let Ok(ip) = input.parse::<std::net::IpAddr>() {
@@ -76,6 +223,100 @@ impl SiteIp {
     }
 }
 
+/// Where the rules of a `MatchProxy` layer come from.
+#[derive(Clone, Debug)]
+pub enum RuleSource {
+    /// v2ray geoip.dat/geosite.dat pair
+    GeoDat {
+        geoip: Option<PathBuf>,
+        geosite: Option<PathBuf>,
+    },
+    /// Text file with one `TYPE,VALUE,ACTION` rule per line
+    RuleFile(PathBuf),
+    /// Rules in the rule file format, e.g. built-in defaults
+    Inline(String),
+    /// Rules downloaded from a Clash style rule provider
+    #[cfg(not(target_arch = "wasm32"))]
+    Provider(RuleProvider),
+}
+
+impl RuleSource {
+    pub fn load(&self) -> Result<MatchProxy> {
+        match self {
+            RuleSource::GeoDat { geoip, geosite } => {
+                MatchProxy::from_geo_dat(geoip.as_ref(), geosite.as_ref())
+            }
+            RuleSource::RuleFile(path) => MatchProxy::from_rule_file(path),
+            RuleSource::Inline(content) => MatchProxy::from_rule_str(content),
+            #[cfg(not(target_arch = "wasm32"))]
+            RuleSource::Provider(provider) => {
+                let mut ins = MatchProxy::default();
+                ins.add_rule_str(&provider.rule_lines()?, RuleOrigin::Remote, 0)?;
+                ins.log_cidr_merges();
+                Ok(ins)
+            }
+        }
+    }
+}
+
+/// Whether domains that no domain rule matched are resolved to apply IP rules.
+///
+/// A domain decided by a domain rule is never resolved, so proxied domains
+/// don't leak to the local resolver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DomainResolve {
+    /// IP rules only apply to IP targets
+    #[default]
+    Never,
+    /// Resolve with the local resolver, IP rules flagged `no-resolve` are skipped
+    Local,
+}
+
+/// A named set of rules consulted before the own rules of a `MatchProxy`.
+#[derive(Clone)]
+struct RuleLayer {
+    name: String,
+    priority: u32,
+    source: RuleSource,
+    /// Shared by the copies `SharedRules::update` makes
+    rules: Arc<MatchProxy>,
+}
+
+/// Hit counters keyed by rule id.
+#[derive(Default)]
+struct RuleHits(RwLock<HashMap<String, AtomicU64>>);
+
+impl RuleHits {
+    fn hit(&self, rule_id: String) {
+        if let Some(counter) = self.0.read().unwrap().get(&rule_id) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.0
+            .write()
+            .unwrap()
+            .entry(rule_id)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HashMap<String, u64> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn reset(&self) {
+        self.0.write().unwrap().clear();
+    }
+}
+
+/// Compiled rules. Clones share their hit counters, so `rule_stats` survive
+/// `SharedRules::update`.
+#[derive(Clone)]
 pub struct MatchProxy {
     plain_site_map: HashMap<String, TrafficStreamRule>,
     root_domain_map: HashMap<String, TrafficStreamRule>,
@@ -88,8 +329,53 @@ pub struct MatchProxy {
     proxy_ipv6_combainer: Ipv6CidrCombiner,
     reject_ipv4_combainer: Ipv4CidrCombiner,
     reject_ipv6_combainer: Ipv6CidrCombiner,
+    /// CIDRs pushed to the direct combiners, before merging adjacent and
+    /// overlapping ones
+    direct_cidrs_pushed: usize,
+    direct_cidrs_pushed_clone: usize,
+    /// CIDRs pushed to the proxy and reject combiners
+    other_cidrs_pushed: usize,
     suffix_domain_map: HashMap<String, TrafficStreamRule>,
     preffix_domain_map: HashMap<String, TrafficStreamRule>,
+    /// `USER-AGENT` keywords, tried in the order they were added
+    user_agents: Vec<(String, TrafficStreamRule)>,
+    user_map: HashMap<String, TrafficStreamRule>,
+    /// Rules of `PROCESS-NAME`, keyed by lowercase executable name
+    process_map: HashMap<String, TrafficStreamRule>,
+    /// `*.example.com` patterns, keyed by `example.com`
+    wildcard_map: HashMap<String, TrafficStreamRule>,
+    /// Rule of the `*` pattern
+    wildcard_any: Option<TrafficStreamRule>,
+    /// IP rules flagged `no-resolve`, kept out of the combiners: they are
+    /// only matched against literal IPs
+    no_resolve_cidrs: Vec<(IpCidr, TrafficStreamRule)>,
+    /// Rules of `IP-ASN`, keyed by autonomous system number
+    asn_map: HashMap<u32, TrafficStreamRule>,
+    /// `IP-ASN` rules flagged `no-resolve`
+    no_resolve_asns: HashSet<u32>,
+    domain_resolve: DomainResolve,
+    /// Reject what no rule matched instead of proxying it
+    default_deny: bool,
+    client_cidrs: Vec<(IpCidr, TrafficStreamRule)>,
+    client_port_map: HashMap<u16, TrafficStreamRule>,
+    /// Ranges of `DST-PORT` rules with their rule id
+    dst_ports: PortRanges<(String, TrafficStreamRule)>,
+    /// Ports of `PORT-SET` definitions, keyed by name
+    port_sets: HashMap<String, Vec<RangeInclusive<u16>>>,
+    /// Destination ports rewritten by `redirect-port=` rules, keyed by rule id
+    redirect_ports: HashMap<String, u16>,
+    /// Proxy groups named as action, keyed by rule id
+    rule_groups: HashMap<String, String>,
+    /// Groups of `PROXY-GROUP` declarations, the only ones rules may name
+    proxy_groups: HashSet<String>,
+    /// `silent` and `verbose` rules, keyed by rule id
+    rule_verbosity: HashMap<String, RuleVerbosity>,
+    rule_hits: Arc<RuleHits>,
+    layers: Vec<RuleLayer>,
+    /// Order the kinds of domain rules are tried in, shared with the layers
+    domain_plan: Vec<DomainStage>,
+    /// Ids of the rules defined more than once while loading
+    duplicate_rules: Vec<String>,
 }
 
 impl Default for MatchProxy {
@@ -106,8 +392,33 @@ impl Default for MatchProxy {
             proxy_ipv6_combainer: Ipv6CidrCombiner::new(),
             reject_ipv4_combainer: Ipv4CidrCombiner::new(),
             reject_ipv6_combainer: Ipv6CidrCombiner::new(),
+            direct_cidrs_pushed: 0,
+            direct_cidrs_pushed_clone: 0,
+            other_cidrs_pushed: 0,
             suffix_domain_map: HashMap::new(),
             preffix_domain_map: HashMap::new(),
+            user_agents: Vec::new(),
+            user_map: HashMap::new(),
+            process_map: HashMap::new(),
+            wildcard_map: HashMap::new(),
+            wildcard_any: None,
+            no_resolve_cidrs: Vec::new(),
+            asn_map: HashMap::new(),
+            no_resolve_asns: HashSet::new(),
+            domain_resolve: DomainResolve::default(),
+            default_deny: false,
+            client_cidrs: Vec::new(),
+            client_port_map: HashMap::new(),
+            dst_ports: PortRanges::default(),
+            port_sets: HashMap::new(),
+            redirect_ports: HashMap::new(),
+            rule_groups: HashMap::new(),
+            proxy_groups: HashSet::new(),
+            rule_verbosity: HashMap::new(),
+            rule_hits: Arc::default(),
+            layers: Vec::new(),
+            domain_plan: DomainStage::DEFAULT_PLAN.to_vec(),
+            duplicate_rules: Vec::new(),
         }
     }
 }
@@ -123,42 +434,501 @@ fn read_geosite_from_dat(geo_siet_file: Option<&PathBuf>) -> GeoSiteList {
     }
 }
 
+fn read_geoip_from_dat(geoip_file: Option<&PathBuf>) -> GeoIpList {
+    if let Some(geoip_file) = geoip_file {
+        let mut file = File::open(geoip_file).expect("Failed to open file");
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).expect("Failed to read file");
+        GeoIpList::decode(&content[..]).expect("Failed to decode binary data")
+    } else {
+        GeoIpList::default()
+    }
+}
+
+/// The cache is usable when it is newer than every source file.
+fn is_cache_fresh(cache_file: &Path, sources: &[Option<&PathBuf>]) -> bool {
+    let cache_modified = match fs::metadata(cache_file).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    sources.iter().flatten().all(|source| {
+        fs::metadata(source)
+            .and_then(|m| m.modified())
+            .map(|modified| modified <= cache_modified)
+            .unwrap_or(false)
+    })
+}
+
+fn is_loaded_country(country_code: &str) -> bool {
+    country_code.to_lowercase() == "cn"
+}
+
 impl MatchProxy {
     pub fn from_geo_dat(
         gepip_file: Option<&PathBuf>,
         geo_site_file: Option<&PathBuf>,
     ) -> Result<Self> {
+        let geo_ips = read_geoip_from_dat(gepip_file).entry;
+        let geo_sites = read_geosite_from_dat(geo_site_file).entry;
+        Self::from_geo_entries(geo_ips, geo_sites)
+    }
+
+    /// Compose several rule sources into one matcher. Layers with a lower
+    /// `priority` are consulted first, the first layer with a matching rule
+    /// decides.
+    pub fn from_multiple_sources(sources: Vec<(&str, u32, RuleSource)>) -> Result<Self> {
+        let mut ins = Self::default();
+        for (name, priority, source) in sources {
+            ins.add_layer(name, priority, source)?;
+        }
+        Ok(ins)
+    }
+
+    /// Load rules in the rule file format: one `TYPE,VALUE,ACTION` rule per
+    /// line, `#` starts a comment. `INCLUDE <path-or-url>` loads the rules of
+    /// another rule file in its place, downloaded rule files may only include
+    /// URLs.
+    pub fn from_rule_str(content: &str) -> Result<Self> {
+        let mut ins = Self::default();
+        ins.add_rule_str(content, RuleOrigin::Local(None), 0)?;
+        ins.log_cidr_merges();
+        Ok(ins)
+    }
+
+    /// Same as `from_rule_str`, relative `INCLUDE` paths are relative to the
+    /// directory of `path`.
+    pub fn from_rule_file(path: &PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut ins = Self::default();
+        ins.add_rule_str(&content, RuleOrigin::Local(path.parent()), 0)
+            .map_err(|e| anyhow!("{:?} {}", path, e))?;
+        ins.log_cidr_merges();
+        Ok(ins)
+    }
+
+    fn add_rule_str(&mut self, content: &str, origin: RuleOrigin, depth: usize) -> Result<()> {
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let res = match line.split_once(char::is_whitespace) {
+                Some((directive, target)) if directive.eq_ignore_ascii_case("INCLUDE") => {
+                    self.include(target.trim(), origin, depth)
+                }
+                _ => self.add_rule_line(line),
+            };
+            res.map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Add the rules of the rule file `target` names, a path or an URL.
+    /// Downloads block, loading rules with `INCLUDE`s of URLs from a runtime
+    /// thread has to go through `spawn_blocking`.
+    fn include(&mut self, target: &str, origin: RuleOrigin, depth: usize) -> Result<()> {
+        // Also stops include cycles
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("INCLUDE nested deeper than {}: {}", MAX_INCLUDE_DEPTH, target));
+        }
+        if target.starts_with("http://") || target.starts_with("https://") {
+            let content = loaders::fetch(target)?;
+            return self
+                .add_rule_str(&content, RuleOrigin::Remote, depth + 1)
+                .map_err(|e| anyhow!("{} {}", target, e));
+        }
+        let path = match origin {
+            RuleOrigin::Local(Some(dir)) => dir.join(target),
+            RuleOrigin::Local(None) => PathBuf::from(target),
+            RuleOrigin::Remote => {
+                return Err(anyhow!("downloaded rules can't INCLUDE the local {}", target));
+            }
+        };
+        let content = fs::read_to_string(&path).map_err(|e| anyhow!("{:?} {}", path, e))?;
+        self.add_rule_str(&content, RuleOrigin::Local(path.parent()), depth + 1)
+            .map_err(|e| anyhow!("{:?} {}", path, e))
+    }
+
+    /// Add one rule such as `DOMAIN-SUFFIX,google.com,proxy`. `DOMAIN` also takes
+    /// `*.example.com` and `*` wildcards, IP rules accept a trailing `no-resolve`
+    /// as in Clash. Rules other than `IP-CIDR` ones accept `redirect-port=8443`
+    /// to connect to another port of the destination, and `silent` or
+    /// `verbose` to log its connections less or more than the listener does.
+    /// An action other than `DIRECT`, `PROXY` and `REJECT` names the proxy
+    /// group of the connections, again not on `IP-CIDR` rules. The group has
+    /// to be declared before by `PROXY-GROUP,streaming`, or be the address of
+    /// a node.
+    ///
+    /// Supported types: `DOMAIN`, `DOMAIN-SUFFIX`, `DOMAIN-KEYWORD`,
+    /// `DOMAIN-ROOT`, `IP-CIDR`, `IP-CIDR6`, `IP-ASN`, `USER-AGENT`, `USER`,
+    /// `SRC-IP-CIDR`, `SRC-PORT` and `PROCESS-NAME`. `IP-ASN` looks IPs up in
+    /// the database of `load_asn_database`, `PROCESS-NAME` only matches
+    /// clients connecting from the loopback interface.
+    pub fn add_rule_line(&mut self, line: &str) -> Result<()> {
+        let parts: Vec<&str> = line.split(',').map(|part| part.trim()).collect();
+        if let [rule_type, name] = parts[..] {
+            if rule_type.eq_ignore_ascii_case("PROXY-GROUP") {
+                return self.add_proxy_group(name);
+            }
+        }
+        if let [rule_type, name, ports] = parts[..] {
+            if rule_type.eq_ignore_ascii_case("PORT-SET") {
+                return self.add_port_set(name, ports);
+            }
+        }
+        let (rule_type, value, action, options) = match parts[..] {
+            [rule_type, value, action, ref options @ ..] => (rule_type, value, action, options),
+            _ => return Err(anyhow!("expected TYPE,VALUE,ACTION[,OPTION]: {}", line)),
+        };
+        let (rule, group) = parse_action(action)?;
+        if let Some(group) = &group {
+            if !self.proxy_groups.contains(group) && group.parse::<SocketAddr>().is_err() {
+                return Err(anyhow!("undeclared proxy group: {}", group));
+            }
+        }
+        let rule_type = rule_type.to_uppercase();
+        let mut no_resolve = false;
+        let mut redirect_port = None;
+        let mut verbosity = None;
+        for option in options {
+            match option.split_once('=') {
+                Some((name, port)) if name.trim().eq_ignore_ascii_case("redirect-port") => {
+                    let port = port.trim().parse().map_err(|_| anyhow!("invalid port: {}", port))?;
+                    redirect_port = Some(port);
+                }
+                None if option.eq_ignore_ascii_case("no-resolve") => no_resolve = true,
+                None if option.eq_ignore_ascii_case("silent") => {
+                    verbosity = Some(RuleVerbosity::Silent)
+                }
+                None if option.eq_ignore_ascii_case("verbose") => {
+                    verbosity = Some(RuleVerbosity::Verbose)
+                }
+                _ => return Err(anyhow!("unknown rule option: {}", option)),
+            }
+        }
+        let rule_id = Self::line_rule_id(&rule_type, value);
+        if let Some(rule_id) = &rule_id {
+            if self.is_defined(&rule_type, value) {
+                self.duplicate_rules.push(rule_id.clone());
+            }
+        }
+        match rule_type.as_str() {
+            "DOMAIN" if value.contains('*') => self.add_wildcard(value, rule)?,
+            "DOMAIN" => self.add_full_domain(value.to_string(), rule),
+            "DOMAIN-SUFFIX" => self.add_domain_suffix(value.to_string(), rule),
+            "DOMAIN-KEYWORD" => self.add_domain_preffix(value.to_string(), rule),
+            "DOMAIN-ROOT" => self.add_root_domain(value, rule),
+            // IP rules share one rule id per action, a port can't be told apart
+            "IP-CIDR" | "IP-CIDR6" if redirect_port.is_some() => {
+                return Err(anyhow!("redirect-port is not supported on IP rules: {}", line))
+            }
+            "IP-CIDR" | "IP-CIDR6" if group.is_some() => {
+                return Err(anyhow!("proxy groups are not supported on IP rules: {}", line))
+            }
+            "IP-CIDR" | "IP-CIDR6" if verbosity.is_some() => {
+                return Err(anyhow!("silent and verbose are not supported on IP rules: {}", line))
+            }
+            "IP-CIDR" | "IP-CIDR6" if no_resolve => self.add_cidr_no_resolve(value, rule)?,
+            "IP-CIDR" | "IP-CIDR6" => self.add_cidr(value, rule)?,
+            "IP-ASN" => self.add_asn(parse_asn(value)?, rule, no_resolve),
+            "USER-AGENT" => self.add_user_agent(value.to_string(), rule),
+            "USER" => self.add_user(value.to_string(), rule),
+            "SRC-IP-CIDR" => self.add_client_cidr(value, rule)?,
+            "SRC-PORT" => self.add_client_port(value.parse()?, rule),
+            "DST-PORT" => self.add_dst_port(value, rule)?,
+            "PROCESS-NAME" => self.add_process_name(value, rule),
+            _ => return Err(anyhow!("unknown rule type: {}", rule_type)),
+        }
+        if let Some(rule_id) = rule_id {
+            match redirect_port {
+                Some(port) => self.redirect_ports.insert(rule_id.clone(), port),
+                None => self.redirect_ports.remove(&rule_id),
+            };
+            match verbosity {
+                Some(verbosity) => self.rule_verbosity.insert(rule_id.clone(), verbosity),
+                None => self.rule_verbosity.remove(&rule_id),
+            };
+            match group {
+                Some(group) => self.rule_groups.insert(rule_id, group),
+                None => self.rule_groups.remove(&rule_id),
+            };
+        }
+        Ok(())
+    }
+
+    /// Whether the rule `TYPE,value` is already defined, adding it again
+    /// replaces it.
+    fn is_defined(&self, rule_type: &str, value: &str) -> bool {
+        match rule_type {
+            "DOMAIN" if value == "*" => self.wildcard_any.is_some(),
+            "DOMAIN" if value.contains('*') => value
+                .strip_prefix("*.")
+                .is_some_and(|suffix| self.wildcard_map.contains_key(&suffix.to_lowercase())),
+            "DOMAIN" => self.plain_site_map.contains_key(value),
+            "DOMAIN-SUFFIX" => self.suffix_domain_map.contains_key(value),
+            "DOMAIN-KEYWORD" => self.preffix_domain_map.contains_key(value),
+            "DOMAIN-ROOT" => parse_domain_name(value)
+                .ok()
+                .and_then(|name| name.root())
+                .is_some_and(|root| self.root_domain_map.contains_key(root)),
+            "IP-ASN" => parse_asn(value).is_ok_and(|asn| self.asn_map.contains_key(&asn)),
+            "USER-AGENT" => self.user_agents.iter().any(|(k, _)| k == value),
+            "USER" => self.user_map.contains_key(value),
+            "SRC-IP-CIDR" => IpCidr::from_str(value)
+                .is_ok_and(|cidr| self.client_cidrs.iter().any(|(c, _)| *c == cidr)),
+            "SRC-PORT" => value
+                .parse()
+                .is_ok_and(|port| self.client_port_map.contains_key(&port)),
+            "DST-PORT" => {
+                let rule_id = format!("dst-port:{}", value);
+                self.dst_ports.values().any(|(id, _)| *id == rule_id)
+            }
+            "PROCESS-NAME" => self.process_map.contains_key(&value.to_lowercase()),
+            _ => false,
+        }
+    }
+
+    /// Id under which the rule `TYPE,value` is matched, `None` for IP rules.
+    fn line_rule_id(rule_type: &str, value: &str) -> Option<String> {
+        let rule_id = match rule_type {
+            "DOMAIN" if value == "*" => "wildcard:*".to_string(),
+            "DOMAIN" if value.contains('*') => format!("domain-wildcard:{}", value.to_lowercase()),
+            "DOMAIN" => format!("domain-full:{}", value),
+            "DOMAIN-SUFFIX" => format!("domain-suffix:{}", value),
+            "DOMAIN-KEYWORD" => format!("domain-prefix:{}", value),
+            "DOMAIN-ROOT" => {
+                let name = parse_domain_name(value).ok()?;
+                format!("domain-root:{}", name.root()?)
+            }
+            "USER-AGENT" => format!("user-agent:{}", value),
+            "USER" => format!("user:{}", value),
+            "SRC-IP-CIDR" => format!("client-cidr:{}", IpCidr::from_str(value).ok()?),
+            "SRC-PORT" => format!("client-port:{}", value.parse::<u16>().ok()?),
+            "DST-PORT" => format!("dst-port:{}", value),
+            "IP-ASN" => format!("ip-asn:{}", parse_asn(value).ok()?),
+            "PROCESS-NAME" => format!("process-name:{}", value.to_lowercase()),
+            _ => return None,
+        };
+        Some(rule_id)
+    }
+
+    /// Load `source` as the layer `name`, replacing a layer with the same name.
+    pub fn add_layer(&mut self, name: &str, priority: u32, source: RuleSource) -> Result<()> {
+        let mut rules = source.load()?;
+        rules.domain_plan = self.domain_plan.clone();
+        self.layers.retain(|layer| layer.name != name);
+        self.layers.push(RuleLayer {
+            name: name.to_string(),
+            priority,
+            source,
+            rules: Arc::new(rules),
+        });
+        self.layers.sort_by_key(|layer| layer.priority);
+        Ok(())
+    }
+
+    /// Load the source of layer `name` again, other layers are kept as is.
+    pub fn reload_layer(&mut self, name: &str) -> Result<()> {
+        let layer = self
+            .layers
+            .iter_mut()
+            .find(|layer| layer.name == name)
+            .ok_or_else(|| anyhow!("unknown rule layer: {}", name))?;
+        let mut rules = layer.source.load()?;
+        rules.domain_plan = self.domain_plan.clone();
+        layer.rules = Arc::new(rules);
+        Ok(())
+    }
+
+    pub fn remove_layer(&mut self, name: &str) {
+        self.layers.retain(|layer| layer.name != name);
+    }
+
+    /// Try the kinds of domain rules in the order of `plan` instead of
+    /// `DomainStage::DEFAULT_PLAN`, in every layer. Each kind has to be listed
+    /// once.
+    pub fn set_domain_plan(&mut self, plan: Vec<DomainStage>) -> Result<()> {
+        rule_plan::validate_plan(&plan)?;
+        for layer in self.layers.iter_mut() {
+            Arc::make_mut(&mut layer.rules).domain_plan = plan.clone();
+        }
+        self.domain_plan = plan;
+        Ok(())
+    }
+
+    /// Evaluation plan with the rule counts, the estimated cost of a domain
+    /// lookup and the domain rules that can never decide: shadowed by an
+    /// earlier rule, defined twice or overlapping with another action.
+    /// Compares every rule with the earlier ones, not meant for every
+    /// connection.
+    pub fn rule_report(&self) -> RuleReport {
+        let layers = self.layers.iter().map(|layer| (format!("{}/", layer.name), &*layer.rules));
+        let sets: Vec<RuleSetView> = layers
+            .chain(std::iter::once((String::new(), self)))
+            .map(|(prefix, rules)| RuleSetView {
+                prefix,
+                stages: self
+                    .domain_plan
+                    .iter()
+                    .map(|stage| (*stage, rules.stage_rules(*stage)))
+                    .collect(),
+                duplicates: rules.duplicate_rules.clone(),
+            })
+            .collect();
+        rule_plan::report(&self.domain_plan, self.rule_counts(), &sets)
+    }
+
+    /// Domain rules of one kind, as value and action.
+    fn stage_rules(&self, stage: DomainStage) -> Vec<(String, TrafficStreamRule)> {
+        let map = match stage {
+            DomainStage::Wildcard => &self.wildcard_map,
+            DomainStage::Suffix => &self.suffix_domain_map,
+            DomainStage::Keyword => &self.preffix_domain_map,
+            DomainStage::Full => &self.plain_site_map,
+            DomainStage::Root => &self.root_domain_map,
+            DomainStage::Regex => {
+                return self
+                    .direct_regex_sites
+                    .iter()
+                    .map(|regex| (regex.as_str().to_string(), TrafficStreamRule::Direct))
+                    .collect()
+            }
+        };
+        map.iter().map(|(value, rule)| (value.clone(), rule.clone())).collect()
+    }
+
+    /// Number of rules per kind, including the rules of every layer.
+    pub fn rule_counts(&self) -> RuleCounts {
+        let mut counts = RuleCounts {
+            domain_full: self.plain_site_map.len(),
+            domain_suffix: self.suffix_domain_map.len(),
+            domain_keyword: self.preffix_domain_map.len(),
+            domain_root: self.root_domain_map.len(),
+            domain_regex: self.direct_regex_sites.len(),
+            domain_wildcard: self.wildcard_map.len() + self.wildcard_any.iter().count(),
+            ip_cidr: self.ip_cidr_count(),
+            ip_cidr_merged: self.cidrs_pushed() - self.ip_cidr_count(),
+            ip_asn: self.asn_map.len(),
+            user_agent: self.user_agents.len(),
+            user: self.user_map.len(),
+            client: self.client_cidrs.len() + self.client_port_map.len() + self.process_map.len(),
+            dst_port: self.dst_ports.len(),
+            layers: Vec::new(),
+        };
+        for layer in self.layers.iter() {
+            let layer_counts = layer.rules.rule_counts();
+            counts.domain_full += layer_counts.domain_full;
+            counts.domain_suffix += layer_counts.domain_suffix;
+            counts.domain_keyword += layer_counts.domain_keyword;
+            counts.domain_root += layer_counts.domain_root;
+            counts.domain_regex += layer_counts.domain_regex;
+            counts.domain_wildcard += layer_counts.domain_wildcard;
+            counts.ip_cidr += layer_counts.ip_cidr;
+            counts.ip_cidr_merged += layer_counts.ip_cidr_merged;
+            counts.ip_asn += layer_counts.ip_asn;
+            counts.user_agent += layer_counts.user_agent;
+            counts.user += layer_counts.user;
+            counts.client += layer_counts.client;
+            counts.dst_port += layer_counts.dst_port;
+            counts.layers.push(layer.name.clone());
+        }
+        counts
+    }
+
+    fn ip_cidr_count(&self) -> usize {
+        self.direct_ipv4_combainer.len()
+            + self.direct_ipv6_combainer.len()
+            + self.proxy_ipv4_combainer.len()
+            + self.proxy_ipv6_combainer.len()
+            + self.reject_ipv4_combainer.len()
+            + self.reject_ipv6_combainer.len()
+            + self.no_resolve_cidrs.len()
+    }
+
+    fn cidrs_pushed(&self) -> usize {
+        self.direct_cidrs_pushed + self.other_cidrs_pushed + self.no_resolve_cidrs.len()
+    }
+
+    /// Tell how much merging adjacent and overlapping CIDRs saved, country
+    /// lists shrink a lot.
+    fn log_cidr_merges(&self) {
+        let (pushed, kept) = (self.cidrs_pushed(), self.ip_cidr_count());
+        if pushed > kept {
+            debug!("merged {} IP CIDRs into {}", pushed, kept);
+        }
+    }
+
+    /// Layer names, by priority.
+    pub fn layer_names(&self) -> Vec<&str> {
+        self.layers.iter().map(|layer| layer.name.as_str()).collect()
+    }
+
+    /// Same as `from_geo_dat`, but keeps the entries actually used in the
+    /// compact `cache_file`. Later runs load the cache instead of decoding the
+    /// full geo files, until one of them gets newer than the cache.
+    pub fn from_geo_dat_cached(
+        gepip_file: Option<&PathBuf>,
+        geo_site_file: Option<&PathBuf>,
+        cache_file: &PathBuf,
+    ) -> Result<Self> {
+        if is_cache_fresh(cache_file, &[gepip_file, geo_site_file]) {
+            match fs::read(cache_file)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Ok(GeoCache::decode(&content[..])?))
+            {
+                Ok(cache) => {
+                    debug!("Loading rules from cache {:?}", cache_file);
+                    return Self::from_geo_entries(cache.geoip, cache.geosite);
+                }
+                Err(e) => warn!("Ignoring rule cache {:?}: {}", cache_file, e),
+            }
+        }
+        let cache = GeoCache {
+            geoip: read_geoip_from_dat(gepip_file)
+                .entry
+                .into_iter()
+                .filter(|geo_ip| is_loaded_country(&geo_ip.country_code))
+                .collect(),
+            geosite: read_geosite_from_dat(geo_site_file)
+                .entry
+                .into_iter()
+                .filter(|geo_site| is_loaded_country(&geo_site.country_code))
+                .collect(),
+        };
+        let mut content = Vec::with_capacity(cache.encoded_len());
+        cache.encode(&mut content)?;
+        if let Err(e) = fs::write(cache_file, content) {
+            warn!("Failed to write rule cache {:?}: {}", cache_file, e);
+        }
+        Self::from_geo_entries(cache.geoip, cache.geosite)
+    }
+
+    fn from_geo_entries(geo_ips: Vec<GeoIp>, geo_sites: Vec<GeoSite>) -> Result<Self> {
         let mut ipv4_combiner = Ipv4CidrCombiner::new();
         let mut ipv6_combiner = Ipv6CidrCombiner::new();
-        if let Some(gepip_file) = gepip_file {
-            let mut file = File::open(gepip_file).expect("Failed to open file");
-            let mut content = Vec::new();
-            file.read_to_end(&mut content).expect("Failed to read file");
-            let geo_ips = GeoIpList::decode(&content[..]).expect("Failed to decode binary data");
-
-            for geo_ip in geo_ips.entry.iter() {
-                if geo_ip.country_code.to_lowercase() == "cn" {
-                    for cidr in &geo_ip.cidr {
-                        if cidr.ip.len() == 4 {
-                            let ipv4_cidr = Ipv4Cidr::from_str(cidr.to_string().as_str()).unwrap();
-                            ipv4_combiner.push(ipv4_cidr);
-                        }
-                        if cidr.ip.len() == 8 {
-                            let ipv6_cidr = Ipv6Cidr::from_str(cidr.to_string().as_str()).unwrap();
-                            ipv6_combiner.push(ipv6_cidr);
-                        }
+        let mut pushed = 0;
+        for geo_ip in geo_ips.iter() {
+            if is_loaded_country(&geo_ip.country_code) {
+                for cidr in &geo_ip.cidr {
+                    if cidr.ip.len() == 4 {
+                        let ipv4_cidr = Ipv4Cidr::from_str(cidr.to_string().as_str()).unwrap();
+                        ipv4_combiner.push(ipv4_cidr);
+                        pushed += 1;
+                    }
+                    if cidr.ip.len() == 8 {
+                        let ipv6_cidr = Ipv6Cidr::from_str(cidr.to_string().as_str()).unwrap();
+                        ipv6_combiner.push(ipv6_cidr);
+                        pushed += 1;
                     }
                 }
             }
-        } else {
         }
         let mut plain_site_map: HashMap<String, TrafficStreamRule> = HashMap::new();
         let mut direct_regex_sites: Vec<Regex> = Vec::new();
         let mut root_domain_map: HashMap<String, TrafficStreamRule> = HashMap::new();
-        let geo_sites = read_geosite_from_dat(geo_site_file).entry;
         for geo_site in geo_sites {
             let geo_site_clone = geo_site.clone();
-            if geo_site_clone.country_code.to_lowercase() == "cn" {
+            if is_loaded_country(&geo_site_clone.country_code) {
                 for domain in geo_site_clone.domain {
                     let site_type = domain.r#type();
                     match site_type {
@@ -197,99 +967,340 @@ impl MatchProxy {
             direct_ipv6_combainer: ipv6_combiner.clone(),
             direct_ipv4_combainer_clone: ipv4_combiner,
             direct_ipv6_combainer_clone: ipv6_combiner,
+            direct_cidrs_pushed: pushed,
+            direct_cidrs_pushed_clone: pushed,
             ..Default::default()
         };
+        ins.log_cidr_merges();
         Ok(ins)
     }
 
-    fn regex_match_cn(&self, input_site: &str) -> bool {
-        for regex in &self.direct_regex_sites {
-            let is_match = regex.is_match(input_site);
-            if is_match {
-                return is_match;
-            }
-        }
-        return false;
+    fn regex_match_cn(&self, input_site: &str) -> Option<&Regex> {
+        self.direct_regex_sites
+            .iter()
+            .find(|regex| regex.is_match(input_site))
     }
 
-    fn domain_match_cn(&self, input_site: &str) -> Option<&TrafficStreamRule> {
+    fn domain_match_cn<'a>(
+        &'a self,
+        input_site: &str,
+    ) -> Option<(&'a String, &'a TrafficStreamRule)> {
         let domain: std::prelude::v1::Result<addr::domain::Name<'_>, addr::error::Error<'_>> =
             parse_domain_name(input_site);
-        let res = match domain {
-            Ok(name) => {
-                let res: Option<&TrafficStreamRule> = if let Some(domain_root) = name.root() {
-                    self.root_domain_map.get(domain_root)
-                } else {
-                    None
-                };
-                res
-            }
+        match domain {
+            Ok(name) => match name.root() {
+                Some(domain_root) => self.root_domain_map.get_key_value(domain_root),
+                None => None,
+            },
             Err(_) => None,
-        };
-        res
+        }
     }
 
-    fn match_preffix(&self, input: &str) -> Option<&TrafficStreamRule> {
-        for (k, v) in self.preffix_domain_map.iter() {
-            if input.contains(k) {
-                return Some(v);
-            }
-        }
-        None
+    fn match_preffix(&self, input: &str) -> Option<(&String, &TrafficStreamRule)> {
+        self.preffix_domain_map
+            .iter()
+            .find(|(k, _)| input.contains(k.as_str()))
+    }
+
+    fn match_suffix(&self, input: &str) -> Option<(&String, &TrafficStreamRule)> {
+        self.suffix_domain_map
+            .iter()
+            .find(|(k, _)| input.contains(k.as_str()))
+    }
+
+    /// Walk the parent domains of `input`, `a.b.example.com` checks
+    /// `b.example.com` then `example.com` and `com`.
+    fn match_wildcard(&self, input: &str) -> Option<(&String, &TrafficStreamRule)> {
+        input
+            .match_indices('.')
+            .find_map(|(i, _)| self.wildcard_map.get_key_value(&input[i + 1..]))
     }
 
-    fn match_suffix(&self, input: &str) -> Option<&TrafficStreamRule> {
-        for (k, v) in self.suffix_domain_map.iter() {
-            if input.contains(k) {
-                return Some(v);
+    /// Rule explicitly matching `input_site` and its rule id, the kinds of
+    /// domain rules are tried in the order of the `domain_plan`.
+    fn match_domain(&self, input_site: &str) -> Option<(String, TrafficStreamRule)> {
+        self.domain_plan
+            .iter()
+            .find_map(|stage| self.match_domain_stage(*stage, input_site))
+    }
+
+    fn match_domain_stage(
+        &self,
+        stage: DomainStage,
+        input_site: &str,
+    ) -> Option<(String, TrafficStreamRule)> {
+        let (value, res) = match stage {
+            DomainStage::Wildcard => self.match_wildcard(input_site)?,
+            DomainStage::Suffix => self.match_suffix(input_site)?,
+            DomainStage::Keyword => self.match_preffix(input_site)?,
+            DomainStage::Full => self.plain_site_map.get_key_value(input_site)?,
+            DomainStage::Root => self.domain_match_cn(input_site)?,
+            DomainStage::Regex => {
+                let regex = self.regex_match_cn(input_site)?;
+                return Some((stage.rule_id(regex.as_str()), TrafficStreamRule::Direct));
             }
-        }
-        None
+        };
+        Some((stage.rule_id(value), res.to_owned()))
     }
 
-    pub fn traffic_stream_domain(&self, input_site: &str) -> TrafficStreamRule {
-        let res = self.match_suffix(input_site);
-        if let Some(res) = res {
-            return res.to_owned();
-        }
-        let res = self.match_preffix(input_site);
-        if let Some(res) = res {
-            return res.to_owned();
-        }
-        let res = self.plain_site_map.get(input_site);
-        if let Some(res) = res {
-            return res.to_owned();
-        }
-        let match_res = self.domain_match_cn(input_site);
-        if let Some(res) = match_res {
-            return res.to_owned();
+    fn match_host(&self, host: &Host) -> Option<(String, TrafficStreamRule)> {
+        self.match_host_rules(host, false).or_else(|| {
+            let rule = self.wildcard_any.clone()?;
+            Some(("wildcard:*".to_string(), rule))
+        })
+    }
+
+    /// Rules of `host`, `resolved` when it is an address of the requested domain.
+    fn match_host_rules(
+        &self,
+        host: &Host,
+        resolved: bool,
+    ) -> Option<(String, TrafficStreamRule)> {
+        // Zoned IPv6 literals, `fe80::1%eth0`, are carried as domains
+        if let Host::Domain(domain) = host {
+            let zoned = domain.split_once('%').and_then(|(ip, _)| ip.parse().ok());
+            if let Some(ip) = zoned {
+                return self.match_host_rules(&Host::Ipv6(ip), resolved);
+            }
         }
-        if self.regex_match_cn(input_site) {
+        let (is_direct, is_reject, is_proxy) = match host {
+            Host::Ipv4(host) => (
+                cidrs_contain(&self.direct_ipv4_combainer, host),
+                cidrs_contain(&self.reject_ipv4_combainer, host),
+                cidrs_contain(&self.proxy_ipv4_combainer, host),
+            ),
+            Host::Ipv6(host) => (
+                cidrs_contain(&self.direct_ipv6_combainer, host),
+                cidrs_contain(&self.reject_ipv6_combainer, host),
+                cidrs_contain(&self.proxy_ipv6_combainer, host),
+            ),
+            Host::Domain(host) => return self.match_domain(host),
+        };
+        let no_resolve = |rule| !resolved && self.no_resolve_contains(host, rule);
+        let rule = if is_direct || no_resolve(TrafficStreamRule::Direct) {
             TrafficStreamRule::Direct
-        } else {
+        } else if is_reject || no_resolve(TrafficStreamRule::Reject) {
+            TrafficStreamRule::Reject
+        } else if is_proxy || no_resolve(TrafficStreamRule::Proxy) {
             TrafficStreamRule::Proxy
+        } else {
+            return self.match_asn(host, resolved);
+        };
+        Some((format!("ip-cidr:{}", rule), rule))
+    }
+
+    /// `IP-ASN` rule of the autonomous system announcing the IP `host`.
+    fn match_asn(&self, host: &Host, resolved: bool) -> Option<(String, TrafficStreamRule)> {
+        if self.asn_map.is_empty() {
+            return None;
+        }
+        let ip = match host {
+            Host::Ipv4(ip) => IpAddr::V4(*ip),
+            Host::Ipv6(ip) => IpAddr::V6(*ip),
+            Host::Domain(_) => return None,
+        };
+        let asn = lookup_asn(ip)?;
+        if resolved && self.no_resolve_asns.contains(&asn) {
+            return None;
         }
+        let rule = self.asn_map.get(&asn)?;
+        Some((format!("ip-asn:{}", asn), rule.to_owned()))
     }
 
-    pub fn traffic_stream(&self, host: &Host) -> TrafficStreamRule {
-        let traffic_stream_res = match host {
-            Host::Ipv4(host) => {
-                if self.direct_ipv4_combainer.contains(&host) {
-                    TrafficStreamRule::Direct
-                } else {
-                    TrafficStreamRule::Proxy
+    // Only `decide_resolving` resolves, see `loaders`
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn match_resolved_ip(&self, ip: IpAddr) -> Option<(String, TrafficStreamRule)> {
+        let host = match ip {
+            IpAddr::V4(ip) => Host::Ipv4(ip),
+            IpAddr::V6(ip) => Host::Ipv6(ip),
+        };
+        self.match_host_rules(&host, true)
+    }
+
+    /// Whether a `no-resolve` IP rule sending to `rule` covers the IP `host`.
+    fn no_resolve_contains(&self, host: &Host, rule: TrafficStreamRule) -> bool {
+        let ip = match host {
+            Host::Ipv4(ip) => IpAddr::V4(*ip),
+            Host::Ipv6(ip) => IpAddr::V6(*ip),
+            Host::Domain(_) => return false,
+        };
+        self.no_resolve_cidrs
+            .iter()
+            .any(|(cidr, cidr_rule)| *cidr_rule == rule && cidr.contains(&ip))
+    }
+
+    fn match_client(
+        &self,
+        user_agent: Option<&str>,
+        client_addr: Option<&SocketAddr>,
+        username: Option<&str>,
+        process: Option<&str>,
+    ) -> Option<(String, TrafficStreamRule)> {
+        if let Some(res) = username.and_then(|username| self.user_map.get_key_value(username)) {
+            return Some((format!("user:{}", res.0), res.1.to_owned()));
+        }
+        let process = process.map(str::to_lowercase);
+        if let Some(res) = process.and_then(|process| self.process_map.get_key_value(&process)) {
+            return Some((format!("process-name:{}", res.0), res.1.to_owned()));
+        }
+        if let Some(user_agent) = user_agent {
+            for (k, v) in self.user_agents.iter() {
+                if user_agent.contains(k) {
+                    return Some((format!("user-agent:{}", k), v.to_owned()));
                 }
             }
-            Host::Ipv6(host) => {
-                if self.direct_ipv6_combainer.contains(&host) {
-                    TrafficStreamRule::Direct
-                } else {
-                    TrafficStreamRule::Proxy
+        }
+        if let Some(client_addr) = client_addr {
+            if let Some(res) = self.client_port_map.get(&client_addr.port()) {
+                return Some((format!("client-port:{}", client_addr.port()), res.to_owned()));
+            }
+            for (cidr, rule) in self.client_cidrs.iter() {
+                if cidr.contains(&client_addr.ip()) {
+                    return Some((format!("client-cidr:{}", cidr), rule.to_owned()));
                 }
             }
-            Host::Domain(host) => self.traffic_stream_domain(&host),
+        }
+        None
+    }
+
+    fn match_port(&self, port: u16) -> Option<(String, TrafficStreamRule)> {
+        self.dst_ports.get(port).cloned()
+    }
+
+    /// Whether the process owning the client's end of the connection is
+    /// needed, looking it up isn't cheap.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn has_process_rules(&self, client_addr: &SocketAddr) -> bool {
+        let ip = match client_addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        ip.is_loopback()
+            && (!self.process_map.is_empty()
+                || self.layers.iter().any(|layer| !layer.rules.process_map.is_empty()))
+    }
+
+    /// Run `matcher` over the layers by priority, then over the own rules.
+    fn first_match<F>(&self, matcher: F) -> Option<RuleDecision>
+    where
+        F: Fn(&MatchProxy) -> Option<(String, TrafficStreamRule)>,
+    {
+        for layer in self.layers.iter() {
+            if let Some((rule_id, rule)) = matcher(&layer.rules) {
+                let redirect_port = layer.rules.redirect_ports.get(&rule_id).copied();
+                let group = layer.rules.rule_groups.get(&rule_id).cloned();
+                let verbosity = layer.rules.rule_verbosity.get(&rule_id).copied();
+                let rule_id = format!("{}/{}", layer.name, rule_id);
+                self.rule_hits.hit(rule_id.clone());
+                return Some(RuleDecision {
+                    rule,
+                    rule_id,
+                    redirect_port,
+                    group,
+                    verbosity,
+                });
+            }
+        }
+        let (rule_id, rule) = matcher(self)?;
+        self.rule_hits.hit(rule_id.clone());
+        let redirect_port = self.redirect_ports.get(&rule_id).copied();
+        let group = self.rule_groups.get(&rule_id).cloned();
+        let verbosity = self.rule_verbosity.get(&rule_id).copied();
+        Some(RuleDecision {
+            rule,
+            rule_id,
+            redirect_port,
+            group,
+            verbosity,
+        })
+    }
+
+    fn final_rule(&self) -> RuleDecision {
+        let rule = match self.default_deny {
+            true => TrafficStreamRule::Reject,
+            false => TrafficStreamRule::Proxy,
         };
-        traffic_stream_res
+        let rule_id = format!("final:{}", rule);
+        self.rule_hits.hit(rule_id.clone());
+        RuleDecision {
+            rule,
+            rule_id,
+            redirect_port: None,
+            group: None,
+            verbosity: None,
+        }
+    }
+
+    pub fn traffic_stream_domain(&self, input_site: &str) -> TrafficStreamRule {
+        self.first_match(|m| m.match_domain(input_site))
+            .unwrap_or_else(|| self.final_rule())
+            .rule
+    }
+
+    /// Rule picked from the client itself (User-Agent for HTTP, source address
+    /// for both proxies), `None` when no client rule matches. `PROCESS-NAME`
+    /// rules don't match here, see `decide`.
+    pub fn traffic_stream_client(
+        &self,
+        user_agent: Option<&str>,
+        client_addr: Option<&SocketAddr>,
+    ) -> Option<TrafficStreamRule> {
+        self.first_match(|m| m.match_client(user_agent, client_addr, None, None))
+            .map(|decision| decision.rule)
+    }
+
+    pub fn traffic_stream(&self, host: &Host) -> TrafficStreamRule {
+        self.first_match(|m| m.match_host(host))
+            .unwrap_or_else(|| self.final_rule())
+            .rule
+    }
+
+    /// Client rules first (authenticated user, User-Agent, source address),
+    /// then the rules of the destination `port` and those of `host`, together
+    /// with the id of the rule that decided. `DST-PORT` rules come before the
+    /// host rules on purpose: `DST-PORT,25,REJECT` applies to every host,
+    /// those a domain or IP rule names included. `PROCESS-NAME` rules only
+    /// match in `decide_resolving`: finding the client's process scans the
+    /// process table, which is done off the caller's thread there.
+    pub fn decide(
+        &self,
+        user_agent: Option<&str>,
+        client_addr: Option<&SocketAddr>,
+        username: Option<&str>,
+        host: &Host,
+        port: Option<u16>,
+    ) -> RuleDecision {
+        self.first_match(|m| m.match_client(user_agent, client_addr, username, None))
+            .or_else(|| port.and_then(|port| self.first_match(|m| m.match_port(port))))
+            .or_else(|| self.first_match(|m| m.match_host(host)))
+            .unwrap_or_else(|| self.final_rule())
+    }
+
+    pub fn set_domain_resolve(&mut self, domain_resolve: DomainResolve) {
+        self.domain_resolve = domain_resolve;
+    }
+
+    /// Allowlist mode: only destinations (or clients) matched by a rule are
+    /// reached, the others are rejected as `final:reject` instead of being
+    /// proxied, e.g. to use the proxy as the egress firewall of containers.
+    /// Route overrides of clients are ignored meanwhile. Kept when the rules
+    /// shared by listeners are replaced, see `SharedRules::set_default_deny`.
+    pub fn set_default_deny(&mut self, default_deny: bool) {
+        self.default_deny = default_deny;
+    }
+
+    pub fn is_default_deny(&self) -> bool {
+        self.default_deny
+    }
+
+    /// Number of matches per rule id (`domain-suffix:bohr.`, `ip-cidr:direct`,
+    /// `final:proxy`...) since the rules were loaded or the last reset.
+    pub fn rule_stats(&self) -> HashMap<String, u64> {
+        self.rule_hits.snapshot()
+    }
+
+    pub fn reset_rule_stats(&self) {
+        self.rule_hits.reset();
     }
 
     fn ip_to_number(ip: Ipv4Addr) -> u32 {
@@ -305,8 +1316,14 @@ impl MatchProxy {
             | (octets[3] as u32)
     }
 
+    /// Adjacent and overlapping CIDRs with the same `rule` are merged, see
+    /// `RuleCounts::ip_cidr_merged`.
     pub fn add_cidr(&mut self, cidr: &str, rule: TrafficStreamRule) -> Result<()> {
         let ip_cidr = IpCidr::from_str(cidr)?;
+        match rule {
+            TrafficStreamRule::Direct => self.direct_cidrs_pushed += 1,
+            _ => self.other_cidrs_pushed += 1,
+        }
         match ip_cidr {
             IpCidr::V4(cidr) => match rule {
                 TrafficStreamRule::Direct => self.direct_ipv4_combainer.push(cidr),
@@ -322,6 +1339,12 @@ impl MatchProxy {
         Ok(())
     }
 
+    /// IP rule applied to IP targets only, never to resolved domains.
+    pub fn add_cidr_no_resolve(&mut self, cidr: &str, rule: TrafficStreamRule) -> Result<()> {
+        self.no_resolve_cidrs.push((IpCidr::from_str(cidr)?, rule));
+        Ok(())
+    }
+
     pub fn add_root_domain(&mut self, domain: &str, rule: TrafficStreamRule) {
         let domain = parse_domain_name(domain);
         let domain_root = match domain {
@@ -340,6 +1363,28 @@ impl MatchProxy {
         self.plain_site_map.insert(domain, rule);
     }
 
+    /// Add a `*.example.com` (subdomains of example.com) or `*` (any host) pattern.
+    pub fn add_wildcard(&mut self, pattern: &str, rule: TrafficStreamRule) -> Result<()> {
+        match pattern.strip_prefix("*.") {
+            _ if pattern == "*" => self.wildcard_any = Some(rule),
+            Some(suffix) if !suffix.is_empty() && !suffix.contains('*') => {
+                self.wildcard_map.insert(suffix.to_lowercase(), rule);
+            }
+            _ => return Err(anyhow!("unsupported wildcard pattern: {}", pattern)),
+        }
+        Ok(())
+    }
+
+    pub fn delete_wildcard(&mut self, pattern: &str) {
+        match pattern.strip_prefix("*.") {
+            _ if pattern == "*" => self.wildcard_any = None,
+            Some(suffix) => {
+                self.wildcard_map.remove(&suffix.to_lowercase());
+            }
+            None => {}
+        }
+    }
+
     pub fn add_domain_suffix(&mut self, suffix: String, rule: TrafficStreamRule) {
         self.suffix_domain_map.insert(suffix, rule);
     }
@@ -347,6 +1392,87 @@ impl MatchProxy {
         self.preffix_domain_map.insert(preffix, rule);
     }
 
+    /// Rule for User-Agents containing `keyword`. When several keywords
+    /// match, the one added first wins; adding a keyword again only replaces
+    /// its rule.
+    pub fn add_user_agent(&mut self, keyword: String, rule: TrafficStreamRule) {
+        match self.user_agents.iter_mut().find(|(k, _)| *k == keyword) {
+            Some((_, existing)) => *existing = rule,
+            None => self.user_agents.push((keyword, rule)),
+        }
+    }
+
+    /// Rule for connections authenticated as `username`.
+    pub fn add_user(&mut self, username: String, rule: TrafficStreamRule) {
+        self.user_map.insert(username, rule);
+    }
+
+    pub fn add_client_cidr(&mut self, cidr: &str, rule: TrafficStreamRule) -> Result<()> {
+        let ip_cidr = IpCidr::from_str(cidr)?;
+        self.client_cidrs.retain(|(c, _)| c != &ip_cidr);
+        self.client_cidrs.push((ip_cidr, rule));
+        Ok(())
+    }
+
+    /// Rule for IPs announced by the autonomous system `asn`, `no_resolve`
+    /// ones only apply to IP targets.
+    pub fn add_asn(&mut self, asn: u32, rule: TrafficStreamRule, no_resolve: bool) {
+        self.asn_map.insert(asn, rule);
+        if no_resolve {
+            self.no_resolve_asns.insert(asn);
+        } else {
+            self.no_resolve_asns.remove(&asn);
+        }
+    }
+
+    /// Rule for clients run by the executable `name`, e.g. `firefox` or
+    /// `firefox.exe` on Windows, compared ignoring case.
+    pub fn add_process_name(&mut self, name: &str, rule: TrafficStreamRule) {
+        self.process_map.insert(name.to_lowercase(), rule);
+    }
+
+    pub fn add_client_port(&mut self, port: u16, rule: TrafficStreamRule) {
+        self.client_port_map.insert(port, rule);
+    }
+
+    /// Rule for destination ports: one port, a range (`6881-6889`), several
+    /// of them separated by `/`, or the name of a port set. Replaces the rule
+    /// with the same `value`, overlapping ranges are matched narrowest first.
+    /// Matched before the host rules, see `decide`.
+    pub fn add_dst_port(&mut self, value: &str, rule: TrafficStreamRule) -> Result<()> {
+        let ranges = match self.port_sets.get(value) {
+            Some(ranges) => ranges.clone(),
+            None => parse_port_ranges(value)?,
+        };
+        self.delete_dst_port(value);
+        let rule_id = format!("dst-port:{}", value);
+        for range in ranges {
+            self.dst_ports.insert(range, (rule_id.clone(), rule.clone()));
+        }
+        Ok(())
+    }
+
+    /// Name `ports` (as in `DST-PORT` rules) for the `DST-PORT` rules that
+    /// follow: `PORT-SET,p2p,6881-6889/51413` then `DST-PORT,p2p,REJECT`.
+    pub fn add_port_set(&mut self, name: &str, ports: &str) -> Result<()> {
+        if name.is_empty() || parse_port_ranges(name).is_ok() {
+            return Err(anyhow!("invalid port set name: {:?}", name));
+        }
+        self.port_sets.insert(name.to_string(), parse_port_ranges(ports)?);
+        Ok(())
+    }
+
+    /// Declare the proxy group `name` for the rules that follow, as
+    /// `PROXY-GROUP,streaming` does. Its nodes are those the listeners set
+    /// with `set_proxy_groups`.
+    pub fn add_proxy_group(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || TrafficStreamRule::from_str(name).is_ok() {
+            return Err(anyhow!("invalid proxy group name: {:?}", name));
+        }
+        self.proxy_groups.insert(name.to_string());
+        Ok(())
+    }
+
     pub fn is_direct(&self, host: &Host) -> bool {
         let traffic_res = self.traffic_stream(host);
         match traffic_res {
@@ -358,6 +1484,8 @@ impl MatchProxy {
     pub fn reset_direct_cidr(&mut self) {
         self.direct_ipv4_combainer = self.direct_ipv4_combainer_clone.clone();
         self.direct_ipv6_combainer = self.direct_ipv6_combainer_clone.clone();
+        self.direct_cidrs_pushed = self.direct_cidrs_pushed_clone;
+        self.no_resolve_cidrs.retain(|(_, rule)| *rule != TrafficStreamRule::Direct);
     }
 
     pub fn clear_not_direct_cidr(&mut self) {
@@ -365,6 +1493,8 @@ impl MatchProxy {
         self.proxy_ipv6_combainer = Ipv6CidrCombiner::default();
         self.reject_ipv4_combainer = Ipv4CidrCombiner::default();
         self.reject_ipv6_combainer = Ipv6CidrCombiner::default();
+        self.other_cidrs_pushed = 0;
+        self.no_resolve_cidrs.retain(|(_, rule)| *rule == TrafficStreamRule::Direct);
     }
 
     pub fn delete_domain_suffix(&mut self, suffix: &str) {
@@ -375,6 +1505,29 @@ impl MatchProxy {
         self.preffix_domain_map.remove(preffix);
     }
 
+    pub fn delete_user_agent(&mut self, keyword: &str) {
+        self.user_agents.retain(|(k, _)| k != keyword);
+    }
+
+    pub fn delete_user(&mut self, username: &str) {
+        self.user_map.remove(username);
+    }
+
+    pub fn delete_client_cidr(&mut self, cidr: &str) -> Result<()> {
+        let ip_cidr = IpCidr::from_str(cidr)?;
+        self.client_cidrs.retain(|(c, _)| c != &ip_cidr);
+        Ok(())
+    }
+
+    pub fn delete_client_port(&mut self, port: u16) {
+        self.client_port_map.remove(&port);
+    }
+
+    pub fn delete_dst_port(&mut self, value: &str) {
+        let rule_id = format!("dst-port:{}", value);
+        self.dst_ports.retain(|(id, _)| *id != rule_id);
+    }
+
     pub fn delete_full_domain(&mut self, domain: &str) {
         self.plain_site_map.remove(domain);
     }
@@ -401,6 +1554,7 @@ mod tests {
     use url::Url;
 
     use super::*;
+    use crate::rule_plan::RuleIssueKind;
 
     #[test]
     fn it_works() -> Result<()> {
@@ -445,4 +1599,344 @@ mod tests {
         assert_eq!(res3, TrafficStreamRule::Proxy);
         Ok(())
     }
+
+    #[test]
+    fn layers_by_priority() -> Result<()> {
+        let mut ins = MatchProxy::from_multiple_sources(vec![
+            (
+                "defaults",
+                10,
+                RuleSource::Inline(
+                    "DOMAIN-SUFFIX,example.com,direct\nIP-CIDR,10.0.0.0/8,direct".into(),
+                ),
+            ),
+            (
+                "user",
+                0,
+                RuleSource::Inline("# overrides\nDOMAIN,www.example.com,reject".into()),
+            ),
+        ])?;
+        assert_eq!(ins.layer_names(), vec!["user", "defaults"]);
+        let www = Host::Domain("www.example.com".to_string());
+        assert_eq!(ins.traffic_stream(&www), TrafficStreamRule::Reject);
+        let api = Host::Domain("api.example.com".to_string());
+        assert_eq!(ins.traffic_stream(&api), TrafficStreamRule::Direct);
+        let ip = Host::Ipv4(Ipv4Addr::new(10, 1, 2, 3));
+        assert_eq!(ins.traffic_stream(&ip), TrafficStreamRule::Direct);
+        assert_eq!(ins.rule_stats().get("user/domain-full:www.example.com"), Some(&1));
+
+        ins.remove_layer("user");
+        assert_eq!(ins.traffic_stream(&www), TrafficStreamRule::Direct);
+        assert!(MatchProxy::from_rule_str("DOMAIN,example.com").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn no_resolve_only_skips_its_own_rule() -> Result<()> {
+        let ins = MatchProxy::from_rule_str(
+            "IP-CIDR,10.0.0.0/8,reject,no-resolve\nIP-CIDR,10.1.0.0/16,direct",
+        )?;
+        let inside = Ipv4Addr::new(10, 1, 2, 3);
+        let outside = Ipv4Addr::new(10, 2, 0, 1);
+        let resolved = ins.match_resolved_ip(IpAddr::V4(inside));
+        assert_eq!(resolved.map(|(_, rule)| rule), Some(TrafficStreamRule::Direct));
+        assert_eq!(ins.match_resolved_ip(IpAddr::V4(outside)), None);
+        assert_eq!(ins.traffic_stream(&Host::Ipv4(outside)), TrafficStreamRule::Reject);
+        assert_eq!(ins.traffic_stream(&Host::Ipv4(inside)), TrafficStreamRule::Direct);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_only_unmatched_domains() -> Result<()> {
+        let mut ins = MatchProxy::from_rule_str(
+            "IP-CIDR,127.0.0.0/8,direct\nIP-CIDR,::1/128,direct,no-resolve",
+        )?;
+        let localhost = Host::Domain("localhost".to_string());
+        let decision = ins.decide_resolving(None, None, None, &localhost, None).await;
+        assert_eq!(decision.rule, TrafficStreamRule::Proxy);
+
+        ins.set_domain_resolve(DomainResolve::Local);
+        let decision = ins.decide_resolving(None, None, None, &localhost, None).await;
+        assert_eq!(decision.rule, TrafficStreamRule::Direct);
+
+        ins.add_full_domain("localhost".to_string(), TrafficStreamRule::Proxy);
+        let decision = ins.decide_resolving(None, None, None, &localhost, None).await;
+        assert_eq!(decision.rule_id, "domain-full:localhost");
+        Ok(())
+    }
+
+    #[test]
+    fn adjacent_cidrs_are_merged() -> Result<()> {
+        let rules = "IP-CIDR,10.0.0.0/25,direct\nIP-CIDR,10.0.0.128/25,direct\n\
+                     IP-CIDR,10.0.0.7/32,direct\nIP-CIDR,192.168.0.0/16,reject\n\
+                     IP-CIDR6,2001:db8::/33,direct\nIP-CIDR6,2001:db8:8000::/33,direct";
+        let ins = MatchProxy::from_rule_str(rules)?;
+        let counts = ins.rule_counts();
+        assert_eq!((counts.ip_cidr, counts.ip_cidr_merged), (3, 3));
+        for (ip, rule) in [
+            ("10.0.0.200", TrafficStreamRule::Direct),
+            ("192.168.1.1", TrafficStreamRule::Reject),
+            ("2001:db8:ffff::1", TrafficStreamRule::Direct),
+            ("10.0.1.1", TrafficStreamRule::Proxy),
+            ("9.255.255.255", TrafficStreamRule::Proxy),
+        ] {
+            let host = Host::parse(&format!("[{}]", ip)).or_else(|_| Host::parse(ip))?;
+            assert_eq!(ins.traffic_stream(&host), rule, "{}", ip);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_patterns() -> Result<()> {
+        let ins = MatchProxy::from_rule_str("DOMAIN,*.example.com,direct\nDOMAIN,*,reject")?;
+        let sub = Host::Domain("www.example.com".to_string());
+        assert_eq!(ins.traffic_stream(&sub), TrafficStreamRule::Direct);
+        let apex = Host::Domain("example.com".to_string());
+        assert_eq!(ins.traffic_stream(&apex), TrafficStreamRule::Reject);
+        assert!(MatchProxy::from_rule_str("DOMAIN,www.*.com,direct").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decisions_as_json() -> Result<()> {
+        let decision = RuleDecision::overridden("direct")?;
+        let json = serde_json::to_string(&decision)?;
+        assert_eq!(
+            json,
+            r#"{"rule":"direct","rule_id":"override:direct","redirect_port":null,"group":null}"#
+        );
+        assert_eq!(serde_json::from_str::<RuleDecision>(&json)?, decision);
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_port_option() -> Result<()> {
+        let ins = MatchProxy::from_multiple_sources(vec![(
+            "shims",
+            0,
+            RuleSource::Inline(
+                "DOMAIN-SUFFIX,example.com,DIRECT,redirect-port=8443\nDOMAIN,*,proxy".into(),
+            ),
+        )])?;
+        let host = Host::Domain("www.example.com".to_string());
+        assert_eq!(ins.decide(None, None, None, &host, None).redirect_port, Some(8443));
+        let other = Host::Domain("www.example.org".to_string());
+        assert_eq!(ins.decide(None, None, None, &other, None).redirect_port, None);
+        assert!(MatchProxy::from_rule_str("IP-CIDR,10.0.0.0/8,direct,redirect-port=80").is_err());
+        assert!(MatchProxy::from_rule_str("DOMAIN,a.com,direct,redirect-port=x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn silent_and_verbose_options() -> Result<()> {
+        let ins = MatchProxy::from_rule_str(concat!(
+            "DOMAIN-SUFFIX,telemetry.example.net,direct,silent\n",
+            "DOMAIN,api.example.com,proxy,VERBOSE\n",
+            "DOMAIN,www.example.com,proxy\n",
+        ))?;
+        let verbosity = |domain: &str| {
+            let host = Host::Domain(domain.to_string());
+            ins.decide(None, None, None, &host, None).verbosity
+        };
+        assert_eq!(verbosity("a.telemetry.example.net"), Some(RuleVerbosity::Silent));
+        assert_eq!(verbosity("api.example.com"), Some(RuleVerbosity::Verbose));
+        assert_eq!(verbosity("www.example.com"), None);
+        assert!(MatchProxy::from_rule_str("IP-CIDR,10.0.0.0/8,direct,silent").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn default_deny() -> Result<()> {
+        let mut ins = MatchProxy::from_rule_str("DOMAIN-SUFFIX,example.com,direct")?;
+        ins.set_default_deny(true);
+        let allowed = ins.decide(None, None, None, &Host::Domain("www.example.com".into()), None);
+        assert_eq!(allowed.rule, TrafficStreamRule::Direct);
+        let denied = ins.decide(None, None, None, &Host::Domain("www.example.org".into()), None);
+        assert_eq!(denied.rule, TrafficStreamRule::Reject);
+        assert_eq!(denied.rule_id, "final:reject");
+        Ok(())
+    }
+
+    #[test]
+    fn destination_port_rules() -> Result<()> {
+        let rules = "PORT-SET,p2p,6881-6889/51413\nDST-PORT,p2p,reject\n\
+                     DST-PORT,6885,direct\nDOMAIN,a.com,direct";
+        let mut ins = MatchProxy::from_rule_str(rules)?;
+        let a = Host::Domain("a.com".into());
+        let decide = |ins: &MatchProxy, port| ins.decide(None, None, None, &a, Some(port));
+        assert_eq!(decide(&ins, 6881).rule_id, "dst-port:p2p");
+        assert_eq!(decide(&ins, 51413).rule, TrafficStreamRule::Reject);
+        assert_eq!(decide(&ins, 6885).rule_id, "dst-port:6885");
+        assert_eq!(decide(&ins, 443).rule_id, "domain-full:a.com");
+        assert_eq!(decide(&ins, 6881).clash_rule_type(), "DstPort");
+        assert_eq!(ins.rule_counts().dst_port, 3);
+
+        ins.add_rule_line("DST-PORT,p2p,proxy")?;
+        assert_eq!(decide(&ins, 6889).rule, TrafficStreamRule::Proxy);
+        assert_eq!(ins.rule_counts().dst_port, 3);
+        ins.delete_dst_port("p2p");
+        assert_eq!(decide(&ins, 6889).rule_id, "domain-full:a.com");
+        assert!(ins.add_rule_line("DST-PORT,bittorrent,reject").is_err());
+        assert!(ins.add_rule_line("PORT-SET,80,443").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn destination_ports_come_before_host_rules() -> Result<()> {
+        let rules = "DST-PORT,25,reject\nDOMAIN,mail.example.com,direct\nIP-CIDR,10.0.0.0/8,direct";
+        let ins = MatchProxy::from_rule_str(rules)?;
+        let mail = Host::Domain("mail.example.com".into());
+        let ip = Host::Ipv4("10.0.0.1".parse()?);
+        for host in [&mail, &ip] {
+            let decision = ins.decide(None, None, None, host, Some(25));
+            assert_eq!(decision.rule_id, "dst-port:25");
+            let resolving = ins.decide_resolving(None, None, None, host, Some(25)).await;
+            assert_eq!(resolving.rule_id, "dst-port:25");
+            let other_port = ins.decide_resolving(None, None, None, host, Some(587)).await;
+            assert_eq!(other_port.rule, TrafficStreamRule::Direct);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zoned_ipv6_hosts_match_ip_rules() -> Result<()> {
+        let ins = MatchProxy::from_rule_str("IP-CIDR6,fe80::/10,reject\nDOMAIN,*,direct")?;
+        for zoned in ["fe80::1%eth0", "fe80::1%3"] {
+            let host = Host::Domain(zoned.to_string());
+            assert_eq!(ins.decide(None, None, None, &host, None).rule_id, "ip-cidr:reject");
+        }
+        let other = Host::Domain("2001:db8::1%eth0".to_string());
+        assert_eq!(ins.decide(None, None, None, &other, None).rule, TrafficStreamRule::Direct);
+        Ok(())
+    }
+
+    #[test]
+    fn group_action() -> Result<()> {
+        let rules = "PROXY-GROUP,streaming\nDOMAIN-SUFFIX,netflix.com,streaming\nDOMAIN,*,proxy";
+        let ins = MatchProxy::from_rule_str(rules)?;
+        let netflix = Host::Domain("www.netflix.com".to_string());
+        let decision = ins.decide(None, None, None, &netflix, None);
+        assert_eq!(decision.rule, TrafficStreamRule::Proxy);
+        assert_eq!(decision.group.as_deref(), Some("streaming"));
+        let other = Host::Domain("www.example.org".to_string());
+        assert_eq!(ins.decide(None, None, None, &other, None).group, None);
+        let ip_group = "PROXY-GROUP,streaming\nIP-CIDR,10.0.0.0/8,streaming";
+        assert!(MatchProxy::from_rule_str(ip_group).is_err());
+        // Typos aren't taken for groups
+        assert!(MatchProxy::from_rule_str("DOMAIN,a.com,PROXI").is_err());
+        assert!(MatchProxy::from_rule_str("PROXY-GROUP,direct").is_err());
+        assert!(MatchProxy::from_rule_str("DOMAIN,a.com,10.0.0.1:1080").is_ok());
+        let forced = RuleDecision::overridden("10.0.0.1:1080")?;
+        assert_eq!(forced.rule, TrafficStreamRule::Proxy);
+        assert_eq!(forced.group.as_deref(), Some("10.0.0.1:1080"));
+        assert_eq!(RuleDecision::overridden("DIRECT")?.rule, TrafficStreamRule::Direct);
+        Ok(())
+    }
+
+    #[test]
+    fn include_rule_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kitty_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lists"))?;
+        let main = dir.join("main.rules");
+        fs::write(&main, "DOMAIN,a.com,direct\nINCLUDE lists/ads.rules\n")?;
+        fs::write(dir.join("lists/ads.rules"), "DOMAIN-SUFFIX,ads.com,reject\n")?;
+        fs::write(dir.join("loop.rules"), "INCLUDE loop.rules\n")?;
+        let ins = MatchProxy::from_rule_file(&main);
+        let looped = MatchProxy::from_rule_file(&dir.join("loop.rules"));
+        fs::remove_dir_all(&dir)?;
+        let ads = Host::Domain("x.ads.com".to_string());
+        assert_eq!(ins?.traffic_stream(&ads), TrafficStreamRule::Reject);
+        assert!(looped.is_err());
+
+        let mut remote = MatchProxy::default();
+        let res = remote.add_rule_str("INCLUDE /etc/passwd\n", RuleOrigin::Remote, 1);
+        assert!(res.unwrap_err().to_string().contains("can't INCLUDE"));
+        Ok(())
+    }
+
+    #[test]
+    fn asn_rules() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kitty_asn_rules_{}", std::process::id()));
+        fs::write(&path, crate::asn::tests::test_database(13335))?;
+        let loaded = crate::asn::load_asn_database(&path);
+        fs::remove_file(&path)?;
+        loaded?;
+        let ins = MatchProxy::from_rule_str("IP-ASN,AS13335,direct\nIP-CIDR,1.0.0.0/24,reject")?;
+        let decision = ins.decide(None, None, None, &Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), None);
+        assert_eq!(decision.rule, TrafficStreamRule::Direct);
+        assert_eq!((decision.clash_rule_type(), decision.clash_payload()), ("IPASN", "13335"));
+        // IP-CIDR rules come first
+        let reject = ins.traffic_stream(&Host::Ipv4(Ipv4Addr::new(1, 0, 0, 1)));
+        assert_eq!(reject, TrafficStreamRule::Reject);
+        let other = ins.traffic_stream(&Host::Ipv4(Ipv4Addr::new(192, 168, 0, 1)));
+        assert_eq!(other, TrafficStreamRule::Proxy);
+        assert!(MatchProxy::from_rule_str("IP-ASN,cloudflare,direct").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn domain_plan_and_report() -> Result<()> {
+        let rules = "DOMAIN-SUFFIX,example.com,direct\nDOMAIN,www.example.com,reject\n\
+                     DOMAIN-KEYWORD,ads,reject\nDOMAIN-KEYWORD,ads.example,direct\n\
+                     DOMAIN,a.com,direct\nDOMAIN,a.com,reject";
+        let mut ins = MatchProxy::from_rule_str(rules)?;
+        let report = ins.rule_report();
+        let issue = |kind, rule_id: &str| {
+            report.issues.iter().any(|i| i.kind == kind && i.rule_id == rule_id)
+        };
+        assert!(issue(RuleIssueKind::Shadowed, "domain-full:www.example.com"));
+        assert!(issue(RuleIssueKind::Ambiguous, "domain-prefix:ads.example"));
+        assert!(issue(RuleIssueKind::Duplicate, "domain-full:a.com"));
+        assert_eq!(report.issue_count, report.issues.len());
+        assert_eq!(ins.traffic_stream_domain("www.example.com"), TrafficStreamRule::Direct);
+
+        let mut plan = DomainStage::DEFAULT_PLAN.to_vec();
+        plan.retain(|stage| *stage != DomainStage::Full);
+        assert!(ins.set_domain_plan(plan.clone()).is_err());
+        plan.insert(0, DomainStage::Full);
+        ins.set_domain_plan(plan)?;
+        assert_eq!(ins.traffic_stream_domain("www.example.com"), TrafficStreamRule::Reject);
+        assert!(!ins.rule_report().issues.iter().any(|i| i.kind == RuleIssueKind::Shadowed));
+        Ok(())
+    }
+
+    #[test]
+    fn user_agent_rules_match_in_order() -> Result<()> {
+        let mut ins = MatchProxy::from_rule_str(
+            "USER-AGENT,Mozilla,direct\nUSER-AGENT,Firefox,reject\nUSER-AGENT,curl,proxy",
+        )?;
+        let firefox = Some("Mozilla/5.0 Firefox/120.0");
+        for _ in 0..16 {
+            assert_eq!(ins.traffic_stream_client(firefox, None), Some(TrafficStreamRule::Direct));
+        }
+        // A keyword added again keeps its place
+        ins.add_user_agent("Mozilla".to_string(), TrafficStreamRule::Proxy);
+        assert_eq!(ins.traffic_stream_client(firefox, None), Some(TrafficStreamRule::Proxy));
+        ins.delete_user_agent("Mozilla");
+        assert_eq!(ins.traffic_stream_client(firefox, None), Some(TrafficStreamRule::Reject));
+        assert_eq!(ins.traffic_stream_client(Some("Wget"), None), None);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn process_name_rules() -> Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let client = std::net::TcpStream::connect(listener.local_addr()?)?;
+        let client_addr = client.local_addr()?;
+        let exe = std::env::current_exe()?;
+        let name = exe.file_name().unwrap().to_string_lossy().to_uppercase();
+        let ins = MatchProxy::from_rule_str(&format!("PROCESS-NAME,{},reject", name))?;
+        let a = Host::Domain("a.com".into());
+        let decision = ins.decide_resolving(None, Some(&client_addr), None, &a, None).await;
+        assert_eq!(decision.rule, TrafficStreamRule::Reject);
+        assert_eq!(decision.clash_rule_type(), "ProcessName");
+        // The sync path doesn't scan the process table
+        let decision = ins.decide(None, Some(&client_addr), None, &a, None);
+        assert_eq!(decision.rule, TrafficStreamRule::Proxy);
+        let remote = SocketAddr::from(([192, 168, 1, 2], client_addr.port()));
+        let decision = ins.decide_resolving(None, Some(&remote), None, &a, None).await;
+        assert_eq!(decision.rule, TrafficStreamRule::Proxy);
+        Ok(())
+    }
 }
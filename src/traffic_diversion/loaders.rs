@@ -0,0 +1,98 @@
+//! What the rules need from the host: downloads, DNS, the process table.
+//! Parsing and matching in the parent module don't touch any of it, so they
+//! build for wasm32 where these lookups are missing.
+
+use anyhow::Result;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use log::debug;
+    use url::Host;
+
+    use super::super::{DomainResolve, MatchProxy, RuleDecision, RuleSource};
+    use crate::dns;
+    use crate::process::process_name;
+    use crate::providers::{self, RuleProvider};
+
+    pub(super) fn fetch(url: &str) -> Result<String> {
+        providers::fetch_blocking(url)
+    }
+
+    impl MatchProxy {
+        /// Same as `decide`, but with `DomainResolve::Local` a domain no rule
+        /// matched is resolved and its addresses checked against the IP rules.
+        pub async fn decide_resolving(
+            &self,
+            user_agent: Option<&str>,
+            client_addr: Option<&SocketAddr>,
+            username: Option<&str>,
+            host: &Host,
+            port: Option<u16>,
+        ) -> RuleDecision {
+            let process = match client_addr {
+                Some(&client_addr) if self.has_process_rules(&client_addr) => {
+                    tokio::task::spawn_blocking(move || process_name(client_addr))
+                        .await
+                        .unwrap_or_default()
+                }
+                _ => None,
+            };
+            let process = process.as_deref();
+            let decision = self
+                .first_match(|m| m.match_client(user_agent, client_addr, username, process))
+                .or_else(|| port.and_then(|port| self.first_match(|m| m.match_port(port))))
+                .or_else(|| self.first_match(|m| m.match_host(host)));
+            if let Some(decision) = decision {
+                return decision;
+            }
+            if let (DomainResolve::Local, Host::Domain(domain)) = (self.domain_resolve, host) {
+                match dns::resolve(domain, 0).await {
+                    Ok(addrs) => {
+                        for addr in addrs {
+                            let decision = self.first_match(|m| m.match_resolved_ip(addr.ip()));
+                            if let Some(decision) = decision {
+                                return decision;
+                            }
+                        }
+                    }
+                    Err(e) => debug!("resolving {} for IP rules failed: {}", domain, e),
+                }
+            }
+            self.final_rule()
+        }
+
+        /// Names of the layers loaded from rule providers.
+        pub(crate) fn provider_layers(&self) -> Vec<String> {
+            self.layers
+                .iter()
+                .filter(|layer| matches!(layer.source, RuleSource::Provider(_)))
+                .map(|layer| layer.name.clone())
+                .collect()
+        }
+
+        /// Rule provider of the layer `name`, `None` when it isn't a provider.
+        pub(crate) fn provider(&self, name: &str) -> Option<RuleProvider> {
+            self.layers.iter().find_map(|layer| match &layer.source {
+                RuleSource::Provider(provider) if layer.name == name => Some(provider.clone()),
+                _ => None,
+            })
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod host {
+    use anyhow::{anyhow, Result};
+
+    pub(super) fn fetch(url: &str) -> Result<String> {
+        Err(anyhow!("can't download {} on wasm32", url))
+    }
+}
+
+/// Body of the rule file at `url`. Blocks, see `providers::fetch_blocking`.
+pub(super) fn fetch(url: &str) -> Result<String> {
+    host::fetch(url)
+}
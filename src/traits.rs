@@ -1,6 +1,75 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use url::Host;
+
+use crate::listener::ConnectionId;
+use crate::traffic_diversion::RuleDecision;
 use crate::types::Address;
 
 pub trait BanlancerTrait {
     async fn get_best_node(&self) -> Address;
 }
 
+/// Any bidirectional byte stream a connection can be relayed over.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+pub type HandshakeFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Describes how a proxied connection is opened on the VPN node.
+///
+/// `request` holds the raw bytes the client sent during the SOCKS5
+/// negotiation (greeting + request). Once the future resolves, everything the
+/// node sends is relayed back to the client as is.
+pub trait UpstreamHandshake: Send + Sync {
+    fn handshake<'a>(
+        &'a self,
+        stream: &'a mut BoxedStream,
+        host: &'a Host,
+        port: u16,
+        request: &'a [u8],
+    ) -> HandshakeFuture<'a>;
+}
+
+/// A TCP connection as seen by a `RouteHook`, once parsed and matched
+/// against the rules.
+#[derive(Clone, Debug)]
+pub struct RouteRequest {
+    pub connection: Option<ConnectionId>,
+    /// `socks5` or `http`
+    pub listener: &'static str,
+    pub source: Option<SocketAddr>,
+    /// Requested destination, before any `redirect-port=` of the rule
+    pub target: Address,
+    /// Host the rules matched, the sniffed SNI when it differs from `target`
+    pub rule_host: String,
+    pub username: Option<String>,
+    /// What the rules, route hints and learned routes decided
+    pub decision: RuleDecision,
+}
+
+/// Answer of a `RouteHook`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteDecision {
+    /// Connect as `RouteRequest::decision` says
+    Keep,
+    /// Route the connection this way instead, e.g. a decision from
+    /// `RuleDecision::overridden("reject")` to veto it
+    Replace(RuleDecision),
+}
+
+pub type RouteFuture<'a> = Pin<Box<dyn Future<Output = RouteDecision> + Send + 'a>>;
+
+/// Policy of the embedding application, asked for the route of every TCP
+/// connection after rule matching and before connecting. The connection
+/// waits for the answer, slow hooks delay every connection.
+pub trait RouteHook: Send + Sync {
+    fn on_route(&self, request: RouteRequest) -> RouteFuture<'_>;
+}
@@ -2,14 +2,18 @@
 // #[macro_use]
 // extern crate serde_derive;
 
+use anyhow::anyhow;
 use log::error;
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 use url::{Host, ParseError};
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, io};
 use thiserror::Error;
 use tokio::sync::Mutex;
@@ -27,9 +31,79 @@ pub enum KittyProxyError {
 
     #[error("error: {0}")]
     Error(#[from] anyhow::Error),
+
+    #[error("Upstream stalled for {0:?}")]
+    UpstreamStalled(Duration),
+}
+
+impl KittyProxyError {
+    /// Whether trying again may succeed, for retry wrappers of embedders.
+    /// Malformed or refused requests fail the same way every time.
+    pub fn retry_advice(&self) -> RetryAdvice {
+        match self {
+            KittyProxyError::Io(e) => io_retry_advice(e),
+            KittyProxyError::Proxy(code) => RetryAdvice::from(*code),
+            KittyProxyError::ParseError(_) => RetryAdvice::Never,
+            KittyProxyError::Error(e) => match e.downcast_ref::<io::Error>() {
+                Some(e) => io_retry_advice(e),
+                None => RetryAdvice::Never,
+            },
+            KittyProxyError::UpstreamStalled(_) => RetryAdvice::Immediately,
+        }
+    }
+
+    /// `retry_advice` is `Later` or `Immediately`.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.retry_advice(), RetryAdvice::Later | RetryAdvice::Immediately)
+    }
+}
+
+/// Connection level failures are worth another try, invalid input or
+/// missing permissions are not.
+fn io_retry_advice(e: &io::Error) -> RetryAdvice {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::WouldBlock => RetryAdvice::Immediately,
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => RetryAdvice::Later,
+        _ => RetryAdvice::Never,
+    }
 }
 
-#[derive(Debug, Snafu)]
+/// Errors of a running listener, reported through the channel returned by `serve()`.
+#[derive(Error, Debug)]
+pub enum ProxyRuntimeError {
+    #[error("Failed to bind {0}: {1}")]
+    Bind(String, io::Error),
+
+    /// Accepting a connection failed (e.g. EMFILE), the listener keeps going
+    #[error("Accept error: {0}")]
+    Accept(io::Error),
+
+    /// The accept loop stopped without being asked to
+    #[error("Listener closed")]
+    ListenerClosed,
+
+    #[error("No VPN node available")]
+    NodePoolEmpty,
+
+    /// A VPN node failed the connect test of `validate_nodes` when serving started
+    #[error("VPN node {0} unreachable: {1}")]
+    NodeUnreachable(SocketAddr, io::Error),
+
+    /// Refused to listen on a non-loopback address without credentials nor
+    /// `allow_lan`, see `ProxyConfig::allow_lan`
+    #[error("Refusing to expose {0} without authentication, set allow_lan to listen on it")]
+    LanNotAllowed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Snafu)]
 /// Possible SOCKS5 Response Codes
 pub enum ResponseCode {
     Success = 0x00,
@@ -51,6 +125,51 @@ pub enum ResponseCode {
     AddrTypeNotSupported = 0x08,
     #[snafu(display("HTTP Proxy Error: Bad Gateway (502)"))]
     HttpBadGateway = 0x502,
+    #[snafu(display("HTTP Proxy Error: Proxy Authentication Required (407)"))]
+    HttpProxyAuthRequired = 0x407,
+    /// Every healthy VPN node reached its `max_connections`, sent as 503 to
+    /// HTTP clients and as X'03' to SOCKS clients
+    #[snafu(display("All VPN nodes at capacity"))]
+    NodesSaturated = 0x503,
+    /// The client opens connections faster than its `connection_rate`, sent
+    /// as 429 to HTTP clients and as X'02' to SOCKS clients
+    #[snafu(display("Too many new connections"))]
+    TooManyConnections = 0x429,
+}
+
+/// What a client should do about a failed request, see
+/// `KittyProxyError::retry_advice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryAdvice {
+    /// Refused by the rules or not supported, retrying fails the same way
+    Never,
+    /// Retry with proxy credentials
+    Authenticate,
+    /// No VPN node available right now
+    Later,
+    /// The connection failed, the next attempt may succeed
+    Immediately,
+}
+
+impl From<ResponseCode> for RetryAdvice {
+    fn from(code: ResponseCode) -> Self {
+        match code {
+            ResponseCode::RuleFailure
+            | ResponseCode::CommandNotSupported
+            | ResponseCode::AddrTypeNotSupported => RetryAdvice::Never,
+            ResponseCode::HttpProxyAuthRequired => RetryAdvice::Authenticate,
+            ResponseCode::NodesSaturated
+            | ResponseCode::NetworkUnreachable
+            | ResponseCode::TooManyConnections => RetryAdvice::Later,
+            ResponseCode::Success
+            | ResponseCode::Failure
+            | ResponseCode::HostUnreachable
+            | ResponseCode::ConnectionRefused
+            | ResponseCode::TtlExpired
+            | ResponseCode::HttpBadGateway => RetryAdvice::Immediately,
+        }
+    }
 }
 
 impl From<KittyProxyError> for ResponseCode {
@@ -60,14 +179,17 @@ impl From<KittyProxyError> for ResponseCode {
             KittyProxyError::Io(_) => ResponseCode::Failure,
             KittyProxyError::ParseError(_) => ResponseCode::Failure,
             KittyProxyError::Error(_) => ResponseCode::Failure,
+            KittyProxyError::UpstreamStalled(_) => ResponseCode::TtlExpired,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub struct NodeInfo {
     pub socket_addr: SocketAddr,
     pub node_number: i8,
+    /// Connections the node accepts at the same time, unlimited when `None`
+    pub max_connections: Option<usize>,
 }
 
 impl NodeInfo {
@@ -75,8 +197,14 @@ impl NodeInfo {
         Self {
             socket_addr: SocketAddr::new(ip_addr, port),
             node_number,
+            max_connections: None,
         }
     }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -87,6 +215,15 @@ pub enum Address {
     DomainNameAddress(String, u16),
 }
 
+impl Address {
+    pub fn port(&self) -> u16 {
+        match *self {
+            Address::SocketAddress(ref addr) => addr.port(),
+            Address::DomainNameAddress(_, port) => port,
+        }
+    }
+}
+
 impl From<NodeInfo> for Address {
     fn from(value: NodeInfo) -> Self {
         Address::SocketAddress(value.socket_addr)
@@ -152,6 +289,152 @@ impl From<(IpAddr, u16)> for Address {
     }
 }
 
+/// Destination of a connection as requested by a client, e.g. `example.com:443`
+/// or `[::1]:22`, as the parsers of the listeners produce it (`SOCKSReq`,
+/// `authority_target` of the HTTP listener, UDP-over-TCP frames) and as it is
+/// logged. The rule engine takes the `host` and `port` apart, it also builds
+/// for wasm32 without this module, and connects go to the `Address` it
+/// converts into.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TargetAddr {
+    pub host: Host,
+    pub port: u16,
+}
+
+impl TargetAddr {
+    pub fn new(host: Host, port: u16) -> Self {
+        Self { host, port }
+    }
+
+    /// Connectable address, see `host_port_to_socketaddr`.
+    pub fn to_address(&self) -> Address {
+        host_port_to_socketaddr(&self.host, self.port)
+    }
+
+    /// Socket addresses to try in order: the IP itself, or the addresses the
+    /// domain resolves to.
+    pub async fn socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match self.to_address() {
+            Address::SocketAddress(addr) => Ok(vec![addr]),
+            Address::DomainNameAddress(domain, port) => crate::dns::resolve(&domain, port).await,
+        }
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // IPv6 hosts display in brackets, with a zone too (`[fe80::1%eth0]:22`)
+        match &self.host {
+            Host::Domain(domain) if domain.contains(':') => write!(f, "[{}]:{}", domain, self.port),
+            host => write!(f, "{}:{}", host, self.port),
+        }
+    }
+}
+
+impl FromStr for TargetAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("missing port in {}", s))?;
+        let port = port.parse().map_err(|e| anyhow!("invalid port in {}: {}", s, e))?;
+        let unbracketed = host.strip_prefix('[').and_then(|h| h.strip_suffix(']'));
+        if let Some(zoned) = unbracketed.filter(|h| scoped_ipv6(h).is_some()) {
+            return Ok(Self { host: Host::Domain(zoned.to_owned()), port });
+        }
+        let host = Host::parse(host).map_err(|e| anyhow!("invalid host in {}: {}", s, e))?;
+        Ok(Self { host, port })
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let host = match addr.ip() {
+            IpAddr::V4(ip) => Host::Ipv4(ip),
+            IpAddr::V6(ip) => Host::Ipv6(ip),
+        };
+        Self::new(host, addr.port())
+    }
+}
+
+impl From<&Address> for TargetAddr {
+    fn from(addr: &Address) -> Self {
+        match addr {
+            Address::SocketAddress(addr) => Self::from(*addr),
+            Address::DomainNameAddress(domain, port) => Self::new(Host::Domain(domain.clone()), *port),
+        }
+    }
+}
+
+impl From<&TargetAddr> for Address {
+    fn from(target: &TargetAddr) -> Self {
+        target.to_address()
+    }
+}
+
+/// Longest domain name, RFC 1035, without the trailing dot.
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Domain of a SOCKS address as sent by the client, rejected with
+/// `ErrorKind::InvalidData` unless it is a sane host name: 1 to 253 bytes of
+/// ASCII letters, digits, `-`, `_` and `.`, a trailing dot allowed.
+/// Internationalized names are expected punycode encoded. Link-local IPv6
+/// addresses with a zone (`fe80::1%eth0`) are accepted too, there is no
+/// other way to send them.
+pub fn socks_domain(bytes: &[u8]) -> io::Result<String> {
+    let name = bytes.strip_suffix(b".").unwrap_or(bytes);
+    let sane = |b: &u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.');
+    let zoned = || std::str::from_utf8(bytes).is_ok_and(|s| scoped_ipv6(s).is_some());
+    if name.is_empty() || name.len() > MAX_DOMAIN_LEN || !(name.iter().all(sane) || zoned()) {
+        let message = format!("invalid domain {:?}", String::from_utf8_lossy(bytes));
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    // ASCII only, see above
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// IPv4-mapped IPv6 hosts (`::ffff:a.b.c.d`) as the IPv4 host they stand
+/// for, so IPv4 rules apply to dual-stack clients too.
+pub fn normalize_host(host: Host) -> Host {
+    match host {
+        Host::Ipv6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => Host::Ipv4(ip),
+            None => Host::Ipv6(ip),
+        },
+        host => host,
+    }
+}
+
+/// Build a connectable address from a parsed host and port, IPv4-mapped
+/// hosts normalized to IPv4.
+///
+/// IPv6 hosts are kept as socket addresses so that they are rendered with
+/// brackets (`[::1]:443`), domains are left for DNS to resolve.
+pub fn host_port_to_socketaddr(host: &Host, port: u16) -> Address {
+    match &normalize_host(host.clone()) {
+        Host::Ipv4(ip) => Address::from((IpAddr::V4(*ip), port)),
+        Host::Ipv6(ip) => Address::from((IpAddr::V6(*ip), port)),
+        Host::Domain(domain) => match scoped_ipv6(domain) {
+            Some((ip, scope_id)) => Address::SocketAddress(SocketAddrV6::new(ip, port, 0, scope_id).into()),
+            None => Address::DomainNameAddress(domain.to_owned(), port),
+        },
+    }
+}
+
+/// IPv6 address with a zone, `fe80::1%eth0` or `fe80::1%2`, as the address
+/// and the scope id of the interface. Interface names are known on Linux and
+/// Windows, elsewhere zones must be numeric.
+pub fn scoped_ipv6(host: &str) -> Option<(Ipv6Addr, u32)> {
+    let (ip, zone) = host.split_once('%')?;
+    let ip = ip.parse().ok()?;
+    let scope_id = match zone.parse() {
+        Ok(index) => index,
+        Err(_) => crate::netif::interface_index(zone)?,
+    };
+    Some((ip, scope_id))
+}
+
 impl From<&Address> for Host {
     fn from(value: &Address) -> Host {
         match value {
@@ -163,3 +446,101 @@ impl From<&Address> for Host {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_tell_whether_to_retry() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(KittyProxyError::from(refused).is_retryable());
+        let invalid = io::Error::from(io::ErrorKind::InvalidData);
+        assert!(!KittyProxyError::from(invalid).is_retryable());
+        let wrapped = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(KittyProxyError::from(wrapped).is_retryable());
+        assert!(!KittyProxyError::from(anyhow!("Not support version: 4.")).is_retryable());
+        let rejected = KittyProxyError::from(ResponseCode::RuleFailure);
+        assert_eq!(rejected.retry_advice(), RetryAdvice::Never);
+        let saturated = KittyProxyError::from(ResponseCode::NodesSaturated);
+        assert_eq!(saturated.retry_advice(), RetryAdvice::Later);
+        let auth = KittyProxyError::from(ResponseCode::HttpProxyAuthRequired);
+        assert!(!auth.is_retryable());
+        assert!(KittyProxyError::UpstreamStalled(Duration::from_secs(30)).is_retryable());
+    }
+
+    #[test]
+    fn host_port_to_socketaddr_works() {
+        let v4 = host_port_to_socketaddr(&Host::Ipv4(Ipv4Addr::new(127, 0, 0, 1)), 1080);
+        assert_eq!(v4.to_string(), "127.0.0.1:1080");
+        let v6 = host_port_to_socketaddr(&Host::Ipv6(Ipv6Addr::LOCALHOST), 443);
+        assert_eq!(v6.to_string(), "[::1]:443");
+        let domain = host_port_to_socketaddr(&Host::Domain("example.com".to_string()), 80);
+        assert_eq!(domain.to_string(), "example.com:80");
+        let mapped = Host::Ipv6("::ffff:10.0.0.1".parse().unwrap());
+        assert_eq!(host_port_to_socketaddr(&mapped, 80).to_string(), "10.0.0.1:80");
+    }
+
+    #[tokio::test]
+    async fn target_addr_round_trips() {
+        for s in ["example.com:443", "10.0.0.1:53", "[::1]:22"] {
+            let target: TargetAddr = s.parse().unwrap();
+            assert_eq!(target.to_string(), s);
+        }
+        let mapped: TargetAddr = "[::ffff:10.0.0.1]:80".parse().unwrap();
+        assert_eq!(mapped.to_address().to_string(), "10.0.0.1:80");
+        assert_eq!(mapped.socket_addrs().await.unwrap(), ["10.0.0.1:80".parse().unwrap()]);
+        let socket: SocketAddr = "[::1]:22".parse().unwrap();
+        assert_eq!(TargetAddr::from(socket).to_string(), "[::1]:22");
+        for invalid in ["example.com", "example.com:http", "exa mple.com:80", ":80"] {
+            assert!(invalid.parse::<TargetAddr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn zoned_ipv6_targets_keep_their_scope() {
+        let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(scoped_ipv6("fe80::1%3"), Some((ip, 3)));
+        assert_eq!(scoped_ipv6("fe80::1"), None);
+        assert_eq!(scoped_ipv6("example.com%3"), None);
+        assert_eq!(scoped_ipv6("fe80::1%no-such-interface0"), None);
+        #[cfg(target_os = "linux")]
+        assert_eq!(scoped_ipv6("fe80::1%lo"), Some((ip, 1)));
+
+        let target: TargetAddr = "[fe80::1%3]:22".parse().unwrap();
+        assert_eq!(target.to_string(), "[fe80::1%3]:22");
+        let Address::SocketAddress(SocketAddr::V6(addr)) = target.to_address() else {
+            panic!("{:?} is not an IPv6 address", target);
+        };
+        assert_eq!((*addr.ip(), addr.port(), addr.scope_id()), (ip, 22, 3));
+        assert_eq!(socks_domain(b"fe80::1%3").unwrap(), "fe80::1%3");
+        assert!(socks_domain(b"fe80::1").is_err());
+    }
+
+    #[test]
+    fn socks_domains_are_host_names() {
+        assert_eq!(socks_domain(b"example.com").unwrap(), "example.com");
+        assert_eq!(socks_domain(b"_srv.xn--bcher-kva.de.").unwrap(), "_srv.xn--bcher-kva.de.");
+        assert!(socks_domain(&[b'a'; 253]).is_ok());
+        assert!(socks_domain(&[b'a'; 254]).is_err());
+        let invalid: [&[u8]; 6] = [
+            b"",
+            b".",
+            b"exa\0mple.com",
+            b"ex ample.com",
+            b"\xff\xfe",
+            "bücher.de".as_bytes(),
+        ];
+        for invalid in invalid {
+            let err = socks_domain(invalid).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn node_info_from_json() {
+        let json = r#"{"socket_addr":"10.0.0.1:1080","node_number":2}"#;
+        let node: NodeInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(node, NodeInfo::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1080, 2));
+    }
+}
@@ -0,0 +1,185 @@
+//! UDP ASSOCIATE with the datagrams carried on the SOCKS5 control connection,
+//! for clients on networks where the UDP relay port can't be reached.
+//!
+//! A client asks for it with its own command, CMD X'05' in place of UDP
+//! ASSOCIATE's X'03', so clients speaking plain RFC 1928 never see the
+//! framing. Listeners without `udp_over_tcp` answer it with X'07' (command not
+//! supported) and the client can fall back to standard UDP ASSOCIATE.
+//!
+//! Every datagram, in both directions, is framed like the UDP request header
+//! of RFC 1928 (S7), the reserved field holding the length of DATA:
+//!
+//!    +-----+------+------+----------+----------+----------+
+//!    | LEN | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+//!    +-----+------+------+----------+----------+----------+
+//!    |  2  |  1   |  1   | Variable |    2     |   LEN    |
+//!    +-----+------+------+----------+----------+----------+
+//!
+//! Frames sent to the client carry the address the datagram came from.
+//! Fragmented datagrams (FRAG not 0) are dropped, as most servers do.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use url::Host;
+
+use crate::types::{normalize_host, socks_domain, TargetAddr};
+
+const ATYP_V4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_V6: u8 = 0x04;
+
+/// A datagram read from the client.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Frame {
+    pub target: TargetAddr,
+    /// Not 0 for a fragment
+    pub frag: u8,
+    pub data: Vec<u8>,
+}
+
+/// Next datagram sent by the client, `None` once it closed the connection.
+pub(crate) async fn read_frame<R>(reader: &mut R) -> io::Result<Option<Frame>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+    let host = match header[3] {
+        ATYP_V4 => {
+            let mut addr = [0u8; 4];
+            reader.read_exact(&mut addr).await?;
+            Host::Ipv4(Ipv4Addr::from(addr))
+        }
+        ATYP_V6 => {
+            let mut addr = [0u8; 16];
+            reader.read_exact(&mut addr).await?;
+            normalize_host(Host::Ipv6(Ipv6Addr::from(addr)))
+        }
+        ATYP_DOMAIN => {
+            let mut dlen = [0u8; 1];
+            reader.read_exact(&mut dlen).await?;
+            let mut domain = vec![0u8; dlen[0] as usize];
+            reader.read_exact(&mut domain).await?;
+            Host::Domain(socks_domain(&domain)?)
+        }
+        atyp => {
+            let message = format!("unknown address type {} in UDP frame", atyp);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+    };
+    let mut port = [0u8; 2];
+    reader.read_exact(&mut port).await?;
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(Frame {
+        target: TargetAddr::new(host, u16::from_be_bytes(port)),
+        frag: header[2],
+        data,
+    }))
+}
+
+/// Send the client a datagram received from `from`.
+pub(crate) async fn write_frame<W>(
+    writer: &mut W,
+    from: SocketAddr,
+    data: &[u8],
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "datagram too large"))?;
+    let mut frame = Vec::with_capacity(22 + data.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.push(0);
+    match from.ip() {
+        IpAddr::V4(ip) => {
+            frame.push(ATYP_V4);
+            frame.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => {
+                frame.push(ATYP_V4);
+                frame.extend_from_slice(&ip.octets());
+            }
+            None => {
+                frame.push(ATYP_V6);
+                frame.extend_from_slice(&ip.octets());
+            }
+        },
+    }
+    frame.extend_from_slice(&from.port().to_be_bytes());
+    frame.extend_from_slice(data);
+    writer.write_all(&frame).await
+}
+
+/// Sockets the datagrams of one association are sent from, the IPv6 one is
+/// missing on hosts without IPv6.
+pub(crate) struct UdpSockets {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+impl UdpSockets {
+    pub async fn bind() -> io::Result<Self> {
+        let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+        Ok(Self { v4, v6 })
+    }
+
+    pub async fn send_to(&self, data: &[u8], target: SocketAddr) -> io::Result<usize> {
+        match (target, &self.v6) {
+            (SocketAddr::V4(_), _) => self.v4.send_to(data, target).await,
+            (SocketAddr::V6(_), Some(v6)) => v6.send_to(data, target).await,
+            (SocketAddr::V6(_), None) => Err(io::ErrorKind::AddrNotAvailable.into()),
+        }
+    }
+
+    /// Next datagram received on either socket.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some(v6) = &self.v6 else {
+            return self.v4.recv_from(buf).await;
+        };
+        loop {
+            let socket = tokio::select! {
+                res = self.v4.readable() => res.map(|_| &self.v4)?,
+                res = v6.readable() => res.map(|_| v6)?,
+            };
+            match socket.try_recv_from(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() -> io::Result<()> {
+        let from: SocketAddr = "[::ffff:10.0.0.1]:53".parse().unwrap();
+        let mut buf = Vec::new();
+        write_frame(&mut buf, from, b"answer").await?;
+        let mut reader = &buf[..];
+        let frame = read_frame(&mut reader).await?.unwrap();
+        assert_eq!(frame.target.host, Host::<String>::Ipv4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!((frame.target.port, frame.frag, &frame.data[..]), (53, 0, &b"answer"[..]));
+        assert!(read_frame(&mut reader).await?.is_none());
+
+        let domain = [&[0, 2, 0, ATYP_DOMAIN, 3][..], b"a.b", &[0, 80], b"hi"].concat();
+        let frame = read_frame(&mut &domain[..]).await?.unwrap();
+        assert_eq!(frame.target.to_string(), "a.b:80");
+        assert_eq!(&frame.data[..], b"hi");
+        Ok(())
+    }
+}
@@ -112,6 +112,15 @@ pub struct GeoSiteList {
     #[prost(message, repeated, tag="1")]
     pub entry: ::prost::alloc::vec::Vec<GeoSite>,
 }
+/// kitty_proxy rule cache: the GeoIP/GeoSite entries actually loaded, so that
+/// the next start does not have to decode the full geoip.dat/geosite.dat.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GeoCache {
+    #[prost(message, repeated, tag="1")]
+    pub geoip: ::prost::alloc::vec::Vec<GeoIp>,
+    #[prost(message, repeated, tag="2")]
+    pub geosite: ::prost::alloc::vec::Vec<GeoSite>,
+}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RoutingRule {
     #[prost(string, tag="18")]
@@ -0,0 +1,86 @@
+//! Run the proxies as a Windows service.
+//!
+//! The service main function (see `windows_service::define_windows_service!`)
+//! creates the shutdown watch channel handed to `serve()`, calls `register`,
+//! starts the proxies and waits for the channel to flip, then reports
+//! `ServiceHandle::stopped`.
+
+use std::time::Duration;
+
+use log::info;
+use tokio::sync::watch;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+
+/// Time the service manager grants us to close the listeners.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+/// Status reporting of a registered service.
+pub struct ServiceHandle {
+    status_handle: ServiceStatusHandle,
+}
+
+fn status(
+    current_state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    wait_hint: Duration,
+) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted,
+        exit_code: ServiceExitCode::NO_ERROR,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    }
+}
+
+/// Register the control handler of `service_name` and report it running. Stop
+/// and Shutdown requests of the service manager send `true` on `shutdown_tx`,
+/// like ctrl-c does for console runs.
+pub fn register(
+    service_name: &str,
+    shutdown_tx: watch::Sender<bool>,
+) -> windows_service::Result<ServiceHandle> {
+    let event_handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            info!("Service stop requested");
+            let _ = shutdown_tx.send(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = service_control_handler::register(service_name, event_handler)?;
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        Duration::default(),
+    ))?;
+    Ok(ServiceHandle { status_handle })
+}
+
+impl ServiceHandle {
+    /// Tell the service manager the listeners are being closed.
+    pub fn stopping(&self) -> windows_service::Result<()> {
+        self.status_handle.set_service_status(status(
+            ServiceState::StopPending,
+            ServiceControlAccept::empty(),
+            STOP_WAIT_HINT,
+        ))
+    }
+
+    /// Report the service stopped, the process should exit right after.
+    pub fn stopped(&self) -> windows_service::Result<()> {
+        self.status_handle.set_service_status(status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            Duration::default(),
+        ))
+    }
+}